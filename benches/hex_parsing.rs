@@ -0,0 +1,181 @@
+//! Compare parsing a 16 MiB image with the previous line-based parsers, which decode all
+//! lines into a `Vec` before filling a dense buffer, with streaming it from a reader.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use merge_tool::config::AddressRange;
+use merge_tool::{intel_hex, srecord};
+
+const IMAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+fn image() -> (AddressRange, Vec<u8>) {
+    let range = AddressRange::new(0x9000_0000, 0x9000_0000 + IMAGE_SIZE);
+    let data = (0..IMAGE_SIZE).map(|x| (x % 251) as u8).collect();
+    (range, data)
+}
+
+/// The parsers as of before streaming, without word addressing, kept as baseline.
+#[allow(clippy::needless_range_loop)]
+mod legacy {
+    use merge_tool::config::AddressRange;
+    use std::io::BufRead;
+
+    struct Line {
+        address: u64,
+        data: Vec<u8>,
+        kind: u8,
+    }
+
+    /// The trimmed, non-empty lines of `text`, as the former `load_lines`.
+    pub fn load_lines(text: &str) -> Vec<String> {
+        text.as_bytes()
+            .lines()
+            .map(|x| x.unwrap().trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect()
+    }
+
+    pub fn intel_hex(range: &AddressRange, lines: Vec<String>) -> Option<Vec<u8>> {
+        let lines: Option<Vec<_>> = lines.into_iter().map(intel_hex_line).collect();
+        let mut extend_line_address = 0_u64;
+        let mut ret = vec![0xFF; range.len() as usize];
+        for line in &lines? {
+            match line.kind {
+                0x04 | 0x02 if line.address != 0 => return None,
+                0x04 => {
+                    extend_line_address = ((line.data[0] as u64) << 8) + line.data[1] as u64;
+                    extend_line_address <<= 16;
+                }
+                0x02 => {
+                    extend_line_address = ((line.data[0] as u64) << 8) + line.data[1] as u64;
+                    extend_line_address *= 16;
+                }
+                0x00 => {
+                    let addr = extend_line_address + line.address;
+                    if addr < range.begin || addr >= range.end {
+                        continue;
+                    }
+                    for k in 0..line.data.len() {
+                        let idx = k + (addr as usize) - (range.begin as usize);
+                        ret[idx] = line.data[k];
+                    }
+                }
+                0x01 => break,
+                _ => {}
+            }
+        }
+        Some(ret)
+    }
+
+    fn intel_hex_line(line: String) -> Option<Line> {
+        if line.as_bytes().first() != Some(&b':') {
+            return None;
+        }
+        let data = hex::decode(&line[1..]).ok()?;
+        let count = data[0] as usize;
+        if count + 5 != data.len() {
+            return None;
+        }
+        Some(Line {
+            address: ((data[1] as u64) << 8) + data[2] as u64,
+            data: data[4..4 + count].to_vec(),
+            kind: data[3],
+        })
+    }
+
+    pub fn srecord(range: &AddressRange, lines: Vec<String>) -> Option<Vec<u8>> {
+        let lines: Option<Vec<_>> = lines.into_iter().map(srecord_line).collect();
+        let mut ret = vec![0xFF; range.len() as usize];
+        for line in lines? {
+            match line.kind {
+                0 => continue,
+                3 => {
+                    let addr = line.address;
+                    if addr < range.begin || addr > range.end {
+                        continue;
+                    }
+                    for k in 0..line.data.len() {
+                        let idx = k + (addr as usize) - (range.begin as usize);
+                        ret[idx] = line.data[k];
+                    }
+                }
+                7 => break,
+                _ => return None,
+            }
+        }
+        Some(ret)
+    }
+
+    fn srecord_line(line: String) -> Option<Line> {
+        let line = line.as_bytes();
+        if line.len() < 2 || line[0] != b'S' {
+            return None;
+        }
+        let kind = (line[1] as char).to_digit(10)? as u8;
+        let decoded = hex::decode(&line[2..]).ok()?;
+        if decoded[0] as usize != decoded.len() - 1 {
+            return None;
+        }
+        let sum: u32 = decoded.iter().map(|x| *x as u32).sum();
+        if sum & 0xFF != 0xFF {
+            return None;
+        }
+        let addr = &decoded[1..5];
+        Some(Line {
+            address: ((addr[0] as u64) << 24)
+                | ((addr[1] as u64) << 16)
+                | ((addr[2] as u64) << 8)
+                | (addr[3] as u64),
+            data: decoded[5..decoded.len() - 1].to_vec(),
+            kind,
+        })
+    }
+}
+
+fn intel_hex(c: &mut Criterion) {
+    let (range, data) = image();
+    let text = intel_hex::serialize(false, &range, &data);
+    assert_eq!(
+        legacy::intel_hex(&range, legacy::load_lines(&text)),
+        Some(data)
+    );
+    let mut group = c.benchmark_group("intel_hex_16MiB");
+    group.sample_size(10);
+    group.bench_function("collected_lines", |b| {
+        b.iter(|| legacy::intel_hex(&range, legacy::load_lines(&text)).unwrap())
+    });
+    group.bench_function("streaming", |b| {
+        b.iter(|| {
+            intel_hex::read(text.as_bytes(), false)
+                .unwrap()
+                .segments
+                .to_dense(&range, 0xFF)
+        })
+    });
+    group.finish();
+}
+
+fn srecord(c: &mut Criterion) {
+    let (range, data) = image();
+    let text = srecord::serialize(false, &range, &data);
+    assert_eq!(
+        legacy::srecord(&range, legacy::load_lines(&text)),
+        Some(data)
+    );
+    let mut group = c.benchmark_group("srecord_16MiB");
+    group.sample_size(10);
+    group.bench_function("collected_lines", |b| {
+        b.iter(|| legacy::srecord(&range, legacy::load_lines(&text)).unwrap())
+    });
+    group.bench_function("streaming", |b| {
+        b.iter(|| {
+            srecord::read(text.as_bytes(), false)
+                .unwrap()
+                .segments
+                .to_dense(&range, 0xFF)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, intel_hex, srecord);
+criterion_main!(benches);
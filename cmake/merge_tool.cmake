@@ -73,3 +73,47 @@ function(merge_tool_bundle)
         COMMAND "${MERGE_TOOL_BIN}" bundle -i ${ARG_INFO_FILE} --output-dir ${ARG_OUTPUT_DIR} ${VERSIONED_FLAG}
         DEPENDS ${ARG_INFO_FILE})
 endfunction()
+
+function(merge_tool_extract)
+    set(oneValueArgs TARGET_NAME CONFIG_FILE IMAGE APP PREFIX C_HEADER ENV_FILE JSON_FILE)
+
+    cmake_parse_arguments(ARG "" "${oneValueArgs}" "" ${ARGN})
+
+    set(EXTRACT_ARGS "")
+    set(EXTRACT_OUTPUTS "")
+
+    if(ARG_CONFIG_FILE)
+        list(APPEND EXTRACT_ARGS -c "${ARG_CONFIG_FILE}")
+    endif()
+
+    if(DEFINED ARG_IMAGE)
+        list(APPEND EXTRACT_ARGS --image ${ARG_IMAGE})
+    endif()
+
+    if(ARG_PREFIX)
+        list(APPEND EXTRACT_ARGS --prefix ${ARG_PREFIX})
+    endif()
+
+    if(ARG_C_HEADER)
+        list(APPEND EXTRACT_ARGS --c-header "${ARG_C_HEADER}")
+        list(APPEND EXTRACT_OUTPUTS ${ARG_C_HEADER})
+    endif()
+
+    if(ARG_ENV_FILE)
+        list(APPEND EXTRACT_ARGS --env "${ARG_ENV_FILE}")
+        list(APPEND EXTRACT_OUTPUTS ${ARG_ENV_FILE})
+    endif()
+
+    if(ARG_JSON_FILE)
+        list(APPEND EXTRACT_ARGS --json "${ARG_JSON_FILE}")
+        list(APPEND EXTRACT_OUTPUTS ${ARG_JSON_FILE})
+    endif()
+
+    add_custom_command(
+        OUTPUT ${EXTRACT_OUTPUTS}
+        DEPENDS ${ARG_APP} ${ARG_CONFIG_FILE}
+        COMMAND "${MERGE_TOOL_BIN}" extract ${EXTRACT_ARGS} "${ARG_APP}"
+    )
+
+    add_custom_target(${ARG_TARGET_NAME} ALL DEPENDS ${EXTRACT_OUTPUTS})
+endfunction()
@@ -18,13 +18,16 @@ pub const JSON_FILE_EXTENSION: &'static str = "gctapkg.json";
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct App {
-    pub product_id: u16,
+    pub product_id: u32,
     pub node_id: u8,
     pub version: Version,
     pub crc: u32,
 
     #[serde(default = "default_signature_type")]
     pub signature_type: SignatureType,
+    /// Anti-rollback security version written into the header.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub security_version: Option<u16>,
 
     pub image: Vec<Section>,
 }
@@ -35,14 +38,30 @@ fn default_signature_type() -> SignatureType {
 
 impl App {
     pub fn from_loaded_firmware(
-        product_id: u16,
+        product_id: u32,
         loaded_fw: &crate::process::LoadedFirmware,
     ) -> Self {
         let app = &loaded_fw.app;
         let config = &loaded_fw.config;
 
-        let app_data = &app.data[0..loaded_fw.app.image_length()];
-        let image = Section::new(app.range.begin, app_data.to_vec());
+        let mut image: Vec<_> = app
+            .written_pages(app.image_length())
+            .iter()
+            .map(|(address, data)| Section::new(address, data.to_vec()))
+            .collect();
+        for region in &loaded_fw.regions {
+            if !region.config.include_in_script {
+                continue;
+            }
+            let fw = &region.fw;
+            image.extend(
+                fw.written_pages(fw.image_length())
+                    .iter()
+                    .map(|(address, data)| {
+                        Section::with_region(&region.config.name, address, data.to_vec())
+                    }),
+            );
+        }
 
         App {
             product_id: product_id,
@@ -50,7 +69,8 @@ impl App {
             version: config.version.clone().unwrap_or(Version::new(0, 0, 0)),
             crc: loaded_fw.load_crc(),
             signature_type: config.signature_type,
-            image: vec![image],
+            security_version: config.fixed_security_version(),
+            image,
         }
     }
 }
@@ -60,6 +80,9 @@ pub struct Section {
     offset: u64,
     #[serde(with = "base64")]
     data: Vec<u8>,
+    /// Name of the app region the section belongs to, `None` for the application.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    region: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -73,7 +96,7 @@ impl AppPackage {
     }
 
     pub fn from_loaded_firmware_images(
-        product_id: u16,
+        product_id: u32,
         fws: &crate::process::LoadedFirmwareImages,
     ) -> Self {
         let apps = fws
@@ -117,7 +140,32 @@ impl AppPackage {
 
 impl Section {
     pub fn new(offset: u64, data: Vec<u8>) -> Self {
-        Section { offset, data }
+        Section {
+            offset,
+            data,
+            region: None,
+        }
+    }
+
+    /// A section of the app region `region`.
+    pub fn with_region(region: &str, offset: u64, data: Vec<u8>) -> Self {
+        Section {
+            offset,
+            data,
+            region: Some(region.to_string()),
+        }
+    }
+
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
     }
 }
 
@@ -184,12 +232,16 @@ mod test {
     #[test]
     fn test_app_package() {
         let app = App {
-            product_id: 0x1234,
+            product_id: 0x1234_5678,
             node_id: 0x12,
             version: Version::new(1, 2, 3),
             crc: 0x12345678,
             signature_type: SignatureType::Unsigned,
-            image: vec![Section::new(0, vec![0x12, 0x34, 0x56, 0x78])],
+            security_version: Some(3),
+            image: vec![
+                Section::new(0, vec![0x12, 0x34, 0x56, 0x78]),
+                Section::with_region("qspi", 0x9000_0000, vec![0xAB]),
+            ],
         };
 
         let app_package = AppPackage::new(vec![app]);
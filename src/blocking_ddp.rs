@@ -46,9 +46,13 @@ impl Protocol for BlockingDdpProtocol {
         )]
     }
 
-    fn start_transmit(&self, fw_id: u8, _erase_time: u32) -> Vec<Command> {
+    fn start_transmit(&self, fw_id: u8, address_space: u8, _erase_time: u32) -> Vec<Command> {
+        let mut tx = vec![self.ddp_code | 0x80, fw_id, CMD_START_TRANSMIT];
+        if address_space != 0 {
+            tx.push(address_space);
+        }
         vec![ddp::query(
-            vec![self.ddp_code | 0x80, fw_id, CMD_START_TRANSMIT],
+            tx,
             vec![COM_OK, fw_id, STATE_RX_DATA, STATUS_SUCCESS],
         )]
     }
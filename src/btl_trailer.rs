@@ -1,9 +1,14 @@
+use byteorder::{ByteOrder, LittleEndian};
+use semver::Version;
+use serde::Serialize;
+
 use crate::crc::crc32;
 use crate::firmware::Firmware;
 use crate::Error;
 
 pub const TRAILER_MAGIC: [u8; 4] = [0x15, 0xE0, 0x27, 0x53];
 pub const TRAILER_VERSION: u8 = 1;
+pub const TRAILER_VERSION_2: u8 = 2;
 
 /// Byte length of the trailer content fields (magic + version + reserved + btl_len + btl_crc).
 const TRAILER_CONTENT_SIZE: usize = 16;
@@ -12,9 +17,116 @@ const TRAILER_CONTENT_SIZE: usize = 16;
 /// (trailer_length: 4 B + trailer_checksum: 4 B).
 const TRAILER_META_SIZE: usize = 8;
 
-/// Total bytes consumed at the end of the BTL buffer.
+/// Total bytes consumed at the end of the BTL buffer by a version 1 trailer.
 pub const TRAILER_TOTAL_SIZE: usize = TRAILER_CONTENT_SIZE + TRAILER_META_SIZE;
 
+/// Type codes of the TLV fields of a version 2 trailer.
+pub const TLV_PADDING: u8 = 0x00;
+pub const TLV_BTL_VERSION: u8 = 0x01;
+pub const TLV_PRODUCT_ID: u8 = 0x02;
+pub const TLV_TIMESTAMP: u8 = 0x03;
+pub const TLV_PROTOCOL_VERSION: u8 = 0x04;
+pub const TLV_TRUSTED_KEY_IDS: u8 = 0x05;
+
+/// A TLV field of a version 2 trailer.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum TrailerField {
+    /// Encoded as major (u16), minor (u16) and patch (u32), like the firmware header.
+    BtlVersion(Version),
+    ProductId(u32),
+    /// Build time in seconds since the unix epoch (u64).
+    Timestamp(u64),
+    ProtocolVersion(u8),
+    /// CRC32 of each public key trusted by the bootloader (u32 each).
+    TrustedKeyIds(Vec<u32>),
+    /// A field unknown to this version of the tool. Readers must skip unknown fields.
+    Unknown {
+        tag: u8,
+        value: Vec<u8>,
+    },
+}
+
+impl TrailerField {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), Error> {
+        let (tag, value) = match self {
+            TrailerField::BtlVersion(version) => {
+                let mut value = vec![0_u8; 8];
+                LittleEndian::write_u16(&mut value[0..2], version.major as u16);
+                LittleEndian::write_u16(&mut value[2..4], version.minor as u16);
+                LittleEndian::write_u32(&mut value[4..8], version.patch as u32);
+                (TLV_BTL_VERSION, value)
+            }
+            // 16-bit product IDs keep the 2-byte encoding of older bootloaders
+            TrailerField::ProductId(x) if *x <= 0xFFFF => {
+                (TLV_PRODUCT_ID, (*x as u16).to_le_bytes().to_vec())
+            }
+            TrailerField::ProductId(x) => (TLV_PRODUCT_ID, x.to_le_bytes().to_vec()),
+            TrailerField::Timestamp(x) => (TLV_TIMESTAMP, x.to_le_bytes().to_vec()),
+            TrailerField::ProtocolVersion(x) => (TLV_PROTOCOL_VERSION, vec![*x]),
+            TrailerField::TrustedKeyIds(ids) => (
+                TLV_TRUSTED_KEY_IDS,
+                ids.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            ),
+            TrailerField::Unknown { tag, value } => (*tag, value.clone()),
+        };
+        if tag == TLV_PADDING || value.len() > u8::MAX as usize {
+            return Err(Error::InvalidBtlTrailerField);
+        }
+        out.push(tag);
+        out.push(value.len() as u8);
+        out.extend(value);
+        Ok(())
+    }
+
+    fn decode(tag: u8, value: &[u8]) -> Result<Self, Error> {
+        let expect_len = |len: usize| {
+            if value.len() == len {
+                Ok(())
+            } else {
+                Err(Error::InvalidBtlTrailerField)
+            }
+        };
+        let ret = match tag {
+            TLV_BTL_VERSION => {
+                expect_len(8)?;
+                TrailerField::BtlVersion(Version::new(
+                    LittleEndian::read_u16(&value[0..2]) as u64,
+                    LittleEndian::read_u16(&value[2..4]) as u64,
+                    LittleEndian::read_u32(&value[4..8]) as u64,
+                ))
+            }
+            TLV_PRODUCT_ID if value.len() == 4 => {
+                TrailerField::ProductId(LittleEndian::read_u32(value))
+            }
+            TLV_PRODUCT_ID => {
+                expect_len(2)?;
+                TrailerField::ProductId(LittleEndian::read_u16(value) as u32)
+            }
+            TLV_TIMESTAMP => {
+                expect_len(8)?;
+                TrailerField::Timestamp(LittleEndian::read_u64(value))
+            }
+            TLV_PROTOCOL_VERSION => {
+                expect_len(1)?;
+                TrailerField::ProtocolVersion(value[0])
+            }
+            TLV_TRUSTED_KEY_IDS => {
+                let chunks = value.chunks_exact(4);
+                if !chunks.remainder().is_empty() {
+                    return Err(Error::InvalidBtlTrailerField);
+                }
+                let ids = chunks.map(LittleEndian::read_u32).collect();
+                TrailerField::TrustedKeyIds(ids)
+            }
+            tag => TrailerField::Unknown {
+                tag,
+                value: value.to_vec(),
+            },
+        };
+        Ok(ret)
+    }
+}
+
 /// Write the bootloader trailer into the last [`TRAILER_TOTAL_SIZE`] bytes of the BTL firmware
 /// data buffer.
 ///
@@ -42,40 +154,160 @@ pub const TRAILER_TOTAL_SIZE: usize = TRAILER_CONTENT_SIZE + TRAILER_META_SIZE;
 /// - `buf_len − 8`: Trailer length = 16 (u32 LE)
 /// - `buf_len − 4`: CRC32 over the 16-byte content (u32 LE)
 ///
-/// Returns [`Error::BtlTooSmallForTrailer`] when the firmware buffer lacks sufficient space.
+/// Returns [`Error::BtlTooSmallForTrailer`] when the firmware buffer lacks sufficient space
+/// and [`Error::BtlTrailerAlreadyPresent`] if the bootloader already carries a valid trailer.
 pub fn write_btl_trailer(btl: &mut Firmware) -> Result<(), Error> {
+    write_trailer(btl, TRAILER_VERSION, &[])
+}
+
+/// Write a version 2 bootloader trailer, which extends the version 1 content with
+/// TLV fields.
+///
+/// The 16-byte content of version 1 is followed by the `fields`, each encoded as type (u8),
+/// length (u8) and value. The fields are padded with [`TLV_PADDING`] bytes (which have no
+/// length byte) such that the trailer length is a multiple of 4.
+pub fn write_btl_trailer_v2(btl: &mut Firmware, fields: &[TrailerField]) -> Result<(), Error> {
+    let mut body = Vec::new();
+    for field in fields {
+        field.encode(&mut body)?;
+    }
+    while body.len() % 4 != 0 {
+        body.push(TLV_PADDING);
+    }
+    write_trailer(btl, TRAILER_VERSION_2, &body)
+}
+
+fn write_trailer(btl: &mut Firmware, version: u8, body: &[u8]) -> Result<(), Error> {
+    if let Ok(Some(_)) = BtlTrailer::parse(btl) {
+        return Err(Error::BtlTrailerAlreadyPresent);
+    }
     let buf_len = btl.data.len();
     let btl_length = btl.image_length();
+    let content_size = TRAILER_CONTENT_SIZE + body.len();
 
-    if btl_length + TRAILER_TOTAL_SIZE > buf_len {
+    if btl_length + content_size + TRAILER_META_SIZE > buf_len {
         return Err(Error::BtlTooSmallForTrailer);
     }
 
     let btl_crc = crc32(&btl.data[0..btl_length]);
 
-    // Build the 16-byte content block.
-    let mut content = [0u8; TRAILER_CONTENT_SIZE];
+    // Build the content block.
+    let mut content = vec![0u8; TRAILER_CONTENT_SIZE];
     content[0..4].copy_from_slice(&TRAILER_MAGIC);
-    content[4] = TRAILER_VERSION;
+    content[4] = version;
     // bytes 5..8: reserved, already zero
     content[8..12].copy_from_slice(&(btl_length as u32).to_le_bytes());
     content[12..16].copy_from_slice(&btl_crc.to_le_bytes());
+    content.extend_from_slice(body);
 
     let content_crc = crc32(&content);
 
     // Write content.
-    let content_start = buf_len - TRAILER_TOTAL_SIZE;
-    btl.data[content_start..content_start + TRAILER_CONTENT_SIZE].copy_from_slice(&content);
+    let meta_start = buf_len - TRAILER_META_SIZE;
+    btl.write(meta_start - content_size, &content);
 
     // Write meta-data.
-    let meta_start = buf_len - TRAILER_META_SIZE;
-    btl.data[meta_start..meta_start + 4]
-        .copy_from_slice(&(TRAILER_CONTENT_SIZE as u32).to_le_bytes());
-    btl.data[buf_len - 4..buf_len].copy_from_slice(&content_crc.to_le_bytes());
+    btl.write(meta_start, &(content_size as u32).to_le_bytes());
+    btl.write(buf_len - 4, &content_crc.to_le_bytes());
 
     Ok(())
 }
 
+/// A bootloader trailer read back from a bootloader image.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BtlTrailer {
+    pub version: u8,
+    /// Length of the trailer content, as stored in the meta-data.
+    pub content_length: u32,
+    pub btl_length: u32,
+    pub btl_crc: u32,
+    /// TLV fields of a version 2 trailer. Empty for version 1.
+    pub fields: Vec<TrailerField>,
+}
+
+impl BtlTrailer {
+    /// Read the trailer from the last bytes of the BTL firmware data buffer.
+    ///
+    /// Returns `None` if the trailer meta-data is erased, i.e. no trailer was written.
+    /// Otherwise the trailer is decoded in the same order a bootloader would do it: the
+    /// meta-data is walked backwards to locate the content (length and checksum), then the
+    /// magic and version are checked and finally the CRC of the bootloader is validated.
+    pub fn parse(btl: &Firmware) -> Result<Option<Self>, Error> {
+        Self::parse_data(&btl.data)
+    }
+
+    /// Same as [`BtlTrailer::parse`], for a bootloader region ending at the end of `data`.
+    pub fn parse_data(data: &[u8]) -> Result<Option<Self>, Error> {
+        let buf_len = data.len();
+        if buf_len < TRAILER_META_SIZE {
+            return Ok(None);
+        }
+        let meta_start = buf_len - TRAILER_META_SIZE;
+        if data[meta_start..].iter().all(|x| *x == 0xFF) {
+            return Ok(None);
+        }
+
+        let content_length = LittleEndian::read_u32(&data[meta_start..meta_start + 4]);
+        let content_size = content_length as usize;
+        if content_size < TRAILER_CONTENT_SIZE || content_size > meta_start {
+            return Err(Error::InvalidBtlTrailerLength);
+        }
+        let content_start = meta_start - content_size;
+        let content = &data[content_start..meta_start];
+        if crc32(content) != LittleEndian::read_u32(&data[buf_len - 4..]) {
+            return Err(Error::InvalidBtlTrailerChecksum);
+        }
+
+        if content[0..4] != TRAILER_MAGIC {
+            return Err(Error::InvalidBtlTrailerMagic);
+        }
+        let version = content[4];
+        let fields = match version {
+            TRAILER_VERSION if content_size != TRAILER_CONTENT_SIZE => {
+                return Err(Error::InvalidBtlTrailerLength)
+            }
+            TRAILER_VERSION => Vec::new(),
+            TRAILER_VERSION_2 => Self::parse_fields(&content[TRAILER_CONTENT_SIZE..])?,
+            _ => return Err(Error::UnsupportedBtlTrailerVersion(version)),
+        };
+
+        let btl_length = LittleEndian::read_u32(&content[8..12]);
+        let btl_crc = LittleEndian::read_u32(&content[12..16]);
+        if btl_length as usize > content_start {
+            return Err(Error::InvalidBtlLength);
+        }
+        if crc32(&data[..btl_length as usize]) != btl_crc {
+            return Err(Error::InvalidBtlCrc);
+        }
+
+        Ok(Some(BtlTrailer {
+            version,
+            content_length,
+            btl_length,
+            btl_crc,
+            fields,
+        }))
+    }
+
+    fn parse_fields(mut body: &[u8]) -> Result<Vec<TrailerField>, Error> {
+        let mut ret = Vec::new();
+        while let Some((&tag, rest)) = body.split_first() {
+            if tag == TLV_PADDING {
+                body = rest;
+                continue;
+            }
+            let (&len, rest) = rest.split_first().ok_or(Error::InvalidBtlTrailerField)?;
+            if rest.len() < len as usize {
+                return Err(Error::InvalidBtlTrailerField);
+            }
+            let (value, rest) = rest.split_at(len as usize);
+            ret.push(TrailerField::decode(tag, value)?);
+            body = rest;
+        }
+        Ok(ret)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -198,6 +430,198 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn error_when_trailer_is_written_twice() {
+        let mut btl = make_btl(128, 64);
+        write_btl_trailer(&mut btl).unwrap();
+        assert!(matches!(
+            write_btl_trailer(&mut btl),
+            Err(Error::BtlTrailerAlreadyPresent)
+        ));
+    }
+
+    // ── Parsing ──────────────────────────────────────────────────────────────
+
+    /// Overwrite a content byte of a written trailer and fix up the trailer checksum.
+    fn patch_content(btl: &mut Firmware, offset: usize, value: u8) {
+        let n = btl.data.len();
+        let content_start = n - TRAILER_TOTAL_SIZE;
+        btl.data[content_start + offset] = value;
+        let crc = crc32(&btl.data[content_start..content_start + TRAILER_CONTENT_SIZE]);
+        btl.data[n - 4..].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn parse_returns_written_trailer() {
+        let mut btl = make_btl(192, 50);
+        let btl_length = btl.image_length();
+        let btl_crc = crc32(&btl.data[..btl_length]);
+        write_btl_trailer(&mut btl).unwrap();
+        assert_eq!(
+            BtlTrailer::parse(&btl).unwrap(),
+            Some(BtlTrailer {
+                version: TRAILER_VERSION,
+                content_length: TRAILER_CONTENT_SIZE as u32,
+                btl_length: btl_length as u32,
+                btl_crc,
+                fields: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_returns_none_without_trailer() {
+        let btl = make_btl(128, 64);
+        assert_eq!(BtlTrailer::parse(&btl).unwrap(), None);
+    }
+
+    #[test]
+    fn parse_detects_invalid_length() {
+        let mut btl = make_btl(128, 64);
+        write_btl_trailer(&mut btl).unwrap();
+        let n = btl.data.len();
+        btl.data[n - TRAILER_META_SIZE] = 200;
+        assert!(matches!(
+            BtlTrailer::parse(&btl),
+            Err(Error::InvalidBtlTrailerLength)
+        ));
+    }
+
+    #[test]
+    fn parse_detects_invalid_checksum() {
+        let mut btl = make_btl(128, 64);
+        write_btl_trailer(&mut btl).unwrap();
+        let n = btl.data.len();
+        btl.data[n - TRAILER_TOTAL_SIZE + 8] ^= 0x01;
+        assert!(matches!(
+            BtlTrailer::parse(&btl),
+            Err(Error::InvalidBtlTrailerChecksum)
+        ));
+    }
+
+    #[test]
+    fn parse_detects_invalid_magic() {
+        let mut btl = make_btl(128, 64);
+        write_btl_trailer(&mut btl).unwrap();
+        patch_content(&mut btl, 0, 0x00);
+        assert!(matches!(
+            BtlTrailer::parse(&btl),
+            Err(Error::InvalidBtlTrailerMagic)
+        ));
+    }
+
+    #[test]
+    fn parse_detects_unsupported_version() {
+        let mut btl = make_btl(128, 64);
+        write_btl_trailer(&mut btl).unwrap();
+        patch_content(&mut btl, 4, 7);
+        assert!(matches!(
+            BtlTrailer::parse(&btl),
+            Err(Error::UnsupportedBtlTrailerVersion(7))
+        ));
+    }
+
+    #[test]
+    fn parse_detects_invalid_btl_length() {
+        let mut btl = make_btl(128, 64);
+        write_btl_trailer(&mut btl).unwrap();
+        patch_content(&mut btl, 9, 0x01);
+        assert!(matches!(
+            BtlTrailer::parse(&btl),
+            Err(Error::InvalidBtlLength)
+        ));
+    }
+
+    #[test]
+    fn parse_detects_modified_bootloader() {
+        let mut btl = make_btl(128, 64);
+        write_btl_trailer(&mut btl).unwrap();
+        btl.data[10] ^= 0xFF;
+        assert!(matches!(BtlTrailer::parse(&btl), Err(Error::InvalidBtlCrc)));
+    }
+
+    // ── Version 2 ────────────────────────────────────────────────────────────
+
+    fn v2_fields() -> Vec<TrailerField> {
+        vec![
+            TrailerField::BtlVersion(Version::new(1, 2, 3)),
+            TrailerField::ProductId(0x605),
+            TrailerField::Timestamp(1_600_000_000),
+            TrailerField::ProtocolVersion(1),
+            TrailerField::TrustedKeyIds(vec![0x12345678, 0x9ABCDEF0]),
+            TrailerField::Unknown {
+                tag: 0x80,
+                value: vec![1, 2, 3],
+            },
+        ]
+    }
+
+    #[test]
+    fn v2_trailer_round_trip() {
+        let mut btl = make_btl(256, 50);
+        let btl_length = btl.image_length() as u32;
+        write_btl_trailer_v2(&mut btl, &v2_fields()).unwrap();
+
+        let n = btl.data.len();
+        let content_length = read_u32_le(&btl.data, n - TRAILER_META_SIZE);
+        assert_eq!(content_length % 4, 0);
+        let content_start = n - TRAILER_META_SIZE - content_length as usize;
+        assert_eq!(&btl.data[content_start..content_start + 4], &TRAILER_MAGIC);
+
+        let trailer = BtlTrailer::parse(&btl).unwrap().unwrap();
+        assert_eq!(trailer.version, TRAILER_VERSION_2);
+        assert_eq!(trailer.content_length, content_length);
+        assert_eq!(trailer.btl_length, btl_length);
+        assert_eq!(trailer.fields, v2_fields());
+    }
+
+    #[test]
+    fn v2_product_id_above_16_bits() {
+        for product_id in [0x605, 0x1234_5678] {
+            let mut btl = make_btl(256, 50);
+            write_btl_trailer_v2(&mut btl, &[TrailerField::ProductId(product_id)]).unwrap();
+            let trailer = BtlTrailer::parse(&btl).unwrap().unwrap();
+            assert_eq!(trailer.fields, vec![TrailerField::ProductId(product_id)]);
+        }
+    }
+
+    #[test]
+    fn v2_trailer_without_fields_has_v1_size() {
+        let mut btl = make_btl(128, 64);
+        write_btl_trailer_v2(&mut btl, &[]).unwrap();
+        let trailer = BtlTrailer::parse(&btl).unwrap().unwrap();
+        assert_eq!(trailer.content_length as usize, TRAILER_CONTENT_SIZE);
+        assert!(trailer.fields.is_empty());
+    }
+
+    #[test]
+    fn v2_error_when_fields_leave_no_room() {
+        // 64 bytes of code, 40 bytes of fields and 24 bytes of trailer exceed 128 bytes.
+        let mut btl = make_btl(128, 64);
+        let fields = vec![TrailerField::TrustedKeyIds(vec![0; 10])];
+        assert!(matches!(
+            write_btl_trailer_v2(&mut btl, &fields),
+            Err(Error::BtlTooSmallForTrailer)
+        ));
+    }
+
+    #[test]
+    fn v2_parse_detects_truncated_field() {
+        let mut btl = make_btl(128, 64);
+        write_btl_trailer_v2(&mut btl, &[TrailerField::ProductId(1)]).unwrap();
+        // the product ID field follows the 16-byte v1 content: tag, length, value
+        let n = btl.data.len();
+        let field_start = n - TRAILER_META_SIZE - 4;
+        btl.data[field_start + 1] = 3;
+        let content_start = n - TRAILER_META_SIZE - TRAILER_CONTENT_SIZE - 4;
+        let crc = crc32(&btl.data[content_start..n - TRAILER_META_SIZE]);
+        btl.data[n - 4..].copy_from_slice(&crc.to_le_bytes());
+        assert!(matches!(
+            BtlTrailer::parse(&btl),
+            Err(Error::InvalidBtlTrailerField)
+        ));
+    }
+
     // ── Idempotency / full-round-trip ────────────────────────────────────────
 
     #[test]
@@ -20,6 +20,33 @@ pub fn extract_version_from_changelog_file(fpath: &Path) -> Result<Version, Erro
     extract_version_from_changelog(&changelog)
 }
 
+/// Derive the anti-rollback security version from a changelog: it is the number of
+/// released versions with a `### Security` section. Unreleased changes are not counted.
+pub fn extract_security_version_from_changelog(changelog: &str) -> Result<u16, Error> {
+    let mut released = None;
+    let mut counted = false;
+    let mut security_version = 0_u16;
+    for line in changelog.lines() {
+        let line = line.trim();
+        if line.starts_with("## ") {
+            released = Some(parse_version_from_changelog_line(line).is_ok());
+            counted = false;
+        } else if line.starts_with("### Security") && released == Some(true) && !counted {
+            security_version += 1;
+            counted = true;
+        }
+    }
+    if released.is_none() {
+        return Err(Error::CannotParseChangelog);
+    }
+    Ok(security_version)
+}
+
+pub fn extract_security_version_from_changelog_file(fpath: &Path) -> Result<u16, Error> {
+    let changelog = fs::read_to_string(fpath)?;
+    extract_security_version_from_changelog(&changelog)
+}
+
 fn parse_version_from_changelog_line(line: &str) -> Result<Version, Error> {
     // line is prepended by "## " and version is between [ ] and is of the form "X.Y.Z"
     // use a regex to extract the version
@@ -43,4 +70,32 @@ mod tests {
         let version = extract_version_from_changelog(changelog).unwrap();
         assert_eq!(version, Version::new(0, 1, 0));
     }
+
+    #[test]
+    fn test_extract_security_version_from_changelog() {
+        let changelog = r#"
+## [Unreleased]
+### Security
+- Not yet released
+
+## [1.2.0] - 2021-03-01
+### Security
+- Fix buffer overflow
+### Security
+- Duplicate section
+
+## [1.1.0] - 2021-02-01
+### Fixed
+- Typo
+
+## [1.0.0] - 2021-01-01
+### Security
+- Check signature
+"#;
+        assert_eq!(
+            extract_security_version_from_changelog(changelog).unwrap(),
+            2
+        );
+        assert!(extract_security_version_from_changelog("no versions").is_err());
+    }
 }
@@ -1,8 +1,13 @@
+use crate::elf;
+use crate::header::{HeaderLayout, V1_LAYOUT};
+use crate::metadata::MetadataEntry;
+use crate::signer::SignerConfig;
 use crate::Error;
 use chrono::{DateTime, Utc};
 use regex::Regex;
 use semver::Version;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::{self, canonicalize, File};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
@@ -12,7 +17,7 @@ pub const DDP_CMD_CODE: u8 = 0x10;
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Config {
     #[serde(default = "default::product_id")]
-    pub product_id: u16,
+    pub product_id: u32,
     pub product_name: String,
     #[serde(default = "default::btl_version")]
     pub btl_version: u8,
@@ -32,12 +37,60 @@ pub struct Config {
 
     #[serde(skip)]
     pub ed25519_private_key: Option<[u8; 32]>,
+
+    #[serde(skip)]
+    pub ecdsa_p256_private_key: Option<[u8; 32]>,
+
+    /// Creates the signatures of signed images.
+    #[serde(default = "Default::default")]
+    pub signer: SignerConfig,
+
+    /// Leave signed images unsigned, such that their digests can be signed separately.
+    #[serde(skip)]
+    pub defer_signing: bool,
+
+    /// User-defined header layouts, referenced by name from `FwConfig::header_layout`.
+    #[serde(
+        default = "Default::default",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub header_layouts: BTreeMap<String, HeaderLayout>,
 }
 
 #[derive(Eq, PartialEq, Copy, Clone, Serialize, Deserialize, Debug)]
 pub enum SignatureType {
     Unsigned,
     Ed25519,
+    /// ECDSA on the NIST P-256 curve over the SHA-256 digest of the image.
+    EcdsaP256,
+}
+
+impl SignatureType {
+    /// Offset of the image CRC, which follows the signature.
+    pub fn crc_offset(&self) -> usize {
+        match self {
+            SignatureType::Unsigned => 0,
+            SignatureType::Ed25519 | SignatureType::EcdsaP256 => 64,
+        }
+    }
+
+    /// Length of the public keys, which is 32 bytes for Ed25519 and 64 bytes for the
+    /// uncompressed P-256 point `x || y`.
+    pub fn public_key_len(&self) -> usize {
+        match self {
+            SignatureType::Unsigned => 0,
+            SignatureType::Ed25519 => 32,
+            SignatureType::EcdsaP256 => 64,
+        }
+    }
+
+    /// The signature type of a public key, derived from its length.
+    pub fn of_public_key(public_key: &[u8]) -> Option<SignatureType> {
+        [SignatureType::Ed25519, SignatureType::EcdsaP256]
+            .iter()
+            .copied()
+            .find(|x| x.public_key_len() == public_key.len())
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -56,8 +109,36 @@ pub struct FwConfig {
     pub include_in_script: bool,
     #[serde(default = "default::header_offset")]
     pub header_offset: u64,
+    /// Name of the header layout, either the built-in `v1` or one of `Config::header_layouts`.
+    #[serde(default = "default::header_layout")]
+    pub header_layout: String,
 
-    pub hex_file_format: HexFileFormat,
+    /// Default for `app_format`, `btl_format` and `output_formats`.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub hex_file_format: Option<HexFileFormat>,
+    /// Format of the application file. Detected from the file if neither this nor
+    /// `hex_file_format` is given.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub app_format: Option<HexFileFormat>,
+    /// Format of the bootloader file. Detected from the file if neither this nor
+    /// `hex_file_format` is given.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub btl_format: Option<HexFileFormat>,
+    /// Formats of the generated hex files. Defaults to `hex_file_format` or Intel HEX.
+    #[serde(default = "Default::default", skip_serializing_if = "Vec::is_empty")]
+    pub output_formats: Vec<OutputFormat>,
+    /// Address at which a raw binary application is placed. Defaults to the start of
+    /// `app_address`.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub app_base_address: Option<u64>,
+    /// Address at which a raw binary bootloader is placed. Defaults to the start of
+    /// `btl_address`.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub btl_base_address: Option<u64>,
+    /// How data of the hex files outside of `app_address` and `btl_address` and conflicting
+    /// records are handled.
+    #[serde(default = "Default::default")]
+    pub address_check: AddressCheck,
     pub device_config: DeviceConfig,
     #[serde(default = "Default::default")]
     pub timings: Timings,
@@ -65,8 +146,112 @@ pub struct FwConfig {
     #[serde(default = "default::signature_type")]
     pub signature_type: SignatureType,
 
+    /// Overrides `Config::signer` for this image.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub signer: Option<SignerConfig>,
+
+    /// Anti-rollback security version written into the header. Resolved to
+    /// `SecurityVersion::Fixed` when the images are loaded.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub security_version: Option<SecurityVersion>,
+
     #[serde(default = "Default::default")]
     pub btl_trailer: bool,
+
+    /// User-defined fields written into the application image.
+    #[serde(default = "Default::default", skip_serializing_if = "Vec::is_empty")]
+    pub metadata: Vec<MetadataEntry>,
+
+    /// If present, a version 2 bootloader trailer is written instead of a version 1 trailer.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub btl_trailer_v2: Option<BtlTrailerConfig>,
+
+    /// Further address windows of the application, e.g. in external flash.
+    #[serde(default = "Default::default", skip_serializing_if = "Vec::is_empty")]
+    pub app_regions: Vec<AppRegion>,
+
+    /// Further files merged into the merged images, e.g. calibration data.
+    #[serde(default = "Default::default", skip_serializing_if = "Vec::is_empty")]
+    pub extra_inputs: Vec<ExtraInput>,
+}
+
+/// An address window of the application besides `app_address`.
+///
+/// Regions are not covered by the CRC and signature of the application image. In the script,
+/// each region is programmed as separate address space before the application.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AppRegion {
+    pub name: String,
+    pub address: AddressRange,
+    /// File containing the data of the region. Defaults to the application file.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Format of `path`. Detected from the file if not given.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub format: Option<HexFileFormat>,
+    /// Address at which a raw binary file is placed. Defaults to the start of `address`.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub base_address: Option<u64>,
+    /// Page size of the memory of the region. Defaults to `device_config.page_size`.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub page_size: Option<u64>,
+    #[serde(default = "default::region_include_in_script")]
+    pub include_in_script: bool,
+}
+
+impl AppRegion {
+    pub fn base_address(&self) -> u64 {
+        self.base_address.unwrap_or(self.address.begin)
+    }
+
+    /// The device config of `fw_config` with the page size of the region.
+    pub fn device_config(&self, fw_config: &FwConfig) -> DeviceConfig {
+        DeviceConfig {
+            page_size: self.page_size.unwrap_or(fw_config.device_config.page_size),
+            ..fw_config.device_config.clone()
+        }
+    }
+}
+
+/// A file placed into the merged images besides the bootloader and the application.
+///
+/// Extra inputs are neither part of the update script nor of the app package.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ExtraInput {
+    pub name: String,
+    pub path: String,
+    pub address: AddressRange,
+    /// Format of `path`. Detected from the file if not given.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub format: Option<HexFileFormat>,
+    /// Address at which a raw binary file is placed. Defaults to the start of `address`.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub base_address: Option<u64>,
+    /// If present, bytes of `address` not written by the file are set to this value.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub fill: Option<u8>,
+    /// Store the CRC32 of `address` in its last 4 bytes.
+    #[serde(default = "Default::default")]
+    pub crc: bool,
+}
+
+impl ExtraInput {
+    pub fn base_address(&self) -> u64 {
+        self.base_address.unwrap_or(self.address.begin)
+    }
+}
+
+/// Meta-data of the bootloader stored in a version 2 bootloader trailer.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BtlTrailerConfig {
+    pub btl_version: Version,
+    /// Bootload protocol version supported by the bootloader. Defaults to `Config::btl_version`.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u8>,
+    /// Hex encoded Ed25519 or P-256 public keys trusted by the bootloader. Their key IDs
+    /// (CRC32 of the public key) are stored in the trailer.
+    #[serde(default = "Default::default")]
+    pub trusted_public_keys: Vec<String>,
 }
 
 impl Default for FwConfig {
@@ -81,25 +266,215 @@ impl Default for FwConfig {
             write_data_size: default::write_data_size(),
             include_in_script: false,
             header_offset: default::header_offset(),
-            hex_file_format: HexFileFormat::default(),
+            header_layout: default::header_layout(),
+            hex_file_format: None,
+            app_format: None,
+            btl_format: None,
+            output_formats: Vec::new(),
+            app_base_address: None,
+            btl_base_address: None,
+            address_check: AddressCheck::default(),
             device_config: DeviceConfig::default(),
             timings: Timings::default(),
             signature_type: default::signature_type(),
+            signer: None,
+            security_version: None,
             btl_trailer: Default::default(),
+            metadata: Vec::new(),
+            btl_trailer_v2: None,
+            app_regions: Vec::new(),
+            extra_inputs: Vec::new(),
         }
     }
 }
 
+/// The anti-rollback security version of an image. Bootloaders reject images with a
+/// lower security version than the one installed.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum SecurityVersion {
+    Fixed(u16),
+    /// The number of released versions with a `### Security` section in the changelog,
+    /// given relative to the config file.
+    Changelog {
+        changelog: String,
+    },
+}
+
 impl FwConfig {
     pub fn designator(&self) -> String {
         format!("f{}", self.node_id)
     }
 
+    /// The resolved security version, see [`FwConfig::security_version`].
+    pub fn fixed_security_version(&self) -> Option<u16> {
+        match self.security_version {
+            Some(SecurityVersion::Fixed(x)) => Some(x),
+            _ => None,
+        }
+    }
+
     pub fn crc_offset(&self) -> usize {
-        match self.signature_type {
-            SignatureType::Unsigned => 0,
-            SignatureType::Ed25519 => 64,
+        self.signature_type.crc_offset()
+    }
+
+    /// Format of the application file, `None` if it is detected from the file content.
+    pub fn app_format(&self) -> Option<HexFileFormat> {
+        self.app_format.or(self.hex_file_format)
+    }
+
+    /// Format of the bootloader file, `None` if it is detected from the file content.
+    pub fn btl_format(&self) -> Option<HexFileFormat> {
+        self.btl_format.or(self.hex_file_format)
+    }
+
+    /// Formats of the generated hex files. The first one is listed in `info.json`.
+    pub fn output_formats(&self) -> Vec<OutputFormat> {
+        if !self.output_formats.is_empty() {
+            return self.output_formats.clone();
         }
+        vec![self.hex_file_format.unwrap_or_default().into()]
+    }
+
+    /// Check that all output formats can be written with their record options.
+    pub fn validate_output_formats(&self) -> Result<(), Error> {
+        for output in self.output_formats() {
+            if !output.format.is_writable() {
+                return Err(Error::InvalidConfig(format!(
+                    "Image {} cannot be saved as {:?}, use `app_format` and `btl_format` to load such files",
+                    self.designator(),
+                    output.format
+                )));
+            }
+            let record_length = output.records.record_length;
+            let word_addressing = self.device_config.word_addressing;
+            if record_length == 0
+                || record_length > MAX_RECORD_LENGTH
+                || (word_addressing && record_length % 2 != 0)
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "Invalid record length {} of the {:?} output of image {}",
+                    record_length,
+                    output.format,
+                    self.designator()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the app regions have distinct names and do not overlap each other, the
+    /// application or the bootloader.
+    pub fn validate_app_regions(&self) -> Result<(), Error> {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"^[\w-]+$").unwrap();
+        }
+        if self.app_regions.len() > u8::MAX as usize {
+            return Err(Error::InvalidConfig(format!(
+                "Image {} has more than {} app regions",
+                self.designator(),
+                u8::MAX
+            )));
+        }
+        let mut ranges = vec![
+            ("app".to_string(), &self.app_address),
+            ("btl".to_string(), &self.btl_address),
+        ];
+        for region in &self.app_regions {
+            if !RE.is_match(&region.name)
+                || self
+                    .app_regions
+                    .iter()
+                    .filter(|x| x.name == region.name)
+                    .count()
+                    > 1
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "Invalid or duplicate app region name `{}` of image {}",
+                    region.name,
+                    self.designator()
+                )));
+            }
+            if region.page_size == Some(0) {
+                return Err(Error::InvalidConfig(format!(
+                    "Page size of app region `{}` of image {} must not be 0",
+                    region.name,
+                    self.designator()
+                )));
+            }
+            let address = &region.address;
+            if let Some((name, _)) = ranges
+                .iter()
+                .find(|(_, x)| address.begin < x.end && x.begin < address.end)
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "App region `{}` of image {} overlaps `{}`",
+                    region.name,
+                    self.designator(),
+                    name
+                )));
+            }
+            ranges.push((region.name.clone(), address));
+        }
+        Ok(())
+    }
+
+    /// Check that the extra inputs have distinct names and do not overlap each other, the
+    /// application, the bootloader or the app regions.
+    pub fn validate_extra_inputs(&self) -> Result<(), Error> {
+        let mut ranges = vec![
+            ("app".to_string(), &self.app_address),
+            ("btl".to_string(), &self.btl_address),
+        ];
+        ranges.extend(
+            self.app_regions
+                .iter()
+                .map(|x| (x.name.clone(), &x.address)),
+        );
+        for input in &self.extra_inputs {
+            if self
+                .extra_inputs
+                .iter()
+                .filter(|x| x.name == input.name)
+                .count()
+                > 1
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "Duplicate extra input name `{}` of image {}",
+                    input.name,
+                    self.designator()
+                )));
+            }
+            let address = &input.address;
+            if input.crc && address.len() < 4 {
+                return Err(Error::InvalidConfig(format!(
+                    "Extra input `{}` of image {} is too short for a CRC",
+                    input.name,
+                    self.designator()
+                )));
+            }
+            if let Some((name, _)) = ranges
+                .iter()
+                .find(|(_, x)| address.begin < x.end && x.begin < address.end)
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "Extra input `{}` of image {} overlaps `{}`",
+                    input.name,
+                    self.designator(),
+                    name
+                )));
+            }
+            ranges.push((input.name.clone(), address));
+        }
+        Ok(())
+    }
+
+    pub fn app_base_address(&self) -> u64 {
+        self.app_base_address.unwrap_or(self.app_address.begin)
+    }
+
+    pub fn btl_base_address(&self) -> u64 {
+        self.btl_base_address.unwrap_or(self.btl_address.begin)
     }
 }
 
@@ -153,10 +528,56 @@ impl Config {
     pub fn load_from_string(data: &str) -> Result<Config, Error> {
         let mut config: Config = serde_json::from_str(data).map_err(Error::CannotParseConfig)?;
         Self::validate_product_name(&config.product_name)?;
+        for fw_config in &config.images {
+            config.header_layout(fw_config)?;
+            fw_config.validate_output_formats()?;
+            fw_config.validate_app_regions()?;
+            fw_config.validate_extra_inputs()?;
+        }
         config.ed25519_private_key = Self::load_private_key_from_env()?;
+        config.ecdsa_p256_private_key = crate::ecdsa_p256::load_private_key_from_env()?;
         Ok(config)
     }
 
+    /// Look up the header layout of an image and check that the header fits
+    /// between the CRC and the end of the application.
+    pub fn header_layout(&self, fw_config: &FwConfig) -> Result<&HeaderLayout, Error> {
+        let name = &fw_config.header_layout;
+        if let Some(name) = self
+            .header_layouts
+            .keys()
+            .find(|x| HeaderLayout::builtin(x).is_some())
+        {
+            return Err(Error::InvalidConfig(format!(
+                "Header layout `{}` is built-in and cannot be redefined",
+                name
+            )));
+        }
+        let layout = self
+            .header_layouts
+            .get(name)
+            .or_else(|| HeaderLayout::builtin(name))
+            .ok_or_else(|| Error::InvalidConfig(format!("Unknown header layout `{}`", name)))?;
+        layout.validate()?;
+
+        let multiplier = if self.byte_addresses {
+            1
+        } else {
+            fw_config.device_config.byte_address_multiplier()
+        };
+        let header_offset = (fw_config.header_offset * multiplier) as usize;
+        let app_length = (fw_config.app_address.len() * multiplier) as usize;
+        if header_offset < fw_config.crc_offset() + 4 || header_offset + layout.length > app_length
+        {
+            return Err(Error::InvalidConfig(format!(
+                "Header of image {} with layout `{}` does not fit between the CRC and the end of the application",
+                fw_config.designator(),
+                name
+            )));
+        }
+        Ok(layout)
+    }
+
     fn load_private_key_from_env() -> Result<Option<[u8; 32]>, Error> {
         crate::ed25519::load_private_key_from_env()
     }
@@ -172,7 +593,24 @@ impl Config {
                 fwconfig.btl_address.begin *= 2;
                 fwconfig.btl_address.end = 2 * fwconfig.btl_address.end;
                 fwconfig.header_offset *= 2;
+                fwconfig.app_base_address = fwconfig.app_base_address.map(|x| x * 2);
+                fwconfig.btl_base_address = fwconfig.btl_base_address.map(|x| x * 2);
                 fwconfig.device_config.page_size *= 2;
+                for entry in &mut fwconfig.metadata {
+                    entry.address = entry.address.map(|x| x * 2);
+                    entry.offset = entry.offset.map(|x| x * 2);
+                }
+                for region in &mut fwconfig.app_regions {
+                    region.address.begin *= 2;
+                    region.address.end *= 2;
+                    region.base_address = region.base_address.map(|x| x * 2);
+                    region.page_size = region.page_size.map(|x| x * 2);
+                }
+                for input in &mut fwconfig.extra_inputs {
+                    input.address.begin *= 2;
+                    input.address.end *= 2;
+                    input.base_address = input.base_address.map(|x| x * 2);
+                }
             }
         }
         self.byte_addresses = true;
@@ -189,7 +627,24 @@ impl Config {
                 fwconfig.btl_address.begin /= 2;
                 fwconfig.btl_address.end /= 2;
                 fwconfig.header_offset /= 2;
+                fwconfig.app_base_address = fwconfig.app_base_address.map(|x| x / 2);
+                fwconfig.btl_base_address = fwconfig.btl_base_address.map(|x| x / 2);
                 fwconfig.device_config.page_size /= 2;
+                for entry in &mut fwconfig.metadata {
+                    entry.address = entry.address.map(|x| x / 2);
+                    entry.offset = entry.offset.map(|x| x / 2);
+                }
+                for region in &mut fwconfig.app_regions {
+                    region.address.begin /= 2;
+                    region.address.end /= 2;
+                    region.base_address = region.base_address.map(|x| x / 2);
+                    region.page_size = region.page_size.map(|x| x / 2);
+                }
+                for input in &mut fwconfig.extra_inputs {
+                    input.address.begin /= 2;
+                    input.address.end /= 2;
+                    input.base_address = input.base_address.map(|x| x / 2);
+                }
             }
         }
         self.byte_addresses = true;
@@ -209,6 +664,10 @@ impl Default for Config {
             byte_addresses: false,
             build_time: default::default_time(),
             ed25519_private_key: None,
+            ecdsa_p256_private_key: None,
+            signer: SignerConfig::default(),
+            defer_signing: false,
+            header_layouts: BTreeMap::new(),
         }
     }
 }
@@ -222,7 +681,7 @@ pub struct Timings {
     pub erase_time: u32,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
 pub struct AddressRange {
     pub begin: u64,
     pub end: u64,
@@ -237,10 +696,16 @@ impl AddressRange {
     }
 }
 
-#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub enum HexFileFormat {
     IntelHex,
     SRecord,
+    /// TI-TXT as generated by the MSP430 and C2000 tool chains.
+    TiTxt,
+    /// ELF executable, loading the `PT_LOAD` segments at their physical address. Input only.
+    Elf,
+    /// Raw binary placed at a configured base address. Input only.
+    Binary,
 }
 
 impl Default for HexFileFormat {
@@ -254,10 +719,157 @@ impl HexFileFormat {
         match self {
             HexFileFormat::IntelHex => "hex",
             HexFileFormat::SRecord => "s37",
+            HexFileFormat::TiTxt => "txt",
+            HexFileFormat::Elf => "elf",
+            HexFileFormat::Binary => "bin",
+        }
+    }
+
+    /// Detect the format of a file. Binary files are recognized by their `.bin` extension, all
+    /// other formats by their content.
+    pub fn detect(path: &Path) -> Result<Self, Error> {
+        let ext = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default();
+        if ext.eq_ignore_ascii_case("bin") {
+            return Ok(HexFileFormat::Binary);
+        }
+        let data = fs::read(path)?;
+        Self::from_content(&data).ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "Cannot detect the format of {}, set `app_format` or `btl_format`",
+                path.display()
+            ))
+        })
+    }
+
+    /// Guess the format from the first bytes of a file. Binary files cannot be recognized.
+    pub fn from_content(data: &[u8]) -> Option<Self> {
+        if elf::is_elf(data) {
+            return Some(HexFileFormat::Elf);
+        }
+        match data.iter().find(|x| !x.is_ascii_whitespace()) {
+            Some(b':') => Some(HexFileFormat::IntelHex),
+            Some(b'S') => Some(HexFileFormat::SRecord),
+            Some(b'@') => Some(HexFileFormat::TiTxt),
+            _ => None,
+        }
+    }
+
+    /// Whether images can be saved in this format.
+    pub fn is_writable(&self) -> bool {
+        match self {
+            HexFileFormat::IntelHex | HexFileFormat::SRecord | HexFileFormat::TiTxt => true,
+            HexFileFormat::Elf | HexFileFormat::Binary => false,
+        }
+    }
+}
+
+/// Maximum number of data bytes per record, limited by the S-Record byte count.
+pub const MAX_RECORD_LENGTH: usize = 250;
+
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+/// Records used for addresses beyond 64 KiB in Intel HEX files.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum IntelAddressing {
+    /// Extended linear address records (type 04) and start linear address records (type 05).
+    #[default]
+    Linear,
+    /// Extended segment address records (type 02) and start segment address records (type 03),
+    /// limited to the first MiB.
+    Segment,
+}
+
+/// Layout of the records of generated hex files.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct RecordOptions {
+    /// Number of data bytes per record, or per line for TI-TXT files.
+    #[serde(default = "default::record_length")]
+    pub record_length: usize,
+    #[serde(default = "Default::default")]
+    pub line_ending: LineEnding,
+    #[serde(default = "Default::default")]
+    pub intel_addressing: IntelAddressing,
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        RecordOptions {
+            record_length: default::record_length(),
+            line_ending: LineEnding::default(),
+            intel_addressing: IntelAddressing::default(),
+        }
+    }
+}
+
+/// A generated hex file format. Given either by the name of the format or as an object
+/// containing the format and its record options.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(from = "OutputFormatConfig")]
+pub struct OutputFormat {
+    pub format: HexFileFormat,
+    #[serde(flatten)]
+    pub records: RecordOptions,
+}
+
+impl From<HexFileFormat> for OutputFormat {
+    fn from(format: HexFileFormat) -> Self {
+        OutputFormat {
+            format,
+            records: RecordOptions::default(),
         }
     }
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OutputFormatConfig {
+    Format(HexFileFormat),
+    WithOptions {
+        format: HexFileFormat,
+        #[serde(flatten)]
+        records: RecordOptions,
+    },
+}
+
+impl From<OutputFormatConfig> for OutputFormat {
+    fn from(x: OutputFormatConfig) -> Self {
+        match x {
+            OutputFormatConfig::Format(format) => format.into(),
+            OutputFormatConfig::WithOptions { format, records } => OutputFormat { format, records },
+        }
+    }
+}
+
+/// Handling of hex file data outside of the configured address range and of records writing
+/// different values to the same address.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum AddressCheck {
+    /// Fail to load the file.
+    #[default]
+    Strict,
+    /// Log a warning and drop the data outside of the address range.
+    Warn,
+    /// Silently drop the data outside of the address range.
+    Ignore,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum Endianness {
     Big,
@@ -310,9 +922,15 @@ pub mod default {
     pub fn header_offset() -> u64 {
         4
     }
+    pub fn header_layout() -> String {
+        super::V1_LAYOUT.to_string()
+    }
     pub fn include_in_script() -> bool {
         false
     }
+    pub fn region_include_in_script() -> bool {
+        true
+    }
     pub fn btl_version() -> u8 {
         1
     }
@@ -322,7 +940,7 @@ pub mod default {
     pub fn use_backdoor() -> bool {
         false
     }
-    pub fn product_id() -> u16 {
+    pub fn product_id() -> u32 {
         0
     }
     pub fn blocking() -> bool {
@@ -333,6 +951,10 @@ pub mod default {
         16
     }
 
+    pub fn record_length() -> usize {
+        16
+    }
+
     pub fn zero_u32() -> u32 {
         0
     }
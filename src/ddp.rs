@@ -24,6 +24,15 @@ pub const STATE_DONE: u8 = 0x06;
 pub const STATE_ERR: u8 = 0x07;
 
 pub const STATUS_SUCCESS: u8 = 0x00;
+pub const STATUS_UNEXPECTED_CMD: u8 = 0x01;
+pub const STATUS_INVALID_CMD: u8 = 0x02;
+pub const STATUS_INVALID_FRAME_LENGTH: u8 = 0x03;
+pub const STATUS_INCOMPATIBLE: u8 = 0x04;
+pub const STATUS_OUT_OF_BOUNDS: u8 = 0x05;
+pub const STATUS_NOT_READY: u8 = 0x06;
+pub const STATUS_INVALID_LENGTH_IN_HEADER: u8 = 0x07;
+pub const STATUS_FLASH: u8 = 0x08;
+pub const STATUS_INVALID_CRC: u8 = 0x09;
 
 pub struct DdpProtocol {
     ddp_code: u8,
@@ -88,10 +97,14 @@ impl Protocol for DdpProtocol {
         ]
     }
 
-    fn start_transmit(&self, fw_id: u8, erase_time: u32) -> Vec<Command> {
+    fn start_transmit(&self, fw_id: u8, address_space: u8, erase_time: u32) -> Vec<Command> {
+        let mut tx = vec![self.ddp_code, fw_id, CMD_START_TRANSMIT];
+        if address_space != 0 {
+            tx.push(address_space);
+        }
         vec![
             Command::SetTimeOut(erase_time),
-            write(vec![self.ddp_code, fw_id, CMD_START_TRANSMIT]),
+            write(tx),
             Command::SetTimeOut(0),
             query(
                 vec![self.ddp_code | 0x80, fw_id, CMD_NONE],
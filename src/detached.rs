@@ -0,0 +1,322 @@
+//! Detached signatures: the images are generated unsigned, their digests are signed on a
+//! separate machine and the signatures are inserted afterwards.
+//!
+//! `generate --emit-digests` writes a [`DigestFile`], `sign-digests` adds the signatures
+//! with [`sign_digests`] and `apply-signatures` re-generates all outputs with the signed
+//! images using [`apply_signatures`].
+
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::config::SignatureType;
+use crate::crc::crc32;
+use crate::header::Header;
+use crate::inspect::InspectOptions;
+use crate::process::{self, GenerateOptions, LoadedFirmwareImages};
+use crate::signer::{self, Signer, SignerConfig};
+use crate::verify::{self, VerifyReport};
+use crate::Error;
+
+pub const DIGEST_FILE_NAME: &str = "digests.json";
+
+/// The digests of the signed images of a product, optionally with their signatures.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DigestFile {
+    pub product_id: u32,
+    pub product_name: String,
+    /// Build time of the images, which is reused when the signatures are applied.
+    pub build_time: DateTime<Utc>,
+    pub images: Vec<ImageDigest>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ImageDigest {
+    pub fw_id: u8,
+    pub version: Version,
+    /// Start address of the application image.
+    pub address: u64,
+    pub length: u32,
+    pub crc: u32,
+    /// Key ID written into the header, i.e. the CRC32 of the public key of the signer.
+    pub key_id: u32,
+    pub signature_type: SignatureType,
+    /// Hex encoded digest of the image without the signature, see [`signer::image_digest`].
+    pub digest: String,
+    /// Hex encoded public key of the signer, added by `sign-digests`.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// Hex encoded signature of the digest, added by `sign-digests`.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl ImageDigest {
+    fn designator(&self) -> String {
+        format!("f{}", self.fw_id)
+    }
+
+    fn digest(&self) -> Result<Vec<u8>, Error> {
+        hex::decode(&self.digest)
+            .ok()
+            .filter(|x| x.len() == signer::image_digest_len(self.signature_type))
+            .ok_or_else(|| {
+                Error::DetachedSignature(format!("Invalid digest of image {}", self.designator()))
+            })
+    }
+
+    fn signature(&self) -> Result<[u8; 64], Error> {
+        self.signature
+            .as_ref()
+            .and_then(|x| hex::decode(x).ok())
+            .and_then(|x| x.try_into().ok())
+            .ok_or_else(|| {
+                Error::DetachedSignature(format!(
+                    "Missing or invalid signature of image {}",
+                    self.designator()
+                ))
+            })
+    }
+
+    fn public_key(&self) -> Result<Vec<u8>, Error> {
+        let public_key = self.public_key.as_ref().ok_or_else(|| {
+            Error::DetachedSignature(format!("Missing public key of image {}", self.designator()))
+        })?;
+        signer::decode_public_key(public_key)
+    }
+
+    /// Check that the public key matches the key ID and the signature type and that the
+    /// signature is valid.
+    fn check(&self) -> Result<(Vec<u8>, [u8; 64]), Error> {
+        let public_key = self.public_key()?;
+        let signature = self.signature()?;
+        if crc32(&public_key) != self.key_id
+            || SignatureType::of_public_key(&public_key) != Some(self.signature_type)
+        {
+            return Err(Error::DetachedSignature(format!(
+                "The public key of image {} does not match its key ID 0x{:08X}",
+                self.designator(),
+                self.key_id
+            )));
+        }
+        signer::verify_digest(&self.digest()?, &signature, &public_key)?;
+        Ok((public_key, signature))
+    }
+}
+
+impl DigestFile {
+    /// Collect the digests of the signed images.
+    pub fn from_loaded_firmware_images(loaded: &LoadedFirmwareImages) -> Result<Self, Error> {
+        let mut images = Vec::new();
+        for fw in &loaded.images {
+            if fw.config.signature_type == SignatureType::Unsigned {
+                continue;
+            }
+            let layout = loaded.config.header_layout(&fw.config)?;
+            let mut app = fw.app.clone();
+            let key_id = Header::with_layout(&mut app, fw.config.header_offset, layout)?.key_id();
+            images.push(ImageDigest {
+                fw_id: fw.config.node_id,
+                version: fw.config.version.clone().unwrap(),
+                address: fw.app.range.begin,
+                length: fw.app.image_length() as u32,
+                crc: fw.load_crc(),
+                key_id,
+                signature_type: fw.config.signature_type,
+                digest: hex::encode(signer::image_digest(&fw.app, fw.config.signature_type)),
+                public_key: None,
+                signature: None,
+            });
+        }
+        Ok(DigestFile {
+            product_id: loaded.config.product_id,
+            product_name: loaded.config.product_name.clone(),
+            build_time: loaded.config.build_time,
+            images,
+        })
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<Self, Error> {
+        let data = fs::read_to_string(path)?;
+        serde_json::from_str(&data).map_err(Error::CannotParseConfig)
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> Result<(), Error> {
+        let data = serde_json::to_string_pretty(self).unwrap();
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Designators of the images whose signature is still missing.
+    pub fn unsigned_images(&self) -> Vec<String> {
+        self.images
+            .iter()
+            .filter(|x| x.signature.is_none())
+            .map(ImageDigest::designator)
+            .collect()
+    }
+}
+
+/// Sign the digests of the images whose key ID and signature type match `signer`.
+///
+/// Images signed with other keys are left for another run with their signer. Returns the
+/// number of signed images; fails if no image matches the signer.
+pub fn sign_digests(digests: &mut DigestFile, signer: &dyn Signer) -> Result<usize, Error> {
+    let public_key = signer.public_key()?;
+    let key_id = crc32(&public_key);
+    let mut signed = 0;
+    for image in &mut digests.images {
+        if image.key_id != key_id || image.signature_type != signer.signature_type() {
+            continue;
+        }
+        let signature = signer.sign_digest(&image.digest()?)?;
+        image.public_key = Some(hex::encode(&public_key));
+        image.signature = Some(hex::encode(signature));
+        image.check()?;
+        signed += 1;
+    }
+    if signed == 0 {
+        return Err(Error::DetachedSignature(format!(
+            "No image expects a {:?} signature of the key with ID 0x{:08X}",
+            signer.signature_type(),
+            key_id
+        )));
+    }
+    Ok(signed)
+}
+
+/// Re-generate the images with the build time of `signatures`, insert the signatures and
+/// write all outputs as `generate` does. Fails if an image is not signed yet or differs from
+/// its digest.
+///
+/// Returns the result of verifying the written outputs.
+pub fn apply_signatures(
+    options: &GenerateOptions,
+    signatures: &DigestFile,
+) -> Result<VerifyReport, Error> {
+    let unsigned = signatures.unsigned_images();
+    if !unsigned.is_empty() {
+        return Err(Error::DetachedSignature(format!(
+            "Images {} are not signed yet",
+            unsigned.join(", ")
+        )));
+    }
+    fs::create_dir_all(&options.output_dir)?;
+
+    let mut config = options.config.clone();
+    config.build_time = signatures.build_time;
+    config.defer_signing = true;
+    // the private keys are not needed, the key IDs are derived from the public keys. The
+    // digests are listed in the order of the signed images, whose IDs may be read from the
+    // headers only while loading.
+    let signed = config
+        .images
+        .iter_mut()
+        .filter(|x| x.signature_type != SignatureType::Unsigned);
+    for (fw_config, image) in signed.zip(&signatures.images) {
+        let public_key = image.public_key.clone();
+        fw_config.signer = public_key.map(|public_key| SignerConfig::PublicKey { public_key });
+    }
+    let mut loaded =
+        process::load_firmware_images(&config, &options.config_dir, options.repo_dir.as_deref())?;
+
+    let mut public_keys = Vec::new();
+    for fw in &mut loaded.images {
+        if fw.config.signature_type == SignatureType::Unsigned {
+            continue;
+        }
+        let id = fw.config.node_id;
+        let image = signatures
+            .images
+            .iter()
+            .find(|x| x.fw_id == id)
+            .ok_or_else(|| {
+                Error::DetachedSignature(format!("No signature for image f{} given", id))
+            })?;
+        if signer::image_digest(&fw.app, fw.config.signature_type) != image.digest()? {
+            return Err(Error::DetachedSignature(format!(
+                "Image f{} differs from the signed digest, the inputs have changed",
+                id
+            )));
+        }
+        let (public_key, signature) = image.check()?;
+        fw.app.write(0, &signature);
+        signer::verify_image(&fw.app.data[..fw.app.image_length()], &public_key)?;
+        public_keys.push(public_key);
+    }
+    process::save_outputs(&loaded, options)?;
+
+    let verify_options = InspectOptions {
+        public_keys,
+        ..Default::default()
+    };
+    verify::verify_info(&options.output_dir.join("info.json"), &verify_options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signer::KeySigner;
+
+    fn digest_file(key_id: u32) -> DigestFile {
+        DigestFile {
+            product_id: 1,
+            product_name: "test".to_string(),
+            build_time: Utc::now(),
+            images: vec![ImageDigest {
+                fw_id: 1,
+                version: Version::new(1, 2, 3),
+                address: 0x8000,
+                length: 256,
+                crc: 0x1234,
+                key_id,
+                signature_type: SignatureType::Ed25519,
+                digest: hex::encode([0x5A; 64]),
+                public_key: None,
+                signature: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn sign_and_check_digests() {
+        let signer = KeySigner::new(SignatureType::Ed25519, [7; 32]);
+        let key_id = crc32(&signer.public_key().unwrap());
+        let mut digests = digest_file(key_id);
+        sign_digests(&mut digests, &signer).unwrap();
+        digests.images[0].check().unwrap();
+
+        digests.images[0].digest = hex::encode([0x5B; 64]);
+        assert!(digests.images[0].check().is_err());
+
+        let mut digests = digest_file(key_id + 1);
+        assert!(sign_digests(&mut digests, &signer).is_err());
+        assert!(digests.images[0].signature.is_none());
+
+        let signer = KeySigner::new(SignatureType::EcdsaP256, [7; 32]);
+        let mut digests = digest_file(crc32(&signer.public_key().unwrap()));
+        assert!(sign_digests(&mut digests, &signer).is_err());
+        digests.images[0].signature_type = SignatureType::EcdsaP256;
+        digests.images[0].digest = hex::encode([0x5A; 32]);
+        sign_digests(&mut digests, &signer).unwrap();
+        digests.images[0].check().unwrap();
+    }
+
+    #[test]
+    fn sign_digests_of_matching_images() {
+        let signer = KeySigner::new(SignatureType::Ed25519, [7; 32]);
+        let mut digests = digest_file(crc32(&signer.public_key().unwrap()));
+        let mut other = digests.images[0].clone();
+        other.fw_id = 2;
+        other.key_id += 1;
+        digests.images.push(other);
+        assert_eq!(sign_digests(&mut digests, &signer).unwrap(), 1);
+        digests.images[0].check().unwrap();
+        assert!(digests.images[1].signature.is_none());
+        assert_eq!(digests.unsigned_images(), vec!["f2".to_string()]);
+    }
+}
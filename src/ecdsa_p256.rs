@@ -0,0 +1,149 @@
+//! ECDSA signatures on the NIST P-256 curve, for devices with a hardware accelerator
+//! for P-256 but not for Ed25519.
+//!
+//! The layout of a signed image is the same as for Ed25519: the 64-byte signature `r || s`
+//! occupies the first 64 bytes and covers the SHA-256 digest of everything from byte 64
+//! onwards. Public keys are the 64-byte uncompressed point `x || y` without the SEC1
+//! `0x04` prefix, the key ID is the CRC32 thereof.
+
+use std::convert::TryInto;
+
+use p256::ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use crate::firmware::Firmware;
+use crate::Error;
+
+pub const ENV_VAR: &str = "MERGE_TOOL_ECDSA_P256_PRIVATE_KEY";
+pub const ENV_FILE_VAR: &str = "MERGE_TOOL_ECDSA_P256_PRIVATE_KEY_FILE";
+
+/// Decode a hex encoded 32-byte private key scalar.
+pub fn decode_private_key_hex(input: &str) -> Result<[u8; 32], Error> {
+    let private_key = crate::ed25519::decode_private_key_hex(input)?;
+    signing_key(&private_key)?;
+    Ok(private_key)
+}
+
+fn signing_key(private_key: &[u8; 32]) -> Result<SigningKey, Error> {
+    SigningKey::from_slice(private_key).map_err(|_| Error::InvalidPrivateKey)
+}
+
+/// The SHA-256 digest covered by the signature, i.e. of the image without the signature.
+pub fn image_digest(fw: &Firmware) -> [u8; 32] {
+    data_digest(&fw.data[..fw.image_length()])
+}
+
+fn data_digest(image: &[u8]) -> [u8; 32] {
+    let mut sha = Sha256::new();
+    Digest::input(&mut sha, &image[64..]);
+
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&sha.result());
+    digest
+}
+
+/// Generate a new random P-256 private key, returned as raw 32-byte scalar.
+pub fn generate_private_key() -> [u8; 32] {
+    use rand::rngs::OsRng;
+    SigningKey::random(&mut OsRng).to_bytes().into()
+}
+
+/// Derive the 64-byte public key `x || y` from a private key.
+pub fn public_key_bytes(private_key: &[u8; 32]) -> Result<[u8; 64], Error> {
+    let point = signing_key(private_key)?
+        .verifying_key()
+        .to_encoded_point(false);
+    Ok(point.as_bytes()[1..].try_into().unwrap())
+}
+
+/// Load the private key from environment variables, see
+/// [`crate::ed25519::load_private_key_from_env`].
+pub fn load_private_key_from_env() -> Result<Option<[u8; 32]>, Error> {
+    let private_key = crate::ed25519::load_key_from_env(ENV_FILE_VAR, ENV_VAR)?;
+    if let Some(private_key) = &private_key {
+        signing_key(private_key)?;
+    }
+    Ok(private_key)
+}
+
+/// Verify a firmware image signature against the given public key.
+pub fn verify(fw: &Firmware, public_key: &[u8; 64]) -> Result<(), Error> {
+    verify_image(&fw.data[..fw.image_length()], public_key)
+}
+
+/// Verify the signature of an image whose length is already known, see
+/// [`crate::ed25519::verify_image`].
+pub fn verify_image(image: &[u8], public_key: &[u8; 64]) -> Result<(), Error> {
+    let sig_bytes: [u8; 64] = image
+        .get(..64)
+        .and_then(|s| s.try_into().ok())
+        .ok_or(Error::InvalidSignature)?;
+    verify_digest(&data_digest(image), &sig_bytes, public_key)
+}
+
+/// Verify the signature of an image digest, see [`image_digest`].
+pub fn verify_digest(
+    digest: &[u8; 32],
+    signature: &[u8; 64],
+    public_key: &[u8; 64],
+) -> Result<(), Error> {
+    let mut point = [0x04; 65];
+    point[1..].copy_from_slice(public_key);
+    let verifying_key =
+        VerifyingKey::from_sec1_bytes(&point).map_err(|_| Error::InvalidSignature)?;
+    let signature = Signature::from_slice(signature).map_err(|_| Error::InvalidSignature)?;
+    verifying_key
+        .verify_prehash(digest, &signature)
+        .map_err(|_| Error::InvalidSignature)
+}
+
+/// Sign a firmware image with the given private key, see [`crate::ed25519::sign`].
+pub fn sign(fw: &mut Firmware, private_key: &[u8; 32]) -> Result<(), Error> {
+    let signature = sign_digest(&image_digest(fw), private_key)?;
+    fw.write(0, &signature);
+    Ok(())
+}
+
+/// Sign the digest of an image, see [`image_digest`]. The signature is deterministic
+/// as of RFC 6979.
+pub fn sign_digest(digest: &[u8; 32], private_key: &[u8; 32]) -> Result<[u8; 64], Error> {
+    let signature: Signature = signing_key(private_key)?
+        .sign_prehash(digest)
+        .map_err(|_| Error::InvalidPrivateKey)?;
+    Ok(signature.to_bytes().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AddressRange, DeviceConfig};
+
+    fn firmware() -> Firmware {
+        let data = (0..256_u32).map(|x| (x as u8) | 0x01).collect();
+        Firmware::new(AddressRange::new(0, 256), DeviceConfig::default(), data).unwrap()
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let private_key = generate_private_key();
+        let public_key = public_key_bytes(&private_key).unwrap();
+        let mut fw = firmware();
+        sign(&mut fw, &private_key).unwrap();
+        verify(&fw, &public_key).unwrap();
+
+        fw.data[100] ^= 1;
+        assert!(verify(&fw, &public_key).is_err());
+        fw.data[100] ^= 1;
+
+        let other_key = public_key_bytes(&generate_private_key()).unwrap();
+        assert!(verify(&fw, &other_key).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_private_keys() {
+        assert!(decode_private_key_hex(&hex::encode([0; 32])).is_err());
+        assert!(decode_private_key_hex(&hex::encode([0xFF; 32])).is_err());
+        assert!(decode_private_key_hex(&hex::encode([1; 32])).is_ok());
+    }
+}
@@ -5,16 +5,21 @@ use sha2::{Digest, Sha512};
 pub const ENV_VAR: &str = "MERGE_TOOL_ED25519_PRIVATE_KEY";
 pub const ENV_FILE_VAR: &str = "MERGE_TOOL_ED25519_PRIVATE_KEY_FILE";
 
-fn decode_private_key_hex(input: &str) -> Result<[u8; 32], Error> {
+/// Decode a hex encoded 32-byte private key seed.
+pub fn decode_private_key_hex(input: &str) -> Result<[u8; 32], Error> {
     use std::convert::TryInto;
     let bytes = hex::decode(input.trim()).map_err(|_| Error::InvalidPrivateKey)?;
     bytes.try_into().map_err(|_| Error::InvalidPrivateKey)
 }
 
-fn image_digest(fw: &Firmware) -> [u8; 64] {
-    let image_len = fw.image_length();
+/// The SHA-512 digest covered by the signature, i.e. of the image without the signature.
+pub fn image_digest(fw: &Firmware) -> [u8; 64] {
+    data_digest(&fw.data[..fw.image_length()])
+}
+
+fn data_digest(image: &[u8]) -> [u8; 64] {
     let mut sha = Sha512::new();
-    Digest::input(&mut sha, &fw.data[64..image_len]);
+    Digest::input(&mut sha, &image[64..]);
 
     let mut digest = [0u8; 64];
     digest.copy_from_slice(&sha.result());
@@ -42,7 +47,12 @@ pub fn public_key_bytes(private_key: &[u8; 32]) -> [u8; 32] {
 /// as a hex-encoded 32-byte private key seed.
 /// Otherwise `MERGE_TOOL_ED25519_PRIVATE_KEY` is used directly as a hex-encoded seed.
 pub fn load_private_key_from_env() -> Result<Option<[u8; 32]>, Error> {
-    match std::env::var(ENV_FILE_VAR) {
+    load_key_from_env(ENV_FILE_VAR, ENV_VAR)
+}
+
+/// Load a hex encoded 32-byte key from the file named by `file_var` or else from `var`.
+pub(crate) fn load_key_from_env(file_var: &str, var: &str) -> Result<Option<[u8; 32]>, Error> {
+    match std::env::var(file_var) {
         Ok(path) => {
             let content = std::fs::read_to_string(path).map_err(|_| Error::InvalidPrivateKey)?;
             return decode_private_key_hex(&content).map(Some);
@@ -51,7 +61,7 @@ pub fn load_private_key_from_env() -> Result<Option<[u8; 32]>, Error> {
         Err(_) => return Err(Error::InvalidPrivateKey),
     }
 
-    match std::env::var(ENV_VAR) {
+    match std::env::var(var) {
         Err(std::env::VarError::NotPresent) => Ok(None),
         Err(_) => Err(Error::InvalidPrivateKey),
         Ok(val) => decode_private_key_hex(&val).map(Some),
@@ -63,20 +73,36 @@ pub fn load_private_key_from_env() -> Result<Option<[u8; 32]>, Error> {
 /// The 64-byte signature is expected at the first 64 bytes of the image.
 /// It must cover the SHA-512 digest of everything from byte 64 onwards.
 pub fn verify(fw: &Firmware, public_key: &[u8; 32]) -> Result<(), Error> {
-    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+    verify_image(&fw.data[..fw.image_length()], public_key)
+}
+
+/// Verify the signature of an image whose length is already known,
+/// e.g. from the length field of the firmware header.
+///
+/// `image` must contain the complete image, starting with the 64-byte signature.
+pub fn verify_image(image: &[u8], public_key: &[u8; 32]) -> Result<(), Error> {
     use std::convert::TryInto;
 
-    let verifying_key =
-        VerifyingKey::from_bytes(public_key).map_err(|_| Error::InvalidSignature)?;
-    let sig_bytes: [u8; 64] = fw
-        .data
+    let sig_bytes: [u8; 64] = image
         .get(..64)
         .and_then(|s| s.try_into().ok())
         .ok_or(Error::InvalidSignature)?;
-    let signature = Signature::from_bytes(&sig_bytes);
-    let digest = image_digest(fw);
+    verify_digest(&data_digest(image), &sig_bytes, public_key)
+}
+
+/// Verify the signature of an image digest, see [`image_digest`].
+pub fn verify_digest(
+    digest: &[u8; 64],
+    signature: &[u8; 64],
+    public_key: &[u8; 32],
+) -> Result<(), Error> {
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    let verifying_key =
+        VerifyingKey::from_bytes(public_key).map_err(|_| Error::InvalidSignature)?;
+    let signature = Signature::from_bytes(signature);
     verifying_key
-        .verify(&digest, &signature)
+        .verify(digest, &signature)
         .map_err(|_| Error::InvalidSignature)
 }
 
@@ -88,14 +114,17 @@ pub fn verify(fw: &Firmware, public_key: &[u8; 32]) -> Result<(), Error> {
 /// The key-id and CRC must already be written into the image before calling this
 /// (i.e. `configure_header` must have run first).
 pub fn sign(fw: &mut Firmware, private_key: &[u8; 32]) -> Result<(), Error> {
-    use ed25519_dalek::{Signer, SigningKey};
-    let signing_key = SigningKey::from_bytes(private_key);
-    let digest = image_digest(fw);
-    let signature = signing_key.sign(&digest);
-    fw.data[..64].copy_from_slice(&signature.to_bytes());
+    let signature = sign_digest(&image_digest(fw), private_key);
+    fw.write(0, &signature);
     Ok(())
 }
 
+/// Sign the digest of an image, see [`image_digest`].
+pub fn sign_digest(digest: &[u8; 64], private_key: &[u8; 32]) -> [u8; 64] {
+    use ed25519_dalek::{Signer, SigningKey};
+    SigningKey::from_bytes(private_key).sign(digest).to_bytes()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,208 @@
+//! Loading of ELF executables.
+//!
+//! Only the program headers are evaluated: the file content of each `PT_LOAD` segment is placed
+//! at its physical address, such that initialized data is stored at its load address in flash.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::path::Path;
+
+use crate::config::AddressRange;
+use crate::segments::SegmentMap;
+use crate::Error;
+
+const MAGIC: &[u8] = b"\x7FELF";
+const CLASS_32: u8 = 1;
+const CLASS_64: u8 = 2;
+const DATA_LSB: u8 = 1;
+const DATA_MSB: u8 = 2;
+const PT_LOAD: u32 = 1;
+
+/// Loadable content of an ELF file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ElfFile {
+    /// Content of the `PT_LOAD` segments, at their physical addresses.
+    pub segments: SegmentMap,
+    pub entry_point: Option<u64>,
+    /// Spans written by multiple segments with different values.
+    pub conflicts: Vec<AddressRange>,
+}
+
+pub fn load(path: &Path) -> Result<ElfFile, Error> {
+    let data = std::fs::read(path)?;
+    parse(&data).map_err(|x| match x {
+        Error::InvalidElfFile(reason) => {
+            Error::InvalidElfFile(format!("{}: {}", path.display(), reason))
+        }
+        x => x,
+    })
+}
+
+/// Whether `data` starts with the ELF magic number.
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: u64, len: u64) -> Result<&'a [u8], Error> {
+        let end = offset
+            .checked_add(len)
+            .filter(|x| *x <= self.data.len() as u64)
+            .ok_or_else(|| {
+                Error::InvalidElfFile(format!(
+                    "0x{:X} bytes at offset 0x{:X} exceed the file size",
+                    len, offset
+                ))
+            })?;
+        Ok(&self.data[offset as usize..end as usize])
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16, Error> {
+        let data = self.bytes(offset, 2)?;
+        Ok(if self.big_endian {
+            BigEndian::read_u16(data)
+        } else {
+            LittleEndian::read_u16(data)
+        })
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32, Error> {
+        let data = self.bytes(offset, 4)?;
+        Ok(if self.big_endian {
+            BigEndian::read_u32(data)
+        } else {
+            LittleEndian::read_u32(data)
+        })
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64, Error> {
+        let data = self.bytes(offset, 8)?;
+        Ok(if self.big_endian {
+            BigEndian::read_u64(data)
+        } else {
+            LittleEndian::read_u64(data)
+        })
+    }
+}
+
+/// Parse the `PT_LOAD` segments and the entry point of an ELF file.
+///
+/// Segments without file content, such as `.bss`, are skipped.
+pub fn parse(data: &[u8]) -> Result<ElfFile, Error> {
+    if !is_elf(data) || data.len() < 16 {
+        return Err(Error::InvalidElfFile("not an ELF file".to_string()));
+    }
+    let wide = match data[4] {
+        CLASS_32 => false,
+        CLASS_64 => true,
+        x => return Err(Error::InvalidElfFile(format!("unsupported class {}", x))),
+    };
+    let big_endian = match data[5] {
+        DATA_LSB => false,
+        DATA_MSB => true,
+        x => return Err(Error::InvalidElfFile(format!("unsupported encoding {}", x))),
+    };
+    let reader = Reader { data, big_endian };
+    let (entry, phoff, phentsize, phnum) = if wide {
+        (
+            reader.u64(0x18)?,
+            reader.u64(0x20)?,
+            reader.u16(0x36)?,
+            reader.u16(0x38)?,
+        )
+    } else {
+        (
+            reader.u32(0x18)? as u64,
+            reader.u32(0x1C)? as u64,
+            reader.u16(0x2A)?,
+            reader.u16(0x2C)?,
+        )
+    };
+
+    let mut ret = ElfFile {
+        entry_point: Some(entry).filter(|x| *x != 0),
+        ..Default::default()
+    };
+    for k in 0..phnum as u64 {
+        let header = phoff + k * phentsize as u64;
+        if reader.u32(header)? != PT_LOAD {
+            continue;
+        }
+        let (offset, paddr, filesz) = if wide {
+            (
+                reader.u64(header + 0x08)?,
+                reader.u64(header + 0x18)?,
+                reader.u64(header + 0x20)?,
+            )
+        } else {
+            (
+                reader.u32(header + 0x04)? as u64,
+                reader.u32(header + 0x0C)? as u64,
+                reader.u32(header + 0x10)? as u64,
+            )
+        };
+        let content = reader.bytes(offset, filesz)?;
+        ret.conflicts.extend(ret.segments.conflicts(paddr, content));
+        ret.segments.insert(paddr, content);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A little endian 32 bit ELF file with two loadable segments and a `.bss` segment.
+    fn elf32() -> Vec<u8> {
+        let mut data = vec![0; 0x34];
+        data[..7].copy_from_slice(&[0x7F, b'E', b'L', b'F', CLASS_32, DATA_LSB, 1]);
+        LittleEndian::write_u32(&mut data[0x18..], 0x0800_0101);
+        LittleEndian::write_u32(&mut data[0x1C..], 0x34);
+        LittleEndian::write_u16(&mut data[0x2A..], 0x20);
+        LittleEndian::write_u16(&mut data[0x2C..], 3);
+        // (offset, vaddr, paddr, filesz)
+        let headers = [
+            (0x94, 0x0800_0000, 0x0800_0000, 4),
+            (0x98, 0x2000_0000, 0x0800_0004, 2),
+            (0x9A, 0x2000_0002, 0x2000_0002, 0),
+        ];
+        for (offset, vaddr, paddr, filesz) in headers.iter() {
+            let mut header = [0; 0x20];
+            LittleEndian::write_u32(&mut header[0x00..], PT_LOAD);
+            LittleEndian::write_u32(&mut header[0x04..], *offset);
+            LittleEndian::write_u32(&mut header[0x08..], *vaddr);
+            LittleEndian::write_u32(&mut header[0x0C..], *paddr);
+            LittleEndian::write_u32(&mut header[0x10..], *filesz);
+            LittleEndian::write_u32(&mut header[0x14..], filesz + 0x10);
+            data.extend(&header);
+        }
+        data.extend(&[1, 2, 3, 4, 5, 6]);
+        data
+    }
+
+    #[test]
+    fn load_segments_at_physical_address() {
+        let file = parse(&elf32()).unwrap();
+        assert_eq!(file.entry_point, Some(0x0800_0101));
+        let segments: Vec<_> = file.segments.iter().collect();
+        assert_eq!(segments, vec![(0x0800_0000, &[1, 2, 3, 4, 5, 6][..])]);
+        assert!(file.conflicts.is_empty());
+    }
+
+    #[test]
+    fn reject_truncated_files() {
+        let mut data = elf32();
+        data.truncate(0x98);
+        let err = parse(&data).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid ELF file: 0x2 bytes at offset 0x98 exceed the file size"
+        );
+        let err = parse(b"\x7FELF\x03").unwrap_err();
+        assert_eq!(err.to_string(), "invalid ELF file: not an ELF file");
+    }
+}
@@ -0,0 +1,189 @@
+//! Read the header and user-defined fields of an application image, such that the
+//! build can be configured with values declared in the firmware source.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::header::{self, Header};
+use crate::inspect::{self, InputFormat, InspectOptions};
+use crate::metadata::{self, MetadataEntry};
+use crate::Error;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum ExtractedValue {
+    Number(u64),
+    Text(String),
+}
+
+/// Named values read from an image, in the order of the header layout followed by the
+/// meta-data fields.
+#[derive(Clone, Debug, Default)]
+pub struct Extracted {
+    pub fields: Vec<(String, ExtractedValue)>,
+}
+
+impl Extracted {
+    pub fn get(&self, name: &str) -> Option<&ExtractedValue> {
+        self.fields.iter().find(|(x, _)| x == name).map(|(_, x)| x)
+    }
+
+    fn push(&mut self, name: &str, value: ExtractedValue) -> Result<(), Error> {
+        if self.get(name).is_some() {
+            return Err(Error::InvalidConfig(format!(
+                "Field `{}` is defined twice",
+                name
+            )));
+        }
+        self.fields.push((name.to_string(), value));
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        let map: serde_json::Map<_, _> = self
+            .fields
+            .iter()
+            .map(|(name, value)| (name.clone(), serde_json::to_value(value).unwrap()))
+            .collect();
+        serde_json::to_string_pretty(&map).unwrap()
+    }
+
+    /// `NAME=value` lines, as read by dotenv and similar tools. Names are upper-cased.
+    pub fn to_env(&self, prefix: &str) -> String {
+        let mut ret = String::new();
+        for (name, value) in &self.fields {
+            let value = match value {
+                ExtractedValue::Number(x) => x.to_string(),
+                ExtractedValue::Text(x) => format!("\"{}\"", escape(x)),
+            };
+            writeln!(ret, "{}{}={}", prefix, name.to_uppercase(), value).unwrap();
+        }
+        ret
+    }
+
+    /// A C header with a `#define` for each field. Names are upper-cased.
+    pub fn to_c_header(&self, prefix: &str) -> String {
+        let guard = format!("{}MERGE_TOOL_EXTRACT_H", prefix.to_uppercase());
+        let mut ret = String::new();
+        writeln!(ret, "/* Generated by merge_tool extract. Do not edit. */").unwrap();
+        writeln!(ret, "#ifndef {}", guard).unwrap();
+        writeln!(ret, "#define {}", guard).unwrap();
+        writeln!(ret).unwrap();
+        for (name, value) in &self.fields {
+            let value = match value {
+                ExtractedValue::Number(x) if *x > u32::MAX as u64 => format!("{}ULL", x),
+                ExtractedValue::Number(x) => format!("{}U", x),
+                ExtractedValue::Text(x) => format!("\"{}\"", escape(x)),
+            };
+            writeln!(ret, "#define {}{} {}", prefix, name.to_uppercase(), value).unwrap();
+        }
+        writeln!(ret).unwrap();
+        writeln!(ret, "#endif /* {} */", guard).unwrap();
+        ret
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.chars().flat_map(|x| x.escape_default()).collect()
+}
+
+/// Extract the fields of the image with index `image` of `config` (or its first image) from `path`.
+///
+/// The header is located with the header offset and layout of the image config and the
+/// meta-data fields of the config are decoded as well.
+pub fn extract_with_config(
+    path: &Path,
+    config: &Config,
+    image: Option<usize>,
+) -> Result<Extracted, Error> {
+    let mut config = config.clone();
+    config.transform_to_byte_addrs();
+    let idx = image.unwrap_or(0);
+    let fw_config = config.images.get(idx).ok_or_else(|| {
+        Error::InvalidConfig(format!(
+            "Config does not contain an image with index {}",
+            idx
+        ))
+    })?;
+    let options = InspectOptions {
+        header_offset: Some(fw_config.header_offset),
+        header_layout: config.header_layout(fw_config)?.clone(),
+        base_address: fw_config.app_address.begin,
+        device_config: fw_config.device_config.clone(),
+        ..Default::default()
+    };
+    extract(
+        path,
+        &options,
+        Some(fw_config.app_address.begin),
+        &fw_config.metadata,
+    )
+}
+
+/// Extract the header fields of the application image in `path`.
+///
+/// The application is assumed to start at `app_address`. Otherwise, the image is searched as
+/// in `inspect` and, if no image with a valid CRC is found, it is assumed to start at the
+/// beginning of the file. This allows reading images directly from the compiler output.
+pub fn extract(
+    path: &Path,
+    options: &InspectOptions,
+    app_address: Option<u64>,
+    metadata: &[MetadataEntry],
+) -> Result<Extracted, Error> {
+    let format = InputFormat::detect(path)?;
+    if format == InputFormat::AppPackage {
+        return Err(Error::InvalidConfig(
+            "Cannot extract fields from app packages".to_string(),
+        ));
+    }
+    let mut fw = inspect::load_firmware(path, format, options)?;
+    let (start, header_offset) = match app_address {
+        Some(address) => {
+            let start = address
+                .checked_sub(fw.range.begin)
+                .ok_or(Error::InvalidAddress)?;
+            (start as usize, options.header_offset.unwrap_or(4))
+        }
+        None => match inspect::find_image(&mut fw, options) {
+            Some((start, _, header_offset)) => (start, header_offset),
+            None => (0, options.header_offset.unwrap_or(4)),
+        },
+    };
+    let address = fw.range.begin + start as u64;
+    let config = fw.config.clone();
+
+    let mut ret = Extracted::default();
+    let layout = &options.header_layout;
+    let header = Header::with_layout(&mut fw, (start as u64) + header_offset, layout)?;
+    if layout.field(header::MAJOR_VERSION).is_some() {
+        let version = format!(
+            "{}.{}.{}",
+            header.major_version(),
+            header.minor_version(),
+            header.patch_version()
+        );
+        ret.push("version", ExtractedValue::Text(version))?;
+    }
+    for field in &layout.fields {
+        let value = if field.name == header::GIT_SHA || ![1, 2, 4, 6, 8].contains(&field.width) {
+            ExtractedValue::Text(hex::encode(header.field_bytes(&field.name).unwrap()))
+        } else {
+            ExtractedValue::Number(header.read(&field.name))
+        };
+        ret.push(&field.name, value)?;
+    }
+
+    for (k, entry) in metadata.iter().enumerate() {
+        let Some(name) = &entry.name else {
+            continue;
+        };
+        let value = metadata::decode(entry, &fw.data[start..], address, &config)
+            .map_err(|msg| Error::InvalidConfig(format!("Metadata entry {}: {}", k, msg)))?;
+        ret.push(name, value)?;
+    }
+    Ok(ret)
+}
@@ -1,55 +1,345 @@
-use crate::config::{AddressRange, DeviceConfig, Endianness, HexFileFormat};
-use crate::{intel_hex, srecord, Error};
+use crate::config::{
+    AddressCheck, AddressRange, DeviceConfig, Endianness, HexFileFormat, OutputFormat,
+};
+use crate::segments::SegmentMap;
+use crate::{elf, format_spans, intel_hex, srecord, ti_txt, Error};
 use std::iter::repeat;
 use std::path::Path;
 
+/// `len` rounded up to full pages.
+fn round_to_pages(len: usize, page_size: usize) -> usize {
+    let last_page_size = len % page_size;
+    if last_page_size == 0 {
+        return len;
+    }
+    len + page_size - last_page_size
+}
+
+/// Content of a hex, S-Record, TI-TXT, ELF or binary file.
+pub struct HexFileContent {
+    /// Data records, at byte addresses.
+    pub segments: SegmentMap,
+    pub srecord_header: Option<Vec<u8>>,
+    pub entry_point: Option<u64>,
+    /// Spans written by multiple records with different values.
+    pub conflicts: Vec<AddressRange>,
+}
+
+impl HexFileContent {
+    /// Load a file of the given format. Binary files are placed at `base_address`, leaving out
+    /// runs of 0xFF.
+    pub fn load(
+        path: &Path,
+        file_format: &HexFileFormat,
+        config: &DeviceConfig,
+        base_address: u64,
+    ) -> Result<Self, Error> {
+        Ok(match file_format {
+            HexFileFormat::IntelHex => {
+                let file = intel_hex::load(path, config.word_addressing)?;
+                HexFileContent {
+                    segments: file.segments,
+                    srecord_header: None,
+                    entry_point: file.entry_point,
+                    conflicts: file.conflicts,
+                }
+            }
+            HexFileFormat::SRecord => {
+                let file = srecord::load(path, config.word_addressing)?;
+                HexFileContent {
+                    segments: file.segments,
+                    srecord_header: file.header,
+                    entry_point: file.entry_point,
+                    conflicts: file.conflicts,
+                }
+            }
+            HexFileFormat::TiTxt => {
+                let file = ti_txt::load(path, config.word_addressing)?;
+                HexFileContent {
+                    segments: file.segments,
+                    srecord_header: None,
+                    entry_point: None,
+                    conflicts: file.conflicts,
+                }
+            }
+            HexFileFormat::Elf => {
+                let file = elf::load(path)?;
+                HexFileContent {
+                    segments: file.segments,
+                    srecord_header: None,
+                    entry_point: file.entry_point,
+                    conflicts: file.conflicts,
+                }
+            }
+            HexFileFormat::Binary => {
+                let data = std::fs::read(path)?;
+                HexFileContent {
+                    segments: SegmentMap::from_dense(base_address, &data, 0xFF),
+                    srecord_header: None,
+                    entry_point: None,
+                    conflicts: Vec::new(),
+                }
+            }
+        })
+    }
+
+    /// Log the `outside` spans and conflicting records of the file at `path`.
+    pub fn warn(&self, path: &Path, outside: &[AddressRange]) {
+        if !outside.is_empty() {
+            log::warn!(
+                "{}: ignoring data outside of the address range at {}",
+                path.display(),
+                format_spans(outside)
+            );
+        }
+        if !self.conflicts.is_empty() {
+            log::warn!(
+                "{}: records write different values to {}",
+                path.display(),
+                format_spans(&self.conflicts)
+            );
+        }
+    }
+
+    /// Check the file at `path` for data outside of all `ranges` and for conflicting records.
+    ///
+    /// Depending on `check`, these are rejected, reported as warning or ignored.
+    pub fn check(
+        &self,
+        path: &Path,
+        ranges: &[AddressRange],
+        check: AddressCheck,
+    ) -> Result<(), Error> {
+        let outside = self.segments.outside_all(ranges);
+        match check {
+            AddressCheck::Strict if !outside.is_empty() => Err(Error::HexDataOutOfRange {
+                path: path.to_path_buf(),
+                spans: outside,
+            }),
+            AddressCheck::Strict if !self.conflicts.is_empty() => {
+                Err(Error::ConflictingHexRecords {
+                    path: path.to_path_buf(),
+                    spans: self.conflicts.clone(),
+                })
+            }
+            AddressCheck::Warn => {
+                self.warn(path, &outside);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The firmware covering `range`, containing the data of the file within the range.
+    pub fn into_firmware(
+        self,
+        range: AddressRange,
+        config: DeviceConfig,
+    ) -> Result<Firmware, Error> {
+        let mut ret = Firmware::from_segments(range, config, &self.segments)?;
+        ret.srecord_header = self.srecord_header;
+        ret.entry_point = self.entry_point;
+        Ok(ret)
+    }
+}
+
 #[derive(Clone)]
 pub struct Firmware {
     pub range: AddressRange,
     pub config: DeviceConfig,
+    /// Content of `range`. Bytes which have not been written are 0xFF.
     pub data: Vec<u8>,
+    /// Header record (`S0`) of S-Record files, written back when saving as S-Record.
+    pub srecord_header: Option<Vec<u8>>,
+    /// Entry point given by the start address record of Intel HEX files or the termination
+    /// record of S-Record files.
+    pub entry_point: Option<u64>,
+    /// Sorted and disjoint address ranges which have been loaded or written.
+    written: Vec<AddressRange>,
 }
 
 impl Firmware {
+    /// Create a firmware from a dense buffer. As the buffer does not tell which bytes have been
+    /// written, all bytes other than 0xFF are considered as written.
     pub fn new(range: AddressRange, config: DeviceConfig, data: Vec<u8>) -> Result<Self, Error> {
+        if data.len() != range.len() as usize {
+            return Err(Error::InvalidDataLength);
+        }
+        let segments = SegmentMap::from_dense(range.begin, &data, 0xFF);
+        Self::from_segments(range, config, &segments)
+    }
+
+    /// Create a firmware covering `range` from the data of `segments` within the range.
+    pub fn from_segments(
+        range: AddressRange,
+        config: DeviceConfig,
+        segments: &SegmentMap,
+    ) -> Result<Self, Error> {
         if range.begin % config.page_size != 0 {
             return Err(Error::AddressRangeNotAlignedToPage);
         }
         if range.end % config.page_size != 0 {
             return Err(Error::AddressRangeNotAlignedToPage);
         }
-        if data.len() != range.len() as usize {
-            return Err(Error::InvalidDataLength);
-        }
+        let segments = segments.restrict(&range);
         Ok(Self {
-            data,
+            data: segments.to_dense(&range, 0xFF),
+            written: segments.coverage(),
             range,
             config,
+            srecord_header: None,
+            entry_point: None,
         })
     }
 
+    /// Load the data of a hex file within `range`. Binary files are placed at `base_address`.
+    ///
+    /// Depending on `check`, data outside of `range` and records writing different values to the
+    /// same address are rejected, reported as warning or ignored.
     pub fn load_from_file(
         path: &Path,
         file_format: &HexFileFormat,
         config: &DeviceConfig,
         range: &AddressRange,
+        base_address: u64,
+        check: AddressCheck,
     ) -> Result<Firmware, Error> {
-        let ret = match file_format {
-            HexFileFormat::IntelHex => intel_hex::load(path, config.word_addressing, &range),
-            HexFileFormat::SRecord => srecord::load(path, config.word_addressing, &range),
-        };
-        ret.and_then(|data| Firmware::new(range.clone(), config.clone(), data))
+        let file = HexFileContent::load(path, file_format, config, base_address)?;
+        file.check(path, std::slice::from_ref(range), check)?;
+        file.into_firmware(range.clone(), config.clone())
     }
 
-    pub fn write_to_file(&self, path: &Path, file_format: &HexFileFormat) -> Result<(), Error> {
-        match file_format {
-            HexFileFormat::IntelHex => {
-                intel_hex::save(path, self.config.word_addressing, &self.range, &self.data)
+    /// The written bytes.
+    pub fn segments(&self) -> SegmentMap {
+        let mut ret = SegmentMap::new();
+        for range in &self.written {
+            let begin = (range.begin - self.range.begin) as usize;
+            let end = (range.end - self.range.begin) as usize;
+            ret.insert(range.begin, &self.data[begin..end]);
+        }
+        ret
+    }
+
+    /// The pages within the first `length` bytes which contain written bytes, merged into
+    /// contiguous segments. Unwritten bytes within these pages are 0xFF.
+    pub fn written_pages(&self, length: usize) -> SegmentMap {
+        let page_size = self.config.page_size as usize;
+        let mut ret = SegmentMap::new();
+        for range in &self.written {
+            let begin = (range.begin - self.range.begin) as usize;
+            if begin >= length {
+                break;
             }
-            HexFileFormat::SRecord => {
-                srecord::save(path, self.config.word_addressing, &self.range, &self.data)
+            let end = ((range.end - self.range.begin) as usize).min(length);
+            let begin = begin - begin % page_size;
+            let end = round_to_pages(end, page_size).min(self.data.len());
+            ret.insert(self.range.begin + begin as u64, &self.data[begin..end]);
+        }
+        ret
+    }
+
+    /// Whether the byte at `idx` has been loaded or written.
+    pub fn is_written(&self, idx: usize) -> bool {
+        let address = self.range.begin + idx as u64;
+        self.written
+            .iter()
+            .any(|x| x.begin <= address && address < x.end)
+    }
+
+    /// Write `data` at offset `idx` and mark it as written.
+    pub fn write(&mut self, idx: usize, data: &[u8]) {
+        self.data[idx..idx + data.len()].copy_from_slice(data);
+        self.mark_written(idx, data.len());
+    }
+
+    fn mark_written(&mut self, idx: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let begin = self.range.begin + idx as u64;
+        let mut ret = AddressRange::new(begin, begin + len as u64);
+        self.written.retain(|x| {
+            if x.end < ret.begin || ret.end < x.begin {
+                return true;
+            }
+            ret = AddressRange::new(ret.begin.min(x.begin), ret.end.max(x.end));
+            false
+        });
+        let pos = self.written.partition_point(|x| x.begin < ret.begin);
+        self.written.insert(pos, ret);
+    }
+
+    /// Set all bytes which have not been written to `value` and mark the whole range as written.
+    pub fn fill(&mut self, value: u8) {
+        let mut idx = 0;
+        for range in &self.written {
+            let begin = (range.begin - self.range.begin) as usize;
+            self.data[idx..begin].iter_mut().for_each(|x| *x = value);
+            idx = (range.end - self.range.begin) as usize;
+        }
+        self.data[idx..].iter_mut().for_each(|x| *x = value);
+        self.written = vec![self.range.clone()];
+    }
+
+    /// Erase all data, i.e. fill the buffer with 0xFF and mark it as not written.
+    pub fn erase(&mut self) {
+        self.data.iter_mut().for_each(|x| *x = 0xFF);
+        self.written.clear();
+    }
+
+    /// Erase the data within `range` and mark it as not written.
+    pub fn erase_range(&mut self, range: &AddressRange) {
+        let begin = range.begin.clamp(self.range.begin, self.range.end);
+        let end = range.end.clamp(begin, self.range.end);
+        if begin == end {
+            return;
+        }
+        let offset = self.range.begin;
+        self.data[(begin - offset) as usize..(end - offset) as usize]
+            .iter_mut()
+            .for_each(|x| *x = 0xFF);
+        let mut written = Vec::new();
+        for x in &self.written {
+            if x.begin < begin {
+                written.push(AddressRange::new(x.begin, x.end.min(begin)));
+            }
+            if end < x.end {
+                written.push(AddressRange::new(x.begin.max(end), x.end));
             }
         }
+        self.written = written;
+    }
+
+    pub fn write_to_file(&self, path: &Path, output: &OutputFormat) -> Result<(), Error> {
+        let word_addressing = self.config.word_addressing;
+        let options = &output.records;
+        match output.format {
+            HexFileFormat::IntelHex => intel_hex::save(
+                path,
+                word_addressing,
+                &self.range,
+                &self.data,
+                self.entry_point,
+                options,
+            ),
+            HexFileFormat::SRecord => srecord::save(
+                path,
+                word_addressing,
+                &self.range,
+                &self.data,
+                self.srecord_header.as_deref(),
+                self.entry_point,
+                options,
+            ),
+            HexFileFormat::TiTxt => {
+                ti_txt::save(path, word_addressing, &self.range, &self.data, options)
+            }
+            HexFileFormat::Elf | HexFileFormat::Binary => Err(Error::InvalidConfig(format!(
+                "Cannot save {} as {:?}",
+                path.display(),
+                output.format
+            ))),
+        }
     }
 
     pub fn write_binary_to_file(&self, path: &Path) -> Result<(), Error> {
@@ -88,14 +378,8 @@ impl Firmware {
         let lsb = (data & 0xFF) as u8;
         let msb = ((data >> 8) & 0xFF) as u8;
         match self.config.endianness {
-            Endianness::Big => {
-                self.data[idx] = msb;
-                self.data[idx + 1] = lsb;
-            }
-            Endianness::Little => {
-                self.data[idx] = lsb;
-                self.data[idx + 1] = msb;
-            }
+            Endianness::Big => self.write(idx, &[msb, lsb]),
+            Endianness::Little => self.write(idx, &[lsb, msb]),
         }
     }
 
@@ -112,21 +396,22 @@ impl Firmware {
         }
     }
 
+    /// Length from the start of the firmware to the last written byte, rounded up to full pages.
     pub fn image_length(&self) -> usize {
-        let mut k = self.data.len() - 1;
-        while self.data[k] == 0xFF {
-            k -= 1;
-            if k == 0 {
-                break;
-            }
-        }
-        let len = k + 1;
-        let page_size = self.config.page_size as usize;
-        let last_page_size = len % page_size;
-        if last_page_size == 0 {
-            return len;
-        }
-        return len + page_size - last_page_size;
+        self.image_length_from(0)
+    }
+
+    /// Length from offset `start` to the last written byte, rounded up to full pages.
+    ///
+    /// If nothing has been written after `start`, the length of one page is returned.
+    pub fn image_length_from(&self, start: usize) -> usize {
+        let end = self
+            .written
+            .last()
+            .map(|x| (x.end - self.range.begin) as usize)
+            .unwrap_or(0);
+        let len = end.saturating_sub(start).max(1);
+        round_to_pages(len, self.config.page_size as usize)
     }
 
     pub fn concatenate(first: &Firmware, second: &Firmware) -> Result<Firmware, Error> {
@@ -141,7 +426,99 @@ impl Firmware {
         new_code.extend(repeat(0xFF).take(gap as usize));
         new_code.extend(second.data.clone());
 
-        let ret = Firmware::new(new_range, first.config.clone(), new_code)?;
+        let mut ret = Firmware::new(new_range, first.config.clone(), new_code)?;
+        ret.written = first.written.clone();
+        ret.written.extend(second.written.iter().cloned());
+        // the merged image carries the entry point of the application
+        ret.srecord_header = second
+            .srecord_header
+            .clone()
+            .or_else(|| first.srecord_header.clone());
+        ret.entry_point = second.entry_point.or(first.entry_point);
         Ok(ret)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn firmware(segments: &SegmentMap) -> Firmware {
+        let config = DeviceConfig {
+            page_size: 16,
+            ..Default::default()
+        };
+        Firmware::from_segments(AddressRange::new(0x100, 0x160), config, segments).unwrap()
+    }
+
+    #[test]
+    fn image_length_counts_written_0xff() {
+        let mut segments = SegmentMap::new();
+        segments.insert(0x100, &[1, 2, 3]);
+        segments.insert(0x110, &[0xFF, 0xFF]);
+        let mut fw = firmware(&segments);
+        assert_eq!(fw.image_length(), 32);
+        assert!(fw.is_written(0x10));
+        assert!(!fw.is_written(0x12));
+
+        fw.write_u16(0x40, 0xFFFF);
+        assert_eq!(fw.image_length(), 80);
+        assert_eq!(fw.image_length_from(0x40), 16);
+        fw.erase();
+        assert_eq!(fw.image_length(), 16);
+        assert!(fw.segments().is_empty());
+
+        // dense data without coverage information
+        let mut data = vec![0xFF; 0x60];
+        data[0x11] = 0;
+        let fw = Firmware::new(AddressRange::new(0x100, 0x160), fw.config.clone(), data).unwrap();
+        assert_eq!(fw.image_length(), 32);
+    }
+
+    #[test]
+    fn fill_unwritten_bytes() {
+        let mut segments = SegmentMap::new();
+        segments.insert(0x104, &[1, 2]);
+        segments.insert(0x150, &[0xFF]);
+        let mut fw = firmware(&segments);
+        fw.fill(0);
+        assert_eq!(fw.image_length(), 0x60);
+        assert_eq!(fw.data[..6], [0, 0, 0, 0, 1, 2]);
+        assert_eq!(fw.data[0x4F..0x52], [0, 0xFF, 0]);
+        assert_eq!(fw.segments().coverage(), vec![fw.range.clone()]);
+
+        fw.erase_range(&AddressRange::new(0x110, 0x120));
+        fw.erase_range(&AddressRange::new(0x150, 0x200));
+        assert_eq!(fw.data[0x10..0x20], [0xFF; 16]);
+        assert_eq!(
+            fw.segments().coverage(),
+            vec![
+                AddressRange::new(0x100, 0x110),
+                AddressRange::new(0x120, 0x150)
+            ]
+        );
+    }
+
+    #[test]
+    fn written_pages_skip_unwritten_pages() {
+        let mut segments = SegmentMap::new();
+        segments.insert(0x104, &[1, 2]);
+        segments.insert(0x112, &[3]);
+        segments.insert(0x134, &[4]);
+        segments.insert(0x150, &[5]);
+        let fw = firmware(&segments);
+        assert_eq!(fw.segments(), segments);
+
+        let pages = fw.written_pages(0x40);
+        let coverage = pages.coverage();
+        assert_eq!(
+            coverage,
+            vec![
+                AddressRange::new(0x100, 0x120),
+                AddressRange::new(0x130, 0x140)
+            ]
+        );
+        let first = pages.iter().next().unwrap().1;
+        assert_eq!(&first[0..6], &[0xFF, 0xFF, 0xFF, 0xFF, 1, 2]);
+    }
+}
@@ -1,11 +1,77 @@
+use std::collections::HashMap;
+use std::fs;
 use std::path::Path;
 
+use gix::bstr::BString;
 use gix::commit::describe::SelectRef;
+use gix::index::entry::Mode;
+use gix::ObjectId;
 
 pub struct GitDescription {
     pub parent_tag_name: Option<String>,
     pub on_tag: bool,
     pub sha: String,
+    /// Full commit id of HEAD.
+    pub commit_id: ObjectId,
+    /// Tracked files differ from HEAD, either in the index or in the working tree.
+    pub dirty: bool,
+    /// Commit time of HEAD in seconds since the unix epoch.
+    pub commit_time: i64,
+    /// Number of commits between HEAD and the parent tag, if there is one.
+    pub distance: Option<u32>,
+}
+
+/// Same as `git describe --dirty`, untracked files are ignored.
+fn is_dirty(repo: &gix::Repository, tree: &gix::Tree<'_>) -> anyhow::Result<bool> {
+    let Some(work_dir) = repo.work_dir() else {
+        return Ok(false);
+    };
+    let mut recorder = gix::traverse::tree::Recorder::default();
+    tree.traverse().breadthfirst(&mut recorder)?;
+    let head_entries: HashMap<BString, ObjectId> = recorder
+        .records
+        .into_iter()
+        .filter(|x| !x.mode.is_tree())
+        .map(|x| (x.filepath, x.oid))
+        .collect();
+
+    let index = repo.index_or_empty()?;
+    if index.entries().len() != head_entries.len() {
+        return Ok(true);
+    }
+    for entry in index.entries() {
+        let path = entry.path(&index);
+        if head_entries.get(path) != Some(&entry.id) {
+            return Ok(true);
+        }
+        if entry.mode == Mode::COMMIT {
+            continue;
+        }
+        let path = work_dir.join(gix::path::from_bstr(path));
+        let data = if entry.mode == Mode::SYMLINK {
+            fs::read_link(&path).map(|x| gix::path::into_bstr(x).to_vec())
+        } else {
+            let Ok(metadata) = fs::symlink_metadata(&path) else {
+                return Ok(true);
+            };
+            let mtime = metadata
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            if metadata.len() == entry.stat.size as u64 && mtime == entry.stat.mtime.secs as u64 {
+                continue;
+            }
+            fs::read(&path)
+        };
+        let Ok(data) = data else {
+            return Ok(true);
+        };
+        let id = gix::objs::compute_hash(repo.object_hash(), gix::objs::Kind::Blob, &data);
+        if id != entry.id {
+            return Ok(true);
+        }
+    }
+    Ok(false)
 }
 
 fn retrieve_description_inner(repo_path: &Path) -> anyhow::Result<GitDescription> {
@@ -16,15 +82,19 @@ fn retrieve_description_inner(repo_path: &Path) -> anyhow::Result<GitDescription
         .describe()
         .names(SelectRef::AllTags)
         .id_as_fallback(false);
-    let sha = format!("{}", head_commit.id())
-        .chars()
-        .take(6)
-        .collect::<String>();
+    let commit_id = head_commit.id;
+    let sha = format!("{}", commit_id).chars().take(6).collect::<String>();
+    let dirty = is_dirty(&repo, &head_commit.tree()?)?;
+    let commit_time = head_commit.time()?.seconds;
     let Some(resolution) = describe.try_resolve()? else {
         return Ok(GitDescription {
             parent_tag_name: None,
             on_tag: false,
             sha,
+            commit_id,
+            dirty,
+            commit_time,
+            distance: None,
         });
     };
     // save because we don't ask for a fallback
@@ -41,6 +111,10 @@ fn retrieve_description_inner(repo_path: &Path) -> anyhow::Result<GitDescription
         parent_tag_name: Some(tag_name.to_string()),
         on_tag: tag_id == head_id,
         sha,
+        commit_id,
+        dirty,
+        commit_time,
+        distance: Some(resolution.outcome.depth),
     })
 }
 
@@ -60,3 +134,57 @@ impl GitDescription {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn dirty_state_and_distance() {
+        let dir = std::env::temp_dir().join(format!("merge_tool_git_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        git(&dir, &["init", "-q"]);
+        git(&dir, &["add", "a.txt"]);
+        git(&dir, &["commit", "-q", "-m", "first"]);
+        git(&dir, &["tag", "v1.0.0"]);
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        git(&dir, &["add", "b.txt"]);
+        git(&dir, &["commit", "-q", "-m", "second"]);
+
+        let desc = retrieve_description(&dir).unwrap();
+        assert_eq!(desc.parent_tag_name.as_deref(), Some("v1.0.0"));
+        assert_eq!(desc.distance, Some(1));
+        assert!(!desc.dirty);
+        assert!(desc.commit_time > 0);
+        assert!(desc.commit_id.to_string().starts_with(&desc.sha));
+
+        // untracked files are ignored
+        fs::write(dir.join("c.txt"), "c").unwrap();
+        assert!(!retrieve_description(&dir).unwrap().dirty);
+
+        git(&dir, &["add", "c.txt"]);
+        assert!(retrieve_description(&dir).unwrap().dirty);
+        git(&dir, &["rm", "-q", "--cached", "c.txt"]);
+        assert!(!retrieve_description(&dir).unwrap().dirty);
+
+        fs::write(dir.join("a.txt"), "modified").unwrap();
+        assert!(retrieve_description(&dir).unwrap().dirty);
+        fs::remove_file(dir.join("a.txt")).unwrap();
+        assert!(retrieve_description(&dir).unwrap().dirty);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,112 +1,380 @@
+use serde::{Deserialize, Serialize};
+
 use crate::firmware::Firmware;
 use crate::Error;
 
-const PRODUCT_ID_OFFSET: usize = 0;
-const FW_ID_OFFSET: usize = 2;
-const MAJOR_VERSION_OFFSET: usize = 4;
-const MINOR_VERSION_OFFSET: usize = 6;
-const PATCH_VERSION_OFFSET: usize = 8;
-const LENGTH_OFFSET: usize = 12;
-const BUILD_VARIANT_OFFSET: usize = 16;
-const TIMESTAMP_OFFSET: usize = 18;
-const KEY_ID_OFFSET: usize = 24;
+/// Names of the built-in header layouts.
+pub const V1_LAYOUT: &str = "v1";
+pub const V1_GIT_LAYOUT: &str = "v1_git";
+pub const V1_SECURITY_LAYOUT: &str = "v1_security";
+pub const V1_GIT_SECURITY_LAYOUT: &str = "v1_git_security";
+
+pub const PRODUCT_ID: &str = "product_id";
+pub const NODE_ID: &str = "node_id";
+pub const MAJOR_VERSION: &str = "major_version";
+pub const MINOR_VERSION: &str = "minor_version";
+pub const PATCH_VERSION: &str = "patch_version";
+pub const LENGTH: &str = "length";
+pub const BUILD_VARIANT: &str = "build_variant";
+pub const TIMESTAMP: &str = "timestamp";
+pub const KEY_ID: &str = "key_id";
+pub const GIT_SHA: &str = "git_sha";
+pub const GIT_DIRTY: &str = "git_dirty";
+pub const GIT_COMMIT_TIME: &str = "git_commit_time";
+pub const GIT_DISTANCE: &str = "git_distance";
+pub const SECURITY_VERSION: &str = "security_version";
+
+/// Fields with a meaning to the merge tool and the minimum width required to store them.
+/// Other field names may be used to reserve space in the header.
+const KNOWN_FIELDS: [(&str, usize); 13] = [
+    (PRODUCT_ID, 2),
+    (NODE_ID, 1),
+    (MAJOR_VERSION, 2),
+    (MINOR_VERSION, 2),
+    (PATCH_VERSION, 4),
+    (LENGTH, 4),
+    (BUILD_VARIANT, 2),
+    (TIMESTAMP, 6),
+    (KEY_ID, 4),
+    (GIT_DIRTY, 1),
+    (GIT_COMMIT_TIME, 4),
+    (GIT_DISTANCE, 2),
+    (SECURITY_VERSION, 2),
+];
+
+/// Fields describing the git commit, which are written if a git repository is available.
+pub const GIT_FIELDS: [&str; 4] = [GIT_SHA, GIT_DIRTY, GIT_COMMIT_TIME, GIT_DISTANCE];
+
+/// Maximum width of the `git_sha` field, i.e. the length of a SHA-1 commit id.
+const GIT_SHA_MAX_WIDTH: usize = 20;
+
+lazy_static! {
+    static ref V1: HeaderLayout = HeaderLayout::v1();
+    static ref V1_GIT: HeaderLayout = HeaderLayout::v1_git();
+    static ref V1_SECURITY: HeaderLayout = HeaderLayout::v1_security();
+    static ref V1_GIT_SECURITY: HeaderLayout = HeaderLayout::v1_git_security();
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct HeaderFieldLayout {
+    pub name: String,
+    /// Byte offset of the field within the header.
+    pub offset: usize,
+    /// Width of the field in bytes.
+    pub width: usize,
+}
+
+/// Describes where the fields are located within the firmware header.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct HeaderLayout {
+    /// Length of the header in bytes.
+    pub length: usize,
+    pub fields: Vec<HeaderFieldLayout>,
+}
+
+impl Default for HeaderLayout {
+    fn default() -> Self {
+        Self::v1()
+    }
+}
+
+impl HeaderLayout {
+    /// The built-in 32-byte layout, refer to `doc/flash_layout.md`.
+    pub fn v1() -> Self {
+        let fields = [
+            (PRODUCT_ID, 0, 2),
+            (NODE_ID, 2, 1),
+            (MAJOR_VERSION, 4, 2),
+            (MINOR_VERSION, 6, 2),
+            (PATCH_VERSION, 8, 4),
+            (LENGTH, 12, 4),
+            (BUILD_VARIANT, 16, 2),
+            (TIMESTAMP, 18, 6),
+            (KEY_ID, 24, 4),
+        ];
+        HeaderLayout {
+            length: 32,
+            fields: fields
+                .iter()
+                .map(|(name, offset, width)| HeaderFieldLayout {
+                    name: name.to_string(),
+                    offset: *offset,
+                    width: *width,
+                })
+                .collect(),
+        }
+    }
+
+    /// Look up a built-in layout by name.
+    pub fn builtin(name: &str) -> Option<&'static HeaderLayout> {
+        match name {
+            V1_LAYOUT => Some(&V1),
+            V1_GIT_LAYOUT => Some(&V1_GIT),
+            V1_SECURITY_LAYOUT => Some(&V1_SECURITY),
+            V1_GIT_SECURITY_LAYOUT => Some(&V1_GIT_SECURITY),
+            _ => None,
+        }
+    }
+
+    /// The `v1` layout with the first 3 bytes of the commit id and the dirty flag
+    /// in the reserved bytes 28 to 31.
+    pub fn v1_git() -> Self {
+        let mut ret = Self::v1();
+        for (name, offset, width) in [(GIT_SHA, 28, 3), (GIT_DIRTY, 31, 1)] {
+            ret.fields.push(HeaderFieldLayout {
+                name: name.to_string(),
+                offset,
+                width,
+            });
+        }
+        ret
+    }
+
+    /// The `v1` layout with the 16-bit anti-rollback security version in the reserved
+    /// bytes 28 and 29.
+    pub fn v1_security() -> Self {
+        let mut ret = Self::v1();
+        ret.fields.push(HeaderFieldLayout {
+            name: SECURITY_VERSION.to_string(),
+            offset: 28,
+            width: 2,
+        });
+        ret
+    }
 
-const HEADER_LENGTH: usize = 32;
+    /// The `v1_git` layout extended to 36 bytes with the security version in bytes 32 and
+    /// 33, as `v1_git` and `v1_security` both use the reserved bytes of `v1`.
+    pub fn v1_git_security() -> Self {
+        let mut ret = Self::v1_git();
+        ret.length = 36;
+        ret.fields.push(HeaderFieldLayout {
+            name: SECURITY_VERSION.to_string(),
+            offset: 32,
+            width: 2,
+        });
+        ret
+    }
 
+    pub fn has_git_fields(&self) -> bool {
+        GIT_FIELDS.iter().any(|x| self.field(x).is_some())
+    }
+
+    pub fn field(&self, name: &str) -> Option<&HeaderFieldLayout> {
+        self.fields.iter().find(|x| x.name == name)
+    }
+
+    /// Check that all fields fit into the header without overlapping and that the fields
+    /// known to the merge tool have a supported width.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg: String| Err(Error::InvalidConfig(format!("Header layout: {}", msg)));
+        if self.field(LENGTH).is_none() {
+            return invalid(format!("`{}` field is required", LENGTH));
+        }
+        for (idx, field) in self.fields.iter().enumerate() {
+            if field.width == 0 || field.offset + field.width > self.length {
+                return invalid(format!(
+                    "field `{}` does not fit into the header of {} bytes",
+                    field.name, self.length
+                ));
+            }
+            if field.name == GIT_SHA && field.width > GIT_SHA_MAX_WIDTH {
+                return invalid(format!(
+                    "field `{}` is wider than {} bytes",
+                    field.name, GIT_SHA_MAX_WIDTH
+                ));
+            }
+            if let Some((_, min_width)) = KNOWN_FIELDS.iter().find(|(x, _)| *x == field.name) {
+                if field.width < *min_width || ![1, 2, 4, 6, 8].contains(&field.width) {
+                    return invalid(format!(
+                        "field `{}` has unsupported width {}",
+                        field.name, field.width
+                    ));
+                }
+            }
+            for other in &self.fields[..idx] {
+                if other.name == field.name {
+                    return invalid(format!("field `{}` is defined twice", field.name));
+                }
+                if field.offset < other.offset + other.width
+                    && other.offset < field.offset + field.width
+                {
+                    return invalid(format!(
+                        "fields `{}` and `{}` overlap",
+                        other.name, field.name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Accesses the firmware header of an application image.
+///
+/// Fields missing in the header layout read as 0 and writes to them are ignored.
 pub struct Header<'a> {
     fw: &'a mut Firmware,
     offset: usize,
+    layout: &'a HeaderLayout,
 }
 
 impl<'a> Header<'a> {
+    /// Access a header with the built-in [`V1_LAYOUT`].
     pub fn new(fw: &'a mut Firmware, offset: u64) -> Result<Self, Error> {
-        if fw.data.len() < offset as usize + HEADER_LENGTH {
+        Self::with_layout(fw, offset, &V1)
+    }
+
+    pub fn with_layout(
+        fw: &'a mut Firmware,
+        offset: u64,
+        layout: &'a HeaderLayout,
+    ) -> Result<Self, Error> {
+        if fw.data.len() < offset as usize + layout.length {
             Err(Error::ImageTooShortForHeader)
         } else {
             Ok(Self {
                 fw,
                 offset: offset as usize,
+                layout,
             })
         }
     }
 
-    pub fn product_id(&self) -> u16 {
-        self.fw.read_u16(self.offset + PRODUCT_ID_OFFSET)
+    /// Read a numeric field. Fields of 6 and 8 bytes are stored as 32-bit low word
+    /// followed by the high word.
+    pub fn read(&self, name: &str) -> u64 {
+        let Some(field) = self.layout.field(name) else {
+            return 0;
+        };
+        let idx = self.offset + field.offset;
+        match field.width {
+            1 => self.fw.data[idx] as u64,
+            2 => self.fw.read_u16(idx) as u64,
+            6 => self.fw.read_u32(idx) as u64 | (self.fw.read_u16(idx + 4) as u64) << 32,
+            8 => self.fw.read_u32(idx) as u64 | (self.fw.read_u32(idx + 4) as u64) << 32,
+            _ => self.fw.read_u32(idx) as u64,
+        }
     }
 
-    pub fn set_product_id(&mut self, value: u16) {
-        self.fw.write_u16(self.offset + PRODUCT_ID_OFFSET, value);
+    /// Write a numeric field, using the same encoding as [`Header::read`].
+    pub fn write(&mut self, name: &str, value: u64) {
+        let Some(field) = self.layout.field(name) else {
+            return;
+        };
+        let idx = self.offset + field.offset;
+        match field.width {
+            1 => self.fw.write(idx, &[value as u8]),
+            2 => self.fw.write_u16(idx, value as u16),
+            6 => {
+                self.fw.write_u32(idx, (value & 0xFFFFFFFF) as u32);
+                self.fw.write_u16(idx + 4, ((value >> 32) & 0xFFFF) as u16);
+            }
+            8 => {
+                self.fw.write_u32(idx, (value & 0xFFFFFFFF) as u32);
+                self.fw.write_u32(idx + 4, (value >> 32) as u32);
+            }
+            _ => self.fw.write_u32(idx, value as u32),
+        }
+    }
+
+    /// Raw bytes of a field, e.g. of a field holding a string or a hash.
+    pub fn field_bytes(&self, name: &str) -> Option<&[u8]> {
+        let field = self.layout.field(name)?;
+        let idx = self.offset + field.offset;
+        Some(&self.fw.data[idx..idx + field.width])
+    }
+
+    /// Write the raw bytes of a field. `value` is truncated to the width of the field.
+    pub fn set_field_bytes(&mut self, name: &str, value: &[u8]) {
+        let Some(field) = self.layout.field(name) else {
+            return;
+        };
+        let idx = self.offset + field.offset;
+        let len = value.len().min(field.width);
+        self.fw.write(idx, &value[..len]);
+    }
+
+    pub fn product_id(&self) -> u32 {
+        self.read(PRODUCT_ID) as u32
+    }
+
+    pub fn set_product_id(&mut self, value: u32) {
+        self.write(PRODUCT_ID, value as u64);
     }
 
     pub fn major_version(&self) -> u16 {
-        self.fw.read_u16(self.offset + MAJOR_VERSION_OFFSET)
+        self.read(MAJOR_VERSION) as u16
     }
 
     pub fn set_major_version(&mut self, value: u16) {
-        self.fw.write_u16(self.offset + MAJOR_VERSION_OFFSET, value);
+        self.write(MAJOR_VERSION, value as u64);
     }
 
     pub fn minor_version(&self) -> u16 {
-        self.fw.read_u16(self.offset + MINOR_VERSION_OFFSET)
+        self.read(MINOR_VERSION) as u16
     }
 
     pub fn set_minor_version(&mut self, value: u16) {
-        self.fw.write_u16(self.offset + MINOR_VERSION_OFFSET, value);
+        self.write(MINOR_VERSION, value as u64);
     }
 
     pub fn patch_version(&self) -> u32 {
-        self.fw.read_u32(self.offset + PATCH_VERSION_OFFSET)
+        self.read(PATCH_VERSION) as u32
     }
 
     pub fn set_patch_version(&mut self, value: u32) {
-        self.fw.write_u32(self.offset + PATCH_VERSION_OFFSET, value);
+        self.write(PATCH_VERSION, value as u64);
     }
 
     pub fn fw_id(&self) -> u8 {
-        self.fw.data[self.offset + FW_ID_OFFSET]
+        self.read(NODE_ID) as u8
     }
 
     pub fn set_fw_id(&mut self, value: u8) {
-        self.fw.data[self.offset + FW_ID_OFFSET] = value;
+        self.write(NODE_ID, value as u64);
     }
 
     pub fn length(&self) -> u32 {
-        self.fw.read_u32(self.offset + LENGTH_OFFSET)
+        self.read(LENGTH) as u32
     }
 
     pub fn set_length(&mut self, value: u32) {
-        self.fw.write_u32(self.offset + LENGTH_OFFSET, value);
+        self.write(LENGTH, value as u64);
     }
 
     pub fn set_timestamp(&mut self, value: u64) {
-        self.fw
-            .write_u32(self.offset + TIMESTAMP_OFFSET, (value & 0xFFFFFFFF) as u32);
-        self.fw.write_u16(
-            self.offset + TIMESTAMP_OFFSET + 4,
-            ((value >> 32) & 0xFFFF) as u16,
-        );
+        self.write(TIMESTAMP, value);
     }
 
     pub fn get_timestamp(&self) -> u64 {
-        let low = self.fw.read_u32(self.offset + TIMESTAMP_OFFSET) as u64;
-        let high = self.fw.read_u16(self.offset + TIMESTAMP_OFFSET + 4) as u64;
-        low | (high << 32)
+        self.read(TIMESTAMP)
     }
 
     pub fn get_build_variant(&self) -> u16 {
-        self.fw.read_u16(self.offset + BUILD_VARIANT_OFFSET)
+        self.read(BUILD_VARIANT) as u16
     }
 
     pub fn set_build_variant(&mut self, value: u16) {
-        self.fw.write_u16(self.offset + BUILD_VARIANT_OFFSET, value);
+        self.write(BUILD_VARIANT, value as u64);
     }
 
     pub fn key_id(&self) -> u32 {
-        self.fw.read_u32(self.offset + KEY_ID_OFFSET)
+        self.read(KEY_ID) as u32
     }
 
     pub fn set_key_id(&mut self, value: u32) {
-        self.fw.write_u32(self.offset + KEY_ID_OFFSET, value);
+        self.write(KEY_ID, value as u64);
+    }
+
+    /// The anti-rollback security version, if the layout has the field.
+    pub fn security_version(&self) -> Option<u16> {
+        self.layout
+            .field(SECURITY_VERSION)
+            .map(|_| self.read(SECURITY_VERSION) as u16)
+    }
+
+    pub fn set_security_version(&mut self, value: u16) {
+        self.write(SECURITY_VERSION, value as u64);
     }
 }
 
@@ -130,4 +398,99 @@ mod test {
         header.set_timestamp(0x000056789ABCDEF0);
         assert_eq!(header.get_timestamp(), 0x000056789ABCDEF0);
     }
+
+    #[test]
+    fn v1_layout_matches_fixed_offsets() {
+        use crate::firmware::Firmware;
+        use crate::header::{Header, HeaderLayout};
+
+        HeaderLayout::v1().validate().unwrap();
+        let mut fw = Firmware::new(
+            AddressRange::new(0, 64),
+            DeviceConfig::default(),
+            vec![0xFF; 64],
+        )
+        .unwrap();
+        let mut header = Header::new(&mut fw, 4).unwrap();
+        header.set_product_id(0x1234);
+        header.set_fw_id(0x56);
+        header.set_patch_version(0x789ABCDE);
+        header.set_length(0x100);
+        header.set_key_id(0x11223344);
+        assert_eq!(&fw.data[4..8], &[0x34, 0x12, 0x56, 0xFF]);
+        assert_eq!(&fw.data[12..16], &[0xDE, 0xBC, 0x9A, 0x78]);
+        assert_eq!(&fw.data[16..20], &[0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(
+            &fw.data[28..36],
+            &[0x44, 0x33, 0x22, 0x11, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn builtin_layouts_are_valid() {
+        use crate::header::{HeaderLayout, GIT_SHA, SECURITY_VERSION};
+
+        for name in ["v1", "v1_git", "v1_security", "v1_git_security"] {
+            HeaderLayout::builtin(name).unwrap().validate().unwrap();
+        }
+        // `v1_git` and `v1_security` share the reserved bytes
+        let mut layout = HeaderLayout::v1_git();
+        let security_version = HeaderLayout::v1_security().field(SECURITY_VERSION).cloned();
+        layout.fields.push(security_version.unwrap());
+        assert!(layout.validate().is_err());
+
+        let layout = HeaderLayout::v1_git_security();
+        assert_eq!(layout.field(GIT_SHA).unwrap().offset, 28);
+        assert_eq!(layout.field(SECURITY_VERSION).unwrap().offset, 32);
+    }
+
+    #[test]
+    fn custom_layout() {
+        use crate::config::Endianness;
+        use crate::firmware::Firmware;
+        use crate::header::{Header, HeaderFieldLayout, HeaderLayout};
+
+        let field = |name: &str, offset, width| HeaderFieldLayout {
+            name: name.to_string(),
+            offset,
+            width,
+        };
+        let layout = HeaderLayout {
+            length: 16,
+            fields: vec![
+                field("product_id", 0, 4),
+                field("length", 4, 4),
+                field("git_sha", 8, 4),
+                field("timestamp", 12, 4),
+            ],
+        };
+        // 4 bytes are too short for the timestamp
+        assert!(layout.validate().is_err());
+
+        let mut layout = layout;
+        layout.fields[3].width = 8;
+        assert!(layout.validate().is_err());
+        layout.length = 20;
+        layout.validate().unwrap();
+        layout.fields[2].offset = 6;
+        assert!(layout.validate().is_err());
+        layout.fields[2].offset = 8;
+
+        let config = DeviceConfig {
+            endianness: Endianness::Big,
+            ..Default::default()
+        };
+        let mut fw = Firmware::new(AddressRange::new(0, 64), config, vec![0xFF; 64]).unwrap();
+        assert!(Header::with_layout(&mut fw, 48, &layout).is_err());
+        let mut header = Header::with_layout(&mut fw, 0, &layout).unwrap();
+        header.set_product_id(0x1234_5678);
+        header.set_fw_id(0x56);
+        header.set_timestamp(0x0102030405060708);
+        assert_eq!(header.product_id(), 0x1234_5678);
+        assert_eq!(header.fw_id(), 0);
+        assert_eq!(header.get_timestamp(), 0x0102030405060708);
+        assert_eq!(&fw.data[0..4], &[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(&fw.data[8..12], &[0xFF; 4]);
+        assert_eq!(&fw.data[12..20], &[5, 6, 7, 8, 1, 2, 3, 4]);
+    }
 }
@@ -0,0 +1,738 @@
+//! Decode the content of hex files, binary images and app packages.
+//!
+//! This allows examining artifacts without having access to the config file
+//! which was used to generate them. The application image is located by
+//! searching for a firmware header whose length field and CRC are consistent.
+
+use std::fmt;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use semver::Version;
+use serde::Serialize;
+
+use crate::app_package::{App, AppPackage, BINARY_FILE_EXTENSION, JSON_FILE_EXTENSION};
+use crate::btl_trailer::{BtlTrailer, TrailerField, TRAILER_MAGIC, TRAILER_TOTAL_SIZE};
+use crate::config::{AddressRange, DeviceConfig, HexFileFormat, SignatureType};
+use crate::crc::crc32;
+use crate::firmware::{Firmware, HexFileContent};
+use crate::header::{self, Header, HeaderLayout};
+use crate::key_ring::KeyRing;
+use crate::segments::SegmentMap;
+use crate::signer;
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum InputFormat {
+    IntelHex,
+    SRecord,
+    TiTxt,
+    Elf,
+    Binary,
+    AppPackage,
+}
+
+impl InputFormat {
+    /// Guess the format from the file extension, falling back to the file content.
+    pub fn detect(path: &Path) -> Result<Self, Error> {
+        let name = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        if name.ends_with(BINARY_FILE_EXTENSION) || name.ends_with(JSON_FILE_EXTENSION) {
+            return Ok(InputFormat::AppPackage);
+        }
+        let ext = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default();
+        match ext.to_lowercase().as_str() {
+            "hex" | "ihex" => return Ok(InputFormat::IntelHex),
+            "s37" | "s19" | "s28" | "srec" | "mot" => return Ok(InputFormat::SRecord),
+            "txt" => return Ok(InputFormat::TiTxt),
+            "elf" | "axf" | "out" => return Ok(InputFormat::Elf),
+            "bin" => return Ok(InputFormat::Binary),
+            _ => {}
+        }
+        let data = std::fs::read(path)?;
+        Ok(match HexFileFormat::from_content(&data) {
+            Some(format) => format.into(),
+            None => InputFormat::Binary,
+        })
+    }
+}
+
+impl From<HexFileFormat> for InputFormat {
+    fn from(x: HexFileFormat) -> Self {
+        match x {
+            HexFileFormat::IntelHex => InputFormat::IntelHex,
+            HexFileFormat::SRecord => InputFormat::SRecord,
+            HexFileFormat::TiTxt => InputFormat::TiTxt,
+            HexFileFormat::Elf => InputFormat::Elf,
+            HexFileFormat::Binary => InputFormat::Binary,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct InspectOptions {
+    /// Offset of the header relative to the start of the application image.
+    /// Defaults to directly after the CRC.
+    pub header_offset: Option<u64>,
+    pub header_layout: HeaderLayout,
+    /// Address at which a binary file is placed.
+    pub base_address: u64,
+    pub device_config: DeviceConfig,
+    /// Public keys used to verify the signature of signed images. The key is selected
+    /// by matching the key ID in the header against the CRC32 of the public key. The
+    /// signature type is derived from the length of the key.
+    pub public_keys: Vec<Vec<u8>>,
+    /// Trusted keys, selected by the key ID like `public_keys`. Images signed with a
+    /// revoked key or built outside of the validity window of the key are rejected.
+    pub key_ring: KeyRing,
+    /// Address ranges erased after loading a hex or binary file, e.g. the extra inputs of a
+    /// merged file.
+    pub exclude: Vec<AddressRange>,
+    /// Let `verify` pass images which must be signed, but whose signature cannot be checked
+    /// as no public key is given.
+    pub allow_unverified: bool,
+}
+
+impl InspectOptions {
+    /// The public key with the given key ID, taken from `public_keys` or the key ring.
+    fn public_key(&self, key_id: u32) -> Option<Vec<u8>> {
+        self.public_keys
+            .iter()
+            .find(|x| crc32(&x[..]) == key_id)
+            .cloned()
+            .or_else(|| self.key_ring.find(key_id)?.public_key().ok())
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        if self.device_config.page_size == 0 {
+            return Err(Error::InvalidConfig(
+                "The page size must not be 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum SignatureStatus {
+    Unsigned,
+    NotVerified,
+    /// None of the given public keys matches the key ID in the header.
+    UnknownKey,
+    /// The key is revoked in the key ring.
+    RevokedKey,
+    /// The image was built outside of the validity window of the key.
+    ExpiredKey,
+    Valid,
+    Invalid,
+}
+
+/// Meta-data stored in an app package alongside the image.
+#[derive(Debug, Serialize)]
+pub struct PackageInfo {
+    pub product_id: u32,
+    pub node_id: u8,
+    pub version: Version,
+    pub crc: u32,
+    pub signature_type: SignatureType,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageReport {
+    pub address: u64,
+    pub header_offset: u64,
+    /// `None` if the image is signed, but no public key with its key ID tells the type.
+    pub signature_type: Option<SignatureType>,
+    pub product_id: u32,
+    pub node_id: u8,
+    pub version: Version,
+    pub length: u32,
+    /// Length of the image without trailing padding, rounded up to full pages.
+    /// This is the value written to the length field when the image is generated.
+    pub data_length: u32,
+    pub build_variant: u16,
+    pub timestamp: u64,
+    pub key_id: u32,
+    /// Anti-rollback security version, if the header layout contains a `security_version` field.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub security_version: Option<u16>,
+    /// Leading bytes of the commit id, if the header layout contains a `git_sha` field.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_sha: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_dirty: Option<bool>,
+    pub stored_crc: u32,
+    pub computed_crc: Option<u32>,
+    pub signature: SignatureStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<PackageInfo>,
+}
+
+impl ImageReport {
+    pub fn crc_valid(&self) -> bool {
+        self.computed_crc == Some(self.stored_crc)
+    }
+
+    /// Description of the signature type, e.g. `Ed25519`.
+    pub fn signature_description(&self) -> String {
+        match self.signature_type {
+            Some(signature_type) => format!("{:?}", signature_type),
+            None => "signed, unknown type".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrailerReport {
+    /// Address of the end of the bootloader region, i.e. the end of the trailer.
+    pub end_address: u64,
+    #[serde(flatten)]
+    pub trailer: Option<BtlTrailer>,
+    /// Set if the trailer is present but invalid.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InspectReport {
+    pub file: String,
+    pub format: InputFormat,
+    pub address_range: AddressRange,
+    pub images: Vec<ImageReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub btl_trailer: Option<TrailerReport>,
+}
+
+impl InspectReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("This shouldn't fail")
+    }
+}
+
+pub fn inspect_file(path: &Path, options: &InspectOptions) -> Result<InspectReport, Error> {
+    options.check()?;
+    let format = InputFormat::detect(path)?;
+    let file = path.to_string_lossy().to_string();
+    let mut report = match format {
+        InputFormat::AppPackage => {
+            let package = AppPackage::load_from_file(path)?;
+            let mut images = Vec::new();
+            let mut range: Option<AddressRange> = None;
+            for app in &package.app {
+                let mut fw = app_to_firmware(app, &options.device_config)?;
+                let header_offset = options
+                    .header_offset
+                    .unwrap_or(app.signature_type.crc_offset() as u64 + 4);
+                let mut image =
+                    read_image(&mut fw, 0, header_offset, Some(app.signature_type), options)?;
+                image.package = Some(PackageInfo {
+                    product_id: app.product_id,
+                    node_id: app.node_id,
+                    version: app.version.clone(),
+                    crc: app.crc,
+                    signature_type: app.signature_type,
+                });
+                range = Some(match range {
+                    Some(x) => {
+                        AddressRange::new(x.begin.min(fw.range.begin), x.end.max(fw.range.end))
+                    }
+                    None => fw.range.clone(),
+                });
+                images.push(image);
+            }
+            InspectReport {
+                file,
+                format,
+                address_range: range.unwrap_or_default(),
+                images,
+                btl_trailer: None,
+            }
+        }
+        _ => {
+            let mut fw = load_firmware(path, format, options)?;
+            inspect_firmware(&mut fw, format, options)?
+        }
+    };
+    report.file = path.to_string_lossy().to_string();
+    Ok(report)
+}
+
+/// Search for an application image in `fw` and decode its header.
+///
+/// If an application image is preceded by other data, that data is treated as bootloader
+/// and checked for a bootloader trailer.
+pub fn inspect_firmware(
+    fw: &mut Firmware,
+    format: InputFormat,
+    options: &InspectOptions,
+) -> Result<InspectReport, Error> {
+    options.check()?;
+    let mut images = Vec::new();
+    let mut btl_end = fw.data.len();
+    if let Some((start, signature_type, header_offset)) = find_image(fw, options) {
+        images.push(read_image(
+            fw,
+            start,
+            header_offset,
+            signature_type,
+            options,
+        )?);
+        btl_end = start;
+    } else if let Some(header_offset) = options.header_offset {
+        // nothing consistent found, decode whatever is at the requested location
+        images.push(read_image(
+            fw,
+            0,
+            header_offset,
+            Some(SignatureType::Unsigned),
+            options,
+        )?);
+        btl_end = 0;
+    }
+    let btl_trailer = read_trailer(fw, btl_end);
+    Ok(InspectReport {
+        file: String::new(),
+        format,
+        address_range: fw.range.clone(),
+        images,
+        btl_trailer,
+    })
+}
+
+/// Load a hex or binary file. The address range is extended to full pages.
+pub fn load_firmware(
+    path: &Path,
+    format: InputFormat,
+    options: &InspectOptions,
+) -> Result<Firmware, Error> {
+    options.check()?;
+    let mut fw = load_unfiltered(path, format, options)?;
+    for range in &options.exclude {
+        fw.erase_range(range);
+    }
+    Ok(fw)
+}
+
+fn load_unfiltered(
+    path: &Path,
+    format: InputFormat,
+    options: &InspectOptions,
+) -> Result<Firmware, Error> {
+    let config = &options.device_config;
+    let page_size = config.page_size;
+    let format = match format {
+        InputFormat::Binary => {
+            let data = std::fs::read(path)?;
+            let range = AddressRange::new(
+                options.base_address,
+                options.base_address + data.len() as u64,
+            );
+            let range = align_to_pages(&range, page_size);
+            let mut padded = vec![0xFF; range.len() as usize];
+            let offset = (options.base_address - range.begin) as usize;
+            padded[offset..offset + data.len()].copy_from_slice(&data);
+            return Firmware::new(range, config.clone(), padded);
+        }
+        InputFormat::IntelHex => HexFileFormat::IntelHex,
+        InputFormat::SRecord => HexFileFormat::SRecord,
+        InputFormat::TiTxt => HexFileFormat::TiTxt,
+        InputFormat::Elf => HexFileFormat::Elf,
+        InputFormat::AppPackage => unreachable!(),
+    };
+    let file = HexFileContent::load(path, &format, config, options.base_address)?;
+    file.warn(path, &[]);
+    let range = file.segments.range().ok_or(Error::InvalidHexFile)?;
+    let range = align_to_pages(&range, page_size);
+    file.into_firmware(range, config.clone())
+}
+
+/// The application image of `app`, leaving out the sections of app regions.
+fn app_to_firmware(app: &App, config: &DeviceConfig) -> Result<Firmware, Error> {
+    let mut segments = SegmentMap::new();
+    for section in app.image.iter().filter(|x| x.region().is_none()) {
+        segments.insert(section.offset(), section.data());
+    }
+    let range = segments.range().ok_or(Error::InvalidDataLength)?;
+    let range = align_to_pages(&range, config.page_size);
+    Firmware::from_segments(range, config.clone(), &segments)
+}
+
+fn align_to_pages(range: &AddressRange, page_size: u64) -> AddressRange {
+    let begin = range.begin - range.begin % page_size;
+    let end = range.end.div_ceil(page_size) * page_size;
+    AddressRange::new(begin, end)
+}
+
+/// Returns the image start, the signature type and the header offset of the first
+/// page-aligned location with a consistent header length and CRC.
+///
+/// All signature types share the same layout. The type of a signed image is taken from
+/// the public key with the key ID in the header, it is `None` if no such key is given.
+pub(crate) fn find_image(
+    fw: &mut Firmware,
+    options: &InspectOptions,
+) -> Option<(usize, Option<SignatureType>, u64)> {
+    let page_size = options.device_config.page_size as usize;
+    for start in (0..fw.data.len()).step_by(page_size) {
+        for signature_type in [SignatureType::Unsigned, SignatureType::Ed25519] {
+            let crc_offset = signature_type.crc_offset();
+            let header_offset = options.header_offset.unwrap_or(crc_offset as u64 + 4);
+            let Ok(header) =
+                Header::with_layout(fw, start as u64 + header_offset, &options.header_layout)
+            else {
+                continue;
+            };
+            let length = header.length() as usize;
+            let key_id = header.key_id();
+            let Some(end) = image_end(fw, start, length, crc_offset) else {
+                continue;
+            };
+            if crc32(&fw.data[start + crc_offset + 4..end]) == fw.read_u32(start + crc_offset) {
+                let signature_type = match signature_type {
+                    SignatureType::Unsigned => Some(signature_type),
+                    _ => options
+                        .public_key(key_id)
+                        .and_then(|x| SignatureType::of_public_key(&x)),
+                };
+                return Some((start, signature_type, header_offset));
+            }
+        }
+    }
+    None
+}
+
+fn image_end(fw: &Firmware, start: usize, length: usize, crc_offset: usize) -> Option<usize> {
+    let end = start.checked_add(length)?;
+    if length < crc_offset + 4 || end > fw.data.len() {
+        None
+    } else {
+        Some(end)
+    }
+}
+
+fn read_image(
+    fw: &mut Firmware,
+    start: usize,
+    header_offset: u64,
+    signature_type: Option<SignatureType>,
+    options: &InspectOptions,
+) -> Result<ImageReport, Error> {
+    // all signature types share the layout of Ed25519 signed images
+    let crc_offset = signature_type
+        .unwrap_or(SignatureType::Ed25519)
+        .crc_offset();
+    let address = fw.range.begin + start as u64;
+    let header = Header::with_layout(fw, start as u64 + header_offset, &options.header_layout)?;
+    let version = Version::new(
+        header.major_version() as u64,
+        header.minor_version() as u64,
+        header.patch_version() as u64,
+    );
+    let mut report = ImageReport {
+        address,
+        header_offset,
+        signature_type,
+        product_id: header.product_id(),
+        node_id: header.fw_id(),
+        version,
+        length: header.length(),
+        data_length: 0,
+        build_variant: header.get_build_variant(),
+        timestamp: header.get_timestamp(),
+        key_id: header.key_id(),
+        security_version: header.security_version(),
+        git_sha: header.field_bytes(header::GIT_SHA).map(hex::encode),
+        git_dirty: header
+            .field_bytes(header::GIT_DIRTY)
+            .map(|_| header.read(header::GIT_DIRTY) != 0),
+        stored_crc: 0,
+        computed_crc: None,
+        signature: SignatureStatus::Unsigned,
+        package: None,
+    };
+    report.stored_crc = fw.read_u32(start + crc_offset);
+    report.data_length = fw.image_length_from(start) as u32;
+    let end = image_end(fw, start, report.length as usize, crc_offset);
+    report.computed_crc = end.map(|end| crc32(&fw.data[start + crc_offset + 4..end]));
+    let key = options.public_key(report.key_id);
+    let ring_entry = options.key_ring.find(report.key_id);
+    let revoked = ring_entry.is_some_and(|x| x.revoked);
+    // images without timestamp are not checked against the validity window
+    let expired = match (ring_entry, report.timestamp) {
+        (Some(entry), timestamp) if timestamp != 0 => {
+            DateTime::<Utc>::from_timestamp(timestamp as i64, 0)
+                .is_none_or(|time| !entry.is_valid_at(time))
+        }
+        _ => false,
+    };
+    report.signature = match (signature_type, key.as_deref(), end) {
+        (Some(SignatureType::Unsigned), _, _) => SignatureStatus::Unsigned,
+        (_, None, _) if options.public_keys.is_empty() && options.key_ring.keys.is_empty() => {
+            SignatureStatus::NotVerified
+        }
+        (_, None, _) => SignatureStatus::UnknownKey,
+        _ if revoked => SignatureStatus::RevokedKey,
+        _ if expired => SignatureStatus::ExpiredKey,
+        (_, Some(_), None) => SignatureStatus::Invalid,
+        (_, Some(key), _) if SignatureType::of_public_key(key) != signature_type => {
+            SignatureStatus::Invalid
+        }
+        (_, Some(key), Some(end)) => match signer::verify_image(&fw.data[start..end], key) {
+            Ok(()) => SignatureStatus::Valid,
+            Err(_) => SignatureStatus::Invalid,
+        },
+    };
+    Ok(report)
+}
+
+/// Decode a bootloader trailer ending at `btl_end`, if there is one.
+///
+/// Bootloaders without a trailer may use the end of their region for code. Thus, if the
+/// trailer cannot even be located, it is only reported if the magic is found at the
+/// position of a version 1 trailer.
+fn read_trailer(fw: &Firmware, btl_end: usize) -> Option<TrailerReport> {
+    let data = &fw.data[..btl_end];
+    let has_magic = btl_end >= TRAILER_TOTAL_SIZE
+        && data[btl_end - TRAILER_TOTAL_SIZE..btl_end - TRAILER_TOTAL_SIZE + 4] == TRAILER_MAGIC;
+    let (trailer, error) = match BtlTrailer::parse_data(data) {
+        Ok(None) => return None,
+        Ok(Some(trailer)) => (Some(trailer), None),
+        Err(Error::InvalidBtlTrailerLength | Error::InvalidBtlTrailerChecksum) if !has_magic => {
+            return None
+        }
+        Err(err) => (None, Some(err.to_string())),
+    };
+    Some(TrailerReport {
+        end_address: fw.range.begin + btl_end as u64,
+        trailer,
+        error,
+    })
+}
+
+fn yes_no(x: bool) -> &'static str {
+    if x {
+        "ok"
+    } else {
+        "MISMATCH"
+    }
+}
+
+impl fmt::Display for InspectReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "File:           {}", self.file)?;
+        writeln!(f, "Format:         {:?}", self.format)?;
+        writeln!(
+            f,
+            "Address range:  0x{:08X} - 0x{:08X}",
+            self.address_range.begin, self.address_range.end
+        )?;
+        if self.images.is_empty() {
+            writeln!(f, "No application image found.")?;
+        }
+        for image in &self.images {
+            writeln!(f)?;
+            writeln!(f, "Application image at 0x{:08X}", image.address)?;
+            writeln!(f, "  Header offset:  {}", image.header_offset)?;
+            writeln!(f, "  Product ID:     0x{:04X}", image.product_id)?;
+            writeln!(f, "  Node ID:        {}", image.node_id)?;
+            writeln!(f, "  Version:        {}", image.version)?;
+            writeln!(
+                f,
+                "  Length:         {} (data {}, {})",
+                image.length,
+                image.data_length,
+                yes_no(image.length == image.data_length)
+            )?;
+            writeln!(f, "  Build variant:  {}", image.build_variant)?;
+            let time = DateTime::<Utc>::from_timestamp(image.timestamp as i64, 0)
+                .map(|x| x.to_rfc3339())
+                .unwrap_or_else(|| "invalid".to_string());
+            writeln!(f, "  Timestamp:      {} ({})", image.timestamp, time)?;
+            writeln!(f, "  Key ID:         0x{:08X}", image.key_id)?;
+            if let Some(security_version) = image.security_version {
+                writeln!(f, "  Security ver.:  {}", security_version)?;
+            }
+            if let Some(sha) = &image.git_sha {
+                let dirty = if image.git_dirty == Some(true) {
+                    " (dirty)"
+                } else {
+                    ""
+                };
+                writeln!(f, "  Git commit:     {}{}", sha, dirty)?;
+            }
+            match image.computed_crc {
+                Some(crc) => writeln!(
+                    f,
+                    "  CRC:            0x{:08X} (computed 0x{:08X}, {})",
+                    image.stored_crc,
+                    crc,
+                    yes_no(image.crc_valid())
+                )?,
+                None => writeln!(
+                    f,
+                    "  CRC:            0x{:08X} (cannot compute, invalid length)",
+                    image.stored_crc
+                )?,
+            }
+            match image.signature {
+                SignatureStatus::Unsigned => writeln!(f, "  Signature:      none")?,
+                status => writeln!(
+                    f,
+                    "  Signature:      {} ({:?})",
+                    image.signature_description(),
+                    status
+                )?,
+            }
+            if let Some(package) = &image.package {
+                writeln!(f, "  Package meta-data:")?;
+                writeln!(
+                    f,
+                    "    Product ID:   0x{:04X} ({})",
+                    package.product_id,
+                    yes_no(package.product_id == image.product_id)
+                )?;
+                writeln!(
+                    f,
+                    "    Node ID:      {} ({})",
+                    package.node_id,
+                    yes_no(package.node_id == image.node_id)
+                )?;
+                writeln!(f, "    Version:      {}", package.version)?;
+                writeln!(
+                    f,
+                    "    CRC:          0x{:08X} ({})",
+                    package.crc,
+                    yes_no(package.crc == image.stored_crc)
+                )?;
+            }
+        }
+        if let Some(report) = &self.btl_trailer {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "Bootloader trailer ending at 0x{:08X}",
+                report.end_address
+            )?;
+            if let Some(error) = &report.error {
+                writeln!(f, "  INVALID:        {}", error)?;
+            }
+            if let Some(trailer) = &report.trailer {
+                writeln!(f, "  Version:        {}", trailer.version)?;
+                writeln!(f, "  BTL length:     {}", trailer.btl_length)?;
+                writeln!(f, "  BTL CRC:        0x{:08X} (ok)", trailer.btl_crc)?;
+                for field in &trailer.fields {
+                    match field {
+                        TrailerField::BtlVersion(x) => writeln!(f, "  BTL version:    {}", x)?,
+                        TrailerField::ProductId(x) => writeln!(f, "  Product ID:     0x{:04X}", x)?,
+                        TrailerField::Timestamp(x) => writeln!(f, "  Build time:     {}", x)?,
+                        TrailerField::ProtocolVersion(x) => writeln!(f, "  Protocol:       {}", x)?,
+                        TrailerField::TrustedKeyIds(ids) => {
+                            let ids: Vec<_> = ids.iter().map(|x| format!("0x{:08X}", x)).collect();
+                            writeln!(f, "  Trusted keys:   {}", ids.join(", "))?
+                        }
+                        TrailerField::Unknown { tag, value } => {
+                            writeln!(f, "  Field 0x{:02X}:     {}", tag, hex::encode(value))?
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecdsa_p256;
+    use crate::ed25519;
+    use crate::signer::{KeySigner, Signer};
+
+    fn signed_firmware(signer: &KeySigner) -> Firmware {
+        let data = (0..256_u32).map(|x| (x as u8) | 0x01).collect();
+        let range = AddressRange::new(0x1000, 0x1100);
+        let mut fw = Firmware::new(range, DeviceConfig::default(), data).unwrap();
+        {
+            let mut header = Header::new(&mut fw, 68).unwrap();
+            header.set_product_id(0x1234);
+            header.set_length(256);
+            header.set_key_id(crc32(&signer.public_key().unwrap()));
+        }
+        let crc = crc32(&fw.data[68..256]);
+        fw.write_u32(64, crc);
+        signer::sign(&mut fw, signer).unwrap();
+        fw
+    }
+
+    #[test]
+    fn signed_image_is_detected_and_verified() {
+        let private_key = ed25519::generate_private_key();
+        let mut fw = signed_firmware(&KeySigner::new(SignatureType::Ed25519, private_key));
+        let mut options = InspectOptions {
+            public_keys: vec![ed25519::public_key_bytes(&private_key).to_vec()],
+            ..Default::default()
+        };
+
+        let report = inspect_firmware(&mut fw, InputFormat::Binary, &options).unwrap();
+        assert_eq!(report.images.len(), 1);
+        let image = &report.images[0];
+        assert_eq!(image.address, 0x1000);
+        assert_eq!(image.header_offset, 68);
+        assert_eq!(image.product_id, 0x1234);
+        assert!(image.crc_valid());
+        assert_eq!(image.signature, SignatureStatus::Valid);
+
+        let other_key = ed25519::public_key_bytes(&ed25519::generate_private_key());
+        options.public_keys = vec![other_key.to_vec()];
+        let report = inspect_firmware(&mut fw, InputFormat::Binary, &options).unwrap();
+        assert_eq!(report.images[0].signature, SignatureStatus::UnknownKey);
+
+        fw.data[10] ^= 0xFF;
+        options
+            .public_keys
+            .push(ed25519::public_key_bytes(&private_key).to_vec());
+        let report = inspect_firmware(&mut fw, InputFormat::Binary, &options).unwrap();
+        assert_eq!(report.images[0].signature, SignatureStatus::Invalid);
+    }
+
+    #[test]
+    fn ecdsa_p256_image_is_detected_by_its_key() {
+        let private_key = ecdsa_p256::generate_private_key();
+        let public_key = ecdsa_p256::public_key_bytes(&private_key).unwrap();
+        let mut fw = signed_firmware(&KeySigner::new(SignatureType::EcdsaP256, private_key));
+
+        let report = inspect_firmware(&mut fw, InputFormat::Binary, &Default::default()).unwrap();
+        assert_eq!(report.images[0].signature, SignatureStatus::NotVerified);
+        assert_eq!(report.images[0].signature_type, None);
+        assert!(report
+            .to_string()
+            .contains("Signature:      signed, unknown type (NotVerified)"));
+
+        let options = InspectOptions {
+            public_keys: vec![public_key.to_vec()],
+            ..Default::default()
+        };
+        let report = inspect_firmware(&mut fw, InputFormat::Binary, &options).unwrap();
+        assert_eq!(
+            report.images[0].signature_type,
+            Some(SignatureType::EcdsaP256)
+        );
+        assert_eq!(report.images[0].signature, SignatureStatus::Valid);
+    }
+
+    #[test]
+    fn rejects_zero_page_size() {
+        let private_key = ed25519::generate_private_key();
+        let mut fw = signed_firmware(&KeySigner::new(SignatureType::Ed25519, private_key));
+        let mut options = InspectOptions::default();
+        options.device_config.page_size = 0;
+        let result = inspect_firmware(&mut fw, InputFormat::Binary, &options);
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+    }
+}
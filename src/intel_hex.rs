@@ -1,92 +1,202 @@
-use crate::config::AddressRange;
-use crate::{load_lines, swap_bytearray, Error};
+use crate::config::{AddressRange, IntelAddressing, RecordOptions};
+use crate::segments::SegmentMap;
+use crate::{for_each_line, swap_bytearray, Error};
 use hex;
 use std::cmp::min;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
 
-struct Line {
+/// Length of the longest record: byte count, address, type, 255 data bytes and checksum.
+const MAX_RECORD_LEN: usize = 260;
+
+struct Line<'a> {
     address: u64,
-    data: Vec<u8>,
+    data: &'a mut [u8],
     kind: u8,
 }
 
-pub fn load(path: &Path, word_addressing: bool, range: &AddressRange) -> Result<Vec<u8>, Error> {
-    let lines = load_lines(path)?;
-    parse(word_addressing, range, lines.into_iter())
+/// Content of an Intel HEX file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IntelHexFile {
+    /// Data records, at byte addresses.
+    pub segments: SegmentMap,
+    /// Start address given by a start segment (0x03) or start linear address (0x05) record.
+    pub entry_point: Option<u64>,
+    /// Spans written by multiple records with different values.
+    pub conflicts: Vec<AddressRange>,
+}
+
+pub fn load(path: &Path, word_addressing: bool) -> Result<IntelHexFile, Error> {
+    let file = File::open(path).map_err(Error::Io)?;
+    read(BufReader::new(file), word_addressing).map_err(|x| x.in_file(path))
 }
 
+/// Parse the data records within `range` into a buffer. Bytes not contained in the file are 0xFF.
 pub fn parse<T: Iterator<Item = String>>(
     word_addressing: bool,
     range: &AddressRange,
     lines: T,
 ) -> Result<Vec<u8>, Error> {
-    let lines: Result<Vec<_>, _> = lines.map(parse_line).collect();
-    let lines = lines?;
-    let mut extend_line_address = 0_u64;
-    let multiplier = if word_addressing { 2 } else { 1 };
-    let mut ret = vec![0xFF; range.len() as usize * multiplier];
-    for line in &lines {
+    parse_file(word_addressing, lines).map(|x| x.segments.to_dense(range, 0xFF))
+}
+
+/// Parse the data records as well as the start address.
+///
+/// Empty lines are skipped but counted, such that errors refer to the line number in the file.
+/// For word addressed devices, addresses are converted to byte addresses.
+pub fn parse_file<T: Iterator<Item = String>>(
+    word_addressing: bool,
+    lines: T,
+) -> Result<IntelHexFile, Error> {
+    let mut parser = Parser::new(word_addressing);
+    for (k, line) in lines.enumerate() {
+        if !parser.feed(k + 1, &line)? {
+            break;
+        }
+    }
+    Ok(parser.file)
+}
+
+/// Parse a file from `reader` like [`parse_file`], one line at a time without collecting the
+/// lines or records.
+pub fn read<R: BufRead>(reader: R, word_addressing: bool) -> Result<IntelHexFile, Error> {
+    let mut parser = Parser::new(word_addressing);
+    for_each_line(reader, |number, line| parser.feed(number, line))?;
+    Ok(parser.file)
+}
+
+/// Incremental parser, decoding each record into the same buffer.
+struct Parser {
+    word_addressing: bool,
+    extend_line_address: u64,
+    buffer: [u8; MAX_RECORD_LEN],
+    file: IntelHexFile,
+}
+
+impl Parser {
+    fn new(word_addressing: bool) -> Self {
+        Self {
+            word_addressing,
+            extend_line_address: 0,
+            buffer: [0; MAX_RECORD_LEN],
+            file: IntelHexFile::default(),
+        }
+    }
+
+    /// Parse line `number` of the file. Returns `false` once the end of file record is reached.
+    fn feed(&mut self, number: usize, line: &str) -> Result<bool, Error> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(true);
+        }
+        let error = |reason| Error::InvalidHexRecord {
+            path: None,
+            line: number,
+            reason,
+        };
+        let line = parse_line(line, &mut self.buffer).map_err(error)?;
+        let multiplier = if self.word_addressing { 2 } else { 1 };
         match line.kind {
             0x04 => {
-                if line.address == 0 {
-                    extend_line_address = (line.data[0] as u64) << 8;
-                    extend_line_address += line.data[1] as u64;
-                    extend_line_address <<= 16;
-                } else {
-                    return Err(Error::InvalidHexFile);
-                }
+                self.extend_line_address = (line.data[0] as u64) << 8;
+                self.extend_line_address += line.data[1] as u64;
+                self.extend_line_address <<= 16;
             }
             0x02 => {
-                if line.address == 0 {
-                    extend_line_address = (line.data[0] as u64) << 8;
-                    extend_line_address += line.data[1] as u64;
-                    extend_line_address *= 16;
-                } else {
-                    return Err(Error::InvalidHexFile);
-                }
+                self.extend_line_address = (line.data[0] as u64) << 8;
+                self.extend_line_address += line.data[1] as u64;
+                self.extend_line_address *= 16;
             }
             0x00 => {
-                let addr = extend_line_address + line.address;
-                if addr < range.begin || addr >= range.end {
-                    continue;
-                }
-                for k in 0..line.data.len() {
-                    let idx = k + (addr as usize) - (range.begin as usize);
-                    ret[idx] = line.data[k];
+                let addr = (self.extend_line_address + line.address) * multiplier;
+                let data = line.data;
+                if self.word_addressing {
+                    if data.len() % 2 != 0 {
+                        return Err(error(
+                            "odd number of bytes in word addressed record".to_string(),
+                        ));
+                    }
+                    swap_bytearray(data);
                 }
+                let conflicts = self.file.segments.conflicts(addr, data);
+                self.file.conflicts.extend(conflicts);
+                self.file.segments.insert(addr, data);
+            }
+            0x03 => {
+                let segment = ((line.data[0] as u64) << 8) | line.data[1] as u64;
+                let offset = ((line.data[2] as u64) << 8) | line.data[3] as u64;
+                self.file.entry_point = Some((segment << 4) + offset);
+            }
+            0x05 => {
+                self.file.entry_point = Some(
+                    line.data
+                        .iter()
+                        .fold(0_u64, |acc, x| (acc << 8) | (*x as u64)),
+                );
             }
-            0x01 => break,
+            0x01 => return Ok(false),
             _ => {}
         }
+        Ok(true)
     }
-    if word_addressing {
-        swap_bytearray(&mut ret);
-    }
-    Ok(ret)
 }
 
-fn parse_line(line: String) -> Result<Line, Error> {
-    if line.len() == 0 {
-        return Err(Error::InvalidHexFile);
+/// Decode `line` into `buffer`. The data of the returned record refers to the buffer.
+fn parse_line<'a>(line: &str, buffer: &'a mut [u8; MAX_RECORD_LEN]) -> Result<Line<'a>, String> {
+    let digits = line
+        .strip_prefix(':')
+        .ok_or_else(|| "record does not start with `:`".to_string())?;
+    let len = digits.len() / 2;
+    if len > MAX_RECORD_LEN {
+        return Err("record is too long".to_string());
     }
-    if line.as_bytes()[0] != b':' {
-        return Err(Error::InvalidHexFile);
+    let data = &mut buffer[..len];
+    hex::decode_to_slice(digits, data).map_err(|_| "invalid hex digits".to_string())?;
+    if data.len() < 5 {
+        return Err("record is too short".to_string());
     }
-    let data = hex::decode(&line[1..]).map_err(|_| Error::InvalidHexFile)?;
     let count = data[0] as usize;
     if count + 5 != data.len() {
-        return Err(Error::InvalidHexFile);
+        return Err(format!(
+            "byte count {} does not match record length {}",
+            count,
+            data.len() - 5
+        ));
+    }
+    let expected = checksum(&data[..data.len() - 1]);
+    let found = data[data.len() - 1];
+    if expected != found {
+        return Err(format!(
+            "checksum is 0x{:02X}, expected 0x{:02X}",
+            found, expected
+        ));
     }
     let a1 = data[1] as u64;
     let a2 = data[2] as u64;
     let addr = a2 + (a1 << 8);
     let kind = data[3];
-    let data: Vec<_> = data[4..4 + count].to_vec();
+    let expected_count = match kind {
+        0x00 => None,
+        0x01 => Some(0),
+        0x02 | 0x04 => Some(2),
+        0x03 | 0x05 => Some(4),
+        _ => return Err(format!("unsupported record type 0x{:02X}", kind)),
+    };
+    if let Some(expected_count) = expected_count {
+        if count != expected_count {
+            return Err(format!(
+                "record type 0x{:02X} must contain {} bytes",
+                kind, expected_count
+            ));
+        }
+        if addr != 0 {
+            return Err(format!("record type 0x{:02X} must have address 0", kind));
+        }
+    }
     Ok(Line {
         address: addr,
-        data,
+        data: &mut data[4..4 + count],
         kind,
     })
 }
@@ -96,73 +206,142 @@ fn checksum(data: &[u8]) -> u8 {
     ((-1 * result) & 0xFF_i32) as u8
 }
 
-const WRITE_DATA_PER_LINE: usize = 16;
-
 fn get_extended_addr(addr: u64) -> u64 {
     (addr >> 16) & 0xFFFF
 }
 
 pub fn serialize(word_addressing: bool, range: &AddressRange, data: &[u8]) -> String {
+    serialize_file(
+        word_addressing,
+        range,
+        data,
+        None,
+        &RecordOptions::default(),
+    )
+}
+
+/// Serialize `data` and, if given, the entry point as start address record.
+///
+/// Records do not cross 64 KiB boundaries. Lines which only contain 0xFF are skipped. With
+/// segment addressing, only the first MiB can be addressed, see [`check_addressing`].
+pub fn serialize_file(
+    word_addressing: bool,
+    range: &AddressRange,
+    data: &[u8],
+    entry_point: Option<u64>,
+    options: &RecordOptions,
+) -> String {
     let mut data = data.to_vec();
     if word_addressing {
         swap_bytearray(&mut data);
     }
+    let bytes_per_address = if word_addressing { 2 } else { 1 };
+    let len = min(data.len(), range.len() as usize);
     let mut lines = Vec::new();
     let mut extended_addr = 0;
-    for k in (0..data.len()).step_by(WRITE_DATA_PER_LINE) {
-        let endidx = min(k + WRITE_DATA_PER_LINE, data.len());
-        let endidx = min(endidx, (range.end - range.begin) as usize);
-        if k >= endidx {
-            break;
-        }
-        let len = endidx - k;
+    let mut k = 0;
+    while k < len {
         let mut address = (k as u64) + range.begin;
         if word_addressing {
             address >>= 1;
         }
+        let to_boundary = (0x1_0000 - (address & 0xFFFF)) * bytes_per_address;
+        let endidx = min(k + options.record_length, len);
+        let endidx = min(endidx, k + to_boundary as usize);
+        let data_slice = &data[k..endidx];
+        k = endidx;
+        if data_slice.iter().all(|x| *x == 0xFF) {
+            continue;
+        }
         if get_extended_addr(address) != extended_addr {
             extended_addr = get_extended_addr(address);
-            lines.push(write_extended_addr(address));
+            lines.push(write_extended_addr(address, options.intel_addressing));
         }
         let mut out = Vec::new();
         out.extend(&[
-            len as u8,
+            data_slice.len() as u8,
             (address >> 8) as u8,
             (address & 0xFF) as u8,
             0_u8,
         ]);
-        let data_slice = &data[k..endidx];
-        if data_slice.iter().all(|x| *x == 0xFF) {
-            continue;
-        }
         out.extend(data_slice);
         out.push(checksum(&out));
         lines.push(out);
     }
+    if let Some(entry_point) = entry_point {
+        let mut out = match options.intel_addressing {
+            IntelAddressing::Linear => {
+                let mut out = vec![0x04, 0x00, 0x00, 0x05];
+                out.extend(&(entry_point as u32).to_be_bytes());
+                out
+            }
+            IntelAddressing::Segment => {
+                let segment = ((entry_point >> 4) & 0xF000) as u16;
+                let offset = (entry_point & 0xFFFF) as u16;
+                let mut out = vec![0x04, 0x00, 0x00, 0x03];
+                out.extend(&segment.to_be_bytes());
+                out.extend(&offset.to_be_bytes());
+                out
+            }
+        };
+        out.push(checksum(&out));
+        lines.push(out);
+    }
     lines.push(vec![0x00, 0x00, 0x00, 0x01, 0xFF]);
 
     let lines: Vec<_> = lines
         .iter()
         .map(|x| format!(":{}", hex::encode_upper(x)))
         .collect();
-    lines.join("\n")
+    lines.join(options.line_ending.as_str())
 }
 
-fn write_extended_addr(addr: u64) -> Vec<u8> {
-    let mut out = vec![0x02 as u8, 0x00, 0x00, 0x04];
-    out.push(((addr >> 24) & 0xFF) as u8);
-    out.push(((addr >> 16) & 0xFF) as u8);
+fn write_extended_addr(addr: u64, addressing: IntelAddressing) -> Vec<u8> {
+    let (kind, base) = match addressing {
+        IntelAddressing::Linear => (0x04, addr >> 16),
+        IntelAddressing::Segment => (0x02, (addr >> 4) & 0xF000),
+    };
+    let mut out = vec![0x02, 0x00, 0x00, kind];
+    out.push(((base >> 8) & 0xFF) as u8);
+    out.push((base & 0xFF) as u8);
     out.push(checksum(&out));
     out
 }
 
+/// Check that the addresses of `range` and the entry point can be represented with segment
+/// addressing, if selected.
+pub fn check_addressing(
+    word_addressing: bool,
+    range: &AddressRange,
+    entry_point: Option<u64>,
+    options: &RecordOptions,
+) -> Result<(), Error> {
+    if options.intel_addressing != IntelAddressing::Segment {
+        return Ok(());
+    }
+    let mut end = range.end;
+    if word_addressing {
+        end >>= 1;
+    }
+    if end > 0x10_0000 || entry_point.unwrap_or(0) >= 0x10_0000 {
+        return Err(Error::InvalidConfig(format!(
+            "Intel HEX segment addressing cannot address 0x{:X}..0x{:X}, use linear addressing",
+            range.begin, range.end
+        )));
+    }
+    Ok(())
+}
+
 pub fn save(
     path: &Path,
     word_addressing: bool,
     range: &AddressRange,
-    data: &Vec<u8>,
+    data: &[u8],
+    entry_point: Option<u64>,
+    options: &RecordOptions,
 ) -> Result<(), Error> {
-    let data = serialize(word_addressing, range, data);
+    check_addressing(word_addressing, range, entry_point, options)?;
+    let data = serialize_file(word_addressing, range, data, entry_point, options);
     let mut file = File::create(path).map_err(Error::Io)?;
     file.write_all(data.as_bytes()).map_err(Error::Io)
 }
@@ -170,6 +349,7 @@ pub fn save(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::LineEnding;
 
     #[test]
     fn test_checksum() {
@@ -208,4 +388,95 @@ mod tests {
         let data: Vec<_> = (1u8..21).collect();
         assert_eq!(&parsed, &data);
     }
+
+    fn lines(file: &str) -> impl Iterator<Item = String> + '_ {
+        file.split("\n").map(|x| x.to_string())
+    }
+
+    #[test]
+    fn parse_reports_line_and_reason() {
+        let range = AddressRange::new(0xAB00, 0xAB14);
+        let file =
+            ":10AB00000102030405060708090A0B0C0D0E0F10BD\n\n:04AB100011121314F6\n:00000001FF";
+        let err = parse(false, &range, lines(file)).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: checksum is 0xF6, expected 0xF7");
+        let err = err.in_file(Path::new("app.hex"));
+        assert_eq!(
+            err.to_string(),
+            "app.hex:3: checksum is 0xF6, expected 0xF7"
+        );
+
+        let err = parse(false, &range, lines(":00000006FA")).unwrap_err();
+        assert_eq!(err.to_string(), "line 1: unsupported record type 0x06");
+        let err = parse(false, &range, lines(":0100000401FA")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: record type 0x04 must contain 2 bytes"
+        );
+        let err = parse(false, &range, lines("10AB0000")).unwrap_err();
+        assert_eq!(err.to_string(), "line 1: record does not start with `:`");
+    }
+
+    #[test]
+    fn read_matches_parse_file() {
+        let range = AddressRange::new(0x1_FF00, 0x2_0100);
+        let data: Vec<_> = (0..0x200).map(|x| x as u8).collect();
+        let options = RecordOptions::default();
+        let serialized = serialize_file(false, &range, &data, Some(0x2_0000), &options);
+        let file = read(serialized.as_bytes(), false).unwrap();
+        assert_eq!(file, parse_file(false, lines(&serialized)).unwrap());
+        assert_eq!(file.segments.len(), 1);
+        assert_eq!(file.segments.to_dense(&range, 0xFF), data);
+
+        // lines after the end of file record are not parsed
+        let file = read(&b":0100000001FE\r\n:00000001FF\r\ngarbage"[..], false).unwrap();
+        assert_eq!(file.segments.iter().next(), Some((0, &[1][..])));
+        let err = read(&b"\n:0100000001FF\n"[..], false).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: checksum is 0xFF, expected 0xFE");
+    }
+
+    #[test]
+    fn start_address_round_trip() {
+        let range = AddressRange::new(0xAB00, 0xAB14);
+        let data: Vec<_> = (1u8..21).collect();
+        let options = RecordOptions::default();
+        let serialized = serialize_file(false, &range, &data, Some(0x0800_AB04), &options);
+        assert!(serialized.ends_with(":040000050800AB0440\n:00000001FF"));
+        let parsed = parse_file(false, lines(&serialized)).unwrap();
+        assert_eq!(parsed.segments.to_dense(&range, 0xFF), data);
+        assert_eq!(parsed.entry_point, Some(0x0800_AB04));
+
+        // start segment address: CS = 0x1000, IP = 0x0204
+        let file = ":0400000310000204E3\n:00000001FF";
+        let parsed = parse_file(false, lines(file)).unwrap();
+        assert_eq!(parsed.entry_point, Some(0x1_0204));
+    }
+
+    #[test]
+    fn segment_addressing_and_record_options() {
+        let range = AddressRange::new(0x1_FFF0, 0x2_0020);
+        let data: Vec<_> = (1u8..49).collect();
+        let options = RecordOptions {
+            record_length: 32,
+            line_ending: LineEnding::CrLf,
+            intel_addressing: IntelAddressing::Segment,
+        };
+        let serialized = serialize_file(false, &range, &data, Some(0x1_0204), &options);
+        let records: Vec<_> = serialized.split("\r\n").collect();
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[0], ":020000021000EC");
+        // records end at the 64 KiB boundary
+        assert!(records[1].starts_with(":10FFF00001020304"));
+        assert_eq!(records[2], ":020000022000DC");
+        assert!(records[3].starts_with(":200000001112"));
+        assert_eq!(records[4], ":0400000310000204E3");
+
+        let parsed = parse_file(false, serialized.lines().map(|x| x.to_string())).unwrap();
+        assert_eq!(parsed.segments.to_dense(&range, 0xFF), data);
+        assert_eq!(parsed.entry_point, Some(0x1_0204));
+
+        let range = AddressRange::new(0x10_0000, 0x10_0010);
+        assert!(check_addressing(false, &range, None, &options).is_err());
+        assert!(check_addressing(true, &range, None, &options).is_ok());
+    }
 }
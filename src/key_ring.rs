@@ -0,0 +1,329 @@
+//! Key rings hold the public keys trusted by the bootloaders of a product.
+//!
+//! Each key is identified by its key ID, the CRC32 of the public key, which is written
+//! into the `KEY_ID` field of the header of signed images. Keys may be limited to the
+//! images built within a validity window and revoked keys are kept in the ring, such
+//! that images signed with them are reported as such.
+//!
+//! The ring is stored as JSON, e.g.
+//! `{ "keys": [{ "label": "release-2024", "key_id": 305419896, "signature_type": "Ed25519",
+//! "public_key": "<hex>", "valid_until": "2025-01-01T00:00:00Z" }] }`, and embedded into
+//! the bootloader as C array with [`KeyRing::to_c_array`].
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::SignatureType;
+use crate::crc::crc32;
+use crate::signer;
+use crate::Error;
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct KeyRing {
+    pub keys: Vec<KeyRingEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct KeyRingEntry {
+    pub label: String,
+    /// CRC32 of the public key, stored for reference.
+    pub key_id: u32,
+    pub signature_type: SignatureType,
+    /// Hex encoded public key.
+    pub public_key: String,
+    /// Images built before this time are not accepted.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub valid_from: Option<DateTime<Utc>>,
+    /// Images built after this time are not accepted.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub valid_until: Option<DateTime<Utc>>,
+    #[serde(
+        default = "Default::default",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub revoked: bool,
+}
+
+impl KeyRingEntry {
+    /// Create an entry for a hex encoded public key, deriving its key ID and signature type.
+    pub fn new(label: &str, public_key: &str) -> Result<Self, Error> {
+        let key = signer::decode_public_key(public_key)?;
+        Ok(KeyRingEntry {
+            label: label.to_string(),
+            key_id: crc32(&key),
+            signature_type: SignatureType::of_public_key(&key).unwrap(),
+            public_key: hex::encode(&key),
+            valid_from: None,
+            valid_until: None,
+            revoked: false,
+        })
+    }
+
+    pub fn public_key(&self) -> Result<Vec<u8>, Error> {
+        signer::decode_public_key(&self.public_key)
+    }
+
+    /// Whether images built at `time` may be signed with this key, ignoring revocation.
+    pub fn is_valid_at(&self, time: DateTime<Utc>) -> bool {
+        self.valid_from.is_none_or(|x| x <= time) && self.valid_until.is_none_or(|x| time <= x)
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        // the label is written into a comment of the generated C source
+        if self.label.contains("*/") {
+            return Err(Error::KeyRing(format!(
+                "Label `{}` must not contain `*/`",
+                self.label
+            )));
+        }
+        let key = self
+            .public_key()
+            .map_err(|_| Error::KeyRing(format!("Invalid public key of `{}`", self.label)))?;
+        if crc32(&key) != self.key_id {
+            return Err(Error::KeyRing(format!(
+                "Key ID 0x{:08X} of `{}` is not the CRC32 of its public key",
+                self.key_id, self.label
+            )));
+        }
+        if SignatureType::of_public_key(&key) != Some(self.signature_type) {
+            return Err(Error::KeyRing(format!(
+                "Public key of `{}` is not a {:?} key",
+                self.label, self.signature_type
+            )));
+        }
+        if let (Some(from), Some(until)) = (self.valid_from, self.valid_until) {
+            if until < from {
+                return Err(Error::KeyRing(format!(
+                    "Validity window of `{}` ends before it starts",
+                    self.label
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl KeyRing {
+    pub fn load_from_file(path: &Path) -> Result<Self, Error> {
+        let data = fs::read_to_string(path)?;
+        let ring: KeyRing = serde_json::from_str(&data).map_err(Error::CannotParseConfig)?;
+        ring.validate()?;
+        Ok(ring)
+    }
+
+    /// Load the ring, or create an empty one if the file does not exist.
+    pub fn load_or_default(path: &Path) -> Result<Self, Error> {
+        if path.exists() {
+            Self::load_from_file(path)
+        } else {
+            Ok(KeyRing::default())
+        }
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> Result<(), Error> {
+        let data = serde_json::to_string_pretty(self).unwrap();
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Check the entries and that labels and key IDs are unique. Two different public
+    /// keys with the same CRC32 cannot be told apart by the bootloader.
+    pub fn validate(&self) -> Result<(), Error> {
+        for (idx, entry) in self.keys.iter().enumerate() {
+            entry.check()?;
+            for other in &self.keys[..idx] {
+                if other.label == entry.label {
+                    return Err(Error::KeyRing(format!(
+                        "Label `{}` is used twice",
+                        entry.label
+                    )));
+                }
+                if other.public_key.eq_ignore_ascii_case(&entry.public_key) {
+                    return Err(Error::KeyRing(format!(
+                        "Keys `{}` and `{}` are identical",
+                        other.label, entry.label
+                    )));
+                }
+                if other.key_id == entry.key_id {
+                    return Err(Error::KeyRing(format!(
+                        "Keys `{}` and `{}` share the key ID 0x{:08X}",
+                        other.label, entry.label, entry.key_id
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a key, failing if its label or key ID is already in use.
+    pub fn add(&mut self, entry: KeyRingEntry) -> Result<(), Error> {
+        self.keys.push(entry);
+        if let Err(err) = self.validate() {
+            self.keys.pop();
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Revoke the key with the given label or hex key ID, e.g. `0x12345678`.
+    pub fn revoke(&mut self, key: &str) -> Result<&KeyRingEntry, Error> {
+        let key_id = key
+            .strip_prefix("0x")
+            .and_then(|x| u32::from_str_radix(x, 16).ok());
+        let entry = self
+            .keys
+            .iter_mut()
+            .find(|x| x.label == key || Some(x.key_id) == key_id)
+            .ok_or_else(|| Error::KeyRing(format!("No key `{}` in the key ring", key)))?;
+        entry.revoked = true;
+        Ok(entry)
+    }
+
+    pub fn find(&self, key_id: u32) -> Option<&KeyRingEntry> {
+        self.keys.iter().find(|x| x.key_id == key_id)
+    }
+
+    /// C source defining the array `trusted_keys` of all keys which are not revoked.
+    /// Fails if no key is active, as the bootloader would not accept any image.
+    pub fn to_c_array(&self) -> Result<String, Error> {
+        let keys: Vec<_> = self.keys.iter().filter(|x| !x.revoked).collect();
+        if keys.is_empty() {
+            return Err(Error::KeyRing("The key ring has no active key".to_string()));
+        }
+        let mut ret = String::new();
+        ret += "/* Trusted public keys, generated by merge_tool. Do not edit. */\n\n";
+        ret += "#include <stdint.h>\n\n";
+        ret += &format!("#define TRUSTED_KEY_COUNT {}u\n\n", keys.len());
+        ret += "#define TRUSTED_KEY_ED25519 1u\n";
+        ret += "#define TRUSTED_KEY_ECDSA_P256 2u\n\n";
+        ret += "typedef struct {\n";
+        ret += "    uint32_t key_id;\n";
+        ret += "    uint8_t signature_type;\n";
+        ret += "    uint8_t public_key_length;\n";
+        ret += "    uint8_t public_key[64];\n";
+        ret += "} trusted_key_t;\n\n";
+        ret += "static const trusted_key_t trusted_keys[] = {\n";
+        for entry in keys {
+            let key = entry.public_key().unwrap_or_default();
+            let signature_type = match entry.signature_type {
+                SignatureType::EcdsaP256 => "TRUSTED_KEY_ECDSA_P256",
+                _ => "TRUSTED_KEY_ED25519",
+            };
+            ret += &format!("    /* {} */\n", entry.label);
+            ret += &format!(
+                "    {{ 0x{:08X}u, {}, {}u, {{\n",
+                entry.key_id,
+                signature_type,
+                key.len()
+            );
+            for line in key.chunks(16) {
+                let bytes: Vec<_> = line.iter().map(|x| format!("0x{:02X}", x)).collect();
+                ret += &format!("        {},\n", bytes.join(", "));
+            }
+            ret += "    } },\n";
+        }
+        ret += "};\n";
+        Ok(ret)
+    }
+}
+
+impl fmt::Display for KeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = |x: Option<DateTime<Utc>>| x.map_or("-".to_string(), |x| x.to_rfc3339());
+        for entry in &self.keys {
+            writeln!(
+                f,
+                "0x{:08X}  {:<9}  {:<7}  {} .. {}  {}",
+                entry.key_id,
+                format!("{:?}", entry.signature_type),
+                if entry.revoked { "revoked" } else { "active" },
+                time(entry.valid_from),
+                time(entry.valid_until),
+                entry.label
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ed25519;
+
+    fn entry(label: &str, seed: u8) -> KeyRingEntry {
+        let public_key = ed25519::public_key_bytes(&[seed; 32]);
+        KeyRingEntry::new(label, &hex::encode(public_key)).unwrap()
+    }
+
+    #[test]
+    fn labels_and_key_ids_are_unique() {
+        let mut ring = KeyRing::default();
+        ring.add(entry("a", 1)).unwrap();
+        ring.add(entry("b", 2)).unwrap();
+        assert!(ring.add(entry("a", 3)).is_err());
+        assert!(ring.add(entry("c", 1)).is_err());
+        assert_eq!(ring.keys.len(), 2);
+
+        // different keys with the same CRC32
+        let mut collision = entry("c", 3);
+        collision.key_id = ring.keys[0].key_id;
+        ring.keys.push(collision);
+        assert!(matches!(ring.validate(), Err(Error::KeyRing(_))));
+    }
+
+    #[test]
+    fn revoke_by_label_or_key_id() {
+        let mut ring = KeyRing::default();
+        ring.add(entry("a", 1)).unwrap();
+        ring.add(entry("b", 2)).unwrap();
+        ring.revoke("a").unwrap();
+        let key_id = format!("0x{:08X}", ring.keys[1].key_id);
+        ring.revoke(&key_id).unwrap();
+        assert!(ring.keys.iter().all(|x| x.revoked));
+        assert!(ring.revoke("c").is_err());
+    }
+
+    #[test]
+    fn validity_window() {
+        let time = |x: i64| DateTime::<Utc>::from_timestamp(x, 0).unwrap();
+        let mut entry = entry("a", 1);
+        entry.valid_from = Some(time(100));
+        entry.valid_until = Some(time(200));
+        assert!(!entry.is_valid_at(time(99)));
+        assert!(entry.is_valid_at(time(100)));
+        assert!(entry.is_valid_at(time(200)));
+        assert!(!entry.is_valid_at(time(201)));
+        entry.valid_until = Some(time(50));
+        assert!(entry.check().is_err());
+    }
+
+    #[test]
+    fn c_array_contains_active_keys() {
+        let mut ring = KeyRing::default();
+        ring.add(entry("a", 1)).unwrap();
+        ring.add(entry("b", 2)).unwrap();
+        ring.revoke("b").unwrap();
+        let source = ring.to_c_array().unwrap();
+        assert!(source.contains("#define TRUSTED_KEY_COUNT 1u"));
+        assert!(source.contains(&format!(
+            "0x{:08X}u, TRUSTED_KEY_ED25519, 32u",
+            ring.keys[0].key_id
+        )));
+        assert!(!source.contains(&format!("0x{:08X}u", ring.keys[1].key_id)));
+
+        ring.revoke("a").unwrap();
+        assert!(matches!(ring.to_c_array(), Err(Error::KeyRing(_))));
+    }
+
+    #[test]
+    fn rejects_label_closing_comment() {
+        let mut ring = KeyRing::default();
+        assert!(ring.add(entry("a */ int x; /*", 1)).is_err());
+        assert!(ring.keys.is_empty());
+    }
+}
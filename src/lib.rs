@@ -1,10 +1,11 @@
 #[macro_use]
 extern crate lazy_static;
 
+use config::AddressRange;
 use serde_json::Error as JsonError;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{fmt, io};
 
 pub mod app_package;
@@ -14,35 +15,82 @@ pub mod changelog;
 pub mod config;
 pub mod crc;
 pub mod ddp;
+pub mod detached;
+pub mod ecdsa_p256;
 pub mod ed25519;
+pub mod elf;
+pub mod extract;
 pub mod firmware;
 pub mod git_description;
 pub mod header;
+pub mod inspect;
 pub mod intel_hex;
+pub mod key_ring;
+pub mod metadata;
 pub mod process;
 pub mod protocol;
 pub mod script;
 pub mod script_cmd;
+pub mod script_executor;
+pub mod segments;
+pub mod signer;
 pub mod srecord;
+pub mod stream_transport;
+pub mod ti_txt;
+pub mod verify;
+pub mod virtual_btl;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     AddressRangeNotAlignedToPage,
     BtlTooSmallForTrailer,
+    BtlTrailerAlreadyPresent,
+    InvalidBtlTrailerLength,
+    InvalidBtlTrailerChecksum,
+    InvalidBtlTrailerMagic,
+    InvalidBtlTrailerField,
+    UnsupportedBtlTrailerVersion(u8),
+    InvalidBtlLength,
+    InvalidBtlCrc,
     ImageTooShortForHeader,
     InvalidDataLength,
     InvalidAddress,
     Io(std::io::Error),
     InvalidHexFile,
+    /// Data of a hex or S-Record file lies outside of the configured address range.
+    HexDataOutOfRange {
+        path: PathBuf,
+        spans: Vec<AddressRange>,
+    },
+    /// Records of a hex or S-Record file write different values to the same addresses.
+    ConflictingHexRecords {
+        path: PathBuf,
+        spans: Vec<AddressRange>,
+    },
+    /// A malformed record in a hex or S-Record file. `line` is 1-based.
+    InvalidHexRecord {
+        path: Option<PathBuf>,
+        line: usize,
+        reason: String,
+    },
+    /// A malformed or unsupported ELF file.
+    InvalidElfFile(String),
     InvalidConfig(String),
     CannotParseConfig(JsonError),
     CannotFindGitRepo,
     InvalidProductName,
     InvalidPrivateKey,
     InvalidSignature,
+    /// The signer could not create a signature.
+    SignerFailed(String),
+    /// The digests or signatures of the detached signature workflow do not match the images.
+    DetachedSignature(String),
+    /// The key ring is invalid, e.g. two keys share a key ID, or lacks a key.
+    KeyRing(String),
     CannotParseChangelog,
     Git(anyhow::Error),
     InvalidInfoFile(anyhow::Error),
+    InvalidDdpFrame,
     Other(Box<dyn std::error::Error + 'static>),
 }
 
@@ -56,35 +104,102 @@ impl Error {
     pub fn other<T: std::error::Error + 'static>(x: T) -> Self {
         Error::Other(Box::new(x))
     }
+
+    /// Attach the file name to errors reported by the hex and S-Record parsers.
+    pub(crate) fn in_file(self, file: &Path) -> Self {
+        match self {
+            Error::InvalidHexRecord { line, reason, .. } => Error::InvalidHexRecord {
+                path: Some(file.to_path_buf()),
+                line,
+                reason,
+            },
+            x => x,
+        }
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Error::InvalidHexRecord {
+                path: Some(path),
+                line,
+                reason,
+            } => write!(f, "{}:{}: {}", path.display(), line, reason),
+            Error::InvalidHexRecord { line, reason, .. } => {
+                write!(f, "line {}: {}", line, reason)
+            }
+            Error::InvalidElfFile(reason) => write!(f, "invalid ELF file: {}", reason),
+            Error::SignerFailed(reason) => write!(f, "signer failed: {}", reason),
+            Error::DetachedSignature(reason) => write!(f, "{}", reason),
+            Error::KeyRing(reason) => write!(f, "key ring: {}", reason),
+            Error::HexDataOutOfRange { path, spans } => write!(
+                f,
+                "{}: data outside of the address range at {}",
+                path.display(),
+                format_spans(spans)
+            ),
+            Error::ConflictingHexRecords { path, spans } => write!(
+                f,
+                "{}: records write different values to {}",
+                path.display(),
+                format_spans(spans)
+            ),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
-pub fn swap_bytearray(data: &mut Vec<u8>) {
+/// Format address ranges as `0x1000..0x1010, 0x2000..0x2004`.
+pub fn format_spans(spans: &[AddressRange]) -> String {
+    spans
+        .iter()
+        .map(|x| format!("0x{:X}..0x{:X}", x.begin, x.end))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn swap_bytearray(data: &mut [u8]) {
     for k in (0..data.len()).step_by(2) {
         data.swap(k, k + 1)
     }
 }
 
+/// Read the trimmed lines of a text file. Empty lines are kept, such that the index of a line
+/// corresponds to its line number.
 pub fn load_lines(path: &Path) -> Result<Vec<String>> {
     let file = File::open(path).map_err(Error::Io)?;
     let lines = BufReader::new(file).lines();
     let mut ret = Vec::new();
     for line in lines {
-        let x = line.map_err(Error::Io)?.trim().to_string();
-        if !x.is_empty() {
-            ret.push(x);
-        }
+        ret.push(line.map_err(Error::Io)?.trim().to_string());
     }
     Ok(ret)
 }
 
+/// Call `f` with the number and content of each line of `reader`, reusing a single buffer.
+///
+/// Stops early once `f` returns `false`.
+pub fn for_each_line<R: BufRead, F: FnMut(usize, &str) -> Result<bool>>(
+    mut reader: R,
+    mut f: F,
+) -> Result<()> {
+    let mut line = String::new();
+    let mut number = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).map_err(Error::Io)? == 0 {
+            return Ok(());
+        }
+        number += 1;
+        if !f(number, &line)? {
+            return Ok(());
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
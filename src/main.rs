@@ -1,16 +1,29 @@
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use clap::{crate_authors, crate_version, Arg, ArgAction, ArgMatches, Command};
 
 use merge_tool::changelog::extract_version_from_changelog_file;
-use merge_tool::config::Config;
+use merge_tool::config::{Config, IntelAddressing, LineEnding, SignatureType};
+use merge_tool::detached::{self, DigestFile};
+use merge_tool::extract;
 use merge_tool::git_description::retrieve_description;
+use merge_tool::header::HeaderLayout;
+use merge_tool::inspect::{inspect_file, InspectOptions};
+use merge_tool::key_ring::{KeyRing, KeyRingEntry};
 use merge_tool::process::{self, GenerateOptions};
+use merge_tool::script::Script;
+use merge_tool::script_executor::{self, Listener};
+use merge_tool::signer::{self, KeySigner, Keystore, KeystoreEntry, Signer, SignerConfig};
+use merge_tool::stream_transport::StreamTransport;
+use merge_tool::verify;
+use merge_tool::Error;
 use std::process::exit;
 use std::str::FromStr;
 
 fn main() {
-    env_logger::init();
+    env_logger::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 
     let matches = Command::new("merge_tool")
         .author(crate_authors!())
@@ -21,40 +34,64 @@ fn main() {
         .subcommand(
             Command::new("generate")
                 .about("Create a bootload script, merge firmware files and write a info.json file")
+            .args(generation_args())
             .arg(
-                Arg::new("config")
-                    .short('c')
-                    .long("config")
-                    .value_name("FILE")
-                    .help("Set a config file. Defaults to config.gctmrg."),
-            )
-            .arg(
-                Arg::new("output-dir")
-                    .short('o')
-                    .long("output-dir")
-                    .value_name("FILE")
-                    .help("Output folder for generated files. Defaults to `<config-file-dir>/out`"),
-            )
-            .arg(
-                Arg::new("use-backdoor")
-                    .long("use-backdoor")
+                Arg::new("emit-digests")
+                    .long("emit-digests")
                     .action(ArgAction::SetTrue)
-                    .help("Use the backdoor to validate the firmware image."),
+                    .help("Leave signed images unsigned and write their digests to digests.json, to be signed with `sign-digests`."),
             )
             .arg(
-                Arg::new("repo-path")
-                    .long("repo-path")
-                    .value_name("FILE")
-                    .help("Path to the git repository (or any file within the repository). Defaults to the config file path."),
-            )
-            .arg(
-                Arg::new("timestamp")
-                    .short('t')
-                    .long("timestamp")
-                    .value_name("TIMESTAMP")
-                    .help("Timestamp to use for the generated files in RFC3339. Defaults to the current time.")
+                Arg::new("public-key")
+                    .long("public-key")
+                    .value_name("HEX")
+                    .requires("emit-digests")
+                    .help("Ed25519 or P-256 public key of the key signing the digests. Overrides the signer of the config file."),
             )
         )
+        .subcommand(
+            Command::new("sign-digests")
+                .about("Sign the digests written by `generate --emit-digests` with the key in MERGE_TOOL_ED25519_PRIVATE_KEY_FILE (preferred) or MERGE_TOOL_ED25519_PRIVATE_KEY, or with a key of a keystore")
+                .arg(
+                    Arg::new("digests")
+                        .required(true)
+                        .help("The digests.json file."),
+                )
+                .arg(
+                    Arg::new("output-file")
+                        .short('o')
+                        .long("output-file")
+                        .value_name("FILE")
+                        .help("Output file for the signed digests. Defaults to signatures.json."),
+                )
+                .arg(
+                    Arg::new("keystore")
+                        .long("keystore")
+                        .value_name("FILE")
+                        .requires("label")
+                        .help("Keystore file holding the private key."),
+                )
+                .arg(
+                    Arg::new("label")
+                        .long("label")
+                        .value_name("LABEL")
+                        .requires("keystore")
+                        .help("Label of the key in the keystore."),
+                )
+        )
+        .subcommand(
+            Command::new("apply-signatures")
+                .about("Insert the signatures of `sign-digests` into the images, re-generate all files and verify them")
+                .args(generation_args())
+                .arg(
+                    Arg::new("signatures")
+                        .short('s')
+                        .long("signatures")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("The signed digests written by `sign-digests`."),
+                )
+        )
         .subcommand(
             Command::new("get-version")
                 .about("Extract version information from changelog")
@@ -121,18 +158,347 @@ fn main() {
                     .help("Input files to merge."),
             )
     )
+        .subcommand(
+            Command::new("run-script")
+                .about("Execute a bootload script on a device connected via TCP or a serial port")
+                .arg(
+                    Arg::new("script")
+                        .short('s')
+                        .long("script")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("The script file (.gctbtl) to execute."),
+                )
+                .arg(
+                    Arg::new("tcp")
+                        .long("tcp")
+                        .value_name("ADDRESS")
+                        .conflicts_with("device")
+                        .help("Connect to a TCP socket, e.g. `192.168.1.10:5000`."),
+                )
+                .arg(
+                    Arg::new("device")
+                        .long("device")
+                        .value_name("FILE")
+                        .help("Use a serial port or PTY device, e.g. `/dev/ttyUSB0`. The device must be configured beforehand (e.g. using `stty`)."),
+                )
+                .arg(
+                    Arg::new("timeout")
+                        .long("timeout")
+                        .value_name("MILLISECONDS")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Time to wait for a response before retrying. Defaults to 1000."),
+                )
+                .arg(
+                    Arg::new("retries")
+                        .long("retries")
+                        .value_name("COUNT")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Number of retries if a response is missing or corrupted. Defaults to 3."),
+                )
+        )
+        .subcommand(
+            Command::new("inspect")
+                .about("Decode and print the content of a hex file, binary image or app package")
+                .arg(
+                    Arg::new("file")
+                        .required(true)
+                        .help("The file to inspect (.hex, .s37, .bin, .gctapkg or .gctapkg.json)."),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the result as JSON."),
+                )
+                .arg(
+                    Arg::new("header-offset")
+                        .long("header-offset")
+                        .value_name("OFFSET")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Offset of the header within the application image. Defaults to directly after the CRC."),
+                )
+                .arg(
+                    Arg::new("header-layout")
+                        .long("header-layout")
+                        .value_name("FILE")
+                        .help("JSON file describing the header layout, as in the `header_layouts` section of the config file. Defaults to the built-in `v1` layout."),
+                )
+                .arg(
+                    Arg::new("page-size")
+                        .long("page-size")
+                        .value_name("BYTES")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .help("Flash page size, used to search for the application image. Defaults to 64."),
+                )
+                .arg(
+                    Arg::new("base-address")
+                        .long("base-address")
+                        .value_name("ADDRESS")
+                        .help("Address of the first byte of a .bin file, e.g. `0x8000`. Defaults to 0."),
+                )
+                .arg(
+                    Arg::new("public-key")
+                        .long("public-key")
+                        .value_name("HEX")
+                        .action(ArgAction::Append)
+                        .help("Ed25519 or P-256 public key as hex string, used to verify the signature. May be given multiple times, the key is selected by its key ID."),
+                )
+                .arg(
+                    Arg::new("key-ring")
+                        .long("key-ring")
+                        .value_name("FILE")
+                        .help("Key ring with the trusted public keys. Signatures of revoked keys or of images built outside of the validity window of the key are rejected."),
+                )
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Check CRC, header, signature and bootloader trailer of generated files. Exits with an error if any check fails")
+                .arg(
+                    Arg::new("info")
+                        .short('i')
+                        .long("info")
+                        .value_name("FILE")
+                        .conflicts_with("files")
+                        .help("Verify all files listed in the given info.json file."),
+                )
+                .arg(
+                    Arg::new("files")
+                        .action(ArgAction::Append)
+                        .required_unless_present("info")
+                        .help("Files to verify (.hex, .s37, .bin, .gctapkg or .gctapkg.json)."),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the report as JSON."),
+                )
+                .arg(
+                    Arg::new("header-offset")
+                        .long("header-offset")
+                        .value_name("OFFSET")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Offset of the header within the application image. Defaults to directly after the CRC."),
+                )
+                .arg(
+                    Arg::new("header-layout")
+                        .long("header-layout")
+                        .value_name("FILE")
+                        .help("JSON file describing the header layout, as in the `header_layouts` section of the config file. Defaults to the built-in `v1` layout."),
+                )
+                .arg(
+                    Arg::new("page-size")
+                        .long("page-size")
+                        .value_name("BYTES")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .help("Flash page size, used to search for the application image. Defaults to 64."),
+                )
+                .arg(
+                    Arg::new("base-address")
+                        .long("base-address")
+                        .value_name("ADDRESS")
+                        .help("Address of the first byte of a .bin file, e.g. `0x8000`. Defaults to 0."),
+                )
+                .arg(
+                    Arg::new("public-key")
+                        .long("public-key")
+                        .value_name("HEX")
+                        .action(ArgAction::Append)
+                        .help("Ed25519 or P-256 public key as hex string, used to verify the signature. May be given multiple times, the key is selected by its key ID."),
+                )
+                .arg(
+                    Arg::new("key-ring")
+                        .long("key-ring")
+                        .value_name("FILE")
+                        .help("Key ring with the trusted public keys. Signatures of revoked keys or of images built outside of the validity window of the key are rejected."),
+                )
+                .arg(
+                    Arg::new("allow-unverified")
+                        .long("allow-unverified")
+                        .action(ArgAction::SetTrue)
+                        .help("Pass images which info.json lists as signed without a public key to verify their signature."),
+                )
+        )
+        .subcommand(
+            Command::new("extract")
+                .about("Read the header and meta-data fields of an application image and write them as JSON, .env file or C header")
+                .arg(
+                    Arg::new("file")
+                        .required(true)
+                        .help("The application image (.hex, .s37 or .bin)."),
+                )
+                .arg(
+                    Arg::new("config")
+                        .short('c')
+                        .long("config")
+                        .value_name("FILE")
+                        .help("Config file defining the application address, header layout and meta-data fields of the image."),
+                )
+                .arg(
+                    Arg::new("image")
+                        .long("image")
+                        .value_name("INDEX")
+                        .value_parser(clap::value_parser!(usize))
+                        .requires("config")
+                        .help("Index of the image in the config file. Defaults to 0."),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .value_name("FILE")
+                        .help("Write the fields to a JSON file. `-` writes to stdout."),
+                )
+                .arg(
+                    Arg::new("env")
+                        .long("env")
+                        .value_name("FILE")
+                        .help("Write the fields to a .env file. `-` writes to stdout."),
+                )
+                .arg(
+                    Arg::new("c-header")
+                        .long("c-header")
+                        .value_name("FILE")
+                        .help("Write the fields to a C header. `-` writes to stdout."),
+                )
+                .arg(
+                    Arg::new("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .default_value("")
+                        .help("Prefix of the names in the .env file and C header, e.g. `FW_`."),
+                )
+                .arg(
+                    Arg::new("header-offset")
+                        .long("header-offset")
+                        .value_name("OFFSET")
+                        .value_parser(clap::value_parser!(u64))
+                        .conflicts_with("config")
+                        .help("Offset of the header within the application image. Defaults to 4."),
+                )
+                .arg(
+                    Arg::new("header-layout")
+                        .long("header-layout")
+                        .value_name("FILE")
+                        .conflicts_with("config")
+                        .help("JSON file describing the header layout, as in the `header_layouts` section of the config file. Defaults to the built-in `v1` layout."),
+                )
+                .arg(
+                    Arg::new("page-size")
+                        .long("page-size")
+                        .value_name("BYTES")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .conflicts_with("config")
+                        .help("Flash page size, used to search for the application image. Defaults to 64."),
+                )
+                .arg(
+                    Arg::new("base-address")
+                        .long("base-address")
+                        .value_name("ADDRESS")
+                        .conflicts_with("config")
+                        .help("Address of the first byte of a .bin file, e.g. `0x8000`. Defaults to 0."),
+                )
+        )
+        .subcommand(
+            Command::new("key-ring")
+                .about("Manage a key ring holding the public keys trusted by the bootloaders")
+                .subcommand_required(true)
+                .arg(
+                    Arg::new("file")
+                        .required(true)
+                        .help("The key ring file. It is created when the first key is added."),
+                )
+                .subcommand(
+                    Command::new("add")
+                        .about("Add a public key")
+                        .arg(Arg::new("label").required(true).help("Unique name of the key."))
+                        .arg(
+                            Arg::new("public-key")
+                                .required(true)
+                                .help("Ed25519 or P-256 public key as hex string, as printed by `get-public-key`."),
+                        )
+                        .arg(
+                            Arg::new("valid-from")
+                                .long("valid-from")
+                                .value_name("TIME")
+                                .help("Reject images built before this RFC 3339 timestamp."),
+                        )
+                        .arg(
+                            Arg::new("valid-until")
+                                .long("valid-until")
+                                .value_name("TIME")
+                                .help("Reject images built after this RFC 3339 timestamp."),
+                        )
+                )
+                .subcommand(
+                    Command::new("list")
+                        .about("List the keys with key ID, signature type, state and validity window")
+                        .arg(
+                            Arg::new("json")
+                                .long("json")
+                                .action(ArgAction::SetTrue)
+                                .help("Print the key ring as JSON."),
+                        )
+                )
+                .subcommand(
+                    Command::new("revoke")
+                        .about("Revoke a key. It is kept in the key ring, such that images signed with it are rejected")
+                        .arg(Arg::new("key").required(true).help("Label or key ID, e.g. `0x12345678`, of the key."))
+                )
+                .subcommand(
+                    Command::new("c-array")
+                        .about("Write the keys, which are not revoked, as C array to embed into the bootloader")
+                        .arg(
+                            Arg::new("output-file")
+                                .short('o')
+                                .long("output-file")
+                                .value_name("FILE")
+                                .help("Write the C source to this file instead of stdout."),
+                        )
+                )
+        )
+        .subcommand(
+            Command::new("keystore")
+                .about("Manage a keystore holding private keys encrypted with the passphrase in MERGE_TOOL_KEYSTORE_PASSPHRASE")
+                .subcommand_required(true)
+                .arg(
+                    Arg::new("file")
+                        .required(true)
+                        .help("The keystore file. It is created when the first key is added."),
+                )
+                .subcommand(
+                    Command::new("add")
+                        .about("Generate a new private key, add it encrypted and print its public key")
+                        .arg(Arg::new("label").required(true).help("Unique name of the key."))
+                        .arg(algorithm_arg())
+                        .arg(
+                            Arg::new("import")
+                                .long("import")
+                                .action(ArgAction::SetTrue)
+                                .help("Add the private key in MERGE_TOOL_ED25519_PRIVATE_KEY(_FILE), respectively MERGE_TOOL_ECDSA_P256_PRIVATE_KEY(_FILE), instead of generating one."),
+                        )
+                )
+        )
         .subcommand(
             Command::new("keygen")
-                .about("Generate a new Ed25519 private key and print it as a hex string")
+                .about("Generate a new Ed25519 or P-256 private key and print it as a hex string")
+                .arg(algorithm_arg())
         )
         .subcommand(
             Command::new("get-public-key")
-                .about("Derive the Ed25519 public key from the private key in MERGE_TOOL_ED25519_PRIVATE_KEY_FILE (preferred) or MERGE_TOOL_ED25519_PRIVATE_KEY and print it as a hex string")
+                .about("Derive the public key from the private key in MERGE_TOOL_ED25519_PRIVATE_KEY_FILE (preferred) or MERGE_TOOL_ED25519_PRIVATE_KEY and print it as a hex string. P-256 keys are read from MERGE_TOOL_ECDSA_P256_PRIVATE_KEY(_FILE).")
+                .arg(algorithm_arg())
         )
         .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("generate") {
-        let options = get_generation_options(&matches);
+        let mut options = get_generation_options(&matches);
+        options.emit_digests = matches.get_flag("emit-digests");
+        if let Some(public_key) = matches.get_one::<String>("public-key") {
+            options.config.signer = SignerConfig::PublicKey {
+                public_key: public_key.clone(),
+            };
+        }
 
         if let Err(err) = process::generate(options) {
             println!("Error: Could not generate firmware: {}", err);
@@ -140,6 +506,14 @@ fn main() {
         }
     }
 
+    if let Some(matches) = matches.subcommand_matches("sign-digests") {
+        sign_digests(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("apply-signatures") {
+        apply_signatures(matches);
+    }
+
     if let Some(matches) = matches.subcommand_matches("bundle") {
         let info = matches
             .get_one::<String>("info")
@@ -204,20 +578,45 @@ fn main() {
         }
     }
 
-    if let Some(_) = matches.subcommand_matches("keygen") {
-        let key = merge_tool::ed25519::generate_private_key();
+    if let Some(matches) = matches.subcommand_matches("run-script") {
+        run_script(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("inspect") {
+        inspect(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        verify(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("extract") {
+        extract(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("key-ring") {
+        key_ring(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("keystore") {
+        keystore(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("keygen") {
+        let key = match parse_algorithm_arg(matches) {
+            SignatureType::EcdsaP256 => merge_tool::ecdsa_p256::generate_private_key(),
+            _ => merge_tool::ed25519::generate_private_key(),
+        };
         println!("{}", hex::encode(key));
     }
 
-    if let Some(_) = matches.subcommand_matches("get-public-key") {
-        let private_key = match merge_tool::ed25519::load_private_key_from_env() {
-            Ok(Some(key)) => key,
+    if let Some(matches) = matches.subcommand_matches("get-public-key") {
+        let signature_type = parse_algorithm_arg(matches);
+        let signer = match load_private_key_from_env(signature_type) {
+            Ok(Some(signer)) => signer,
             Ok(None) => {
-                println!(
-                    "Error: neither {} nor {} is set.",
-                    merge_tool::ed25519::ENV_FILE_VAR,
-                    merge_tool::ed25519::ENV_VAR,
-                );
+                let (env_file_var, env_var) = private_key_env_vars(signature_type);
+                println!("Error: neither {} nor {} is set.", env_file_var, env_var);
                 exit(1);
             }
             Err(err) => {
@@ -225,11 +624,34 @@ fn main() {
                 exit(1);
             }
         };
-        let public_key = merge_tool::ed25519::public_key_bytes(&private_key);
+        let public_key = signer.public_key().expect("The private key is valid");
         println!("{}", hex::encode(public_key));
     }
 }
 
+/// Load the private key of the given signature type from the environment.
+fn load_private_key_from_env(signature_type: SignatureType) -> Result<Option<KeySigner>, Error> {
+    let private_key = match signature_type {
+        SignatureType::EcdsaP256 => merge_tool::ecdsa_p256::load_private_key_from_env()?,
+        _ => merge_tool::ed25519::load_private_key_from_env()?,
+    };
+    Ok(private_key.map(|x| KeySigner::new(signature_type, x)))
+}
+
+/// The environment variables holding the private key of the given signature type.
+fn private_key_env_vars(signature_type: SignatureType) -> (&'static str, &'static str) {
+    match signature_type {
+        SignatureType::EcdsaP256 => (
+            merge_tool::ecdsa_p256::ENV_FILE_VAR,
+            merge_tool::ecdsa_p256::ENV_VAR,
+        ),
+        _ => (
+            merge_tool::ed25519::ENV_FILE_VAR,
+            merge_tool::ed25519::ENV_VAR,
+        ),
+    }
+}
+
 fn get_generation_options(matches: &ArgMatches) -> GenerateOptions {
     let config = matches
         .get_one::<String>("config")
@@ -276,16 +698,490 @@ fn get_generation_options(matches: &ArgMatches) -> GenerateOptions {
         config.build_time = timestamp;
     }
 
+    let record_length = matches.get_one::<usize>("record-length");
+    let line_ending = matches
+        .get_one::<String>("line-ending")
+        .map(|x| match x.as_str() {
+            "crlf" => LineEnding::CrLf,
+            _ => LineEnding::Lf,
+        });
+    let intel_addressing = matches
+        .get_one::<String>("intel-addressing")
+        .map(|x| match x.as_str() {
+            "segment" => IntelAddressing::Segment,
+            _ => IntelAddressing::Linear,
+        });
+    for fw_config in &mut config.images {
+        let mut output_formats = fw_config.output_formats();
+        for output in &mut output_formats {
+            if let Some(record_length) = record_length {
+                output.records.record_length = *record_length;
+            }
+            if let Some(line_ending) = line_ending {
+                output.records.line_ending = line_ending;
+            }
+            if let Some(intel_addressing) = intel_addressing {
+                output.records.intel_addressing = intel_addressing;
+            }
+        }
+        fw_config.output_formats = output_formats;
+        if let Err(err) = fw_config.validate_output_formats() {
+            println!("Invalid output format: {}", err);
+            exit(1);
+        }
+    }
+
     GenerateOptions {
         config,
         output_dir,
         config_dir,
         repo_dir,
+        emit_digests: false,
+    }
+}
+
+fn sign_digests(matches: &ArgMatches) {
+    let path = Path::new(matches.get_one::<String>("digests").unwrap());
+    let mut digests = match DigestFile::load_from_file(path) {
+        Ok(x) => x,
+        Err(err) => {
+            println!("Error: Could not load digests: {}", err);
+            exit(1);
+        }
+    };
+    let signer = match matches.get_one::<String>("keystore") {
+        Some(keystore) => Keystore::load_from_file(Path::new(keystore))
+            .and_then(|x| x.signer(matches.get_one::<String>("label").unwrap())),
+        None => {
+            let signature_type = digests
+                .images
+                .iter()
+                .find(|x| x.signature.is_none())
+                .map_or(SignatureType::Ed25519, |x| x.signature_type);
+            match load_private_key_from_env(signature_type) {
+                Ok(Some(signer)) => Ok(signer),
+                Ok(None) => {
+                    let (env_file_var, env_var) = private_key_env_vars(signature_type);
+                    println!(
+                        "Error: neither --keystore, {} nor {} is given.",
+                        env_file_var, env_var,
+                    );
+                    exit(1);
+                }
+                Err(err) => Err(err),
+            }
+        }
+    };
+    let signer = match signer {
+        Ok(x) => x,
+        Err(err) => {
+            println!("Error: Could not load private key: {}", err);
+            exit(1);
+        }
+    };
+    match detached::sign_digests(&mut digests, &signer) {
+        Ok(signed) => {
+            println!("Signed {} image(s)", signed);
+            let unsigned = digests.unsigned_images();
+            if !unsigned.is_empty() {
+                println!(
+                    "Images {} require signatures of other keys",
+                    unsigned.join(", ")
+                );
+            }
+        }
+        Err(err) => {
+            println!("Error: Could not sign digests: {}", err);
+            exit(1);
+        }
+    }
+    let output_file = matches
+        .get_one::<String>("output-file")
+        .cloned()
+        .unwrap_or("signatures.json".to_string());
+    if let Err(err) = digests.save_to_file(Path::new(&output_file)) {
+        println!("Error: Could not save signatures: {}", err);
+        exit(1);
+    }
+}
+
+fn apply_signatures(matches: &ArgMatches) {
+    let options = get_generation_options(matches);
+    let path = Path::new(matches.get_one::<String>("signatures").unwrap());
+    let signatures = match DigestFile::load_from_file(path) {
+        Ok(x) => x,
+        Err(err) => {
+            println!("Error: Could not load signatures: {}", err);
+            exit(1);
+        }
+    };
+    match detached::apply_signatures(&options, &signatures) {
+        Ok(report) => {
+            print!("{}", report);
+            if !report.passed {
+                exit(1);
+            }
+        }
+        Err(err) => {
+            println!("Error: Could not apply signatures: {}", err);
+            exit(1);
+        }
+    }
+}
+
+struct ConsoleListener;
+
+impl Listener for ConsoleListener {
+    fn log(&mut self, msg: &str) {
+        eprint!("\r{:60}\r", "");
+        println!("{}", msg);
+    }
+
+    fn progress(&mut self, progress: u8) {
+        const WIDTH: usize = 40;
+        let filled = progress as usize * WIDTH / 255;
+        eprint!(
+            "\r[{}{}] {:3}%",
+            "#".repeat(filled),
+            " ".repeat(WIDTH - filled),
+            progress as usize * 100 / 255
+        );
+        if progress == 255 {
+            eprintln!();
+        }
+    }
+}
+
+fn run_script(matches: &ArgMatches) {
+    let script_path = matches.get_one::<String>("script").unwrap();
+    let script = match fs::read_to_string(script_path) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Error: Could not read script file: {}", err);
+            exit(1);
+        }
+    };
+    let script = match Script::parse(&script).and_then(|x| x.verify().map(|_| x)) {
+        Ok(script) => script,
+        Err(err) => {
+            println!("Error: Invalid script file: {}", err);
+            exit(1);
+        }
+    };
+
+    let transport = if let Some(addr) = matches.get_one::<String>("tcp") {
+        StreamTransport::connect_tcp(addr)
+    } else if let Some(device) = matches.get_one::<String>("device") {
+        StreamTransport::open_device(Path::new(device))
+    } else {
+        println!("Error: Either --tcp or --device must be specified.");
+        exit(1);
+    };
+    let mut transport = match transport {
+        Ok(transport) => transport,
+        Err(err) => {
+            println!("Error: Could not connect to device: {}", err);
+            exit(1);
+        }
+    };
+    if let Some(timeout) = matches.get_one::<u64>("timeout") {
+        transport.timeout = Duration::from_millis(*timeout);
+    }
+    if let Some(retries) = matches.get_one::<u32>("retries") {
+        transport.retries = *retries;
+    }
+
+    if let Err(err) = script_executor::execute(&script, &mut transport, &mut ConsoleListener) {
+        eprintln!();
+        println!("Error: Script execution failed: {}", err);
+        exit(1);
+    }
+}
+
+fn parse_inspect_options(matches: &ArgMatches) -> InspectOptions {
+    let mut options = parse_image_options(matches);
+    options.public_keys = parse_public_keys(matches);
+    if let Some(path) = matches.get_one::<String>("key-ring") {
+        options.key_ring = match KeyRing::load_from_file(Path::new(path)) {
+            Ok(x) => x,
+            Err(err) => {
+                println!("Error: Could not load key ring: {}", err);
+                exit(1);
+            }
+        };
+    }
+    options
+}
+
+fn parse_image_options(matches: &ArgMatches) -> InspectOptions {
+    let mut options = InspectOptions {
+        header_offset: matches.get_one::<u64>("header-offset").cloned(),
+        ..Default::default()
+    };
+    if let Some(page_size) = matches.get_one::<u64>("page-size") {
+        options.device_config.page_size = *page_size;
+    }
+    if let Some(path) = matches.get_one::<String>("header-layout") {
+        options.header_layout = match load_header_layout(Path::new(path)) {
+            Ok(x) => x,
+            Err(err) => {
+                println!("Error: Invalid header layout: {}", err);
+                exit(1);
+            }
+        };
+    }
+    if let Some(base_address) = matches.get_one::<String>("base-address") {
+        let base_address = base_address.trim_start_matches("0x");
+        options.base_address = match u64::from_str_radix(base_address, 16) {
+            Ok(x) => x,
+            Err(err) => {
+                println!("Error: Invalid base address: {}", err);
+                exit(1);
+            }
+        };
+    }
+    options
+}
+
+fn load_header_layout(path: &Path) -> Result<HeaderLayout, Error> {
+    let data = fs::read_to_string(path)?;
+    let layout: HeaderLayout = serde_json::from_str(&data).map_err(Error::CannotParseConfig)?;
+    layout.validate()?;
+    Ok(layout)
+}
+
+fn inspect(matches: &ArgMatches) {
+    let file = matches.get_one::<String>("file").unwrap();
+    let options = parse_inspect_options(matches);
+    let report = match inspect_file(Path::new(file), &options) {
+        Ok(report) => report,
+        Err(err) => {
+            println!("Error: Could not inspect file: {}", err);
+            exit(1);
+        }
+    };
+    if matches.get_flag("json") {
+        println!("{}", report.to_json());
+    } else {
+        print!("{}", report);
+    }
+}
+
+fn verify(matches: &ArgMatches) {
+    let mut options = parse_inspect_options(matches);
+    options.allow_unverified = matches.get_flag("allow-unverified");
+    let report = if let Some(info) = matches.get_one::<String>("info") {
+        match verify::verify_info(Path::new(info), &options) {
+            Ok(report) => report,
+            Err(err) => {
+                println!("Error: Could not verify files: {}", err);
+                exit(1);
+            }
+        }
+    } else {
+        let files: Vec<_> = matches
+            .get_many::<String>("files")
+            .unwrap()
+            .map(PathBuf::from)
+            .collect();
+        verify::verify_files(&files, &options)
+    };
+    if matches.get_flag("json") {
+        println!("{}", report.to_json());
+    } else {
+        print!("{}", report);
+    }
+    if !report.passed {
+        exit(1);
+    }
+}
+
+fn key_ring(matches: &ArgMatches) {
+    let path = Path::new(matches.get_one::<String>("file").unwrap());
+    // only `add` creates the file, a missing ring must not be taken as an empty one
+    let ring = if matches.subcommand_name() == Some("add") {
+        KeyRing::load_or_default(path)
+    } else {
+        KeyRing::load_from_file(path)
+    };
+    let mut ring = match ring {
+        Ok(x) => x,
+        Err(err) => {
+            println!("Error: Could not load key ring: {}", err);
+            exit(1);
+        }
+    };
+    let result = match matches.subcommand() {
+        Some(("add", matches)) => {
+            let label = matches.get_one::<String>("label").unwrap();
+            let public_key = matches.get_one::<String>("public-key").unwrap();
+            KeyRingEntry::new(label, public_key).and_then(|mut entry| {
+                entry.valid_from = parse_time_arg(matches, "valid-from");
+                entry.valid_until = parse_time_arg(matches, "valid-until");
+                let key_id = entry.key_id;
+                ring.add(entry)?;
+                println!("Added key ID 0x{:08X}", key_id);
+                ring.save_to_file(path)
+            })
+        }
+        Some(("list", matches)) => {
+            if matches.get_flag("json") {
+                println!("{}", serde_json::to_string_pretty(&ring).unwrap());
+            } else {
+                print!("{}", ring);
+            }
+            Ok(())
+        }
+        Some(("revoke", matches)) => {
+            let key = matches.get_one::<String>("key").unwrap();
+            ring.revoke(key)
+                .map(|entry| println!("Revoked key ID 0x{:08X}", entry.key_id))
+                .and_then(|_| ring.save_to_file(path))
+        }
+        Some(("c-array", matches)) => ring.to_c_array().and_then(|source| {
+            match matches.get_one::<String>("output-file") {
+                Some(output_file) => fs::write(output_file, source).map_err(Error::Io),
+                None => {
+                    print!("{}", source);
+                    Ok(())
+                }
+            }
+        }),
+        _ => unreachable!(),
+    };
+    if let Err(err) = result {
+        println!("Error: {}", err);
+        exit(1);
+    }
+}
+
+fn keystore(matches: &ArgMatches) {
+    let path = Path::new(matches.get_one::<String>("file").unwrap());
+    let Ok(passphrase) = std::env::var(signer::KEYSTORE_PASSPHRASE_VAR) else {
+        println!("Error: {} is not set.", signer::KEYSTORE_PASSPHRASE_VAR);
+        exit(1);
+    };
+    let Some(("add", matches)) = matches.subcommand() else {
+        unreachable!()
+    };
+    let label = matches.get_one::<String>("label").unwrap();
+    let signature_type = parse_algorithm_arg(matches);
+    let private_key = if matches.get_flag("import") {
+        let private_key = match signature_type {
+            SignatureType::EcdsaP256 => merge_tool::ecdsa_p256::load_private_key_from_env(),
+            _ => merge_tool::ed25519::load_private_key_from_env(),
+        };
+        match private_key {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                let (env_file_var, env_var) = private_key_env_vars(signature_type);
+                println!("Error: neither {} nor {} is set.", env_file_var, env_var);
+                exit(1);
+            }
+            Err(err) => {
+                println!("Error: Could not load private key: {}", err);
+                exit(1);
+            }
+        }
+    } else {
+        match signature_type {
+            SignatureType::EcdsaP256 => merge_tool::ecdsa_p256::generate_private_key(),
+            _ => merge_tool::ed25519::generate_private_key(),
+        }
+    };
+    let result = Keystore::load_or_default(path).and_then(|mut keystore| {
+        let entry = KeystoreEntry::encrypted(label, signature_type, &private_key, &passphrase)?;
+        keystore.add(entry)?;
+        keystore.save_to_file(path)?;
+        KeySigner::new(signature_type, private_key).public_key()
+    });
+    match result {
+        Ok(public_key) => println!("{}", hex::encode(public_key)),
+        Err(err) => {
+            println!("Error: {}", err);
+            exit(1);
+        }
+    }
+}
+
+fn extract(matches: &ArgMatches) {
+    let file = Path::new(matches.get_one::<String>("file").unwrap());
+    let extracted = if let Some(config) = matches.get_one::<String>("config") {
+        let config = match Config::load_from_file(Path::new(config)) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Cannot load config: {}", err);
+                exit(1);
+            }
+        };
+        let image = matches.get_one::<usize>("image").cloned();
+        extract::extract_with_config(file, &config, image)
+    } else {
+        extract::extract(file, &parse_image_options(matches), None, &[])
+    };
+    let extracted = match extracted {
+        Ok(x) => x,
+        Err(err) => {
+            println!("Error: Could not extract fields: {}", err);
+            exit(1);
+        }
+    };
+
+    let prefix = matches.get_one::<String>("prefix").unwrap();
+    let outputs = [
+        ("json", extracted.to_json() + "\n"),
+        ("env", extracted.to_env(prefix)),
+        ("c-header", extracted.to_c_header(prefix)),
+    ];
+    let mut written = false;
+    for (arg, content) in &outputs {
+        let Some(path) = matches.get_one::<String>(arg) else {
+            continue;
+        };
+        written = true;
+        if path == "-" {
+            print!("{}", content);
+        } else if let Err(err) = fs::write(path, content) {
+            println!("Error: Could not write {}: {}", path, err);
+            exit(1);
+        }
+    }
+    if !written {
+        print!("{}", outputs[0].1);
+    }
+}
+
+fn parse_public_keys(matches: &ArgMatches) -> Vec<Vec<u8>> {
+    let Some(keys) = matches.get_many::<String>("public-key") else {
+        return Vec::new();
+    };
+    let mut ret = Vec::new();
+    for key in keys {
+        let Ok(decoded) = signer::decode_public_key(key) else {
+            println!(
+                "Error: Public key must be 32 (Ed25519) or 64 (P-256) bytes encoded as hex string: {}",
+                key
+            );
+            exit(1);
+        };
+        ret.push(decoded);
+    }
+    ret
+}
+
+fn parse_algorithm_arg(matches: &ArgMatches) -> SignatureType {
+    match matches.get_one::<String>("algorithm").map(|x| x.as_str()) {
+        Some("ecdsa-p256") => SignatureType::EcdsaP256,
+        _ => SignatureType::Ed25519,
     }
 }
 
 fn parse_timestamp_arg(matches: &ArgMatches) -> Option<chrono::DateTime<chrono::Utc>> {
-    let Some(timestamp) = matches.get_one::<String>("timestamp") else {
+    parse_time_arg(matches, "timestamp")
+}
+
+fn parse_time_arg(matches: &ArgMatches, name: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let Some(timestamp) = matches.get_one::<String>(name) else {
         return None;
     };
     match chrono::DateTime::parse_from_rfc3339(timestamp) {
@@ -296,3 +1192,53 @@ fn parse_timestamp_arg(matches: &ArgMatches) -> Option<chrono::DateTime<chrono::
         }
     }
 }
+
+/// Arguments of the commands which generate the output files.
+fn generation_args() -> Vec<Arg> {
+    vec![
+        Arg::new("config")
+            .short('c')
+            .long("config")
+            .value_name("FILE")
+            .help("Set a config file. Defaults to config.gctmrg."),
+        Arg::new("output-dir")
+            .short('o')
+            .long("output-dir")
+            .value_name("FILE")
+            .help("Output folder for generated files. Defaults to `<config-file-dir>/out`"),
+        Arg::new("use-backdoor")
+            .long("use-backdoor")
+            .action(ArgAction::SetTrue)
+            .help("Use the backdoor to validate the firmware image."),
+        Arg::new("repo-path")
+            .long("repo-path")
+            .value_name("FILE")
+            .help("Path to the git repository (or any file within the repository). Defaults to the config file path."),
+        Arg::new("timestamp")
+            .short('t')
+            .long("timestamp")
+            .value_name("TIMESTAMP")
+            .help("Timestamp to use for the generated files in RFC3339. Defaults to the current time."),
+        Arg::new("record-length")
+            .long("record-length")
+            .value_name("BYTES")
+            .value_parser(clap::value_parser!(usize))
+            .help("Number of data bytes per record of all generated hex files. Overrides the config file."),
+        Arg::new("line-ending")
+            .long("line-ending")
+            .value_parser(["lf", "crlf"])
+            .help("Line ending of all generated hex files. Overrides the config file."),
+        Arg::new("intel-addressing")
+            .long("intel-addressing")
+            .value_parser(["linear", "segment"])
+            .help("Address records of generated Intel HEX files. Overrides the config file."),
+    ]
+}
+
+fn algorithm_arg() -> Arg {
+    Arg::new("algorithm")
+        .long("algorithm")
+        .value_parser(["ed25519", "ecdsa-p256"])
+        .default_value("ed25519")
+        .help("Signature algorithm of the key.")
+}
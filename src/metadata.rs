@@ -0,0 +1,481 @@
+//! User-defined meta-data fields, which are written into the application image before the
+//! CRC is computed and the image is signed.
+
+use std::path::Path;
+
+use chrono::DateTime;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::changelog::extract_version_from_changelog_file;
+use crate::config::{Config, DeviceConfig, Endianness};
+use crate::extract::ExtractedValue;
+use crate::firmware::Firmware;
+use crate::git_description::GitDescription;
+use crate::Error;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataType {
+    U8,
+    U16,
+    U32,
+    U64,
+    /// UTF-8 string. On word addressed devices, each character occupies a word.
+    String,
+    Bytes,
+    /// Seconds since the unix epoch, 8 bytes or 4 bytes if `size` is 4.
+    Timestamp,
+    /// Major (u16), minor (u16) and patch (u32), as in the firmware header.
+    Version,
+}
+
+/// Properties of the git commit which may be used as value of a meta-data field.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GitValue {
+    Sha,
+    ShortSha,
+    Dirty,
+    CommitTime,
+    Distance,
+    Tag,
+}
+
+/// A meta-data field written into the application image.
+///
+/// Exactly one of `address` and `offset` must be given. The value is taken from at most one
+/// of `value`, `env`, `git` and `changelog`. Without a source, `timestamp` fields default to
+/// the build time and `version` fields to the version of the image.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct MetadataEntry {
+    /// Name of the field, used by `extract`. Fields without a name are not extracted.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Absolute address of the field, in words for word addressed devices.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub address: Option<u64>,
+    /// Offset of the field relative to the start of the application image.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    #[serde(rename = "type")]
+    pub kind: MetadataType,
+    /// Size of the field in bytes. Strings and bytes are padded with zeroes.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    /// Name of an environment variable holding the value.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub env: Option<String>,
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub git: Option<GitValue>,
+    /// Path to a changelog, relative to the config file. The latest version is used.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub changelog: Option<String>,
+}
+
+enum Value {
+    Int(u64),
+    Text(String),
+    Bytes(Vec<u8>),
+    Version(Version),
+}
+
+struct Context<'a> {
+    config: &'a Config,
+    idx: usize,
+    config_dir: &'a Path,
+    git_description: Option<&'a GitDescription>,
+}
+
+/// Write all meta-data fields of image `idx` into the application `fw`.
+///
+/// Fields must lie within the application, must not overlap each other and must not overlap
+/// the CRC, signature or header of the image.
+pub fn apply(
+    fw: &mut Firmware,
+    config: &Config,
+    idx: usize,
+    config_dir: &Path,
+    git_description: Option<&GitDescription>,
+) -> Result<(), Error> {
+    let ctx = Context {
+        config,
+        idx,
+        config_dir,
+        git_description,
+    };
+    let fw_config = &config.images[idx];
+    let header_end = fw_config.header_offset as usize + config.header_layout(fw_config)?.length;
+    let mut written: Vec<(usize, usize)> = Vec::new();
+    for (k, entry) in fw_config.metadata.iter().enumerate() {
+        let invalid = |msg: String| Error::InvalidConfig(format!("Metadata entry {}: {}", k, msg));
+        let value = resolve(entry, &ctx).map_err(invalid)?;
+        let data = encode(entry, value, fw).map_err(invalid)?;
+
+        let start = match (entry.address, entry.offset) {
+            (Some(address), None) => address
+                .checked_sub(fw.range.begin)
+                .ok_or_else(|| invalid("address is before the application".to_string()))?
+                as usize,
+            (None, Some(offset)) => offset as usize,
+            _ => {
+                return Err(invalid(
+                    "either `address` or `offset` is required".to_string(),
+                ))
+            }
+        };
+        let end = start + data.len();
+        if end > fw.data.len() {
+            return Err(invalid(
+                "field does not fit into the application".to_string(),
+            ));
+        }
+        if start < header_end {
+            return Err(invalid(
+                "field overlaps the CRC, signature or header".to_string(),
+            ));
+        }
+        if written.iter().any(|(s, e)| start < *e && *s < end) {
+            return Err(invalid(
+                "field overlaps another meta-data field".to_string(),
+            ));
+        }
+        fw.write(start, &data);
+        written.push((start, end));
+    }
+    Ok(())
+}
+
+fn resolve(entry: &MetadataEntry, ctx: &Context) -> Result<Value, String> {
+    let sources = [
+        entry.value.is_some(),
+        entry.env.is_some(),
+        entry.git.is_some(),
+        entry.changelog.is_some(),
+    ];
+    if sources.iter().filter(|x| **x).count() > 1 {
+        return Err("only one of `value`, `env`, `git` and `changelog` may be given".to_string());
+    }
+    if let Some(value) = &entry.value {
+        return match value {
+            serde_json::Value::Number(x) => x
+                .as_u64()
+                .map(Value::Int)
+                .ok_or_else(|| format!("invalid number `{}`", x)),
+            serde_json::Value::Bool(x) => Ok(Value::Int(*x as u64)),
+            serde_json::Value::String(x) => Ok(Value::Text(x.clone())),
+            x => Err(format!("unsupported value `{}`", x)),
+        };
+    }
+    if let Some(name) = &entry.env {
+        return std::env::var(name)
+            .map(Value::Text)
+            .map_err(|_| format!("environment variable `{}` is not set", name));
+    }
+    if let Some(git) = entry.git {
+        let desc = ctx
+            .git_description
+            .ok_or_else(|| "git values require a git repository".to_string())?;
+        return Ok(match git {
+            GitValue::Sha if entry.kind == MetadataType::Bytes => {
+                Value::Bytes(desc.commit_id.as_bytes().to_vec())
+            }
+            GitValue::Sha => Value::Text(desc.commit_id.to_string()),
+            GitValue::ShortSha => Value::Text(desc.sha.clone()),
+            GitValue::Dirty => Value::Int(desc.dirty as u64),
+            GitValue::CommitTime => Value::Int(desc.commit_time as u64),
+            GitValue::Distance => Value::Int(desc.distance.unwrap_or(0) as u64),
+            GitValue::Tag => Value::Text(desc.parent_tag_name.clone().unwrap_or_default()),
+        });
+    }
+    if let Some(path) = &entry.changelog {
+        let path = Config::normalize_path(path, ctx.config_dir).map_err(|x| x.to_string())?;
+        return extract_version_from_changelog_file(&path)
+            .map(Value::Version)
+            .map_err(|x| x.to_string());
+    }
+    match entry.kind {
+        MetadataType::Timestamp => Ok(Value::Int(ctx.config.build_time.timestamp() as u64)),
+        MetadataType::Version => ctx.config.images[ctx.idx]
+            .version
+            .clone()
+            .map(Value::Version)
+            .ok_or_else(|| "the image has no version".to_string()),
+        _ => Err("a value is required".to_string()),
+    }
+}
+
+fn parse_int(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let ret = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    ret.map_err(|_| format!("invalid number `{}`", text))
+}
+
+fn encode(entry: &MetadataEntry, value: Value, fw: &Firmware) -> Result<Vec<u8>, String> {
+    let width = match entry.kind {
+        MetadataType::U8 => 1,
+        MetadataType::U16 => 2,
+        MetadataType::U32 => 4,
+        MetadataType::U64 => 8,
+        MetadataType::Timestamp => match entry.size {
+            None | Some(8) => 8,
+            Some(4) => 4,
+            Some(x) => return Err(format!("unsupported size {} of timestamp", x)),
+        },
+        MetadataType::Version => {
+            let version = match value {
+                Value::Version(x) => x,
+                Value::Text(x) => {
+                    Version::parse(&x).map_err(|_| format!("invalid version `{}`", x))?
+                }
+                _ => return Err("expected a version".to_string()),
+            };
+            let mut ret = uint_bytes(version.major, 2, fw);
+            ret.extend(uint_bytes(version.minor, 2, fw));
+            ret.extend(uint_bytes(version.patch, 4, fw));
+            return Ok(ret);
+        }
+        MetadataType::String => {
+            let text = match value {
+                Value::Text(x) => x,
+                Value::Int(x) => x.to_string(),
+                Value::Version(x) => x.to_string(),
+                Value::Bytes(x) => hex::encode(x),
+            };
+            let data = if fw.config.word_addressing {
+                text.bytes()
+                    .flat_map(|x| uint_bytes(x as u64, 2, fw))
+                    .collect()
+            } else {
+                text.into_bytes()
+            };
+            return pad(data, entry.size);
+        }
+        MetadataType::Bytes => {
+            let data = match value {
+                Value::Bytes(x) => x,
+                Value::Text(x) => {
+                    hex::decode(x.trim()).map_err(|_| format!("invalid hex string `{}`", x))?
+                }
+                _ => return Err("expected a hex string".to_string()),
+            };
+            return pad(data, entry.size);
+        }
+    };
+    let value = match value {
+        Value::Int(x) => x,
+        Value::Text(x) if entry.kind == MetadataType::Timestamp => match parse_int(&x) {
+            Ok(x) => x,
+            Err(_) => DateTime::parse_from_rfc3339(&x)
+                .map_err(|_| format!("invalid timestamp `{}`", x))?
+                .timestamp() as u64,
+        },
+        Value::Text(x) => parse_int(&x)?,
+        _ => return Err("expected a number".to_string()),
+    };
+    if width < 8 && value >> (width * 8) != 0 {
+        return Err(format!("value {} does not fit into {} bytes", value, width));
+    }
+    Ok(uint_bytes(value, width, fw))
+}
+
+/// Read back a field from the application image `app`, which starts at `address`.
+pub fn decode(
+    entry: &MetadataEntry,
+    app: &[u8],
+    address: u64,
+    config: &DeviceConfig,
+) -> Result<ExtractedValue, String> {
+    let start = match (entry.address, entry.offset) {
+        (Some(x), None) => x
+            .checked_sub(address)
+            .ok_or_else(|| "address is before the application".to_string())?,
+        (None, Some(x)) => x,
+        _ => return Err("either `address` or `offset` is required".to_string()),
+    } as usize;
+    let data = app.get(start..).unwrap_or_default();
+    let width = match entry.kind {
+        MetadataType::U8 => 1,
+        MetadataType::U16 => 2,
+        MetadataType::U32 => 4,
+        MetadataType::U64 => 8,
+        MetadataType::Timestamp => entry.size.unwrap_or(8),
+        MetadataType::Version => 8,
+        MetadataType::String | MetadataType::Bytes => match entry.size {
+            Some(x) => x,
+            None if entry.kind == MetadataType::String => data.len(),
+            None => return Err("`size` is required to extract bytes".to_string()),
+        },
+    };
+    let data = data
+        .get(..width)
+        .ok_or_else(|| "field does not fit into the application".to_string())?;
+    let ret = match entry.kind {
+        MetadataType::Version => ExtractedValue::Text(format!(
+            "{}.{}.{}",
+            read_uint(&data[0..2], config),
+            read_uint(&data[2..4], config),
+            read_uint(&data[4..8], config)
+        )),
+        MetadataType::String => {
+            let chars: Vec<u8> = if config.word_addressing {
+                data.chunks_exact(2)
+                    .map(|x| read_uint(x, config) as u8)
+                    .collect()
+            } else {
+                data.to_vec()
+            };
+            let end = chars.iter().position(|x| *x == 0).unwrap_or(chars.len());
+            ExtractedValue::Text(String::from_utf8_lossy(&chars[..end]).to_string())
+        }
+        MetadataType::Bytes => ExtractedValue::Text(hex::encode(data)),
+        _ => ExtractedValue::Number(read_uint(data, config)),
+    };
+    Ok(ret)
+}
+
+fn read_uint(data: &[u8], config: &DeviceConfig) -> u64 {
+    let mut bytes = data.to_vec();
+    if let Endianness::Big = config.endianness {
+        bytes.reverse();
+    }
+    bytes.iter().rev().fold(0, |acc, x| (acc << 8) | *x as u64)
+}
+
+fn pad(mut data: Vec<u8>, size: Option<usize>) -> Result<Vec<u8>, String> {
+    if let Some(size) = size {
+        if data.len() > size {
+            return Err(format!(
+                "value of {} bytes exceeds size {}",
+                data.len(),
+                size
+            ));
+        }
+        data.resize(size, 0);
+    }
+    Ok(data)
+}
+
+fn uint_bytes(value: u64, width: usize, fw: &Firmware) -> Vec<u8> {
+    let bytes = value.to_le_bytes();
+    let mut ret = bytes[..width].to_vec();
+    if let Endianness::Big = fw.config.endianness {
+        ret.reverse();
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AddressRange, DeviceConfig};
+
+    fn entry(json: &str) -> MetadataEntry {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn firmware(endianness: Endianness, word_addressing: bool) -> Firmware {
+        let config = DeviceConfig {
+            endianness,
+            word_addressing,
+            ..Default::default()
+        };
+        Firmware::new(AddressRange::new(0, 64), config, vec![0xFF; 64]).unwrap()
+    }
+
+    fn encode_value(json: &str, fw: &Firmware) -> Result<Vec<u8>, String> {
+        let entry = entry(json);
+        let value = match &entry.value {
+            Some(serde_json::Value::Number(x)) => Value::Int(x.as_u64().unwrap()),
+            Some(serde_json::Value::String(x)) => Value::Text(x.clone()),
+            _ => unreachable!(),
+        };
+        encode(&entry, value, fw)
+    }
+
+    #[test]
+    fn encode_respects_endianness() {
+        let le = firmware(Endianness::Little, false);
+        let be = firmware(Endianness::Big, false);
+        let json = r#"{ "offset": 0, "type": "u32", "value": "0x12345678" }"#;
+        assert_eq!(
+            encode_value(json, &le).unwrap(),
+            vec![0x78, 0x56, 0x34, 0x12]
+        );
+        assert_eq!(
+            encode_value(json, &be).unwrap(),
+            vec![0x12, 0x34, 0x56, 0x78]
+        );
+
+        let json = r#"{ "offset": 0, "type": "version", "value": "1.2.3" }"#;
+        assert_eq!(
+            encode_value(json, &be).unwrap(),
+            vec![0, 1, 0, 2, 0, 0, 0, 3]
+        );
+    }
+
+    #[test]
+    fn encode_checks_range_and_size() {
+        let fw = firmware(Endianness::Little, false);
+        let json = r#"{ "offset": 0, "type": "u8", "value": 256 }"#;
+        assert!(encode_value(json, &fw).is_err());
+        let json = r#"{ "offset": 0, "type": "string", "size": 4, "value": "abcde" }"#;
+        assert!(encode_value(json, &fw).is_err());
+        let json = r#"{ "offset": 0, "type": "string", "size": 4, "value": "ab" }"#;
+        assert_eq!(encode_value(json, &fw).unwrap(), b"ab\0\0".to_vec());
+        let json =
+            r#"{ "offset": 0, "type": "timestamp", "size": 4, "value": "1970-01-01T00:01:00Z" }"#;
+        assert_eq!(encode_value(json, &fw).unwrap(), vec![60, 0, 0, 0]);
+    }
+
+    #[test]
+    fn strings_use_a_word_per_character_with_word_addressing() {
+        let fw = firmware(Endianness::Big, true);
+        let json = r#"{ "offset": 0, "type": "string", "value": "ab" }"#;
+        assert_eq!(encode_value(json, &fw).unwrap(), vec![0, b'a', 0, b'b']);
+    }
+
+    #[test]
+    fn decode_reverses_encode() {
+        for (endianness, word_addressing) in [
+            (Endianness::Little, false),
+            (Endianness::Big, false),
+            (Endianness::Big, true),
+        ] {
+            let fw = firmware(endianness, word_addressing);
+            for (json, expected) in [
+                (
+                    r#"{ "offset": 2, "type": "u32", "value": 305419896 }"#,
+                    ExtractedValue::Number(305419896),
+                ),
+                (
+                    r#"{ "offset": 2, "type": "u64", "value": "0x123456789" }"#,
+                    ExtractedValue::Number(0x123456789),
+                ),
+                (
+                    r#"{ "offset": 2, "type": "version", "value": "1.2.3" }"#,
+                    ExtractedValue::Text("1.2.3".to_string()),
+                ),
+                (
+                    r#"{ "offset": 2, "type": "string", "size": 12, "value": "abc" }"#,
+                    ExtractedValue::Text("abc".to_string()),
+                ),
+                (
+                    r#"{ "offset": 2, "type": "bytes", "size": 3, "value": "abcd" }"#,
+                    ExtractedValue::Text("abcd00".to_string()),
+                ),
+            ] {
+                let mut data = vec![0xFF; 16];
+                let encoded = encode_value(json, &fw).unwrap();
+                data[2..2 + encoded.len()].copy_from_slice(&encoded);
+                let decoded = decode(&entry(json), &data, 0, &fw.config).unwrap();
+                assert_eq!(decoded, expected, "{}", json);
+            }
+        }
+    }
+}
@@ -3,15 +3,22 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use crate::app_package::{self, AppPackage};
-use crate::btl_trailer;
-use crate::config::{Config, FwConfig, HexFileFormat, SignatureType, DDP_CMD_CODE};
+use crate::btl_trailer::{self, TrailerField};
+use crate::changelog::extract_security_version_from_changelog_file;
+use crate::config::{
+    AddressRange, AppRegion, BtlTrailerConfig, Config, ExtraInput, FwConfig, HexFileFormat,
+    SecurityVersion, SignatureType, DDP_CMD_CODE,
+};
 use crate::crc::crc32;
 use crate::ddp::DdpProtocol;
-use crate::firmware::Firmware;
+use crate::detached::{self, DigestFile};
+use crate::firmware::{Firmware, HexFileContent};
 use crate::git_description::{retrieve_description, GitDescription};
-use crate::header::Header;
+use crate::header::{self, Header};
+use crate::metadata;
 use crate::protocol::generate_script;
 use crate::script::Script;
+use crate::signer::{self, Signer};
 use crate::Error;
 
 use crate::blocking_ddp::BlockingDdpProtocol;
@@ -19,39 +26,56 @@ use chrono::Utc;
 use semver::{BuildMetadata, Prerelease, Version};
 use serde::{Deserialize, Serialize};
 
+#[derive(Clone)]
 pub struct GenerateOptions {
     pub config: Config,
     pub output_dir: PathBuf,
     pub config_dir: PathBuf,
     pub repo_dir: Option<PathBuf>,
+    /// Leave the images unsigned and write their digests to `digests.json` instead.
+    pub emit_digests: bool,
 }
 
 pub fn generate(options: GenerateOptions) -> Result<(), Error> {
     create_dir_all(&options.output_dir)?;
 
+    let mut config = options.config.clone();
+    config.defer_signing |= options.emit_digests;
     let loaded = load_firmware_images(
-        &options.config,
+        &config,
         &options.config_dir,
         options.repo_dir.as_ref().map(|x| x.as_path()),
     )?;
+    save_outputs(&loaded, &options)?;
 
+    if options.emit_digests {
+        let digests = DigestFile::from_loaded_firmware_images(&loaded)?;
+        digests.save_to_file(&options.output_dir.join(detached::DIGEST_FILE_NAME))?;
+    }
+
+    Ok(())
+}
+
+/// Write the script, the merged images, `info.json`, the hex and binary files and the app
+/// package.
+pub fn save_outputs(loaded: &LoadedFirmwareImages, options: &GenerateOptions) -> Result<(), Error> {
     // create script
-    let script = create_script(&loaded)?;
-    save_script(&script, &loaded, &options.config_dir)?;
+    let script = create_script(loaded)?;
+    save_script(&script, loaded, &options.output_dir)?;
 
     // merge firmware images
-    let merged = merge_all(&loaded)?;
+    let merged = merge_all(loaded)?;
     save_merged_firmware_images(&merged, &options.output_dir)?;
 
     // generate info.json
-    let info = generate_info(&loaded, &options.output_dir)?;
+    let info = generate_info(loaded, &options.output_dir)?;
     save_info(&info, &options.output_dir)?;
 
     // dump individual hex files if requested
-    save_hex_and_bin_images(&loaded, &options.output_dir)?;
+    save_hex_and_bin_images(loaded, &options.output_dir)?;
 
     // generate app package
-    let package = AppPackage::from_loaded_firmware_images(loaded.config.product_id, &loaded);
+    let package = AppPackage::from_loaded_firmware_images(loaded.config.product_id, loaded);
     save_app_package(&package, &options.output_dir, &loaded.app_package_file_name)?;
 
     Ok(())
@@ -60,9 +84,25 @@ pub fn generate(options: GenerateOptions) -> Result<(), Error> {
 pub struct LoadedFirmware {
     pub btl: Firmware,
     pub app: Firmware,
+    /// The app regions, in the order of `FwConfig::app_regions`.
+    pub regions: Vec<LoadedRegion>,
+    /// The extra inputs, in the order of `FwConfig::extra_inputs`.
+    pub extra_inputs: Vec<LoadedExtraInput>,
     pub config: FwConfig,
 }
 
+/// The data of an app region.
+pub struct LoadedRegion {
+    pub config: AppRegion,
+    pub fw: Firmware,
+}
+
+/// The data of an extra input.
+pub struct LoadedExtraInput {
+    pub config: ExtraInput,
+    pub fw: Firmware,
+}
+
 impl LoadedFirmware {
     pub fn load_crc(&self) -> u32 {
         self.app.read_u32(self.config.crc_offset())
@@ -80,6 +120,26 @@ pub struct LoadedFirmwareImages {
     pub app_package_file_name: String,
 }
 
+/// Collect the TLV fields of a version 2 bootloader trailer.
+fn btl_trailer_fields(
+    config: &Config,
+    trailer: &BtlTrailerConfig,
+) -> Result<Vec<TrailerField>, Error> {
+    let mut key_ids = Vec::new();
+    for key in &trailer.trusted_public_keys {
+        let key = signer::decode_public_key(key)
+            .map_err(|_| Error::InvalidConfig(format!("Invalid trusted public key: `{}`", key)))?;
+        key_ids.push(crc32(&key));
+    }
+    Ok(vec![
+        TrailerField::BtlVersion(trailer.btl_version.clone()),
+        TrailerField::ProductId(config.product_id),
+        TrailerField::Timestamp(config.build_time.timestamp() as u64),
+        TrailerField::ProtocolVersion(trailer.protocol_version.unwrap_or(config.btl_version)),
+        TrailerField::TrustedKeyIds(key_ids),
+    ])
+}
+
 pub fn load_firmware_images(
     config: &Config,
     config_dir: &Path,
@@ -99,10 +159,31 @@ pub fn load_firmware_images(
     let mut ret = Vec::new();
     config.transform_to_byte_addrs();
     for idx in 0..config.images.len() {
-        let app = load_app(&mut config, idx, config_dir)?;
+        let signer = match config.images[idx].signature_type {
+            SignatureType::Unsigned => None,
+            _ => Some(signer::from_config(
+                &config,
+                &config.images[idx],
+                config_dir,
+            )?),
+        };
+        let app_file = load_app_file(&mut config, idx, config_dir)?;
+        let regions = load_regions(&mut config, idx, config_dir, &app_file)?;
+        let app = load_app(
+            &mut config,
+            idx,
+            config_dir,
+            app_file,
+            git_description.as_ref(),
+            signer.as_deref(),
+        )?;
         let mut btl = load_btl(&mut config, idx, config_dir)?;
+        let extra_inputs = load_extra_inputs(&mut config, idx, config_dir)?;
 
-        if config.images[idx].btl_trailer {
+        if let Some(trailer) = &config.images[idx].btl_trailer_v2 {
+            let fields = btl_trailer_fields(&config, trailer)?;
+            btl_trailer::write_btl_trailer_v2(&mut btl, &fields)?;
+        } else if config.images[idx].btl_trailer {
             btl_trailer::write_btl_trailer(&mut btl)?;
         }
 
@@ -117,6 +198,8 @@ pub fn load_firmware_images(
         let loaded = LoadedFirmware {
             btl,
             app,
+            regions,
+            extra_inputs,
             config: config.images[idx].clone(),
         };
 
@@ -131,16 +214,101 @@ pub fn load_firmware_images(
     })
 }
 
-pub fn load_app(config: &mut Config, idx: usize, config_dir: &Path) -> Result<Firmware, Error> {
+/// Load the application file. Besides `app_address`, it may contain the data of app regions
+/// without a file of their own.
+pub fn load_app_file(
+    config: &mut Config,
+    idx: usize,
+    config_dir: &Path,
+) -> Result<HexFileContent, Error> {
     let path = Config::normalize_path(&config.images[idx].app_path, config_dir)?;
     config.images[idx].app_path = path.to_str().unwrap().to_string();
-    let fw = Firmware::load_from_file(
+    let fw_config = &config.images[idx];
+    let format = match fw_config.app_format() {
+        Some(format) => format,
+        None => HexFileFormat::detect(&path)?,
+    };
+    let file = HexFileContent::load(
         &path,
-        &config.images[idx].hex_file_format,
-        &config.images[idx].device_config,
-        &config.images[idx].app_address,
+        &format,
+        &fw_config.device_config,
+        fw_config.app_base_address(),
     )?;
-    let mut fw = configure_header(fw, config, idx)?;
+    let mut ranges = vec![fw_config.app_address.clone()];
+    ranges.extend(
+        fw_config
+            .app_regions
+            .iter()
+            .filter(|x| x.path.is_none())
+            .map(|x| x.address.clone()),
+    );
+    file.check(&path, &ranges, fw_config.address_check)?;
+    Ok(file)
+}
+
+/// Load the app regions, either from their own file or from the application file.
+pub fn load_regions(
+    config: &mut Config,
+    idx: usize,
+    config_dir: &Path,
+    app_file: &HexFileContent,
+) -> Result<Vec<LoadedRegion>, Error> {
+    let mut ret = Vec::new();
+    for k in 0..config.images[idx].app_regions.len() {
+        let fw_config = &config.images[idx];
+        let region = &fw_config.app_regions[k];
+        let device_config = region.device_config(fw_config);
+        let fw = match &region.path {
+            Some(path) => {
+                let path = Config::normalize_path(path, config_dir)?;
+                let format = match region.format {
+                    Some(format) => format,
+                    None => HexFileFormat::detect(&path)?,
+                };
+                let fw = Firmware::load_from_file(
+                    &path,
+                    &format,
+                    &device_config,
+                    &region.address,
+                    region.base_address(),
+                    fw_config.address_check,
+                )?;
+                config.images[idx].app_regions[k].path = Some(path.to_str().unwrap().to_string());
+                fw
+            }
+            None => {
+                Firmware::from_segments(region.address.clone(), device_config, &app_file.segments)?
+            }
+        };
+        ret.push(LoadedRegion {
+            config: config.images[idx].app_regions[k].clone(),
+            fw,
+        });
+    }
+    Ok(ret)
+}
+
+pub fn load_app(
+    config: &mut Config,
+    idx: usize,
+    config_dir: &Path,
+    app_file: HexFileContent,
+    git_description: Option<&GitDescription>,
+    signer: Option<&dyn Signer>,
+) -> Result<Firmware, Error> {
+    let fw = app_file.into_firmware(
+        config.images[idx].app_address.clone(),
+        config.images[idx].device_config.clone(),
+    )?;
+    resolve_security_version(&mut config.images[idx], config_dir)?;
+    let mut fw = configure_header(fw, config, idx, git_description, signer)?;
+    metadata::apply(&mut fw, config, idx, config_dir, git_description)?;
+
+    // The meta-data may extend the image, hence the length is written last.
+    let image_length = fw.image_length();
+    let layout = config.header_layout(&config.images[idx])?;
+    Header::with_layout(&mut fw, config.images[idx].header_offset, layout)?
+        .set_length(image_length as u32);
 
     let crc_off = config.images[idx].crc_offset();
     let crc = crc32(&fw.data[crc_off + 4..fw.image_length()]);
@@ -148,10 +316,8 @@ pub fn load_app(config: &mut Config, idx: usize, config_dir: &Path) -> Result<Fi
 
     match config.images[idx].signature_type {
         SignatureType::Unsigned => {}
-        SignatureType::Ed25519 => {
-            let key_bytes = config.ed25519_private_key.unwrap();
-            crate::ed25519::sign(&mut fw, &key_bytes)?;
-        }
+        _ if config.defer_signing => {}
+        _ => signer::sign(&mut fw, signer.unwrap())?,
     }
 
     Ok(fw)
@@ -161,34 +327,88 @@ pub fn load_btl(config: &mut Config, idx: usize, config_dir: &Path) -> Result<Fi
     let path = Config::normalize_path(&config.images[idx].btl_path, config_dir)?;
     config.images[idx].btl_path = path.to_str().unwrap().to_string();
     let fw_config = &config.images[idx];
+    let format = match fw_config.btl_format() {
+        Some(format) => format,
+        None => HexFileFormat::detect(&path)?,
+    };
     Firmware::load_from_file(
         &path,
-        &fw_config.hex_file_format,
+        &format,
         &fw_config.device_config,
         &fw_config.btl_address,
+        fw_config.btl_base_address(),
+        fw_config.address_check,
     )
 }
 
-fn configure_header(mut fw: Firmware, config: &mut Config, idx: usize) -> Result<Firmware, Error> {
+/// Load the extra inputs, apply their fill value and write their CRC.
+pub fn load_extra_inputs(
+    config: &mut Config,
+    idx: usize,
+    config_dir: &Path,
+) -> Result<Vec<LoadedExtraInput>, Error> {
+    let mut ret = Vec::new();
+    for k in 0..config.images[idx].extra_inputs.len() {
+        let path = Config::normalize_path(&config.images[idx].extra_inputs[k].path, config_dir)?;
+        config.images[idx].extra_inputs[k].path = path.to_str().unwrap().to_string();
+        let fw_config = &config.images[idx];
+        let input = &fw_config.extra_inputs[k];
+        let format = match input.format {
+            Some(format) => format,
+            None => HexFileFormat::detect(&path)?,
+        };
+        let mut fw = Firmware::load_from_file(
+            &path,
+            &format,
+            &fw_config.device_config,
+            &input.address,
+            input.base_address(),
+            fw_config.address_check,
+        )?;
+        if let Some(value) = input.fill {
+            fw.fill(value);
+        }
+        if input.crc {
+            let crc_off = fw.data.len() - 4;
+            let crc = crc32(&fw.data[..crc_off]);
+            fw.write_u32(crc_off, crc);
+        }
+        ret.push(LoadedExtraInput {
+            config: input.clone(),
+            fw,
+        });
+    }
+    Ok(ret)
+}
+
+/// Replace a security version derived from the changelog by its value.
+fn resolve_security_version(fw_config: &mut FwConfig, config_dir: &Path) -> Result<(), Error> {
+    if let Some(SecurityVersion::Changelog { changelog }) = &fw_config.security_version {
+        let path = Config::normalize_path(changelog, config_dir)?;
+        let security_version = extract_security_version_from_changelog_file(&path)?;
+        fw_config.security_version = Some(SecurityVersion::Fixed(security_version));
+    }
+    Ok(())
+}
+
+fn configure_header(
+    mut fw: Firmware,
+    config: &mut Config,
+    idx: usize,
+    git_description: Option<&GitDescription>,
+    signer: Option<&dyn Signer>,
+) -> Result<Firmware, Error> {
     let default_config = Config::default();
     let default_fw_config = FwConfig::default();
 
     // Compute key_id before configure_header so it can be included in the CRC.
     let key_id = match config.images[idx].signature_type {
         SignatureType::Unsigned => None,
-        SignatureType::Ed25519 => {
-            let key_bytes = config.ed25519_private_key.ok_or_else(|| {
-                Error::InvalidConfig(format!(
-                    "Ed25519 signature requires {} to be set",
-                    crate::ed25519::ENV_VAR
-                ))
-            })?;
-            Some(crc32(&crate::ed25519::public_key_bytes(&key_bytes)))
-        }
+        _ => Some(crc32(&signer.unwrap().public_key()?)),
     };
 
-    let image_length = fw.image_length();
-    let mut header = Header::new(&mut fw, config.images[idx].header_offset)?;
+    let layout = config.header_layout(&config.images[idx])?.clone();
+    let mut header = Header::with_layout(&mut fw, config.images[idx].header_offset, &layout)?;
     if config.product_id != default_config.product_id && config.product_id != header.product_id() {
         return Err(Error::InvalidConfig(format!(
             "Product ID in firmware and config does not match: {} vs. {}",
@@ -224,11 +444,33 @@ fn configure_header(mut fw: Firmware, config: &mut Config, idx: usize) -> Result
     } else if header.fw_id() == default_fw_config.node_id {
         header.set_fw_id(fw_id);
     }
-    header.set_length(image_length as u32);
     header.set_timestamp(config.build_time.timestamp() as u64);
     if let Some(k) = key_id {
         header.set_key_id(k);
     }
+    if let Some(security_version) = config.images[idx].fixed_security_version() {
+        if header.security_version().is_none() {
+            return Err(Error::InvalidConfig(format!(
+                "Image {} has a security version, but header layout `{}` has no `{}` field",
+                config.images[idx].designator(),
+                config.images[idx].header_layout,
+                header::SECURITY_VERSION
+            )));
+        }
+        header.set_security_version(security_version);
+    }
+    if layout.has_git_fields() {
+        let desc = git_description.ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "Header layout `{}` contains git fields, which requires a git repository",
+                config.images[idx].header_layout
+            ))
+        })?;
+        header.set_field_bytes(header::GIT_SHA, desc.commit_id.as_bytes());
+        header.write(header::GIT_DIRTY, desc.dirty as u64);
+        header.write(header::GIT_COMMIT_TIME, desc.commit_time as u64);
+        header.write(header::GIT_DISTANCE, desc.distance.unwrap_or(0) as u64);
+    }
 
     Ok(fw)
 }
@@ -264,7 +506,20 @@ pub struct MergedFirmwareImages<'a> {
 pub fn merge_all<'a>(loaded: &'a LoadedFirmwareImages) -> Result<MergedFirmwareImages<'a>, Error> {
     let mut ret = Vec::new();
     for fw in &loaded.images {
-        let merged = Firmware::concatenate(&fw.btl, &fw.app)?;
+        let mut parts = vec![&fw.btl, &fw.app];
+        parts.extend(fw.extra_inputs.iter().map(|x| &x.fw));
+        parts.sort_by_key(|x| x.range.begin);
+        let mut merged = parts[0].clone();
+        for part in &parts[1..] {
+            merged = Firmware::concatenate(&merged, part)?;
+        }
+        // the merged image carries the entry point of the application
+        merged.srecord_header = fw
+            .app
+            .srecord_header
+            .clone()
+            .or_else(|| fw.btl.srecord_header.clone());
+        merged.entry_point = fw.app.entry_point.or(fw.btl.entry_point);
         ret.push((merged, fw));
     }
     Ok(MergedFirmwareImages { images: ret })
@@ -276,10 +531,11 @@ pub fn save_merged_firmware_images(
 ) -> Result<(), Error> {
     for fw in &merged.images {
         let node_id = fw.1.config.node_id;
-        let ext = fw.1.config.hex_file_format.file_extension();
-        let fpath = output_dir.join(format!("merged_f{}.{}", node_id, ext));
-        fw.0.write_to_file(&fpath, &fw.1.config.hex_file_format)
-            .unwrap();
+        for fmt in fw.1.config.output_formats() {
+            let ext = fmt.format.file_extension();
+            let fpath = output_dir.join(format!("merged_f{}.{}", node_id, ext));
+            fw.0.write_to_file(&fpath, &fmt)?;
+        }
         let fpath_bin = output_dir.join(format!("merged_f{}.bin", node_id));
         fw.0.write_binary_to_file(&fpath_bin)?;
     }
@@ -292,43 +548,104 @@ pub fn save_hex_and_bin_images(
 ) -> Result<(), Error> {
     for fw in &loaded.images {
         let node_id = fw.config.node_id;
-        let ext = fw.config.hex_file_format.file_extension();
-        let fmt = &fw.config.hex_file_format;
-        fw.app
-            .write_to_file(&output_dir.join(format!("app_f{}.{}", node_id, ext)), fmt)?;
-        fw.btl
-            .write_to_file(&output_dir.join(format!("btl_f{}.{}", node_id, ext)), fmt)?;
+        for fmt in &fw.config.output_formats() {
+            let ext = fmt.format.file_extension();
+            fw.app
+                .write_to_file(&output_dir.join(format!("app_f{}.{}", node_id, ext)), fmt)?;
+            fw.btl
+                .write_to_file(&output_dir.join(format!("btl_f{}.{}", node_id, ext)), fmt)?;
+        }
         fw.app
             .write_binary_to_file(&output_dir.join(format!("app_f{}.bin", node_id)))?;
         fw.btl
             .write_binary_to_file(&output_dir.join(format!("btl_f{}.bin", node_id)))?;
+        for region in &fw.regions {
+            let name = &region.config.name;
+            for fmt in &fw.config.output_formats() {
+                let ext = fmt.format.file_extension();
+                let fpath = output_dir.join(format!("app_f{}_{}.{}", node_id, name, ext));
+                region.fw.write_to_file(&fpath, fmt)?;
+            }
+            let fpath = output_dir.join(format!("app_f{}_{}.bin", node_id, name));
+            region.fw.write_binary_to_file(&fpath)?;
+        }
     }
     Ok(())
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Info {
-    product_id: u16,
-    product_name: String,
-    images: Vec<FwInfo>,
-    files: Vec<String>,
-    script_file: String,
-    package_file: String,
-    output_dir: String,
+    pub product_id: u32,
+    pub product_name: String,
+    pub images: Vec<FwInfo>,
+    pub files: Vec<String>,
+    pub script_file: String,
+    pub package_file: String,
+    pub output_dir: String,
+}
+
+impl Info {
+    pub fn load_from_file(path: &Path) -> Result<Self, Error> {
+        let data = fs::read_to_string(path)?;
+        serde_json::from_str(&data).map_err(|x| crate::Error::InvalidInfoFile(x.into()))
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
-struct FwInfo {
-    fw_id: u8,
-    version: Version,
-    crc: u32,
-    hex_file_format: HexFileFormat,
-    merged_file: String,
-    app_file: String,
-    btl_file: String,
-    merged_bin_file: String,
-    app_bin_file: String,
-    btl_bin_file: String,
+pub struct FwInfo {
+    pub fw_id: u8,
+    pub version: Version,
+    pub crc: u32,
+    /// Signature type of the image, such that `verify` detects unsigned replacements.
+    #[serde(default = "crate::config::default::signature_type")]
+    pub signature_type: SignatureType,
+    pub hex_file_format: HexFileFormat,
+    pub merged_file: String,
+    pub app_file: String,
+    pub btl_file: String,
+    pub merged_bin_file: String,
+    pub app_bin_file: String,
+    pub btl_bin_file: String,
+    /// Hex files written in the further formats of `output_formats`.
+    #[serde(default = "Default::default", skip_serializing_if = "Vec::is_empty")]
+    pub additional_files: Vec<HexFiles>,
+    #[serde(default = "Default::default", skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<RegionFiles>,
+    #[serde(default = "Default::default", skip_serializing_if = "Vec::is_empty")]
+    pub extra_inputs: Vec<ExtraInputInfo>,
+    /// Anti-rollback security version written into the header.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub security_version: Option<u16>,
+}
+
+/// An extra input contained in the merged files.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ExtraInputInfo {
+    pub name: String,
+    pub address: AddressRange,
+    /// CRC32 stored in the last 4 bytes of `address`, if requested.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub crc: Option<u32>,
+}
+
+/// Files of an app region, named `app_f<node>_<region>`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RegionFiles {
+    pub name: String,
+    pub file: String,
+    pub bin_file: String,
+    /// Hex files written in the further formats of `output_formats`.
+    #[serde(default = "Default::default", skip_serializing_if = "Vec::is_empty")]
+    pub additional_files: Vec<String>,
+}
+
+/// Hex files of an image in a single format.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HexFiles {
+    pub hex_file_format: HexFileFormat,
+    pub merged_file: String,
+    pub app_file: String,
+    pub btl_file: String,
 }
 
 pub fn generate_info(fws: &LoadedFirmwareImages, output_dir: &Path) -> Result<Info, Error> {
@@ -336,7 +653,8 @@ pub fn generate_info(fws: &LoadedFirmwareImages, output_dir: &Path) -> Result<In
     let mut fw_infos = Vec::new();
 
     for fw in &fws.images {
-        let ext = fw.config.hex_file_format.file_extension();
+        let output_formats = fw.config.output_formats();
+        let ext = output_formats[0].format.file_extension();
         let node_id = fw.config.node_id;
         let app_file_name = format!("app_f{}.{}", node_id, ext);
         let btl_file_name = format!("btl_f{}.{}", node_id, ext);
@@ -349,18 +667,54 @@ pub fn generate_info(fws: &LoadedFirmwareImages, output_dir: &Path) -> Result<In
         let fw_info = FwInfo {
             fw_id: node_id,
             version: fw.config.version.clone().unwrap(),
-            crc: fw.app.read_u32(0),
+            crc: fw.load_crc(),
+            signature_type: fw.config.signature_type,
             merged_file: merged_hex_file_name.clone(),
             app_file: app_file_name.clone(),
             btl_file: btl_file_name.clone(),
             merged_bin_file: merged_bin_file_name.clone(),
             app_bin_file: app_bin_file_name.clone(),
             btl_bin_file: btl_bin_file_name.clone(),
-            hex_file_format: fw.config.hex_file_format,
+            hex_file_format: output_formats[0].format,
+            additional_files: output_formats[1..]
+                .iter()
+                .map(|x| x.format)
+                .map(|x| HexFiles {
+                    hex_file_format: x,
+                    merged_file: format!("merged_f{}.{}", node_id, x.file_extension()),
+                    app_file: format!("app_f{}.{}", node_id, x.file_extension()),
+                    btl_file: format!("btl_f{}.{}", node_id, x.file_extension()),
+                })
+                .collect(),
+            regions: fw
+                .regions
+                .iter()
+                .map(|x| {
+                    let name = &x.config.name;
+                    let file_name = |ext: &str| format!("app_f{}_{}.{}", node_id, name, ext);
+                    RegionFiles {
+                        name: name.clone(),
+                        file: file_name(ext),
+                        bin_file: file_name("bin"),
+                        additional_files: output_formats[1..]
+                            .iter()
+                            .map(|x| file_name(x.format.file_extension()))
+                            .collect(),
+                    }
+                })
+                .collect(),
+            extra_inputs: fw
+                .extra_inputs
+                .iter()
+                .map(|x| ExtraInputInfo {
+                    name: x.config.name.clone(),
+                    address: x.config.address.clone(),
+                    crc: x.config.crc.then(|| x.fw.read_u32(x.fw.data.len() - 4)),
+                })
+                .collect(),
+            security_version: fw.config.fixed_security_version(),
         };
 
-        fw_infos.push(fw_info);
-
         // add generated files, for possible archival
         files.push(merged_hex_file_name);
         files.push(app_file_name);
@@ -368,6 +722,18 @@ pub fn generate_info(fws: &LoadedFirmwareImages, output_dir: &Path) -> Result<In
         files.push(merged_bin_file_name);
         files.push(app_bin_file_name);
         files.push(btl_bin_file_name);
+        for hex_files in &fw_info.additional_files {
+            files.push(hex_files.merged_file.clone());
+            files.push(hex_files.app_file.clone());
+            files.push(hex_files.btl_file.clone());
+        }
+        for region in &fw_info.regions {
+            files.push(region.file.clone());
+            files.push(region.bin_file.clone());
+            files.extend(region.additional_files.iter().cloned());
+        }
+
+        fw_infos.push(fw_info);
     }
 
     files.push(fws.script_file_name.clone());
@@ -409,11 +775,8 @@ pub fn add_pre_release_info(
 
 pub fn bundle(info: &Path, output_dir: &Path, versioned: bool) -> Result<(), crate::Error> {
     let info = info.canonicalize()?;
-    let info_data = fs::read_to_string(&info)?;
     let info_dir = info.parent().unwrap_or(Path::new("/"));
-
-    let info: Info =
-        serde_json::from_str(&info_data).map_err(|x| crate::Error::InvalidInfoFile(x.into()))?;
+    let info = Info::load_from_file(&info)?;
 
     let mut new_info: Info = info.clone();
     new_info.files = Vec::new();
@@ -421,9 +784,9 @@ pub fn bundle(info: &Path, output_dir: &Path, versioned: bool) -> Result<(), cra
     create_dir_all(output_dir)?;
 
     for (fw, fw_new) in info.images.iter().zip(new_info.images.iter_mut()) {
-        fw_new.app_file = get_app_file_name(&fw, versioned);
-        fw_new.btl_file = get_btl_file_name(&fw, versioned);
-        fw_new.merged_file = get_merged_file_name(&fw, versioned);
+        fw_new.app_file = get_app_file_name(&fw, fw.hex_file_format, versioned);
+        fw_new.btl_file = get_btl_file_name(&fw, fw.hex_file_format, versioned);
+        fw_new.merged_file = get_merged_file_name(&fw, fw.hex_file_format, versioned);
         fw_new.app_bin_file = get_app_bin_file_name(&fw, versioned);
         fw_new.btl_bin_file = get_btl_bin_file_name(&fw, versioned);
         fw_new.merged_bin_file = get_merged_bin_file_name(&fw, versioned);
@@ -455,6 +818,59 @@ pub fn bundle(info: &Path, output_dir: &Path, versioned: bool) -> Result<(), cra
         new_info.files.push(fw_new.app_bin_file.clone());
         new_info.files.push(fw_new.btl_bin_file.clone());
         new_info.files.push(fw_new.merged_bin_file.clone());
+        for (files, files_new) in fw
+            .additional_files
+            .iter()
+            .zip(fw_new.additional_files.iter_mut())
+        {
+            let format = files.hex_file_format;
+            files_new.app_file = get_app_file_name(fw, format, versioned);
+            files_new.btl_file = get_btl_file_name(fw, format, versioned);
+            files_new.merged_file = get_merged_file_name(fw, format, versioned);
+            copy_and_rename(
+                &info_dir.join(&files.app_file),
+                output_dir,
+                &files_new.app_file,
+            )?;
+            copy_and_rename(
+                &info_dir.join(&files.btl_file),
+                output_dir,
+                &files_new.btl_file,
+            )?;
+            copy_and_rename(
+                &info_dir.join(&files.merged_file),
+                output_dir,
+                &files_new.merged_file,
+            )?;
+            new_info.files.push(files_new.app_file.clone());
+            new_info.files.push(files_new.btl_file.clone());
+            new_info.files.push(files_new.merged_file.clone());
+        }
+        for (region, region_new) in fw.regions.iter().zip(fw_new.regions.iter_mut()) {
+            let renamed = |file: &str| {
+                let ext = Path::new(file)
+                    .extension()
+                    .and_then(|x| x.to_str())
+                    .unwrap_or_default();
+                get_region_file_name(fw, &region.name, ext, versioned)
+            };
+            region_new.file = renamed(&region.file);
+            region_new.bin_file = renamed(&region.bin_file);
+            region_new.additional_files =
+                region.additional_files.iter().map(|x| renamed(x)).collect();
+            let files = vec![
+                (&region.file, &region_new.file),
+                (&region.bin_file, &region_new.bin_file),
+            ];
+            let additional = region
+                .additional_files
+                .iter()
+                .zip(&region_new.additional_files);
+            for (file, file_new) in files.into_iter().chain(additional) {
+                copy_and_rename(&info_dir.join(file), output_dir, file_new)?;
+                new_info.files.push(file_new.to_string());
+            }
+        }
     }
 
     new_info.script_file = get_script_file_name(&info, versioned);
@@ -538,46 +954,50 @@ fn get_merged_bin_file_name(fw: &FwInfo, versioned: bool) -> String {
     }
 }
 
-fn get_app_file_name(fw: &FwInfo, versioned: bool) -> String {
+fn get_app_file_name(fw: &FwInfo, format: HexFileFormat, versioned: bool) -> String {
     if versioned {
         format!(
             "app_f{}_{}.{}",
             fw.fw_id,
             fw.version,
-            fw.hex_file_format.file_extension()
+            format.file_extension()
         )
     } else {
-        format!("app_f{}.{}", fw.fw_id, fw.hex_file_format.file_extension())
+        format!("app_f{}.{}", fw.fw_id, format.file_extension())
     }
 }
 
-fn get_btl_file_name(fw: &FwInfo, versioned: bool) -> String {
+fn get_btl_file_name(fw: &FwInfo, format: HexFileFormat, versioned: bool) -> String {
     if versioned {
         format!(
             "btl_f{}_{}.{}",
             fw.fw_id,
             fw.version,
-            fw.hex_file_format.file_extension()
+            format.file_extension()
         )
     } else {
-        format!("btl_f{}.{}", fw.fw_id, fw.hex_file_format.file_extension())
+        format!("btl_f{}.{}", fw.fw_id, format.file_extension())
     }
 }
 
-fn get_merged_file_name(fw: &FwInfo, versioned: bool) -> String {
+fn get_merged_file_name(fw: &FwInfo, format: HexFileFormat, versioned: bool) -> String {
     if versioned {
         format!(
             "merged_f{}_{}.{}",
             fw.fw_id,
             fw.version,
-            fw.hex_file_format.file_extension()
+            format.file_extension()
         )
     } else {
-        format!(
-            "merged_f{}.{}",
-            fw.fw_id,
-            fw.hex_file_format.file_extension()
-        )
+        format!("merged_f{}.{}", fw.fw_id, format.file_extension())
+    }
+}
+
+fn get_region_file_name(fw: &FwInfo, region: &str, ext: &str, versioned: bool) -> String {
+    if versioned {
+        format!("app_f{}_{}_{}.{}", fw.fw_id, region, fw.version, ext)
+    } else {
+        format!("app_f{}_{}.{}", fw.fw_id, region, ext)
     }
 }
 
@@ -1,15 +1,21 @@
 use semver::Version;
 
-use crate::config::Config;
+use crate::config::{Config, FwConfig};
+use crate::firmware::Firmware;
 use crate::process::LoadedFirmwareImages;
 use crate::script_cmd::Command;
 use crate::Error;
 
+/// First bootloader version whose validation data carries the security version.
+pub const ANTI_ROLLBACK_BTL_VERSION: u8 = 3;
+
 pub trait Protocol {
     fn enter(&self, fw_id: u8, wait_time: u32) -> Vec<Command>;
     fn leave(&self, fw_id: u8, wait_time: u32) -> Vec<Command>;
     fn validate(&self, fw_id: u8, data: &[u8], wait_time: u32) -> Vec<Command>;
-    fn start_transmit(&self, fw_id: u8, erase_time: u32) -> Vec<Command>;
+    /// Start the transmission of the application (`address_space` 0) or of an app region
+    /// (`address_space` k for the k-th region).
+    fn start_transmit(&self, fw_id: u8, address_space: u8, erase_time: u32) -> Vec<Command>;
     fn send_data(&self, fw_id: u8, address: u64, data: &[u8]) -> Option<Command>;
     fn finish(&self, fw_id: u8, send_done: u32, crc_check: u32) -> Vec<Command>;
 }
@@ -34,6 +40,29 @@ fn make_header(config: &Config) -> Command {
     Command::Header(header)
 }
 
+/// Send the data of `fw`, with offsets relative to its start. Chunks containing only 0xFF are
+/// skipped.
+fn send_data<P: Protocol>(
+    protocol: &P,
+    fw_config: &FwConfig,
+    fw: &Firmware,
+    ret: &mut Vec<Command>,
+) -> Result<(), Error> {
+    if fw.data.len() % fw_config.write_data_size != 0 {
+        return Err(Error::InvalidConfig(
+            "The length of the firmware image must be a multiple of the data write size."
+                .to_string(),
+        ));
+    }
+    for k in (0..fw.data.len()).step_by(fw_config.write_data_size) {
+        let data = &fw.data[k..k + fw_config.write_data_size];
+        if let Some(cmd) = protocol.send_data(fw_config.node_id, k as u64, data) {
+            ret.push(cmd);
+        }
+    }
+    Ok(())
+}
+
 pub fn generate_script<P: Protocol>(
     protocol: &P,
     fws: &LoadedFirmwareImages,
@@ -46,12 +75,6 @@ pub fn generate_script<P: Protocol>(
         let fw = &loaded_fw.app;
         let fw_config = &loaded_fw.config;
 
-        if fw.data.len() % fw_config.write_data_size != 0 {
-            return Err(Error::InvalidConfig(
-                "The length of the firmware image must be a multiple of the data write size."
-                    .to_string(),
-            ));
-        }
         let id = fw_config.node_id;
         if !fw_config.include_in_script {
             ret.push(Command::Log(format!(
@@ -82,23 +105,41 @@ pub fn generate_script<P: Protocol>(
         validation_data[2] = major_version as u8 & 0xFF;
         validation_data[3] = ((major_version >> 8) & 0xFF) as u8;
         validation_data[4] = config.btl_version;
+        let mut validation_data = validation_data.to_vec();
+        if config.btl_version >= ANTI_ROLLBACK_BTL_VERSION {
+            let security_version = fw_config.fixed_security_version().unwrap_or(0);
+            validation_data.extend_from_slice(&security_version.to_le_bytes());
+        }
         ret.push(Command::Log("Validating firmware...".to_string()));
         ret.extend(protocol.validate(id, &validation_data, config.time_state_transition));
         ret.push(Command::SetErrorMessage("failed".to_string()));
         ret.push(Command::Log("done".to_string()));
+
+        // regions go first, such that the application is only accepted once they are written
+        for (k, region) in loaded_fw.regions.iter().enumerate() {
+            if !region.config.include_in_script {
+                continue;
+            }
+            ret.push(Command::Log(format!("Erasing {}...", region.config.name)));
+            ret.extend(protocol.start_transmit(id, k as u8 + 1, fw_config.timings.erase_time));
+            ret.push(Command::Log("done".to_string()));
+
+            ret.push(Command::SetTimeOut(fw_config.timings.data_send));
+            ret.push(Command::Log(format!(
+                "Programming {}...",
+                region.config.name
+            )));
+            send_data(protocol, fw_config, &region.fw, &mut ret)?;
+            ret.push(Command::Log("done".to_string()));
+        }
+
         ret.push(Command::Log("Erasing...".to_string()));
-        ret.extend(protocol.start_transmit(id, fw_config.timings.erase_time));
+        ret.extend(protocol.start_transmit(id, 0, fw_config.timings.erase_time));
         ret.push(Command::Log("done".to_string()));
 
         ret.push(Command::SetTimeOut(fw_config.timings.data_send));
         ret.push(Command::Log("Programming...".to_string()));
-        assert_eq!(fw.data.len() % fw_config.write_data_size, 0);
-        for k in (0..fw.data.len()).step_by(fw_config.write_data_size) {
-            let cmd = protocol.send_data(id, k as u64, &fw.data[k..k + fw_config.write_data_size]);
-            if let Some(cmd) = cmd {
-                ret.push(cmd);
-            }
-        }
+        send_data(protocol, fw_config, fw, &mut ret)?;
         ret.push(Command::Log("done".to_string()));
 
         ret.push(Command::Log("Checking Signature...".to_string()));
@@ -135,7 +135,11 @@ impl Script {
             let cmd = Command::parse_line(&line)?;
             cmds.push(cmd);
         }
-        Ok(Script::new(cmds))
+        // progress commands are already part of a serialized script
+        Ok(Self {
+            commands: cmds,
+            time_model: Box::new(SimpleTimeModel::new(0.01, 0.01)),
+        })
     }
 
     pub fn verify(&self) -> Result<(), ParseError> {
@@ -147,7 +151,7 @@ impl Script {
             Command::Checksum(x) => x,
             _ => return Err(ParseError::MissingChecksum),
         };
-        let ref_chksum = Command::compute_checksum(&self.commands);
+        let ref_chksum = Command::compute_checksum(&self.commands[..self.commands.len() - 1]);
         if script_chksum == &ref_chksum {
             Ok(())
         } else {
@@ -240,4 +244,22 @@ mod tests {
         }
         assert_eq!(splits.next(), None);
     }
+
+    #[test]
+    fn check_parse_and_verify() {
+        let cmds = vec![
+            Command::Header(vec![("foo".to_string(), "bar".to_string())]),
+            Command::Write(vec![0xab, 0xcd, 0xef]),
+            Command::Query(vec![0xab, 0xcd, 0xef], vec![0x12, 0x34]),
+        ];
+        let script = Script::new(cmds);
+        let serialized = script.serialize();
+
+        let parsed = Script::parse(&serialized).unwrap();
+        parsed.verify().unwrap();
+        assert_eq!(parsed.commands().len(), script.commands().len() + 1);
+
+        let tampered = Script::parse(&serialized.replace(":02ABCDEF", ":02ABCDEE")).unwrap();
+        assert_matches!(tampered.verify(), Err(ParseError::InvalidChecksum));
+    }
 }
@@ -0,0 +1,182 @@
+//! Executes a bootload [`Script`] against a [`Transport`].
+//!
+//! The executor implements the execution state machine described in
+//! `doc/script_file_format.md` and behaves like the reference implementation
+//! in `examples/script.py`.
+
+use crate::script::Script;
+use crate::script_cmd::Command;
+use thiserror::Error;
+
+/// The communication channel to the device(s) being bootloaded.
+pub trait Transport {
+    /// Send a request without waiting for a response.
+    fn write(&mut self, data: &[u8]) -> crate::Result<()>;
+
+    /// Send a request and return the response of the device.
+    fn query(&mut self, data: &[u8]) -> crate::Result<Vec<u8>>;
+
+    /// Wait for the given time in milliseconds.
+    fn sleep(&mut self, ms: u32);
+}
+
+/// Receives the log messages and progress reports of a running script.
+pub trait Listener {
+    fn log(&mut self, msg: &str) {
+        log::info!("{}", msg);
+    }
+
+    /// Progress is reported from 0 to 255.
+    fn progress(&mut self, _progress: u8) {}
+}
+
+/// A [`Listener`] which forwards log messages to the `log` crate and ignores progress reports.
+pub struct LogListener;
+
+impl Listener for LogListener {}
+
+#[derive(Debug, Error)]
+pub enum ExecutionError {
+    #[error("{message}: communication failed: {source}")]
+    Transport {
+        message: String,
+        source: crate::Error,
+    },
+    #[error("{message}: expected `{}` but got `{}`", hex::encode_upper(.expected), hex::encode_upper(.actual))]
+    UnexpectedResponse {
+        message: String,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+}
+
+/// Run all commands of `script` in order.
+///
+/// Execution is aborted on the first failing `Write` or `Query`. The returned error carries
+/// the error message which was active at that point in time.
+pub fn execute(
+    script: &Script,
+    transport: &mut dyn Transport,
+    listener: &mut dyn Listener,
+) -> Result<(), ExecutionError> {
+    let mut timeout = 0_u32;
+    let mut error_message = String::new();
+    for cmd in script.commands() {
+        match cmd {
+            Command::Write(data) => {
+                transport
+                    .write(data)
+                    .map_err(|source| ExecutionError::Transport {
+                        message: error_message.clone(),
+                        source,
+                    })?;
+                if timeout != 0 {
+                    transport.sleep(timeout);
+                }
+            }
+            Command::Query(write, read) => {
+                let response =
+                    transport
+                        .query(write)
+                        .map_err(|source| ExecutionError::Transport {
+                            message: error_message.clone(),
+                            source,
+                        })?;
+                if &response != read {
+                    return Err(ExecutionError::UnexpectedResponse {
+                        message: error_message,
+                        expected: read.clone(),
+                        actual: response,
+                    });
+                }
+                if timeout != 0 {
+                    transport.sleep(timeout);
+                }
+            }
+            Command::SetTimeOut(x) => timeout = *x,
+            Command::SetErrorMessage(x) => error_message = x.clone(),
+            Command::Log(x) => listener.log(x),
+            Command::Progress(x) => listener.progress(*x),
+            Command::Header(_) | Command::Checksum(_) => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    #[derive(Default)]
+    struct Recorder {
+        written: Vec<Vec<u8>>,
+        responses: Vec<Vec<u8>>,
+        sleeps: Vec<u32>,
+        logs: Vec<String>,
+        progress: Vec<u8>,
+    }
+
+    impl Transport for Recorder {
+        fn write(&mut self, data: &[u8]) -> crate::Result<()> {
+            self.written.push(data.to_vec());
+            Ok(())
+        }
+
+        fn query(&mut self, data: &[u8]) -> crate::Result<Vec<u8>> {
+            self.written.push(data.to_vec());
+            Ok(self.responses.remove(0))
+        }
+
+        fn sleep(&mut self, ms: u32) {
+            self.sleeps.push(ms);
+        }
+    }
+
+    impl Listener for Recorder {
+        fn log(&mut self, msg: &str) {
+            self.logs.push(msg.to_string());
+        }
+
+        fn progress(&mut self, progress: u8) {
+            self.progress.push(progress);
+        }
+    }
+
+    #[test]
+    fn timeouts_are_applied_after_write_and_query() {
+        let script = Script::new(vec![
+            Command::Write(vec![1]),
+            Command::SetTimeOut(10),
+            Command::Write(vec![2]),
+            Command::Query(vec![3], vec![4]),
+            Command::Log("done".to_string()),
+        ]);
+        let mut transport = Recorder::default();
+        transport.responses.push(vec![4]);
+        let mut listener = Recorder::default();
+        execute(&script, &mut transport, &mut listener).unwrap();
+        assert_eq!(transport.written, vec![vec![1], vec![2], vec![3]]);
+        assert_eq!(transport.sleeps, vec![10, 10]);
+        assert_eq!(listener.logs, vec!["done".to_string()]);
+        assert_eq!(listener.progress.last(), Some(&255));
+    }
+
+    #[test]
+    fn unexpected_response_aborts_with_error_message() {
+        let script = Script::new(vec![
+            Command::SetErrorMessage("failed".to_string()),
+            Command::Query(vec![1], vec![2]),
+            Command::Write(vec![3]),
+        ]);
+        let mut transport = Recorder::default();
+        transport.responses.push(vec![5]);
+        let ret = execute(&script, &mut transport, &mut LogListener);
+        assert_matches!(ret, Err(ExecutionError::UnexpectedResponse { message, expected, actual }) => {
+            assert_eq!(message, "failed");
+            assert_eq!(expected, vec![2]);
+            assert_eq!(actual, vec![5]);
+        });
+        assert_eq!(transport.written.len(), 1);
+    }
+}
@@ -0,0 +1,320 @@
+//! Sparse memory images.
+//!
+//! Hex and S-Record files only describe the bytes they contain. A [`SegmentMap`] keeps track of
+//! these bytes, such that erased flash can be distinguished from bytes explicitly written as 0xFF.
+
+use std::collections::BTreeMap;
+
+use crate::config::AddressRange;
+
+/// Contiguous runs of bytes, indexed by their start address.
+///
+/// Segments never overlap or touch each other, adjacent data is merged into one segment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SegmentMap {
+    segments: BTreeMap<u64, Vec<u8>>,
+}
+
+impl SegmentMap {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Segments of `data` located at `address`, leaving out runs of `erased` bytes.
+    pub fn from_dense(address: u64, data: &[u8], erased: u8) -> Self {
+        let mut ret = Self::new();
+        let mut k = 0;
+        while k < data.len() {
+            if data[k] == erased {
+                k += 1;
+                continue;
+            }
+            let begin = k;
+            while k < data.len() && data[k] != erased {
+                k += 1;
+            }
+            ret.insert(address + begin as u64, &data[begin..k]);
+        }
+        ret
+    }
+
+    /// Write `data` to `address`. Previously written bytes at the same addresses are replaced.
+    pub fn insert(&mut self, address: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        if self.extend(address, data) {
+            return;
+        }
+        let mut begin = address;
+        let mut end = address + data.len() as u64;
+        let touched: Vec<_> = self
+            .segments
+            .range(..=end)
+            .rev()
+            .take_while(|(k, v)| *k + v.len() as u64 >= address)
+            .map(|(k, _)| *k)
+            .collect();
+        let touched: Vec<_> = touched
+            .into_iter()
+            .map(|k| (k, self.segments.remove(&k).unwrap()))
+            .collect();
+        for (k, v) in &touched {
+            begin = begin.min(*k);
+            end = end.max(*k + v.len() as u64);
+        }
+        let mut merged = vec![0; (end - begin) as usize];
+        for (k, v) in &touched {
+            let offset = (k - begin) as usize;
+            merged[offset..offset + v.len()].copy_from_slice(v);
+        }
+        let offset = (address - begin) as usize;
+        merged[offset..offset + data.len()].copy_from_slice(data);
+        self.segments.insert(begin, merged);
+    }
+
+    /// Write `data` in place if it only touches the segment starting at or before `address`, as
+    /// is the case for records in ascending order. Returns whether the data has been written.
+    fn extend(&mut self, address: u64, data: &[u8]) -> bool {
+        let end = address + data.len() as u64;
+        if self.segments.range(address + 1..=end).next().is_some() {
+            return false;
+        }
+        let (k, v) = match self.segments.range_mut(..=address).next_back() {
+            Some(x) => x,
+            None => return false,
+        };
+        if k + (v.len() as u64) < address {
+            return false;
+        }
+        let offset = (address - k) as usize;
+        let overlap = (v.len() - offset).min(data.len());
+        v[offset..offset + overlap].copy_from_slice(&data[..overlap]);
+        v.extend_from_slice(&data[overlap..]);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Number of segments.
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// The segments in ascending order of their address.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.segments.iter().map(|(k, v)| (*k, v.as_slice()))
+    }
+
+    /// The address ranges covered by the segments.
+    pub fn coverage(&self) -> Vec<AddressRange> {
+        self.iter()
+            .map(|(k, v)| AddressRange::new(k, k + v.len() as u64))
+            .collect()
+    }
+
+    /// Whether all bytes of `range` have been written.
+    pub fn covers(&self, range: &AddressRange) -> bool {
+        if range.len() == 0 {
+            return true;
+        }
+        match self.segments.range(..=range.begin).next_back() {
+            Some((k, v)) => k + v.len() as u64 >= range.end,
+            None => false,
+        }
+    }
+
+    /// The range from the first to the last written byte.
+    pub fn range(&self) -> Option<AddressRange> {
+        let (first, _) = self.segments.iter().next()?;
+        let (last, data) = self.segments.iter().next_back()?;
+        Some(AddressRange::new(*first, last + data.len() as u64))
+    }
+
+    /// Address of the last written byte.
+    pub fn last_address(&self) -> Option<u64> {
+        self.range().map(|x| x.end - 1)
+    }
+
+    /// The part of the segments which lies within `range`.
+    pub fn restrict(&self, range: &AddressRange) -> SegmentMap {
+        let mut ret = SegmentMap::new();
+        for (k, v) in self.iter() {
+            let begin = k.max(range.begin);
+            let end = (k + v.len() as u64).min(range.end);
+            if begin < end {
+                ret.insert(begin, &v[(begin - k) as usize..(end - k) as usize]);
+            }
+        }
+        ret
+    }
+
+    /// The spans outside of `range` which contain data.
+    pub fn outside(&self, range: &AddressRange) -> Vec<AddressRange> {
+        self.outside_all(std::slice::from_ref(range))
+    }
+
+    /// The spans outside of all `ranges` which contain data.
+    pub fn outside_all(&self, ranges: &[AddressRange]) -> Vec<AddressRange> {
+        let mut ret = self.coverage();
+        for range in ranges {
+            let mut remaining = Vec::new();
+            for x in ret {
+                if x.begin < range.begin {
+                    remaining.push(AddressRange::new(x.begin, x.end.min(range.begin)));
+                }
+                if x.end > range.end {
+                    remaining.push(AddressRange::new(x.begin.max(range.end), x.end));
+                }
+            }
+            ret = remaining;
+        }
+        ret
+    }
+
+    /// The spans where writing `data` to `address` would change previously written bytes.
+    pub fn conflicts(&self, address: u64, data: &[u8]) -> Vec<AddressRange> {
+        let end = address + data.len() as u64;
+        let mut ret: Vec<AddressRange> = Vec::new();
+        let overlapping = self
+            .segments
+            .range(..end)
+            .rev()
+            .take_while(|(k, v)| *k + v.len() as u64 > address);
+        for (k, v) in overlapping {
+            let begin = (*k).max(address);
+            let stop = (k + v.len() as u64).min(end);
+            for x in begin..stop {
+                if v[(x - k) as usize] == data[(x - address) as usize] {
+                    continue;
+                }
+                match ret.last_mut() {
+                    Some(last) if last.end == x => last.end = x + 1,
+                    _ => ret.push(AddressRange::new(x, x + 1)),
+                }
+            }
+        }
+        ret.sort_by_key(|x| x.begin);
+        ret
+    }
+
+    /// Join segments separated by at most `max_gap` bytes, filling the gap with `value`.
+    pub fn fill_gaps(&mut self, max_gap: u64, value: u8) {
+        let coverage = self.coverage();
+        for pair in coverage.windows(2) {
+            let gap = pair[1].begin - pair[0].end;
+            if gap <= max_gap {
+                self.insert(pair[0].end, &vec![value; gap as usize]);
+            }
+        }
+    }
+
+    /// Flatten the segments within `range` into a buffer, filling unwritten bytes with `fill`.
+    pub fn to_dense(&self, range: &AddressRange, fill: u8) -> Vec<u8> {
+        let mut ret = vec![fill; range.len() as usize];
+        for (k, v) in self.iter() {
+            let begin = k.max(range.begin);
+            let end = (k + v.len() as u64).min(range.end);
+            if begin < end {
+                let offset = (begin - range.begin) as usize;
+                ret[offset..offset + (end - begin) as usize]
+                    .copy_from_slice(&v[(begin - k) as usize..(end - k) as usize]);
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_merges_adjacent_and_overlapping_data() {
+        let mut map = SegmentMap::new();
+        map.insert(0x10, &[1, 2]);
+        map.insert(0x20, &[5]);
+        map.insert(0x12, &[3, 4]);
+        assert_eq!(map.len(), 2);
+        map.insert(0x0F, &[0, 9]);
+        let segments: Vec<_> = map.iter().collect();
+        assert_eq!(
+            segments,
+            vec![(0x0F, &[0, 9, 2, 3, 4][..]), (0x20, &[5][..])]
+        );
+        assert_eq!(map.range(), Some(AddressRange::new(0x0F, 0x21)));
+        assert_eq!(map.last_address(), Some(0x20));
+    }
+
+    #[test]
+    fn explicit_0xff_is_covered() {
+        let mut map = SegmentMap::new();
+        map.insert(0x100, &[0x12, 0xFF, 0xFF]);
+        assert!(map.covers(&AddressRange::new(0x100, 0x103)));
+        assert!(!map.covers(&AddressRange::new(0x100, 0x104)));
+        assert!(!map.covers(&AddressRange::new(0xFF, 0x101)));
+        assert_eq!(map.last_address(), Some(0x102));
+
+        let dense = SegmentMap::from_dense(0x100, &[0x12, 0xFF, 0xFF], 0xFF);
+        assert_eq!(dense.last_address(), Some(0x100));
+    }
+
+    #[test]
+    fn outside_and_conflicting_spans() {
+        let mut map = SegmentMap::new();
+        map.insert(0x10, &[1, 2, 3, 4]);
+        map.insert(0x20, &[5, 6]);
+        assert_eq!(
+            map.outside(&AddressRange::new(0x12, 0x21)),
+            vec![AddressRange::new(0x10, 0x12), AddressRange::new(0x21, 0x22)]
+        );
+        assert!(map.outside(&AddressRange::new(0x10, 0x22)).is_empty());
+        assert_eq!(
+            map.outside_all(&[AddressRange::new(0x11, 0x13), AddressRange::new(0x20, 0x30)]),
+            vec![AddressRange::new(0x10, 0x11), AddressRange::new(0x13, 0x14)]
+        );
+
+        assert!(map.conflicts(0x11, &[2, 3]).is_empty());
+        assert!(map.conflicts(0x14, &[9, 9]).is_empty());
+        assert_eq!(
+            map.conflicts(0x11, &[2, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 6]),
+            vec![AddressRange::new(0x12, 0x14), AddressRange::new(0x20, 0x21)]
+        );
+    }
+
+    #[test]
+    fn fill_gaps_and_flatten() {
+        let mut map = SegmentMap::new();
+        map.insert(0, &[1]);
+        map.insert(3, &[2]);
+        map.insert(10, &[3]);
+        let range = AddressRange::new(2, 12);
+        assert_eq!(
+            map.to_dense(&range, 0xFF),
+            vec![0xFF, 2, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 3, 0xFF]
+        );
+        assert_eq!(map.restrict(&range).range(), Some(AddressRange::new(3, 11)));
+
+        map.fill_gaps(2, 0);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.iter().next(), Some((0, &[1, 0, 0, 2][..])));
+        map.fill_gaps(u64::MAX, 0xFF);
+        assert_eq!(map.coverage(), vec![AddressRange::new(0, 11)]);
+    }
+
+    #[test]
+    fn insert_in_place() {
+        let mut map = SegmentMap::new();
+        map.insert(0x10, &[1, 2]);
+        map.insert(0x12, &[3, 4]);
+        map.insert(0x11, &[5, 6, 7, 8]);
+        map.insert(0x16, &[9]);
+        assert_eq!(map.iter().next(), Some((0x10, &[1, 5, 6, 7, 8][..])));
+        // data reaching the next segment joins both
+        map.insert(0x15, &[0, 9]);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.iter().next(), Some((0x10, &[1, 5, 6, 7, 8, 0, 9][..])));
+    }
+}
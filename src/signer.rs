@@ -0,0 +1,600 @@
+//! Backends creating the signatures of application images.
+//!
+//! All backends sign the digest of the image as computed by [`image_digest`], i.e. the
+//! SHA-512 digest for Ed25519 and the SHA-256 digest for ECDSA P-256. Besides a key given
+//! by environment variables, the signature may be created by an external command, e.g. a
+//! wrapper around an HSM, or with a key of a keystore file.
+
+use std::convert::TryInto;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use argon2::Argon2;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, FwConfig, SignatureType};
+use crate::ecdsa_p256;
+use crate::ed25519;
+use crate::firmware::Firmware;
+use crate::Error;
+
+/// Selects the signer used for signed images.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum SignerConfig {
+    /// The private key given by `MERGE_TOOL_ED25519_PRIVATE_KEY(_FILE)` or
+    /// `MERGE_TOOL_ECDSA_P256_PRIVATE_KEY(_FILE)`, depending on the signature type.
+    #[default]
+    Env,
+    /// An external command, which reads the hex encoded digest from stdin and writes the
+    /// hex encoded signature to stdout.
+    Command {
+        /// Program and arguments. A program given as relative path is resolved relative to
+        /// the config file.
+        command: Vec<String>,
+        /// Hex encoded public key of the key used by the command. Its length selects
+        /// the signature type.
+        public_key: String,
+    },
+    /// Only the public key is known, e.g. if the digests are signed separately with
+    /// `generate --emit-digests`. Signing fails.
+    PublicKey {
+        /// Hex encoded public key.
+        public_key: String,
+    },
+    /// A key of a keystore file, selected by its label.
+    Keystore {
+        /// Path of the keystore, relative to the config file.
+        path: String,
+        label: String,
+    },
+}
+
+pub trait Signer {
+    fn signature_type(&self) -> SignatureType;
+
+    /// The public key matching the signatures, used to derive the key ID.
+    fn public_key(&self) -> Result<Vec<u8>, Error>;
+
+    /// Sign the digest of an image, see [`image_digest`].
+    fn sign_digest(&self, digest: &[u8]) -> Result<[u8; 64], Error>;
+}
+
+/// Signs with a private key held in memory.
+pub struct KeySigner {
+    signature_type: SignatureType,
+    private_key: [u8; 32],
+}
+
+impl KeySigner {
+    pub fn new(signature_type: SignatureType, private_key: [u8; 32]) -> Self {
+        KeySigner {
+            signature_type,
+            private_key,
+        }
+    }
+}
+
+impl Signer for KeySigner {
+    fn signature_type(&self) -> SignatureType {
+        self.signature_type
+    }
+
+    fn public_key(&self) -> Result<Vec<u8>, Error> {
+        match self.signature_type {
+            SignatureType::Unsigned => Err(Error::InvalidPrivateKey),
+            SignatureType::Ed25519 => Ok(ed25519::public_key_bytes(&self.private_key).to_vec()),
+            SignatureType::EcdsaP256 => {
+                Ok(ecdsa_p256::public_key_bytes(&self.private_key)?.to_vec())
+            }
+        }
+    }
+
+    fn sign_digest(&self, digest: &[u8]) -> Result<[u8; 64], Error> {
+        let invalid_digest =
+            || Error::SignerFailed(format!("invalid digest length {}", digest.len()));
+        match self.signature_type {
+            SignatureType::Unsigned => Err(Error::InvalidPrivateKey),
+            SignatureType::Ed25519 => Ok(ed25519::sign_digest(
+                digest.try_into().map_err(|_| invalid_digest())?,
+                &self.private_key,
+            )),
+            SignatureType::EcdsaP256 => ecdsa_p256::sign_digest(
+                digest.try_into().map_err(|_| invalid_digest())?,
+                &self.private_key,
+            ),
+        }
+    }
+}
+
+/// Signs by running an external command.
+pub struct CommandSigner {
+    program: PathBuf,
+    args: Vec<String>,
+    public_key: Vec<u8>,
+}
+
+impl CommandSigner {
+    pub fn new(command: &[String], public_key: Vec<u8>, config_dir: &Path) -> Result<Self, Error> {
+        let (program, args) = command.split_first().ok_or_else(|| {
+            Error::InvalidConfig("The command of the signer must not be empty".to_string())
+        })?;
+        let mut program = PathBuf::from(program);
+        if program.components().count() > 1 {
+            program = Config::normalize_path(&program.to_string_lossy(), config_dir)?;
+        }
+        Ok(CommandSigner {
+            program,
+            args: args.to_vec(),
+            public_key,
+        })
+    }
+}
+
+impl Signer for CommandSigner {
+    fn signature_type(&self) -> SignatureType {
+        SignatureType::of_public_key(&self.public_key).unwrap()
+    }
+
+    fn public_key(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.public_key.clone())
+    }
+
+    fn sign_digest(&self, digest: &[u8]) -> Result<[u8; 64], Error> {
+        let failed =
+            |reason: String| Error::SignerFailed(format!("{}: {}", self.program.display(), reason));
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| failed(err.to_string()))?;
+        let mut stdin = child.stdin.take().unwrap();
+        writeln!(stdin, "{}", hex::encode(digest)).map_err(|err| failed(err.to_string()))?;
+        drop(stdin);
+        let output = child
+            .wait_with_output()
+            .map_err(|err| failed(err.to_string()))?;
+        if !output.status.success() {
+            return Err(failed(output.status.to_string()));
+        }
+        let signature = String::from_utf8_lossy(&output.stdout);
+        hex::decode(signature.trim())
+            .ok()
+            .and_then(|x| x.try_into().ok())
+            .ok_or_else(|| failed("output is not a hex encoded 64 byte signature".to_string()))
+    }
+}
+
+/// Knows the public key only and refuses to sign.
+pub struct PublicKeySigner {
+    public_key: Vec<u8>,
+}
+
+impl PublicKeySigner {
+    pub fn new(public_key: Vec<u8>) -> Self {
+        PublicKeySigner { public_key }
+    }
+}
+
+impl Signer for PublicKeySigner {
+    fn signature_type(&self) -> SignatureType {
+        SignatureType::of_public_key(&self.public_key).unwrap()
+    }
+
+    fn public_key(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.public_key.clone())
+    }
+
+    fn sign_digest(&self, _digest: &[u8]) -> Result<[u8; 64], Error> {
+        Err(Error::SignerFailed(
+            "only the public key is known, sign the digests emitted by `generate --emit-digests`"
+                .to_string(),
+        ))
+    }
+}
+
+/// Passphrase of the encrypted keys of a keystore.
+pub const KEYSTORE_PASSPHRASE_VAR: &str = "MERGE_TOOL_KEYSTORE_PASSPHRASE";
+
+/// A file holding labelled private keys, e.g.
+/// `{ "keys": [{ "label": "release", "encrypted_private_key": { ... } }] }`.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct Keystore {
+    pub keys: Vec<KeystoreEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct KeystoreEntry {
+    pub label: String,
+    /// Hex encoded 32-byte private key seed or scalar, stored in plain text. For development
+    /// only, as it exposes the key like `MERGE_TOOL_ED25519_PRIVATE_KEY_FILE`.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>,
+    /// The private key, encrypted with the passphrase of `MERGE_TOOL_KEYSTORE_PASSPHRASE`.
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub encrypted_private_key: Option<EncryptedKey>,
+    #[serde(default = "default_signature_type")]
+    pub signature_type: SignatureType,
+}
+
+fn default_signature_type() -> SignatureType {
+    SignatureType::Ed25519
+}
+
+/// A private key encrypted with AES-256-GCM. The AES key is derived from a passphrase with
+/// Argon2id using its default parameters.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct EncryptedKey {
+    /// Hex encoded 16-byte Argon2id salt.
+    pub salt: String,
+    /// Hex encoded 12-byte AES-GCM nonce.
+    pub nonce: String,
+    /// Hex encoded encrypted private key followed by the authentication tag.
+    pub ciphertext: String,
+}
+
+impl EncryptedKey {
+    pub fn encrypt(private_key: &[u8; 32], passphrase: &str) -> Result<Self, Error> {
+        let mut salt = [0_u8; 16];
+        let mut nonce = [0_u8; 12];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = key_cipher(passphrase, &salt)?
+            .encrypt(Nonce::from_slice(&nonce), &private_key[..])
+            .map_err(|_| Error::InvalidPrivateKey)?;
+        Ok(EncryptedKey {
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    /// Decrypt the private key, failing if the passphrase is wrong or the key was modified.
+    pub fn decrypt(&self, passphrase: &str) -> Result<[u8; 32], Error> {
+        let decode = |x: &str| hex::decode(x).map_err(|_| Error::InvalidPrivateKey);
+        let nonce = decode(&self.nonce)?;
+        if nonce.len() != 12 {
+            return Err(Error::InvalidPrivateKey);
+        }
+        let private_key = key_cipher(passphrase, &decode(&self.salt)?)?
+            .decrypt(Nonce::from_slice(&nonce), &decode(&self.ciphertext)?[..])
+            .map_err(|_| Error::InvalidPrivateKey)?;
+        private_key
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::InvalidPrivateKey)
+    }
+}
+
+fn key_cipher(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm, Error> {
+    let mut key = [0_u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| Error::InvalidPrivateKey)?;
+    Ok(Aes256Gcm::new(&key.into()))
+}
+
+impl KeystoreEntry {
+    /// An entry holding `private_key` encrypted with `passphrase`.
+    pub fn encrypted(
+        label: &str,
+        signature_type: SignatureType,
+        private_key: &[u8; 32],
+        passphrase: &str,
+    ) -> Result<Self, Error> {
+        Ok(KeystoreEntry {
+            label: label.to_string(),
+            private_key: None,
+            encrypted_private_key: Some(EncryptedKey::encrypt(private_key, passphrase)?),
+            signature_type,
+        })
+    }
+}
+
+impl Keystore {
+    pub fn load_from_file(path: &Path) -> Result<Keystore, Error> {
+        let data = std::fs::read_to_string(path)?;
+        serde_json::from_str(&data).map_err(Error::CannotParseConfig)
+    }
+
+    /// Load the keystore, or create an empty one if the file does not exist.
+    pub fn load_or_default(path: &Path) -> Result<Keystore, Error> {
+        if path.exists() {
+            Self::load_from_file(path)
+        } else {
+            Ok(Keystore::default())
+        }
+    }
+
+    /// Save the keystore readable by the owner only. The file is written to a temporary
+    /// file first and renamed, such that an interrupted write keeps the previous keys.
+    pub fn save_to_file(&self, path: &Path) -> Result<(), Error> {
+        let data = serde_json::to_string_pretty(self).unwrap();
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        // a stale temporary file may have other permissions
+        let _ = std::fs::remove_file(&tmp_path);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let result = options
+            .open(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(data.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&tmp_path, path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        Ok(result?)
+    }
+
+    /// Add a key, failing if its label is already in use.
+    pub fn add(&mut self, entry: KeystoreEntry) -> Result<(), Error> {
+        if self.keys.iter().any(|x| x.label == entry.label) {
+            return Err(Error::InvalidConfig(format!(
+                "Label `{}` is used twice",
+                entry.label
+            )));
+        }
+        self.keys.push(entry);
+        Ok(())
+    }
+
+    /// The signer for the key with the given label. Encrypted keys are decrypted with the
+    /// passphrase of `MERGE_TOOL_KEYSTORE_PASSPHRASE`.
+    pub fn signer(&self, label: &str) -> Result<KeySigner, Error> {
+        let passphrase = std::env::var(KEYSTORE_PASSPHRASE_VAR).ok();
+        self.signer_with_passphrase(label, passphrase.as_deref())
+    }
+
+    pub fn signer_with_passphrase(
+        &self,
+        label: &str,
+        passphrase: Option<&str>,
+    ) -> Result<KeySigner, Error> {
+        let entry =
+            self.keys.iter().find(|x| x.label == label).ok_or_else(|| {
+                Error::InvalidConfig(format!("No key `{}` in the keystore", label))
+            })?;
+        let private_key = match (&entry.encrypted_private_key, &entry.private_key) {
+            (Some(encrypted), _) => {
+                let passphrase = passphrase.ok_or_else(|| {
+                    Error::InvalidConfig(format!(
+                        "Key `{}` is encrypted, but {} is not set",
+                        label, KEYSTORE_PASSPHRASE_VAR
+                    ))
+                })?;
+                let private_key = encrypted.decrypt(passphrase).map_err(|_| {
+                    Error::InvalidConfig(format!(
+                        "Cannot decrypt key `{}`, the passphrase is wrong",
+                        label
+                    ))
+                })?;
+                hex::encode(private_key)
+            }
+            (None, Some(private_key)) => {
+                log::warn!("Key `{}` of the keystore is not encrypted", label);
+                private_key.clone()
+            }
+            (None, None) => {
+                return Err(Error::InvalidConfig(format!(
+                    "Key `{}` has no private key",
+                    label
+                )))
+            }
+        };
+        let private_key = match entry.signature_type {
+            SignatureType::EcdsaP256 => ecdsa_p256::decode_private_key_hex(&private_key)?,
+            _ => ed25519::decode_private_key_hex(&private_key)?,
+        };
+        Ok(KeySigner::new(entry.signature_type, private_key))
+    }
+}
+
+/// Decode a hex encoded Ed25519 or P-256 public key.
+pub fn decode_public_key(public_key: &str) -> Result<Vec<u8>, Error> {
+    hex::decode(public_key.trim())
+        .ok()
+        .filter(|x| SignatureType::of_public_key(x).is_some())
+        .ok_or_else(|| Error::InvalidConfig(format!("Invalid public key: `{}`", public_key)))
+}
+
+/// Create the signer of an image, selected by `fw_config.signer` or else `config.signer`.
+pub fn from_config(
+    config: &Config,
+    fw_config: &FwConfig,
+    config_dir: &Path,
+) -> Result<Box<dyn Signer>, Error> {
+    let signature_type = fw_config.signature_type;
+    let signer: Box<dyn Signer> = match fw_config.signer.as_ref().unwrap_or(&config.signer) {
+        SignerConfig::Env => {
+            let (private_key, env_var) = match signature_type {
+                SignatureType::EcdsaP256 => (config.ecdsa_p256_private_key, ecdsa_p256::ENV_VAR),
+                _ => (config.ed25519_private_key, ed25519::ENV_VAR),
+            };
+            let private_key = private_key.ok_or_else(|| {
+                Error::InvalidConfig(format!(
+                    "{:?} signature requires {} to be set",
+                    signature_type, env_var
+                ))
+            })?;
+            Box::new(KeySigner::new(signature_type, private_key))
+        }
+        SignerConfig::Command {
+            command,
+            public_key,
+        } => Box::new(CommandSigner::new(
+            command,
+            decode_public_key(public_key)?,
+            config_dir,
+        )?),
+        SignerConfig::PublicKey { public_key } => {
+            Box::new(PublicKeySigner::new(decode_public_key(public_key)?))
+        }
+        SignerConfig::Keystore { path, label } => {
+            let path = Config::normalize_path(path, config_dir)?;
+            Box::new(Keystore::load_from_file(&path)?.signer(label)?)
+        }
+    };
+    if signer.signature_type() != signature_type {
+        return Err(Error::InvalidConfig(format!(
+            "The signer creates {:?} signatures, but {:?} signatures are required",
+            signer.signature_type(),
+            signature_type
+        )));
+    }
+    Ok(signer)
+}
+
+/// The digest covered by the signature of an image, i.e. the SHA-512 digest for
+/// Ed25519 and the SHA-256 digest for ECDSA P-256 of the image without the signature.
+pub fn image_digest(fw: &Firmware, signature_type: SignatureType) -> Vec<u8> {
+    match signature_type {
+        SignatureType::Unsigned => Vec::new(),
+        SignatureType::Ed25519 => ed25519::image_digest(fw).to_vec(),
+        SignatureType::EcdsaP256 => ecdsa_p256::image_digest(fw).to_vec(),
+    }
+}
+
+/// Length of the digests signed by [`Signer::sign_digest`].
+pub fn image_digest_len(signature_type: SignatureType) -> usize {
+    match signature_type {
+        SignatureType::Unsigned => 0,
+        SignatureType::Ed25519 => 64,
+        SignatureType::EcdsaP256 => 32,
+    }
+}
+
+/// Verify the signature of an image digest. The signature type is derived from the
+/// public key.
+pub fn verify_digest(digest: &[u8], signature: &[u8; 64], public_key: &[u8]) -> Result<(), Error> {
+    match SignatureType::of_public_key(public_key) {
+        Some(SignatureType::Ed25519) => ed25519::verify_digest(
+            digest.try_into().map_err(|_| Error::InvalidSignature)?,
+            signature,
+            public_key.try_into().unwrap(),
+        ),
+        Some(SignatureType::EcdsaP256) => ecdsa_p256::verify_digest(
+            digest.try_into().map_err(|_| Error::InvalidSignature)?,
+            signature,
+            public_key.try_into().unwrap(),
+        ),
+        _ => Err(Error::InvalidSignature),
+    }
+}
+
+/// Verify the signature of an image whose length is already known, see
+/// [`ed25519::verify_image`]. The signature type is derived from the public key.
+pub fn verify_image(image: &[u8], public_key: &[u8]) -> Result<(), Error> {
+    match SignatureType::of_public_key(public_key) {
+        Some(SignatureType::Ed25519) => {
+            ed25519::verify_image(image, public_key.try_into().unwrap())
+        }
+        Some(SignatureType::EcdsaP256) => {
+            ecdsa_p256::verify_image(image, public_key.try_into().unwrap())
+        }
+        _ => Err(Error::InvalidSignature),
+    }
+}
+
+/// Sign a firmware image, see [`ed25519::sign`]. The signature is checked against the
+/// public key of the signer.
+pub fn sign(fw: &mut Firmware, signer: &dyn Signer) -> Result<(), Error> {
+    let digest = image_digest(fw, signer.signature_type());
+    let signature = signer.sign_digest(&digest)?;
+    fw.write(0, &signature);
+    verify_image(&fw.data[..fw.image_length()], &signer.public_key()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keystore_selects_key_by_label() {
+        let keystore: Keystore = serde_json::from_str(&format!(
+            r#"{{ "keys": [
+                {{ "label": "debug", "private_key": "{}" }},
+                {{ "label": "release", "private_key": "{}" }}
+            ] }}"#,
+            hex::encode([1; 32]),
+            hex::encode([2; 32])
+        ))
+        .unwrap();
+        let signer = keystore.signer("release").unwrap();
+        assert_eq!(
+            signer.public_key().unwrap(),
+            ed25519::public_key_bytes(&[2; 32])
+        );
+        assert!(keystore.signer("test").is_err());
+    }
+
+    #[test]
+    fn keystore_holds_ecdsa_p256_keys() {
+        let keystore: Keystore = serde_json::from_str(&format!(
+            r#"{{ "keys": [
+                {{ "label": "release", "private_key": "{}", "signature_type": "EcdsaP256" }}
+            ] }}"#,
+            hex::encode([2; 32])
+        ))
+        .unwrap();
+        let signer = keystore.signer("release").unwrap();
+        assert_eq!(signer.signature_type(), SignatureType::EcdsaP256);
+        let public_key = signer.public_key().unwrap();
+        assert_eq!(public_key, ecdsa_p256::public_key_bytes(&[2; 32]).unwrap());
+
+        let digest = [0x5A; 32];
+        let signature = signer.sign_digest(&digest).unwrap();
+        verify_digest(&digest, &signature, &public_key).unwrap();
+        assert!(verify_digest(&[0x5B; 32], &signature, &public_key).is_err());
+        assert!(signer.sign_digest(&[0x5A; 64]).is_err());
+    }
+
+    #[test]
+    fn keystore_decrypts_keys_with_passphrase() {
+        let private_key = ecdsa_p256::generate_private_key();
+        let entry =
+            KeystoreEntry::encrypted("release", SignatureType::EcdsaP256, &private_key, "secret")
+                .unwrap();
+        assert!(!serde_json::to_string(&entry)
+            .unwrap()
+            .contains(&hex::encode(private_key)));
+        let mut keystore = Keystore::default();
+        keystore.add(entry.clone()).unwrap();
+        assert!(keystore.add(entry).is_err());
+
+        let signer = keystore
+            .signer_with_passphrase("release", Some("secret"))
+            .unwrap();
+        assert_eq!(signer.signature_type(), SignatureType::EcdsaP256);
+        assert_eq!(
+            signer.public_key().unwrap(),
+            ecdsa_p256::public_key_bytes(&private_key).unwrap()
+        );
+        assert!(keystore
+            .signer_with_passphrase("release", Some("wrong"))
+            .is_err());
+        assert!(keystore.signer_with_passphrase("release", None).is_err());
+    }
+
+    #[test]
+    fn signer_config_is_tagged() {
+        let config: SignerConfig = serde_json::from_str(
+            r#"{ "type": "Command", "command": ["sign.sh", "-k", "release"], "public_key": "00" }"#,
+        )
+        .unwrap();
+        assert!(matches!(config, SignerConfig::Command { ref command, .. } if command.len() == 3));
+        let config: SignerConfig = serde_json::from_str(r#"{ "type": "Env" }"#).unwrap();
+        assert_eq!(config, SignerConfig::Env);
+    }
+}
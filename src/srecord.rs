@@ -1,134 +1,281 @@
-use crate::config::AddressRange;
-use crate::{load_lines, swap_bytearray, Error};
+use crate::config::{AddressRange, RecordOptions};
+use crate::segments::SegmentMap;
+use crate::{for_each_line, swap_bytearray, Error};
 use hex;
 use std::cmp::min;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
-use std::str::from_utf8;
 
-struct Line {
-    data: Vec<u8>,
+/// Length of the longest record after the type: byte count, 255 bytes of address, data and
+/// checksum.
+const MAX_RECORD_LEN: usize = 256;
+
+struct Line<'a> {
+    data: &'a mut [u8],
     addr: u64,
-    kind: String,
+    kind: u8,
 }
 
-pub fn load(path: &Path, word_addressing: bool, range: &AddressRange) -> Result<Vec<u8>, Error> {
-    let lines = load_lines(path)?;
-    parse(word_addressing, range, lines.into_iter())
+/// Content of an S-Record file.
+///
+/// Besides the data, the header (`S0`) and the entry point (`S7`, `S8` or `S9`) are kept such that
+/// they can be written back when saving the file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SRecordFile {
+    /// Data records, at byte addresses.
+    pub segments: SegmentMap,
+    pub header: Option<Vec<u8>>,
+    pub entry_point: Option<u64>,
+    /// Spans written by multiple records with different values.
+    pub conflicts: Vec<AddressRange>,
 }
 
+/// Number of address bytes of a record type.
+fn address_width(kind: u8) -> Option<usize> {
+    match kind {
+        0 | 1 | 5 | 9 => Some(2),
+        2 | 6 | 8 => Some(3),
+        3 | 7 => Some(4),
+        _ => None,
+    }
+}
+
+pub fn load(path: &Path, word_addressing: bool) -> Result<SRecordFile, Error> {
+    let file = File::open(path).map_err(Error::Io)?;
+    read(BufReader::new(file), word_addressing).map_err(|x| x.in_file(path))
+}
+
+/// Parse the data records within `range` into a buffer. Bytes not contained in the file are 0xFF.
 pub fn parse<T: Iterator<Item = String>>(
     word_addressing: bool,
     range: &AddressRange,
     lines: T,
 ) -> Result<Vec<u8>, Error> {
-    let lines: Result<Vec<_>, _> = lines.map(parse_line).collect();
-    let lines = lines?;
-    let multiplier = if word_addressing { 2 } else { 1 };
-    let mut ret = vec![0xFF; range.len() as usize];
-    for line in lines {
-        match line.kind.as_str() {
-            "S0" => continue,
-            "S3" => {
+    parse_file(word_addressing, lines).map(|x| x.segments.to_dense(range, 0xFF))
+}
+
+/// Parse the data records (`S1`, `S2`, `S3`) as well as the header and entry point.
+///
+/// Record counts (`S5`, `S6`) are checked against the number of data records preceding them.
+/// For word addressed devices, addresses are converted to byte addresses.
+pub fn parse_file<T: Iterator<Item = String>>(
+    word_addressing: bool,
+    lines: T,
+) -> Result<SRecordFile, Error> {
+    let mut parser = Parser::new(word_addressing);
+    for (k, line) in lines.enumerate() {
+        if !parser.feed(k + 1, &line)? {
+            break;
+        }
+    }
+    Ok(parser.file)
+}
+
+/// Parse a file from `reader` like [`parse_file`], one line at a time without collecting the
+/// lines or records.
+pub fn read<R: BufRead>(reader: R, word_addressing: bool) -> Result<SRecordFile, Error> {
+    let mut parser = Parser::new(word_addressing);
+    for_each_line(reader, |number, line| parser.feed(number, line))?;
+    Ok(parser.file)
+}
+
+/// Incremental parser, decoding each record into the same buffer.
+struct Parser {
+    word_addressing: bool,
+    data_records: u64,
+    buffer: [u8; MAX_RECORD_LEN],
+    file: SRecordFile,
+}
+
+impl Parser {
+    fn new(word_addressing: bool) -> Self {
+        Self {
+            word_addressing,
+            data_records: 0,
+            buffer: [0; MAX_RECORD_LEN],
+            file: SRecordFile::default(),
+        }
+    }
+
+    /// Parse line `number` of the file. Returns `false` once the terminating record is reached.
+    fn feed(&mut self, number: usize, line: &str) -> Result<bool, Error> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(true);
+        }
+        let error = |reason| Error::InvalidHexRecord {
+            path: None,
+            line: number,
+            reason,
+        };
+        let line = parse_line(line, &mut self.buffer).map_err(error)?;
+        let multiplier = if self.word_addressing { 2 } else { 1 };
+        match line.kind {
+            0 => self.file.header = Some(line.data.to_vec()),
+            1..=3 => {
+                self.data_records += 1;
                 let addr = line.addr * multiplier;
-                if addr < range.begin || addr > range.end {
-                    continue;
-                }
-                for k in 0..line.data.len() {
-                    let idx = k + (addr as usize) - (range.begin as usize);
-                    ret[idx] = line.data[k];
+                let data = line.data;
+                if self.word_addressing {
+                    if data.len() % 2 != 0 {
+                        return Err(error(
+                            "odd number of bytes in word addressed record".to_string(),
+                        ));
+                    }
+                    swap_bytearray(data);
                 }
+                let conflicts = self.file.segments.conflicts(addr, data);
+                self.file.conflicts.extend(conflicts);
+                self.file.segments.insert(addr, data);
             }
-            "S7" => {
-                break;
+            5 | 6 if line.addr != self.data_records => {
+                return Err(error(format!(
+                    "record count is {}, but {} data records precede it",
+                    line.addr, self.data_records
+                )));
             }
-            _ => {
-                return Err(Error::InvalidHexFile);
+            7..=9 => {
+                self.file.entry_point = Some(line.addr);
+                return Ok(false);
             }
+            _ => {}
         }
+        Ok(true)
     }
-    if word_addressing {
-        swap_bytearray(&mut ret);
-    }
-    Ok(ret)
 }
 
-fn parse_line(line: String) -> Result<Line, Error> {
+/// Decode `line` into `buffer`. The data of the returned record refers to the buffer.
+fn parse_line<'a>(line: &str, buffer: &'a mut [u8; MAX_RECORD_LEN]) -> Result<Line<'a>, String> {
     let line = line.as_bytes();
-    if line.len() < 2 {
-        return Err(Error::InvalidHexFile);
+    if line.len() < 2 || line[0] != b'S' || !line[1].is_ascii_digit() {
+        return Err("record does not start with `S` and a record type".to_string());
+    }
+    let kind = line[1] - b'0';
+    let width = address_width(kind).ok_or_else(|| format!("unsupported record type S{}", kind))?;
+    let digits = &line[2..];
+    let len = digits.len() / 2;
+    if len > MAX_RECORD_LEN {
+        return Err("record is too long".to_string());
+    }
+    let decoded = &mut buffer[..len];
+    hex::decode_to_slice(digits, decoded).map_err(|_| "invalid hex digits".to_string())?;
+    if decoded.len() < width + 2 {
+        return Err("record is too short".to_string());
     }
-    let kind = from_utf8(&line[0..2])
-        .map_err(|_| Error::InvalidHexFile)?
-        .to_string();
-    let decoded = hex::decode(&line[2..]).map_err(|_| Error::InvalidHexFile)?;
     let cnt = decoded[0];
     if cnt as usize != decoded.len() - 1 {
-        return Err(Error::InvalidHexFile);
+        return Err(format!(
+            "byte count {} does not match record length {}",
+            cnt,
+            decoded.len() - 1
+        ));
     }
     let sum: u32 = decoded.iter().map(|x| *x as u32).sum();
     if sum & 0xFF != 0xFF {
-        return Err(Error::InvalidHexFile);
-    }
-    let addr = &decoded[1..5];
-    let addr = ((addr[0] as u64) << 24)
-        | ((addr[1] as u64) << 16)
-        | ((addr[2] as u64) << 8)
-        | (addr[3] as u64);
-    let data: Vec<_> = decoded[5..decoded.len() - 1].iter().map(|x| *x).collect();
-    Ok(Line { data, addr, kind })
+        return Err("checksum mismatch".to_string());
+    }
+    let addr = decoded[1..1 + width]
+        .iter()
+        .fold(0_u64, |acc, x| (acc << 8) | (*x as u64));
+    let end = decoded.len() - 1;
+    Ok(Line {
+        data: &mut decoded[1 + width..end],
+        addr,
+        kind,
+    })
 }
 
-const WRITE_DATA_PER_LINE: usize = 16;
+fn format_record(kind: u8, width: usize, address: u64, data: &[u8]) -> String {
+    let mut out = vec![(width + data.len() + 1) as u8];
+    out.extend(&address.to_be_bytes()[8 - width..]);
+    out.extend(data);
+    let sum: u32 = out.iter().map(|x| *x as u32).sum();
+    let sum = (sum & 0xFF) as u8;
+    out.push(!sum);
+    format!("S{}{}", kind, hex::encode_upper(out))
+}
 
 pub fn serialize(word_addressing: bool, range: &AddressRange, data: &[u8]) -> String {
+    serialize_file(
+        word_addressing,
+        range,
+        data,
+        None,
+        None,
+        &RecordOptions::default(),
+    )
+}
+
+/// Serialize `data` with the narrowest record type (`S1`, `S2` or `S3`) able to represent all
+/// addresses of `range` and the entry point. Lines which only contain 0xFF are skipped.
+///
+/// The `header` is written as `S0` record and the entry point as terminating record.
+pub fn serialize_file(
+    word_addressing: bool,
+    range: &AddressRange,
+    data: &[u8],
+    header: Option<&[u8]>,
+    entry_point: Option<u64>,
+    options: &RecordOptions,
+) -> String {
     let mut data = data.to_vec();
     if word_addressing {
         swap_bytearray(&mut data);
     }
+    let mut max_address = range.end.saturating_sub(1);
+    if word_addressing {
+        max_address >>= 1;
+    }
+    let max_address = max_address.max(entry_point.unwrap_or(0));
+    let (data_kind, width) = if max_address <= 0xFFFF {
+        (1, 2)
+    } else if max_address <= 0xFF_FFFF {
+        (2, 3)
+    } else {
+        (3, 4)
+    };
+
     let mut lines = Vec::new();
-    for k in (0..data.len()).step_by(WRITE_DATA_PER_LINE) {
-        let endidx = min(k + WRITE_DATA_PER_LINE, data.len());
+    if let Some(header) = header {
+        lines.push(format_record(0, 2, 0, header));
+    }
+    for k in (0..data.len()).step_by(options.record_length) {
+        let endidx = min(k + options.record_length, data.len());
         let endidx = min(endidx, (range.end - range.begin) as usize);
         if k >= endidx {
             break;
         }
-        let len = endidx - k + 5;
+        let data_slice = &data[k..endidx];
+        if data_slice.iter().all(|x| *x == 0xFF) {
+            continue;
+        }
         let mut address = (k as u64) + range.begin;
         if word_addressing {
             address >>= 1;
         }
-        let mut out = Vec::new();
-        out.extend(&[
-            len as u8,
-            (address >> 24) as u8,
-            (address >> 16) as u8,
-            (address >> 8) as u8,
-            (address & 0xFF) as u8,
-        ]);
-        out.extend(&data[k..endidx]);
-        let sum: u32 = out.iter().map(|x| *x as u32).sum();
-        let sum = (sum & 0xFF) as u8;
-        out.push(!sum);
-        let line = format!("S3{}", hex::encode_upper(out));
-        lines.push(line);
-    }
-    // write S7
-    let out = &[0x05, 0x00, 0x00, 0x00, 0x00, !5];
-    let line = format!("S7{}", hex::encode_upper(out));
-    lines.push(line);
-
-    lines.join("\n")
+        lines.push(format_record(data_kind, width, address, data_slice));
+    }
+    lines.push(format_record(
+        10 - data_kind,
+        width,
+        entry_point.unwrap_or(0),
+        &[],
+    ));
+
+    lines.join(options.line_ending.as_str())
 }
 
 pub fn save(
     path: &Path,
     word_addressing: bool,
     range: &AddressRange,
-    data: &Vec<u8>,
+    data: &[u8],
+    header: Option<&[u8]>,
+    entry_point: Option<u64>,
+    options: &RecordOptions,
 ) -> Result<(), Error> {
-    let data = serialize(word_addressing, range, data);
+    let data = serialize_file(word_addressing, range, data, header, entry_point, options);
     let mut file = File::create(path).map_err(Error::Io)?;
     file.write_all(data.as_bytes()).map_err(Error::Io)
 }
@@ -137,6 +284,13 @@ pub fn save(
 mod tests {
     use super::*;
 
+    fn lines(file: &str) -> impl Iterator<Item = String> + '_ {
+        file.split("\n")
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string())
+    }
+
     #[test]
     fn test_serialize() {
         let range = AddressRange::new(0xAB00, 0xABFF);
@@ -145,13 +299,35 @@ mod tests {
         let mut iter = serialized.split("\n");
         assert_eq!(
             iter.next(),
-            Some("S3150000AB000102030405060708090A0B0C0D0E0F10B7")
+            Some("S113AB000102030405060708090A0B0C0D0E0F10B9")
         );
-        assert_eq!(iter.next(), Some("S3090000AB1011121314F1"));
-        assert_eq!(iter.next(), Some("S70500000000FA"));
+        assert_eq!(iter.next(), Some("S107AB1011121314F3"));
+        assert_eq!(iter.next(), Some("S9030000FC"));
         assert_eq!(iter.next(), None)
     }
 
+    #[test]
+    fn serialize_selects_narrowest_record_type() {
+        let data = [0xAA, 0xBB];
+        let serialized = serialize(false, &AddressRange::new(0x1_0000, 0x1_0002), &data);
+        assert_eq!(serialized, "S206010000AABB93\nS804000000FB");
+        let serialized = serialize(false, &AddressRange::new(0x100_0000, 0x100_0002), &data);
+        assert_eq!(serialized, "S30701000000AABB92\nS70500000000FA");
+        // word addresses are halved
+        let serialized = serialize(true, &AddressRange::new(0x1_0000, 0x1_0002), &data);
+        assert!(serialized.starts_with("S1058000BBAA"));
+        // the entry point widens the records
+        let serialized = serialize_file(
+            false,
+            &AddressRange::new(0, 2),
+            &data,
+            None,
+            Some(0x12_3456),
+            &RecordOptions::default(),
+        );
+        assert_eq!(serialized, "S206000000AABB94\nS8041234565F");
+    }
+
     #[test]
     fn test_parse() {
         let range = AddressRange::new(0xAB00, 0xABFF);
@@ -160,13 +336,66 @@ mod tests {
         S3090000ab1011121314f1
         S70500000000fa
         "#;
-        let lines = file
-            .split("\n")
-            .map(|x| x.trim())
-            .filter(|x| !x.is_empty())
-            .map(|x| x.to_string());
-        let parsed = parse(false, &range, lines).unwrap();
+        let parsed = parse(false, &range, lines(file)).unwrap();
         let data: Vec<_> = (1u8..21).collect();
         assert_eq!(&parsed[0..20], &data);
     }
+
+    #[test]
+    fn parse_all_record_types() {
+        let range = AddressRange::new(0xAB00, 0xAC00);
+        let file = r#"
+        S00F000068656C6C6F202020202000003C
+        S113AB000102030405060708090A0B0C0D0E0F10B9
+        S20800AB1011121314F2
+        S5030002FA
+        S9030000FC
+        "#;
+        let parsed = parse_file(false, lines(file)).unwrap();
+        let data: Vec<_> = (1u8..21).collect();
+        assert_eq!(&parsed.segments.to_dense(&range, 0xFF)[0..20], &data);
+        assert_eq!(parsed.header.as_deref(), Some(&b"hello     \0\0"[..]));
+        assert_eq!(parsed.entry_point, Some(0));
+
+        // record count does not match
+        let file = "S113AB000102030405060708090A0B0C0D0E0F10B9\nS5030002FA";
+        assert!(parse(false, &range, lines(file)).is_err());
+        // reserved record type
+        assert!(parse(false, &range, lines("S4030000FC")).is_err());
+        // truncated address
+        assert!(parse(false, &range, lines("S2030000FC")).is_err());
+    }
+
+    #[test]
+    fn read_matches_parse_file() {
+        let range = AddressRange::new(0x1_AB00, 0x1_AD00);
+        let data: Vec<_> = (0..0x200).map(|x| x as u8).collect();
+        let options = RecordOptions::default();
+        let serialized =
+            serialize_file(false, &range, &data, Some(b"app"), Some(0x1_AB04), &options);
+        let file = read(serialized.as_bytes(), false).unwrap();
+        assert_eq!(file, parse_file(false, lines(&serialized)).unwrap());
+        assert_eq!(file.segments.len(), 1);
+        assert_eq!(file.segments.to_dense(&range, 0xFF), data);
+
+        let file = read(&b"S9030000FC\n"[..], false).unwrap();
+        assert_eq!(file.entry_point, Some(0));
+        let err = read(&b"\nS9030000FD\n"[..], false).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: checksum mismatch");
+    }
+
+    #[test]
+    fn header_and_entry_point_round_trip() {
+        let range = AddressRange::new(0x1_AB00, 0x1_AC00);
+        let data: Vec<_> = (0..=255).collect();
+        let options = RecordOptions::default();
+        let serialized =
+            serialize_file(false, &range, &data, Some(b"app"), Some(0x1_AB04), &options);
+        assert!(serialized.starts_with("S0060000617070"));
+        assert!(serialized.ends_with("S80401AB044B"));
+        let parsed = parse_file(false, lines(&serialized)).unwrap();
+        assert_eq!(parsed.segments.to_dense(&range, 0xFF), data);
+        assert_eq!(parsed.header.as_deref(), Some(&b"app"[..]));
+        assert_eq!(parsed.entry_point, Some(0x1_AB04));
+    }
 }
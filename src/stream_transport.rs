@@ -0,0 +1,279 @@
+//! A [`Transport`] for byte-stream connections such as TCP sockets or serial ports.
+//!
+//! Since a byte stream does not preserve message boundaries, each DDP frame is
+//! delimited with SLIP framing (RFC 1055). Refer to `doc/ddp_protocol.md`.
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use crate::crc::crc16;
+use crate::script_executor::Transport;
+use crate::Error;
+
+pub const SLIP_END: u8 = 0xC0;
+pub const SLIP_ESC: u8 = 0xDB;
+pub const SLIP_ESC_END: u8 = 0xDC;
+pub const SLIP_ESC_ESC: u8 = 0xDD;
+
+pub const DEFAULT_RESPONSE_TIMEOUT_MS: u64 = 1000;
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Encode a frame for transmission over a byte stream.
+pub fn encode_frame(data: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(data.len() + 2);
+    ret.push(SLIP_END);
+    for x in data {
+        match *x {
+            SLIP_END => ret.extend([SLIP_ESC, SLIP_ESC_END]),
+            SLIP_ESC => ret.extend([SLIP_ESC, SLIP_ESC_ESC]),
+            x => ret.push(x),
+        }
+    }
+    ret.push(SLIP_END);
+    ret
+}
+
+/// Reassembles frames from a received byte stream.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    escape: bool,
+}
+
+impl FrameDecoder {
+    /// Feed a received byte. Returns the frame once its end delimiter has been received.
+    pub fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        if self.escape {
+            self.escape = false;
+            match byte {
+                SLIP_ESC_END => self.buf.push(SLIP_END),
+                SLIP_ESC_ESC => self.buf.push(SLIP_ESC),
+                // protocol violation, drop the frame
+                _ => self.buf.clear(),
+            }
+            return None;
+        }
+        match byte {
+            SLIP_END if self.buf.is_empty() => None,
+            SLIP_END => Some(std::mem::take(&mut self.buf)),
+            SLIP_ESC => {
+                self.escape = true;
+                None
+            }
+            x => {
+                self.buf.push(x);
+                None
+            }
+        }
+    }
+}
+
+/// Check the CRC16 at the end of a DDP frame.
+pub fn check_frame_crc(frame: &[u8]) -> bool {
+    if frame.len() < 3 {
+        return false;
+    }
+    let (payload, crc) = frame.split_at(frame.len() - 2);
+    crc16(payload) == ((crc[0] as u16) << 8 | crc[1] as u16)
+}
+
+/// Sends DDP frames over a byte stream and waits for the responses.
+///
+/// Received data is decoded on a background thread, such that waiting for a response
+/// can time out on any kind of stream. Queries are retried if no response arrives in time
+/// or if the response fails the CRC check.
+pub struct StreamTransport {
+    writer: Box<dyn Write + Send>,
+    frames: Receiver<Vec<u8>>,
+    socket: Option<TcpStream>,
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+impl StreamTransport {
+    pub fn new<R, W>(mut reader: R, writer: W) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut decoder = FrameDecoder::default();
+            let mut buf = [0_u8; 256];
+            loop {
+                let n = match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => n,
+                };
+                for x in &buf[..n] {
+                    if let Some(frame) = decoder.push(*x) {
+                        if tx.send(frame).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            writer: Box::new(writer),
+            frames: rx,
+            socket: None,
+            timeout: Duration::from_millis(DEFAULT_RESPONSE_TIMEOUT_MS),
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    /// Connect to a TCP socket, e.g. a serial-to-ethernet converter.
+    pub fn connect_tcp(addr: &str) -> crate::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let socket = stream.try_clone()?;
+        let mut ret = Self::new(reader, stream);
+        ret.socket = Some(socket);
+        Ok(ret)
+    }
+
+    /// Open a device file, such as a serial port or a PTY.
+    ///
+    /// The device must already be configured (baud rate, raw mode), e.g. with `stty`.
+    pub fn open_device(path: &Path) -> crate::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let reader = file.try_clone()?;
+        Ok(Self::new(reader, file))
+    }
+
+    fn send(&mut self, data: &[u8]) -> crate::Result<()> {
+        self.writer.write_all(&encode_frame(data))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn discard_pending(&mut self) {
+        while self.frames.try_recv().is_ok() {}
+    }
+}
+
+impl Drop for StreamTransport {
+    fn drop(&mut self) {
+        // unblock the reader thread and signal the end of the connection to the peer
+        if let Some(socket) = self.socket.take() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl Transport for StreamTransport {
+    fn write(&mut self, data: &[u8]) -> crate::Result<()> {
+        self.send(data)
+    }
+
+    fn query(&mut self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        let mut last_error = Error::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no response received",
+        ));
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                log::debug!("Retrying query ({}/{}): {}", attempt, self.retries, last_error);
+            }
+            self.discard_pending();
+            self.send(data)?;
+            match self.frames.recv_timeout(self.timeout) {
+                Ok(frame) if check_frame_crc(&frame) => return Ok(frame),
+                Ok(_) => last_error = Error::InvalidDdpFrame,
+                Err(RecvTimeoutError::Timeout) => {
+                    last_error = Error::Io(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "no response received",
+                    ))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "connection closed",
+                    )))
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn sleep(&mut self, ms: u32) {
+        thread::sleep(Duration::from_millis(ms as u64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_round_trip() {
+        let data = vec![0x01, SLIP_END, 0x02, SLIP_ESC, SLIP_ESC_END, 0x03];
+        let encoded = encode_frame(&data);
+        assert_eq!(
+            encoded,
+            vec![
+                SLIP_END,
+                0x01,
+                SLIP_ESC,
+                SLIP_ESC_END,
+                0x02,
+                SLIP_ESC,
+                SLIP_ESC_ESC,
+                SLIP_ESC_END,
+                0x03,
+                SLIP_END
+            ]
+        );
+        let mut decoder = FrameDecoder::default();
+        let frames: Vec<_> = encoded.iter().filter_map(|x| decoder.push(*x)).collect();
+        assert_eq!(frames, vec![data]);
+    }
+
+    #[test]
+    fn query_is_retried_on_invalid_crc() {
+        use std::net::TcpListener;
+
+        let mut response = vec![0x00, 0x01, 0x01, 0x00];
+        let crc = crc16(&response);
+        response.extend([(crc >> 8) as u8, (crc & 0xFF) as u8]);
+        let mut corrupted = response.clone();
+        corrupted[5] ^= 0xFF;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let responses = vec![corrupted, response.clone()];
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = FrameDecoder::default();
+            let mut responses = responses.into_iter();
+            let mut buf = [0_u8; 64];
+            let mut requests = 0;
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    return requests;
+                }
+                for x in &buf[..n] {
+                    if decoder.push(*x).is_some() {
+                        requests += 1;
+                        let response = responses.next().unwrap();
+                        stream.write_all(&encode_frame(&response)).unwrap();
+                    }
+                }
+            }
+        });
+
+        let mut transport = StreamTransport::connect_tcp(&addr).unwrap();
+        assert_eq!(transport.query(&[0x90, 0x01, 0x00]).unwrap(), response);
+        drop(transport);
+        assert_eq!(server.join().unwrap(), 2);
+    }
+}
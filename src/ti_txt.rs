@@ -0,0 +1,204 @@
+//! TI-TXT files as generated by the MSP430 and C2000 tool chains.
+//!
+//! A section starts with an `@ADDR` line, followed by lines of space separated data bytes. The
+//! file is terminated by a line containing `q`.
+
+use crate::config::{AddressRange, RecordOptions};
+use crate::segments::SegmentMap;
+use crate::{load_lines, swap_bytearray, Error};
+use hex;
+use std::cmp::min;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// Content of a TI-TXT file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TiTxtFile {
+    /// Data of all sections, at byte addresses.
+    pub segments: SegmentMap,
+    /// Spans written by multiple sections with different values.
+    pub conflicts: Vec<AddressRange>,
+}
+
+pub fn load(path: &Path, word_addressing: bool) -> Result<TiTxtFile, Error> {
+    let lines = load_lines(path)?;
+    parse_file(word_addressing, lines.into_iter()).map_err(|x| x.in_file(path))
+}
+
+/// Parse the sections within `range` into a buffer. Bytes not contained in the file are 0xFF.
+pub fn parse<T: Iterator<Item = String>>(
+    word_addressing: bool,
+    range: &AddressRange,
+    lines: T,
+) -> Result<Vec<u8>, Error> {
+    parse_file(word_addressing, lines).map(|x| x.segments.to_dense(range, 0xFF))
+}
+
+/// Parse all sections of the file.
+///
+/// Empty lines are skipped but counted, such that errors refer to the line number in the file.
+/// For word addressed devices, addresses are converted to byte addresses.
+pub fn parse_file<T: Iterator<Item = String>>(
+    word_addressing: bool,
+    lines: T,
+) -> Result<TiTxtFile, Error> {
+    let multiplier = if word_addressing { 2 } else { 1 };
+    let mut ret = TiTxtFile::default();
+    let mut address = None;
+    let mut line_count = 0;
+    for (k, line) in lines.enumerate() {
+        line_count = k + 1;
+        let error = |reason: &str| Error::InvalidHexRecord {
+            path: None,
+            line: k + 1,
+            reason: reason.to_string(),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.eq_ignore_ascii_case("q") {
+            return Ok(ret);
+        }
+        if let Some(addr) = line.strip_prefix('@') {
+            let addr = u64::from_str_radix(addr, 16).map_err(|_| error("invalid address"))?;
+            address = Some(addr * multiplier);
+            continue;
+        }
+        let addr = address.ok_or_else(|| error("data before the first address line"))?;
+        let mut data = line
+            .split_whitespace()
+            .map(|x| match x.len() {
+                2 => hex::decode(x).map(|x| x[0]).map_err(|_| ()),
+                _ => Err(()),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| error("invalid data byte"))?;
+        if word_addressing {
+            if data.len() % 2 != 0 {
+                return Err(error("odd number of bytes in word addressed line"));
+            }
+            swap_bytearray(&mut data);
+        }
+        ret.conflicts.extend(ret.segments.conflicts(addr, &data));
+        ret.segments.insert(addr, &data);
+        address = Some(addr + data.len() as u64);
+    }
+    Err(Error::InvalidHexRecord {
+        path: None,
+        line: line_count,
+        reason: "missing `q` at the end of the file".to_string(),
+    })
+}
+
+pub fn serialize(word_addressing: bool, range: &AddressRange, data: &[u8]) -> String {
+    serialize_file(word_addressing, range, data, &RecordOptions::default())
+}
+
+/// Serialize `data` with `options.record_length` bytes per line, skipping lines which only
+/// contain 0xFF.
+pub fn serialize_file(
+    word_addressing: bool,
+    range: &AddressRange,
+    data: &[u8],
+    options: &RecordOptions,
+) -> String {
+    let mut data = data.to_vec();
+    if word_addressing {
+        swap_bytearray(&mut data);
+    }
+    let mut lines = Vec::new();
+    let mut next_address = None;
+    for k in (0..data.len()).step_by(options.record_length) {
+        let endidx = min(k + options.record_length, data.len());
+        let endidx = min(endidx, (range.end - range.begin) as usize);
+        if k >= endidx {
+            break;
+        }
+        let data_slice = &data[k..endidx];
+        if data_slice.iter().all(|x| *x == 0xFF) {
+            continue;
+        }
+        let mut address = (k as u64) + range.begin;
+        if word_addressing {
+            address >>= 1;
+        }
+        if next_address != Some(address) {
+            lines.push(format!("@{:04X}", address));
+        }
+        let mut len = data_slice.len() as u64;
+        if word_addressing {
+            len >>= 1;
+        }
+        next_address = Some(address + len);
+        let bytes: Vec<_> = data_slice.iter().map(|x| format!("{:02X}", x)).collect();
+        lines.push(bytes.join(" "));
+    }
+    lines.push("q".to_string());
+    lines.join(options.line_ending.as_str())
+}
+
+pub fn save(
+    path: &Path,
+    word_addressing: bool,
+    range: &AddressRange,
+    data: &[u8],
+    options: &RecordOptions,
+) -> Result<(), Error> {
+    let data = serialize_file(word_addressing, range, data, options);
+    let mut file = File::create(path).map_err(Error::Io)?;
+    file.write_all(data.as_bytes()).map_err(Error::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(file: &str) -> impl Iterator<Item = String> + '_ {
+        file.split("\n").map(|x| x.to_string())
+    }
+
+    #[test]
+    fn test_serialize() {
+        let range = AddressRange::new(0xAB00, 0xAB40);
+        let mut data = vec![0xFF; 0x40];
+        data[..20].copy_from_slice(&(1u8..21).collect::<Vec<_>>());
+        data[0x30] = 0x42;
+        let serialized = serialize(false, &range, &data);
+        assert_eq!(
+            serialized,
+            "@AB00\n\
+             01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F 10\n\
+             11 12 13 14 FF FF FF FF FF FF FF FF FF FF FF FF\n\
+             @AB30\n\
+             42 FF FF FF FF FF FF FF FF FF FF FF FF FF FF FF\n\
+             q"
+        );
+        assert_eq!(parse(false, &range, lines(&serialized)).unwrap(), data);
+    }
+
+    #[test]
+    fn parse_word_addressed() {
+        let file = "@5580\n12 34 56 78\nq\n";
+        let parsed = parse_file(true, lines(file)).unwrap();
+        let segments: Vec<_> = parsed.segments.iter().collect();
+        assert_eq!(segments, vec![(0xAB00, &[0x34, 0x12, 0x78, 0x56][..])]);
+    }
+
+    #[test]
+    fn parse_reports_line_and_reason() {
+        let err = parse_file(false, lines("01 02\nq")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: data before the first address line"
+        );
+        let err = parse_file(false, lines("@AB00\n\n01 0X\nq")).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: invalid data byte");
+        let err = parse_file(false, lines("@AB00\n01 02")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: missing `q` at the end of the file"
+        );
+    }
+}
@@ -0,0 +1,653 @@
+//! Re-check generated artifacts before they are released.
+//!
+//! Each image is decoded with [`crate::inspect`] and checked for a consistent CRC,
+//! header length, key ID, signature and bootloader trailer. If the artifacts are
+//! given by an `info.json` file, the hex, binary and merged outputs are additionally
+//! compared in their overlapping address ranges.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::config::SignatureType;
+use crate::crc::crc32;
+use crate::firmware::Firmware;
+use crate::inspect::{
+    inspect_file, inspect_firmware, load_firmware, ImageReport, InputFormat, InspectOptions,
+    InspectReport, SignatureStatus, TrailerReport,
+};
+use crate::process::{FwInfo, Info};
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// The check could not be performed, e.g. because no public key was given.
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub file: String,
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    pub passed: bool,
+    pub checks: Vec<Check>,
+}
+
+impl VerifyReport {
+    fn new() -> Self {
+        VerifyReport {
+            passed: true,
+            checks: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, file: &str, name: &'static str, status: CheckStatus, message: String) {
+        if status == CheckStatus::Failed {
+            self.passed = false;
+        }
+        self.checks.push(Check {
+            file: file.to_string(),
+            name,
+            status,
+            message,
+        });
+    }
+
+    fn check(&mut self, file: &str, name: &'static str, ok: bool, message: String) {
+        let status = if ok {
+            CheckStatus::Passed
+        } else {
+            CheckStatus::Failed
+        };
+        self.add(file, name, status, message);
+    }
+
+    pub fn count(&self, status: CheckStatus) -> usize {
+        self.checks.iter().filter(|x| x.status == status).count()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("This shouldn't fail")
+    }
+}
+
+/// Verify individual files.
+///
+/// Files without an application image or bootloader trailer are reported as skipped. Hex files
+/// which overlap in their address ranges must have identical content in the overlap.
+/// Binary files are placed at `options.base_address`.
+pub fn verify_files(files: &[PathBuf], options: &InspectOptions) -> VerifyReport {
+    let mut report = VerifyReport::new();
+    let mut loaded = Vec::new();
+    for path in files {
+        let name = path.to_string_lossy().to_string();
+        let Some((inspected, fw)) = check_file(&mut report, &name, path, None, options) else {
+            continue;
+        };
+        if !options.public_keys.is_empty() || !options.key_ring.keys.is_empty() {
+            for image in &inspected.images {
+                check_signed(&mut report, &name, image, "public keys are given", options);
+            }
+        }
+        if inspected.images.is_empty() && inspected.btl_trailer.is_none() {
+            report.add(
+                &name,
+                "content",
+                CheckStatus::Skipped,
+                "no application image or bootloader trailer found".to_string(),
+            );
+        }
+        if let Some(fw) = fw {
+            if inspected.format != InputFormat::Binary {
+                loaded.push((name, fw));
+            }
+        }
+    }
+    for (k, (name_a, a)) in loaded.iter().enumerate() {
+        for (name_b, b) in &loaded[k + 1..] {
+            if overlap(a, b).is_some() {
+                compare(&mut report, name_a, a, name_b, b);
+            }
+        }
+    }
+    report
+}
+
+/// Verify all artifacts listed in an `info.json` file, as written by `generate`.
+pub fn verify_info(info_path: &Path, options: &InspectOptions) -> Result<VerifyReport, Error> {
+    let info = Info::load_from_file(info_path)?;
+    let dir = info_path.parent().unwrap_or(Path::new("."));
+    let mut report = VerifyReport::new();
+    for fw_info in &info.images {
+        let format = fw_info.hex_file_format.into();
+        let app = check_hex_and_bin(
+            &mut report,
+            dir,
+            &fw_info.app_file,
+            &fw_info.app_bin_file,
+            format,
+            true,
+            options,
+        );
+        let btl = check_hex_and_bin(
+            &mut report,
+            dir,
+            &fw_info.btl_file,
+            &fw_info.btl_bin_file,
+            format,
+            false,
+            options,
+        );
+        // the extra inputs are checked separately
+        let merged_options = InspectOptions {
+            exclude: fw_info
+                .extra_inputs
+                .iter()
+                .map(|x| x.address.clone())
+                .collect(),
+            ..options.clone()
+        };
+        let merged = check_hex_and_bin(
+            &mut report,
+            dir,
+            &fw_info.merged_file,
+            &fw_info.merged_bin_file,
+            format,
+            true,
+            &merged_options,
+        );
+        if fw_info.extra_inputs.iter().any(|x| x.crc.is_some()) {
+            check_extra_inputs(&mut report, dir, fw_info, format, options);
+        }
+
+        for (file, inspected) in [(&fw_info.app_file, &app), (&fw_info.merged_file, &merged)] {
+            let Some(image) = inspected.as_ref().and_then(|x| x.0.images.first()) else {
+                continue;
+            };
+            let message = format!(
+                "info.json lists node {} with CRC 0x{:08X}, image has node {} with CRC 0x{:08X}",
+                fw_info.fw_id, fw_info.crc, image.node_id, image.stored_crc
+            );
+            let ok = image.stored_crc == fw_info.crc && image.node_id == fw_info.fw_id;
+            report.check(file, "info", ok, message);
+            if fw_info.signature_type != SignatureType::Unsigned {
+                let reason = "info.json lists a signed image";
+                check_signed(&mut report, file, image, reason, options);
+            }
+        }
+
+        if let (Some((_, Some(merged))), Some((_, Some(app)))) = (&merged, &app) {
+            compare(
+                &mut report,
+                &fw_info.merged_file,
+                merged,
+                &fw_info.app_file,
+                app,
+            );
+        }
+        if let (Some((_, Some(merged))), Some((_, Some(btl)))) = (&merged, &btl) {
+            compare(
+                &mut report,
+                &fw_info.merged_file,
+                merged,
+                &fw_info.btl_file,
+                btl,
+            );
+        }
+
+        for region in &fw_info.regions {
+            let hex = check_hex_and_bin(
+                &mut report,
+                dir,
+                &region.file,
+                &region.bin_file,
+                format,
+                false,
+                options,
+            );
+            for file in &region.additional_files {
+                let other = check_file(&mut report, file, &dir.join(file), None, options);
+                if let (Some((_, Some(a))), Some((_, Some(b)))) = (&hex, &other) {
+                    compare(&mut report, &region.file, a, file, b);
+                }
+            }
+        }
+
+        // files of further output formats must have the same content
+        for files in &fw_info.additional_files {
+            let format = Some(files.hex_file_format.into());
+            let pairs = [
+                (&files.app_file, &fw_info.app_file, &app),
+                (&files.btl_file, &fw_info.btl_file, &btl),
+                (&files.merged_file, &fw_info.merged_file, &merged),
+            ];
+            for (file, primary_file, primary) in pairs {
+                let other = check_file(&mut report, file, &dir.join(file), format, options);
+                if let (Some((_, Some(a))), Some((_, Some(b)))) = (primary, &other) {
+                    compare(&mut report, primary_file, a, file, b);
+                }
+            }
+        }
+    }
+
+    let package = dir.join(&info.package_file);
+    let package = check_file(&mut report, &info.package_file, &package, None, options);
+    if let Some((inspected, _)) = package {
+        for fw_info in &info.images {
+            let found = inspected
+                .images
+                .iter()
+                .find(|x| x.node_id == fw_info.fw_id && x.stored_crc == fw_info.crc);
+            report.check(
+                &info.package_file,
+                "info",
+                found.is_some(),
+                format!(
+                    "info.json lists node {} with CRC 0x{:08X}",
+                    fw_info.fw_id, fw_info.crc
+                ),
+            );
+            if let Some(image) = found {
+                if fw_info.signature_type != SignatureType::Unsigned {
+                    let reason = "info.json lists a signed image";
+                    check_signed(&mut report, &info.package_file, image, reason, options);
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Check the CRC of the extra inputs in the merged file.
+fn check_extra_inputs(
+    report: &mut VerifyReport,
+    dir: &Path,
+    fw_info: &FwInfo,
+    format: InputFormat,
+    options: &InspectOptions,
+) {
+    let file = &fw_info.merged_file;
+    let fw = match load_firmware(&dir.join(file), format, options) {
+        Ok(fw) => fw,
+        Err(err) => {
+            report.check(file, "load", false, err.to_string());
+            return;
+        }
+    };
+    for input in &fw_info.extra_inputs {
+        let Some(crc) = input.crc else {
+            continue;
+        };
+        let name = format!("{} @ 0x{:08X}", file, input.address.begin);
+        let begin = input.address.begin;
+        let end = input.address.end;
+        if begin < fw.range.begin || end > fw.range.end {
+            let message = format!("extra input `{}` is not contained in the file", input.name);
+            report.check(&name, "crc", false, message);
+            continue;
+        }
+        let begin = (begin - fw.range.begin) as usize;
+        let end = (end - fw.range.begin) as usize;
+        let stored = fw.read_u32(end - 4);
+        let computed = crc32(&fw.data[begin..end - 4]);
+        let message = format!(
+            "extra input `{}`: info.json 0x{:08X}, stored 0x{:08X}, computed 0x{:08X}",
+            input.name, crc, stored, computed
+        );
+        report.check(&name, "crc", stored == crc && computed == crc, message);
+    }
+}
+
+/// Check a hex file and the binary file with the same content. The binary file
+/// is placed at the start address of the hex file.
+fn check_hex_and_bin(
+    report: &mut VerifyReport,
+    dir: &Path,
+    hex_file: &str,
+    bin_file: &str,
+    format: InputFormat,
+    require_image: bool,
+    options: &InspectOptions,
+) -> Option<(InspectReport, Option<Firmware>)> {
+    let hex = check_file(report, hex_file, &dir.join(hex_file), Some(format), options);
+    if require_image {
+        check_image_found(report, hex_file, &hex);
+    }
+    let Some((_, Some(hex_fw))) = &hex else {
+        return hex;
+    };
+    let bin_options = InspectOptions {
+        base_address: hex_fw.range.begin,
+        ..options.clone()
+    };
+    let bin = check_file(
+        report,
+        bin_file,
+        &dir.join(bin_file),
+        Some(InputFormat::Binary),
+        &bin_options,
+    );
+    if require_image {
+        check_image_found(report, bin_file, &bin);
+    }
+    if let Some((_, Some(bin_fw))) = &bin {
+        compare(report, hex_file, hex_fw, bin_file, bin_fw);
+    }
+    hex
+}
+
+fn check_image_found(
+    report: &mut VerifyReport,
+    file: &str,
+    inspected: &Option<(InspectReport, Option<Firmware>)>,
+) {
+    if let Some((inspected, _)) = inspected {
+        if inspected.images.is_empty() {
+            let message = "no application image with consistent length and CRC found";
+            report.check(file, "content", false, message.to_string());
+        }
+    }
+}
+
+/// Load and inspect a file and run the checks of all images and the trailer.
+///
+/// Returns `None` if the file could not be loaded. The firmware is not returned for
+/// app packages.
+fn check_file(
+    report: &mut VerifyReport,
+    name: &str,
+    path: &Path,
+    format: Option<InputFormat>,
+    options: &InspectOptions,
+) -> Option<(InspectReport, Option<Firmware>)> {
+    let format = match format.map(Ok).unwrap_or_else(|| InputFormat::detect(path)) {
+        Ok(format) => format,
+        Err(err) => {
+            report.check(name, "load", false, err.to_string());
+            return None;
+        }
+    };
+    let loaded = if format == InputFormat::AppPackage {
+        inspect_file(path, options).map(|x| (x, None))
+    } else {
+        load_firmware(path, format, options).and_then(|mut fw| {
+            let inspected = inspect_firmware(&mut fw, format, options)?;
+            Ok((inspected, Some(fw)))
+        })
+    };
+    let (inspected, fw) = match loaded {
+        Ok(x) => x,
+        Err(err) => {
+            report.check(name, "load", false, err.to_string());
+            return None;
+        }
+    };
+    for image in &inspected.images {
+        check_image(report, name, image);
+    }
+    if let Some(trailer) = &inspected.btl_trailer {
+        check_trailer(report, name, trailer);
+    }
+    Some((inspected, fw))
+}
+
+/// Fail unsigned images, which must be signed for the given reason, as well as signed
+/// images whose signature is not verified, unless `options.allow_unverified` is set.
+fn check_signed(
+    report: &mut VerifyReport,
+    file: &str,
+    image: &ImageReport,
+    reason: &str,
+    options: &InspectOptions,
+) {
+    let file = format!("{} @ 0x{:08X}", file, image.address);
+    match image.signature {
+        SignatureStatus::Unsigned => {
+            let message = format!("image is unsigned, but {}", reason);
+            report.check(&file, "signature", false, message);
+        }
+        SignatureStatus::NotVerified if !options.allow_unverified => {
+            let message = format!(
+                "no public key given to verify the signature, but {}",
+                reason
+            );
+            report.check(&file, "signature", false, message);
+        }
+        _ => {}
+    }
+}
+
+fn check_image(report: &mut VerifyReport, file: &str, image: &ImageReport) {
+    let file = format!("{} @ 0x{:08X}", file, image.address);
+    match image.computed_crc {
+        Some(crc) => report.check(
+            &file,
+            "crc",
+            image.crc_valid(),
+            format!("stored 0x{:08X}, computed 0x{:08X}", image.stored_crc, crc),
+        ),
+        None => report.check(
+            &file,
+            "crc",
+            false,
+            format!("cannot compute, length {} exceeds the image", image.length),
+        ),
+    }
+    report.check(
+        &file,
+        "length",
+        image.length == image.data_length,
+        format!("header {}, image data {}", image.length, image.data_length),
+    );
+    match image.signature {
+        SignatureStatus::Unsigned => {}
+        SignatureStatus::NotVerified => {
+            report.add(
+                &file,
+                "signature",
+                CheckStatus::Skipped,
+                "no public key given".to_string(),
+            );
+        }
+        SignatureStatus::UnknownKey => report.check(
+            &file,
+            "key-id",
+            false,
+            format!(
+                "key ID 0x{:08X} does not match any public key",
+                image.key_id
+            ),
+        ),
+        SignatureStatus::RevokedKey => report.check(
+            &file,
+            "key-id",
+            false,
+            format!("key ID 0x{:08X} is revoked", image.key_id),
+        ),
+        SignatureStatus::ExpiredKey => report.check(
+            &file,
+            "key-id",
+            false,
+            format!(
+                "key ID 0x{:08X} is not valid at the build time of the image",
+                image.key_id
+            ),
+        ),
+        SignatureStatus::Valid | SignatureStatus::Invalid => {
+            report.check(
+                &file,
+                "key-id",
+                true,
+                format!("key ID 0x{:08X}", image.key_id),
+            );
+            report.check(
+                &file,
+                "signature",
+                image.signature == SignatureStatus::Valid,
+                format!("{} signature", image.signature_description()),
+            );
+        }
+    }
+    if let Some(package) = &image.package {
+        report.check(
+            &file,
+            "package",
+            package.product_id == image.product_id
+                && package.node_id == image.node_id
+                && package.crc == image.stored_crc,
+            format!(
+                "package lists product 0x{:04X}, node {}, CRC 0x{:08X}",
+                package.product_id, package.node_id, package.crc
+            ),
+        );
+    }
+}
+
+fn check_trailer(report: &mut VerifyReport, file: &str, trailer: &TrailerReport) {
+    let file = format!("{} @ 0x{:08X}", file, trailer.end_address);
+    match (&trailer.trailer, &trailer.error) {
+        (Some(x), _) => report.check(
+            &file,
+            "trailer",
+            true,
+            format!(
+                "version {}, BTL length {}, BTL CRC 0x{:08X}",
+                x.version, x.btl_length, x.btl_crc
+            ),
+        ),
+        (None, error) => report.check(&file, "trailer", false, error.clone().unwrap_or_default()),
+    }
+}
+
+fn overlap(a: &Firmware, b: &Firmware) -> Option<(u64, u64)> {
+    let begin = a.range.begin.max(b.range.begin);
+    let end = a.range.end.min(b.range.end);
+    if begin < end {
+        Some((begin, end))
+    } else {
+        None
+    }
+}
+
+/// Compare two images in their overlapping address range.
+fn compare(report: &mut VerifyReport, name_a: &str, a: &Firmware, name_b: &str, b: &Firmware) {
+    let file = format!("{} <-> {}", name_a, name_b);
+    let Some((begin, end)) = overlap(a, b) else {
+        report.check(
+            &file,
+            "consistency",
+            false,
+            "no overlapping address range".to_string(),
+        );
+        return;
+    };
+    let data_a = &a.data[(begin - a.range.begin) as usize..(end - a.range.begin) as usize];
+    let data_b = &b.data[(begin - b.range.begin) as usize..(end - b.range.begin) as usize];
+    let mismatch = data_a.iter().zip(data_b).position(|(x, y)| x != y);
+    let message = match mismatch {
+        Some(idx) => format!("content differs at 0x{:08X}", begin + idx as u64),
+        None => format!("identical in 0x{:08X} - 0x{:08X}", begin, end),
+    };
+    report.check(&file, "consistency", mismatch.is_none(), message);
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let status = match check.status {
+                CheckStatus::Passed => "ok  ",
+                CheckStatus::Failed => "FAIL",
+                CheckStatus::Skipped => "skip",
+            };
+            writeln!(
+                f,
+                "[{}] {:<12} {}: {}",
+                status, check.name, check.file, check.message
+            )?;
+        }
+        writeln!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.count(CheckStatus::Passed),
+            self.count(CheckStatus::Failed),
+            self.count(CheckStatus::Skipped)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AddressRange, DeviceConfig};
+    use crate::crc::crc32;
+    use crate::ed25519;
+    use crate::header::Header;
+
+    #[test]
+    fn signed_image_is_checked_against_key_ring() {
+        let private_key = ed25519::generate_private_key();
+        let public_key = ed25519::public_key_bytes(&private_key);
+        let data = (0..256_u32).map(|x| (x as u8) | 0x01).collect();
+        let range = AddressRange::new(0, 256);
+        let mut fw = Firmware::new(range.clone(), DeviceConfig::default(), data).unwrap();
+        {
+            let mut header = Header::new(&mut fw, 68).unwrap();
+            header.set_length(256);
+            header.set_key_id(crc32(&public_key));
+        }
+        let crc = crc32(&fw.data[68..256]);
+        fw.write_u32(64, crc);
+        ed25519::sign(&mut fw, &private_key).unwrap();
+        let path = std::env::temp_dir().join("merge_tool_verify_signed.bin");
+        fw.write_binary_to_file(&path).unwrap();
+        let files = vec![path.clone()];
+
+        let statuses = |report: &VerifyReport| {
+            report
+                .checks
+                .iter()
+                .map(|x| (x.name, x.status))
+                .collect::<Vec<_>>()
+        };
+
+        let report = verify_files(&files, &InspectOptions::default());
+        assert!(report.passed);
+        assert!(statuses(&report).contains(&("signature", CheckStatus::Skipped)));
+
+        let other_key = ed25519::public_key_bytes(&ed25519::generate_private_key());
+        let mut options = InspectOptions {
+            public_keys: vec![other_key.to_vec()],
+            ..Default::default()
+        };
+        let report = verify_files(&files, &options);
+        assert!(!report.passed);
+        assert!(statuses(&report).contains(&("key-id", CheckStatus::Failed)));
+
+        options.public_keys.push(public_key.to_vec());
+        let report = verify_files(&files, &options);
+        assert!(report.passed, "{}", report);
+        assert!(statuses(&report).contains(&("signature", CheckStatus::Passed)));
+
+        // an unsigned image with a valid CRC instead of the signed one
+        let data = (0..256_u32).map(|x| (x as u8) | 0x01).collect();
+        let mut fw = Firmware::new(range, DeviceConfig::default(), data).unwrap();
+        Header::new(&mut fw, 4).unwrap().set_length(256);
+        let crc = crc32(&fw.data[4..256]);
+        fw.write_u32(0, crc);
+        fw.write_binary_to_file(&path).unwrap();
+        let report = verify_files(&files, &options);
+        assert!(!report.passed);
+        assert!(statuses(&report).contains(&("signature", CheckStatus::Failed)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
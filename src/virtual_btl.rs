@@ -0,0 +1,502 @@
+//! An in-process simulation of a bootloader speaking the DDP bootload protocol.
+//!
+//! The simulated bootloader implements the state machine described in
+//! `doc/bootload_protocol.md` and reassembles the image data it receives.
+//! This allows running generated scripts against it without any hardware.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::config::Config;
+use crate::crc::{crc16, crc32};
+use crate::ddp::{
+    CMD_DATA, CMD_FINISH, CMD_LEAVE, CMD_NONE, CMD_RESET, CMD_START_TRANSMIT, CMD_VALIDATE, COM_OK,
+    STATE_DONE, STATE_ERR, STATE_IDLE, STATE_NOT_IN_BTL, STATE_RX_DATA, STATE_VALIDATED,
+    STATUS_INCOMPATIBLE, STATUS_INVALID_CMD, STATUS_INVALID_CRC, STATUS_INVALID_FRAME_LENGTH,
+    STATUS_INVALID_LENGTH_IN_HEADER, STATUS_OUT_OF_BOUNDS, STATUS_SUCCESS, STATUS_UNEXPECTED_CMD,
+};
+use crate::firmware::Firmware;
+use crate::header::{Header, HeaderLayout};
+use crate::process::LoadedFirmware;
+use crate::protocol::ANTI_ROLLBACK_BTL_VERSION;
+use crate::script_executor::Transport;
+use crate::stream_transport::{check_frame_crc, encode_frame, FrameDecoder};
+use crate::Error;
+
+/// Product ID sent in the validation data to skip the compatibility check.
+const BACKDOOR_PRODUCT_ID: u16 = 0xFFFF;
+
+/// A single node (MCU) of the simulated system.
+pub struct VirtualNode {
+    pub node_id: u8,
+    pub product_id: u32,
+    pub major_version: u16,
+    pub btl_version: u8,
+    /// Lowest security version accepted, raised by each image written successfully. Only
+    /// checked by bootloaders as of [`ANTI_ROLLBACK_BTL_VERSION`].
+    pub security_version: u16,
+    pub crc_offset: usize,
+    pub header_offset: u64,
+    pub header_layout: HeaderLayout,
+    state: u8,
+    status: u8,
+    image: Firmware,
+    /// App regions, selected by address spaces 1 and above.
+    regions: Vec<Firmware>,
+    /// The address space written by data commands.
+    address_space: usize,
+    /// Security version of the image being transmitted.
+    pending_security_version: u16,
+}
+
+impl VirtualNode {
+    /// Create a node with an erased flash region of the same size and layout as `app`.
+    pub fn new(node_id: u8, app: &Firmware) -> Self {
+        let mut image = app.clone();
+        image.erase();
+        Self {
+            node_id,
+            product_id: 0,
+            major_version: 0,
+            btl_version: 1,
+            security_version: 0,
+            crc_offset: 0,
+            header_offset: 4,
+            header_layout: HeaderLayout::v1(),
+            state: STATE_NOT_IN_BTL,
+            status: STATUS_SUCCESS,
+            image,
+            regions: Vec::new(),
+            address_space: 0,
+            pending_security_version: 0,
+        }
+    }
+
+    /// Add an erased app region of the same size and layout as `region`. Its address space
+    /// is the number of regions added so far.
+    pub fn add_region(&mut self, region: &Firmware) {
+        let mut region = region.clone();
+        region.erase();
+        self.regions.push(region);
+    }
+
+    /// Create a node which accepts the image described by `loaded`.
+    pub fn from_loaded_firmware(config: &Config, loaded: &LoadedFirmware) -> Self {
+        let mut ret = Self::new(loaded.config.node_id, &loaded.app);
+        ret.product_id = config.product_id;
+        ret.major_version = loaded
+            .config
+            .version
+            .as_ref()
+            .map(|x| x.major as u16)
+            .unwrap_or(0);
+        ret.btl_version = config.btl_version;
+        ret.crc_offset = loaded.config.crc_offset();
+        ret.header_offset = loaded.config.header_offset;
+        ret.header_layout = config
+            .header_layout(&loaded.config)
+            .cloned()
+            .unwrap_or_default();
+        for region in &loaded.regions {
+            ret.add_region(&region.fw);
+        }
+        ret
+    }
+
+    /// The flash content as written by the bootload process.
+    pub fn image(&self) -> &Firmware {
+        &self.image
+    }
+
+    /// The content of the app region with address space `address_space`, starting at 1.
+    pub fn region(&self, address_space: usize) -> Option<&Firmware> {
+        self.regions.get(address_space.checked_sub(1)?)
+    }
+
+    pub fn state(&self) -> u8 {
+        self.state
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    fn fail(&mut self, status: u8) {
+        // the error code is latched until the next reset
+        if self.status == STATUS_SUCCESS {
+            self.status = status;
+        }
+        self.state = STATE_ERR;
+    }
+
+    fn handle(&mut self, cmd: u8, data: &[u8]) {
+        if cmd == CMD_RESET {
+            self.state = STATE_IDLE;
+            self.status = STATUS_SUCCESS;
+            return;
+        }
+        if self.state == STATE_NOT_IN_BTL || cmd == CMD_NONE {
+            return;
+        }
+        match (cmd, self.state) {
+            (CMD_VALIDATE, STATE_IDLE) => self.validate(data),
+            (CMD_START_TRANSMIT, STATE_VALIDATED | STATE_RX_DATA) => self.start_transmit(data),
+            (CMD_DATA, STATE_RX_DATA) => self.write_data(data),
+            (CMD_FINISH, STATE_RX_DATA) => self.check_image(),
+            (CMD_LEAVE, STATE_DONE) => self.state = STATE_NOT_IN_BTL,
+            (CMD_VALIDATE, _)
+            | (CMD_START_TRANSMIT, _)
+            | (CMD_DATA, _)
+            | (CMD_FINISH, _)
+            | (CMD_LEAVE, _) => self.fail(STATUS_UNEXPECTED_CMD),
+            _ => self.fail(STATUS_INVALID_CMD),
+        }
+    }
+
+    fn validate(&mut self, data: &[u8]) {
+        let anti_rollback = self.btl_version >= ANTI_ROLLBACK_BTL_VERSION;
+        if data.len() != if anti_rollback { 7 } else { 5 } {
+            return self.fail(STATUS_INVALID_FRAME_LENGTH);
+        }
+        let product_id = LittleEndian::read_u16(&data[0..2]);
+        let major_version = LittleEndian::read_u16(&data[2..4]);
+        let btl_version = data[4];
+        // the validation data carries the lower 16 bits of the product ID
+        let product_ok = product_id == self.product_id as u16 || product_id == BACKDOOR_PRODUCT_ID;
+        if !product_ok || major_version != self.major_version || btl_version != self.btl_version {
+            return self.fail(STATUS_INCOMPATIBLE);
+        }
+        if anti_rollback {
+            let security_version = LittleEndian::read_u16(&data[5..7]);
+            if security_version < self.security_version {
+                return self.fail(STATUS_INCOMPATIBLE);
+            }
+            self.pending_security_version = security_version;
+        }
+        self.state = STATE_VALIDATED;
+    }
+
+    /// Erase the application or, if an address space is given, an app region.
+    fn start_transmit(&mut self, data: &[u8]) {
+        let address_space = match data {
+            [] => 0,
+            [x] => *x as usize,
+            _ => return self.fail(STATUS_INVALID_FRAME_LENGTH),
+        };
+        if address_space > self.regions.len() {
+            return self.fail(STATUS_OUT_OF_BOUNDS);
+        }
+        self.address_space = address_space;
+        self.target().erase();
+        self.state = STATE_RX_DATA;
+    }
+
+    /// The memory of the selected address space.
+    fn target(&mut self) -> &mut Firmware {
+        match self.address_space {
+            0 => &mut self.image,
+            k => &mut self.regions[k - 1],
+        }
+    }
+
+    fn write_data(&mut self, data: &[u8]) {
+        if data.len() < 4 {
+            return self.fail(STATUS_INVALID_FRAME_LENGTH);
+        }
+        let offset = LittleEndian::read_u32(&data[0..4]) as usize;
+        let data = &data[4..];
+        let target = self.target();
+        if offset + data.len() > target.data.len() {
+            return self.fail(STATUS_OUT_OF_BOUNDS);
+        }
+        target.write(offset, data);
+    }
+
+    fn check_image(&mut self) {
+        if self.address_space != 0 {
+            // the application must be transmitted last
+            return self.fail(STATUS_UNEXPECTED_CMD);
+        }
+        let header = Header::with_layout(&mut self.image, self.header_offset, &self.header_layout);
+        let length = match header {
+            Ok(header) => header.length() as usize,
+            Err(_) => return self.fail(STATUS_INVALID_LENGTH_IN_HEADER),
+        };
+        if length > self.image.data.len() || length < self.crc_offset + 4 {
+            return self.fail(STATUS_INVALID_LENGTH_IN_HEADER);
+        }
+        let crc = crc32(&self.image.data[self.crc_offset + 4..length]);
+        if crc != self.image.read_u32(self.crc_offset) {
+            return self.fail(STATUS_INVALID_CRC);
+        }
+        self.security_version = self.security_version.max(self.pending_security_version);
+        self.state = STATE_DONE;
+    }
+}
+
+/// A simulated system of one or more bootloader nodes behind a single DDP endpoint.
+///
+/// Implements [`Transport`] such that scripts can be run against it. Sleeps are not
+/// performed but accumulated in [`VirtualBootloader::elapsed_ms`].
+pub struct VirtualBootloader {
+    ddp_code: u8,
+    nodes: Vec<VirtualNode>,
+    elapsed_ms: u64,
+}
+
+impl VirtualBootloader {
+    pub fn new(ddp_code: u8) -> Self {
+        Self {
+            ddp_code,
+            nodes: Vec::new(),
+            elapsed_ms: 0,
+        }
+    }
+
+    pub fn add_node(&mut self, node: VirtualNode) {
+        self.nodes.push(node);
+    }
+
+    pub fn node(&self, node_id: u8) -> Option<&VirtualNode> {
+        self.nodes.iter().find(|x| x.node_id == node_id)
+    }
+
+    pub fn elapsed_ms(&self) -> u64 {
+        self.elapsed_ms
+    }
+
+    /// Process a request frame and return the response frame, if one was requested.
+    pub fn process(&mut self, frame: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        if frame.len() < 5 || !check_frame_crc(frame) {
+            return Err(Error::InvalidDdpFrame);
+        }
+        let payload = &frame[..frame.len() - 2];
+        if payload[0] & 0x7F != self.ddp_code {
+            return Err(Error::InvalidDdpFrame);
+        }
+        let response_requested = payload[0] & 0x80 != 0;
+        let node_id = payload[1];
+        let Some(node) = self.nodes.iter_mut().find(|x| x.node_id == node_id) else {
+            return Ok(None);
+        };
+        node.handle(payload[2], &payload[3..]);
+        if !response_requested {
+            return Ok(None);
+        }
+        let mut response = vec![COM_OK, node_id, node.state, node.status];
+        let crc = crc16(&response);
+        response.push((crc >> 8) as u8);
+        response.push((crc & 0xFF) as u8);
+        Ok(Some(response))
+    }
+
+    /// Serve requests received on a byte stream until the connection is closed.
+    ///
+    /// Frames are delimited as described in [`crate::stream_transport`]. Invalid frames
+    /// are dropped, as a real device would do.
+    pub fn serve<S: Read + Write>(&mut self, mut stream: S) -> crate::Result<()> {
+        let mut decoder = FrameDecoder::default();
+        let mut buf = [0_u8; 256];
+        loop {
+            let n = stream.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            for x in &buf[..n] {
+                let Some(frame) = decoder.push(*x) else {
+                    continue;
+                };
+                if let Ok(Some(response)) = self.process(&frame) {
+                    stream.write_all(&encode_frame(&response))?;
+                }
+            }
+        }
+    }
+}
+
+impl Transport for VirtualBootloader {
+    fn write(&mut self, data: &[u8]) -> crate::Result<()> {
+        self.process(data).map(|_| ())
+    }
+
+    fn query(&mut self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        self.process(data)?.ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no response from bootloader",
+            ))
+        })
+    }
+
+    fn sleep(&mut self, ms: u32) {
+        self.elapsed_ms += ms as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AddressRange, DeviceConfig};
+    use crate::ddp;
+
+    const DDP_CODE: u8 = 0x10;
+
+    fn make_node() -> VirtualNode {
+        let app = Firmware::new(
+            AddressRange::new(0, 128),
+            DeviceConfig::default(),
+            vec![0xFF; 128],
+        )
+        .unwrap();
+        let mut node = VirtualNode::new(1, &app);
+        node.product_id = 0x605;
+        node.major_version = 3;
+        node
+    }
+
+    fn request(btl: &mut VirtualBootloader, data: Vec<u8>) -> Vec<u8> {
+        match ddp::query(data, vec![]) {
+            crate::script_cmd::Command::Query(tx, _) => btl.query(&tx).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn rejects_incompatible_product() {
+        let mut btl = VirtualBootloader::new(DDP_CODE);
+        btl.add_node(make_node());
+        request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_RESET]);
+        let response = request(
+            &mut btl,
+            vec![DDP_CODE | 0x80, 1, CMD_VALIDATE, 1, 2, 3, 0, 1],
+        );
+        assert_eq!(&response[..4], &[COM_OK, 1, STATE_ERR, STATUS_INCOMPATIBLE]);
+
+        // backdoor skips the product check
+        request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_RESET]);
+        let response = request(
+            &mut btl,
+            vec![DDP_CODE | 0x80, 1, CMD_VALIDATE, 0xFF, 0xFF, 3, 0, 1],
+        );
+        assert_eq!(
+            &response[..4],
+            &[COM_OK, 1, STATE_VALIDATED, STATUS_SUCCESS]
+        );
+    }
+
+    #[test]
+    fn rejects_rollback_of_security_version() {
+        let mut node = make_node();
+        node.btl_version = ANTI_ROLLBACK_BTL_VERSION;
+        node.security_version = 2;
+        let mut btl = VirtualBootloader::new(DDP_CODE);
+        btl.add_node(node);
+        let validate = |security_version: u8| {
+            vec![
+                DDP_CODE | 0x80,
+                1,
+                CMD_VALIDATE,
+                5,
+                6,
+                3,
+                0,
+                3,
+                security_version,
+                0,
+            ]
+        };
+
+        request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_RESET]);
+        let response = request(&mut btl, validate(1));
+        assert_eq!(&response[..4], &[COM_OK, 1, STATE_ERR, STATUS_INCOMPATIBLE]);
+
+        request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_RESET]);
+        let response = request(&mut btl, validate(2));
+        assert_eq!(
+            &response[..4],
+            &[COM_OK, 1, STATE_VALIDATED, STATUS_SUCCESS]
+        );
+
+        // the old 5-byte validation data is not accepted anymore
+        request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_RESET]);
+        let response = request(
+            &mut btl,
+            vec![DDP_CODE | 0x80, 1, CMD_VALIDATE, 5, 6, 3, 0, 3],
+        );
+        assert_eq!(
+            &response[..4],
+            &[COM_OK, 1, STATE_ERR, STATUS_INVALID_FRAME_LENGTH]
+        );
+    }
+
+    #[test]
+    fn rejects_image_with_invalid_crc() {
+        let mut btl = VirtualBootloader::new(DDP_CODE);
+        btl.add_node(make_node());
+        request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_RESET]);
+        request(
+            &mut btl,
+            vec![DDP_CODE | 0x80, 1, CMD_VALIDATE, 5, 6, 3, 0, 1],
+        );
+        request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_START_TRANSMIT]);
+        // length field of the header at offset 4 + 12 = 64, but CRC is left erased
+        let mut data = vec![DDP_CODE | 0x80, 1, CMD_DATA, 16, 0, 0, 0];
+        data.extend([64, 0, 0, 0]);
+        let response = request(&mut btl, data);
+        assert_eq!(&response[..4], &[COM_OK, 1, STATE_RX_DATA, STATUS_SUCCESS]);
+        let response = request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_FINISH]);
+        assert_eq!(&response[..4], &[COM_OK, 1, STATE_ERR, STATUS_INVALID_CRC]);
+    }
+
+    #[test]
+    fn writes_app_regions_to_their_address_space() {
+        let mut node = make_node();
+        let region = Firmware::new(
+            AddressRange::new(0x9000_0000, 0x9000_0040),
+            DeviceConfig::default(),
+            vec![0xFF; 64],
+        )
+        .unwrap();
+        node.add_region(&region);
+        let mut btl = VirtualBootloader::new(DDP_CODE);
+        btl.add_node(node);
+        request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_RESET]);
+        request(
+            &mut btl,
+            vec![DDP_CODE | 0x80, 1, CMD_VALIDATE, 5, 6, 3, 0, 1],
+        );
+        request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_START_TRANSMIT, 1]);
+        request(
+            &mut btl,
+            vec![DDP_CODE | 0x80, 1, CMD_DATA, 0x10, 0, 0, 0, 0xAB],
+        );
+        let response = request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_FINISH]);
+        assert_eq!(
+            &response[..4],
+            &[COM_OK, 1, STATE_ERR, STATUS_UNEXPECTED_CMD]
+        );
+        let node = btl.node(1).unwrap();
+        assert_eq!(node.region(1).unwrap().data[0x10], 0xAB);
+        assert!(node.image().segments().is_empty());
+
+        request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_RESET]);
+        request(
+            &mut btl,
+            vec![DDP_CODE | 0x80, 1, CMD_VALIDATE, 5, 6, 3, 0, 1],
+        );
+        let response = request(&mut btl, vec![DDP_CODE | 0x80, 1, CMD_START_TRANSMIT, 2]);
+        assert_eq!(
+            &response[..4],
+            &[COM_OK, 1, STATE_ERR, STATUS_OUT_OF_BOUNDS]
+        );
+    }
+
+    #[test]
+    fn rejects_frame_with_invalid_crc() {
+        let mut btl = VirtualBootloader::new(DDP_CODE);
+        btl.add_node(make_node());
+        let ret = btl.query(&[DDP_CODE | 0x80, 1, CMD_RESET, 0x12, 0x34]);
+        assert!(matches!(ret, Err(Error::InvalidDdpFrame)));
+    }
+}
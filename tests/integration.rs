@@ -1,21 +1,41 @@
 use std::fs::{self, File};
 use std::io::Write;
 use std::iter::repeat;
+use std::net::TcpListener;
 use std::path::{Path, PathBuf};
 
 use byteorder::{ByteOrder, LittleEndian};
 use chrono::{DateTime, Utc};
 use merge_tool::app_package::AppPackage;
-use merge_tool::btl_trailer;
-use merge_tool::config::{AddressRange, Config, DeviceConfig};
+use merge_tool::btl_trailer::{self, BtlTrailer, TrailerField};
+use merge_tool::config::{
+    AddressCheck, AddressRange, AppRegion, BtlTrailerConfig, Config, DeviceConfig, ExtraInput,
+    HexFileFormat, RecordOptions, SecurityVersion, SignatureType, DDP_CMD_CODE,
+};
 use merge_tool::crc::crc32;
+use merge_tool::ddp;
+use merge_tool::detached::{self, DigestFile};
+use merge_tool::ecdsa_p256;
 use merge_tool::ed25519;
+use merge_tool::extract::{self, ExtractedValue};
 use merge_tool::firmware::Firmware;
-use merge_tool::header::Header;
+use merge_tool::git_description::retrieve_description;
+use merge_tool::header::{Header, HeaderLayout};
+use merge_tool::inspect::{self, InputFormat, InspectOptions, SignatureStatus};
 use merge_tool::intel_hex;
-use merge_tool::process;
+use merge_tool::key_ring::{KeyRing, KeyRingEntry};
+use merge_tool::process::{self, GenerateOptions};
+use merge_tool::script::Script;
+use merge_tool::script_executor::{self, LogListener};
+use merge_tool::signer::{KeySigner, Keystore, KeystoreEntry, SignerConfig};
+use merge_tool::srecord;
+use merge_tool::stream_transport::StreamTransport;
+use merge_tool::ti_txt;
+use merge_tool::verify::{self, CheckStatus};
+use merge_tool::virtual_btl::{VirtualBootloader, VirtualNode};
+use semver::Version;
 use serial_test::serial;
-use sha2::{Digest, Sha512};
+use sha2::{Digest, Sha256, Sha512};
 
 fn save_hex(path: &str, data: &[u8], range: &AddressRange) {
     let serialized = intel_hex::serialize(false, range, data);
@@ -169,6 +189,69 @@ fn script() {
     process::save_script(&script, &loaded, &test.output_dir).unwrap();
 }
 
+fn run_script_on_virtual_bootloader(blocking: bool) {
+    let mut test = IntegrationTest::new();
+    test.config.blocking = blocking;
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    let script = process::create_script(&loaded).unwrap();
+
+    let mut btl = VirtualBootloader::new(DDP_CMD_CODE);
+    for fw in &loaded.images {
+        btl.add_node(VirtualNode::from_loaded_firmware(&loaded.config, fw));
+    }
+    script_executor::execute(&script, &mut btl, &mut LogListener).unwrap();
+
+    for fw in &loaded.images {
+        let node = btl.node(fw.config.node_id).unwrap();
+        assert_eq!(node.state(), ddp::STATE_NOT_IN_BTL);
+        assert_eq!(node.image().data, fw.app.data);
+    }
+}
+
+#[test]
+#[serial]
+fn script_runs_on_virtual_bootloader() {
+    run_script_on_virtual_bootloader(false);
+}
+
+#[test]
+#[serial]
+fn blocking_script_runs_on_virtual_bootloader() {
+    run_script_on_virtual_bootloader(true);
+}
+
+#[test]
+#[serial]
+fn script_runs_over_tcp() {
+    let test = IntegrationTest::new();
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    let script = process::create_script(&loaded).unwrap();
+    let script = Script::parse(&script.serialize()).unwrap();
+    script.verify().unwrap();
+
+    let mut btl = VirtualBootloader::new(DDP_CMD_CODE);
+    for fw in &loaded.images {
+        btl.add_node(VirtualNode::from_loaded_firmware(&loaded.config, fw));
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        btl.serve(stream).unwrap();
+        btl
+    });
+
+    let mut transport = StreamTransport::connect_tcp(&addr).unwrap();
+    script_executor::execute(&script, &mut transport, &mut LogListener).unwrap();
+    drop(transport);
+
+    let btl = server.join().unwrap();
+    for fw in &loaded.images {
+        let node = btl.node(fw.config.node_id).unwrap();
+        assert_eq!(node.image().data, fw.app.data);
+    }
+}
+
 #[test]
 #[serial]
 fn hex_images() {
@@ -178,7 +261,7 @@ fn hex_images() {
 
     for fw in &loaded.images {
         let id = fw.config.node_id;
-        let ext = fw.config.hex_file_format.file_extension();
+        let ext = fw.config.output_formats()[0].format.file_extension();
         assert!(test
             .output_dir
             .join(format!("app_f{}.{}", id, ext))
@@ -376,6 +459,403 @@ fn ed25519_sign_and_verify() {
     assert!(ed25519::verify(&fw, found_pub_key).is_err());
 }
 
+fn load_signed(
+    test: &IntegrationTest,
+    signer: SignerConfig,
+) -> Result<Firmware, merge_tool::Error> {
+    let mut config = test.config.clone();
+    config.product_id = 0;
+    config.signer = signer;
+    config.images.truncate(1);
+    config.images[0].signature_type = SignatureType::Ed25519;
+    config.images[0].header_offset = HEADER_OFFSET;
+    let mut loaded = process::load_firmware_images(&config, &test.config_dir, None)?;
+    Ok(loaded.images.remove(0).app)
+}
+
+#[test]
+#[serial]
+fn signers() {
+    let test = IntegrationTest::new();
+    let private_key = ed25519::generate_private_key();
+    let public_key = ed25519::public_key_bytes(&private_key);
+
+    // external command
+    let command = SignerConfig::Command {
+        command: vec!["./mock_signer.sh".to_string(), hex::encode(private_key)],
+        public_key: hex::encode(public_key),
+    };
+    let mut fw = load_signed(&test, command).unwrap();
+    ed25519::verify(&fw, &public_key).unwrap();
+    let header = Header::new(&mut fw, HEADER_OFFSET).unwrap();
+    assert_eq!(header.key_id(), crc32(&public_key));
+
+    // the signature must match the configured public key
+    let other_key = ed25519::generate_private_key();
+    let command = SignerConfig::Command {
+        command: vec!["./mock_signer.sh".to_string(), hex::encode(other_key)],
+        public_key: hex::encode(public_key),
+    };
+    assert!(matches!(
+        load_signed(&test, command),
+        Err(merge_tool::Error::InvalidSignature)
+    ));
+    let command = SignerConfig::Command {
+        command: vec!["false".to_string()],
+        public_key: hex::encode(public_key),
+    };
+    assert!(matches!(
+        load_signed(&test, command),
+        Err(merge_tool::Error::SignerFailed(_))
+    ));
+
+    // keystore
+    let keystore_path = test.output_dir.join("keystore.json");
+    let keystore = format!(
+        r#"{{ "keys": [{{ "label": "other", "private_key": "{}" }}, {{ "label": "release", "private_key": "{}" }}] }}"#,
+        hex::encode(other_key),
+        hex::encode(private_key)
+    );
+    fs::write(&keystore_path, keystore).unwrap();
+    let keystore = SignerConfig::Keystore {
+        path: keystore_path.to_str().unwrap().to_string(),
+        label: "release".to_string(),
+    };
+    let fw_keystore = load_signed(&test, keystore).unwrap();
+    assert_eq!(fw_keystore.data, fw.data);
+}
+
+#[test]
+#[serial]
+fn ecdsa_p256_signed_image() {
+    let test = IntegrationTest::new();
+    let private_key = ecdsa_p256::generate_private_key();
+    let public_key = ecdsa_p256::public_key_bytes(&private_key).unwrap();
+
+    let mut config = test.config.clone();
+    config.product_id = 0;
+    config.images.truncate(1);
+    config.images[0].signature_type = SignatureType::EcdsaP256;
+    config.images[0].header_offset = HEADER_OFFSET;
+
+    // the Ed25519 key is not used for P-256 signatures
+    config.ed25519_private_key = Some(ed25519::generate_private_key());
+    let err = process::load_firmware_images(&config, &test.config_dir, None)
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err, merge_tool::Error::InvalidConfig(_)),
+        "{}",
+        err
+    );
+
+    config.ecdsa_p256_private_key = Some(private_key);
+    let options = GenerateOptions {
+        config,
+        output_dir: test.output_dir.clone(),
+        config_dir: test.config_dir.clone(),
+        repo_dir: None,
+        emit_digests: false,
+    };
+    process::generate(options).unwrap();
+
+    // same layout as Ed25519, signature over SHA-256([64..image_len])
+    let info = process::Info::load_from_file(&test.output_dir.join("info.json")).unwrap();
+    let package_path = test.output_dir.join(&info.package_file);
+    let package = AppPackage::load_from_file(&package_path).unwrap();
+    assert_eq!(package.app[0].signature_type, SignatureType::EcdsaP256);
+    let mut fw = inspect::load_firmware(
+        &test.output_dir.join(&info.images[0].app_file),
+        InputFormat::IntelHex,
+        &Default::default(),
+    )
+    .unwrap();
+    fw.data.truncate(fw.image_length());
+    let mut sha = Sha256::new();
+    Digest::input(&mut sha, &fw.data[CRC_OFFSET..]);
+    let digest: [u8; 32] = sha.result().into();
+    let mut signature = [0; 64];
+    signature.copy_from_slice(&fw.data[..64]);
+    ecdsa_p256::verify_digest(&digest, &signature, &public_key).unwrap();
+    let header = Header::new(&mut fw, HEADER_OFFSET).unwrap();
+    assert_eq!(header.key_id(), crc32(&public_key));
+
+    let options = InspectOptions {
+        public_keys: vec![public_key.to_vec()],
+        ..Default::default()
+    };
+    let info_path = test.output_dir.join("info.json");
+    let report = verify::verify_info(&info_path, &options).unwrap();
+    assert!(report.passed, "{}", report);
+    let report = inspect::inspect_file(&package_path, &options).unwrap();
+    assert_eq!(report.images[0].signature, SignatureStatus::Valid);
+
+    // without a public key the signatures of the signed images cannot be checked
+    let mut options = InspectOptions::default();
+    let report = verify::verify_info(&info_path, &options).unwrap();
+    assert!(!report.passed);
+    assert!(report
+        .checks
+        .iter()
+        .all(|x| x.status != CheckStatus::Failed || x.name == "signature"));
+    options.allow_unverified = true;
+    let report = verify::verify_info(&info_path, &options).unwrap();
+    assert!(report.passed, "{}", report);
+    assert!(report.count(CheckStatus::Skipped) > 0);
+}
+
+#[test]
+#[serial]
+fn key_ring_selects_key_by_key_id() {
+    let test = IntegrationTest::new();
+    let time = |x: &str| DateTime::parse_from_rfc3339(x).unwrap().with_timezone(&Utc);
+    let private_key = ed25519::generate_private_key();
+    let public_key = ed25519::public_key_bytes(&private_key);
+
+    let mut config = test.config.clone();
+    config.product_id = 0;
+    config.build_time = time("2024-06-01T00:00:00Z");
+    config.ed25519_private_key = Some(private_key);
+    config.images.truncate(1);
+    config.images[0].signature_type = SignatureType::Ed25519;
+    config.images[0].header_offset = HEADER_OFFSET;
+    let options = GenerateOptions {
+        config,
+        output_dir: test.output_dir.clone(),
+        config_dir: test.config_dir.clone(),
+        repo_dir: None,
+        emit_digests: false,
+    };
+    process::generate(options).unwrap();
+
+    let mut ring = KeyRing::default();
+    let other_key = ed25519::public_key_bytes(&ed25519::generate_private_key());
+    ring.add(KeyRingEntry::new("old", &hex::encode(other_key)).unwrap())
+        .unwrap();
+    let mut entry = KeyRingEntry::new("release", &hex::encode(public_key)).unwrap();
+    entry.valid_from = Some(time("2024-01-01T00:00:00Z"));
+    ring.add(entry).unwrap();
+    let ring_path = test.output_dir.join("key_ring.json");
+    ring.save_to_file(&ring_path).unwrap();
+    let mut options = InspectOptions {
+        key_ring: KeyRing::load_from_file(&ring_path).unwrap(),
+        ..Default::default()
+    };
+    let info_path = test.output_dir.join("info.json");
+    let signature_checks = |options: &InspectOptions| {
+        let report = verify::verify_info(&info_path, options).unwrap();
+        let passed = report.passed;
+        let checks: Vec<_> = report
+            .checks
+            .into_iter()
+            .filter(|x| x.name == "signature" || x.name == "key-id")
+            .map(|x| x.status)
+            .collect();
+        (passed, checks)
+    };
+    let (passed, checks) = signature_checks(&options);
+    assert!(passed);
+    assert!(!checks.is_empty());
+    assert!(checks.iter().all(|x| *x == CheckStatus::Passed));
+
+    // the image was built before the key became valid
+    options.key_ring.keys[1].valid_from = Some(time("2025-01-01T00:00:00Z"));
+    assert!(!signature_checks(&options).0);
+    options.key_ring.keys[1].valid_from = None;
+
+    options.key_ring.revoke("release").unwrap();
+    let (passed, checks) = signature_checks(&options);
+    assert!(!passed);
+    assert!(checks.contains(&CheckStatus::Failed));
+}
+
+#[test]
+#[serial]
+fn detached_signatures() {
+    let test = IntegrationTest::new();
+    let private_key = ed25519::generate_private_key();
+    let public_key = ed25519::public_key_bytes(&private_key);
+
+    let mut config = test.config.clone();
+    config.product_id = 0;
+    config.build_time = DateTime::<Utc>::from_timestamp(0, 0).unwrap();
+    config.signer = SignerConfig::PublicKey {
+        public_key: hex::encode(public_key),
+    };
+    config.images.truncate(1);
+    config.images[0].signature_type = SignatureType::Ed25519;
+    config.images[0].header_offset = HEADER_OFFSET;
+    let mut options = GenerateOptions {
+        config,
+        output_dir: test.output_dir.clone(),
+        config_dir: test.config_dir.clone(),
+        repo_dir: None,
+        emit_digests: false,
+    };
+    // the private key is not known
+    assert!(matches!(
+        process::generate(options.clone()),
+        Err(merge_tool::Error::SignerFailed(_))
+    ));
+
+    options.emit_digests = true;
+    process::generate(options.clone()).unwrap();
+    let digest_path = test.output_dir.join(detached::DIGEST_FILE_NAME);
+    let mut digests = DigestFile::load_from_file(&digest_path).unwrap();
+    assert_eq!(digests.images.len(), 1);
+    assert_eq!(digests.images[0].key_id, crc32(&public_key));
+    assert!(digests.images[0].signature.is_none());
+
+    // signing on a separate machine, with the wrong and the right key
+    let other = KeySigner::new(SignatureType::Ed25519, ed25519::generate_private_key());
+    assert!(detached::sign_digests(&mut digests.clone(), &other).is_err());
+    detached::sign_digests(
+        &mut digests,
+        &KeySigner::new(SignatureType::Ed25519, private_key),
+    )
+    .unwrap();
+
+    options.emit_digests = false;
+    let report = detached::apply_signatures(&options, &digests).unwrap();
+    assert!(report.passed, "{}", report);
+    assert!(report
+        .checks
+        .iter()
+        .any(|x| x.name == "signature" && x.status == CheckStatus::Passed));
+    let app_file = format!("app_f{}.hex", digests.images[0].fw_id);
+    let mut fw = inspect::load_firmware(
+        &test.output_dir.join(app_file),
+        InputFormat::IntelHex,
+        &Default::default(),
+    )
+    .unwrap();
+    fw.data.truncate(fw.image_length());
+    ed25519::verify(&fw, &public_key).unwrap();
+    let script = fs::read_to_string(test.output_dir.join("Nimbus2000.gctbtl")).unwrap();
+    assert!(script.contains(&hex::encode_upper(&fw.data[..16])));
+
+    // a different image is rejected
+    options.config.images[0].version = Some(Version::new(9, 0, 0));
+    let err = detached::apply_signatures(&options, &digests)
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err, merge_tool::Error::DetachedSignature(_)),
+        "{}",
+        err
+    );
+}
+
+#[test]
+#[serial]
+fn detached_signatures_of_two_keys() {
+    let test = IntegrationTest::new();
+    let private_keys = [
+        ed25519::generate_private_key(),
+        ed25519::generate_private_key(),
+    ];
+    let public_keys = private_keys.map(|x| ed25519::public_key_bytes(&x));
+
+    let mut config = test.config.clone();
+    config.product_id = 0;
+    config.build_time = DateTime::<Utc>::from_timestamp(0, 0).unwrap();
+    config.signer = SignerConfig::PublicKey {
+        public_key: hex::encode(public_keys[0]),
+    };
+    config.images.truncate(2);
+    for (idx, fw_config) in config.images.iter_mut().enumerate() {
+        // applications with room for the signature in front of the CRC and the header
+        let mut data = vec![0x5A; 0x80];
+        data[HEADER_OFFSET as usize + 2] = idx as u8 + 1; // firmware id
+        data[HEADER_OFFSET as usize + 4] = 1; // major
+        let app_path = test.output_dir.join(format!("signed_app_f{}.hex", idx + 1));
+        save_hex(app_path.to_str().unwrap(), &data, &fw_config.app_address);
+        fw_config.app_path = app_path.to_str().unwrap().to_string();
+        fw_config.signature_type = SignatureType::Ed25519;
+        fw_config.header_offset = HEADER_OFFSET;
+    }
+    config.images[1].signer = Some(SignerConfig::PublicKey {
+        public_key: hex::encode(public_keys[1]),
+    });
+    let mut options = GenerateOptions {
+        config,
+        output_dir: test.output_dir.clone(),
+        config_dir: test.config_dir.clone(),
+        repo_dir: None,
+        emit_digests: true,
+    };
+    process::generate(options.clone()).unwrap();
+    let digest_path = test.output_dir.join(detached::DIGEST_FILE_NAME);
+    let mut digests = DigestFile::load_from_file(&digest_path).unwrap();
+    let key_ids: Vec<_> = digests.images.iter().map(|x| x.key_id).collect();
+    assert_eq!(
+        key_ids,
+        vec![crc32(&public_keys[0]), crc32(&public_keys[1])]
+    );
+
+    // each key signs its own image only
+    let signer = KeySigner::new(SignatureType::Ed25519, private_keys[0]);
+    assert_eq!(detached::sign_digests(&mut digests, &signer).unwrap(), 1);
+    assert_eq!(digests.unsigned_images().len(), 1);
+    options.emit_digests = false;
+    let err = detached::apply_signatures(&options, &digests)
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err, merge_tool::Error::DetachedSignature(_)),
+        "{}",
+        err
+    );
+
+    let signer = KeySigner::new(SignatureType::Ed25519, private_keys[1]);
+    assert_eq!(detached::sign_digests(&mut digests, &signer).unwrap(), 1);
+    assert!(digests.unsigned_images().is_empty());
+    let report = detached::apply_signatures(&options, &digests).unwrap();
+    assert!(report.passed, "{}", report);
+    for (image, public_key) in digests.images.iter().zip(&public_keys) {
+        let app_file = format!("app_f{}.hex", image.fw_id);
+        let mut fw = inspect::load_firmware(
+            &test.output_dir.join(app_file),
+            InputFormat::IntelHex,
+            &Default::default(),
+        )
+        .unwrap();
+        fw.data.truncate(fw.image_length());
+        ed25519::verify(&fw, public_key).unwrap();
+    }
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn keystore_file_is_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let test = IntegrationTest::new();
+    let path = test.output_dir.join("keys.json");
+    let mut keystore = Keystore::default();
+    let private_key = ed25519::generate_private_key();
+    keystore
+        .add(
+            KeystoreEntry::encrypted("release", SignatureType::Ed25519, &private_key, "secret")
+                .unwrap(),
+        )
+        .unwrap();
+    keystore.save_to_file(&path).unwrap();
+    keystore
+        .add(
+            KeystoreEntry::encrypted("other", SignatureType::Ed25519, &private_key, "secret")
+                .unwrap(),
+        )
+        .unwrap();
+    keystore.save_to_file(&path).unwrap();
+
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(Keystore::load_from_file(&path).unwrap().keys.len(), 2);
+    assert!(!test.output_dir.join("keys.json.tmp").exists());
+}
+
 #[test]
 #[serial]
 fn btl_trailer_is_written_to_merged_image() {
@@ -431,3 +911,886 @@ fn btl_trailer_is_written_to_merged_image() {
         "trailer should not be present when btl_trailer is false"
     );
 }
+
+#[test]
+#[serial]
+fn btl_trailer_v2_is_written_to_bootloader() {
+    let mut test = IntegrationTest::new();
+    let public_key = [0x42_u8; 32];
+    test.config.images[0].btl_trailer_v2 = Some(BtlTrailerConfig {
+        btl_version: Version::new(2, 1, 0),
+        protocol_version: None,
+        trusted_public_keys: vec![hex::encode(public_key)],
+    });
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    let trailer = BtlTrailer::parse(&loaded.images[0].btl).unwrap().unwrap();
+    assert_eq!(trailer.version, btl_trailer::TRAILER_VERSION_2);
+    assert_eq!(trailer.btl_length, 128);
+    assert_eq!(
+        trailer.fields,
+        vec![
+            TrailerField::BtlVersion(Version::new(2, 1, 0)),
+            TrailerField::ProductId(0x605),
+            TrailerField::Timestamp(1000),
+            TrailerField::ProtocolVersion(test.config.btl_version),
+            TrailerField::TrustedKeyIds(vec![crc32(&public_key)]),
+        ]
+    );
+    assert!(BtlTrailer::parse(&loaded.images[1].btl).unwrap().is_none());
+
+    let trailer_config = test.config.images[0].btl_trailer_v2.as_mut().unwrap();
+    trailer_config.trusted_public_keys = vec!["1234".to_string()];
+    assert!(process::load_firmware_images(&test.config, &test.config_dir, None).is_err());
+}
+
+#[test]
+#[serial]
+fn custom_header_layout() {
+    let mut test = IntegrationTest::new();
+    let layout: HeaderLayout = serde_json::from_str(
+        r#"{
+            "length": 40,
+            "fields": [
+                { "name": "product_id", "offset": 0, "width": 4 },
+                { "name": "node_id", "offset": 4, "width": 2 },
+                { "name": "major_version", "offset": 8, "width": 2 },
+                { "name": "minor_version", "offset": 10, "width": 2 },
+                { "name": "patch_version", "offset": 12, "width": 4 },
+                { "name": "length", "offset": 16, "width": 4 },
+                { "name": "timestamp", "offset": 24, "width": 8 },
+                { "name": "hw_revision_mask", "offset": 32, "width": 8 }
+            ]
+        }"#,
+    )
+    .unwrap();
+    test.config
+        .header_layouts
+        .insert("wide".to_string(), layout.clone());
+    test.config.images[0].header_layout = "wide".to_string();
+    test.config.images[0].version = Some(Version::new(1, 2, 3));
+    test.config.images.truncate(1);
+    // the 32-bit product ID covers the firmware ID of the `v1` layout
+    assert!(process::load_firmware_images(&test.config, &test.config_dir, None).is_err());
+    test.config.product_id = 0x0801_0605;
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    let package = AppPackage::from_loaded_firmware_images(loaded.config.product_id, &loaded);
+    assert_eq!(package.app[0].product_id, 0x0801_0605);
+    let app = &loaded.images[0].app;
+    let header = &app.data[4..44];
+    assert_eq!(&header[8..16], &[1, 0, 2, 0, 3, 0, 0, 0]);
+    assert_eq!(LittleEndian::read_u32(&header[16..20]), 128);
+    assert_eq!(LittleEndian::read_u64(&header[24..32]), 1000);
+    // reserved fields are left untouched
+    assert_eq!(&header[32..40], &app.data[36..44]);
+    assert_eq!(&header[32..40], &[37, 38, 39, 40, 41, 42, 43, 44]);
+
+    let options = InspectOptions {
+        header_layout: layout,
+        ..Default::default()
+    };
+    let mut fw = app.clone();
+    let report = inspect::inspect_firmware(&mut fw, InputFormat::IntelHex, &options).unwrap();
+    assert_eq!(report.images[0].version, Version::new(1, 2, 3));
+    assert_eq!(report.images[0].product_id, 0x0801_0605);
+    assert_eq!(report.images[0].timestamp, 1000);
+
+    let mut config = test.config.clone();
+    let layout = config.header_layouts.get_mut("wide").unwrap();
+    layout.fields[1].offset = 2;
+    assert!(process::load_firmware_images(&config, &test.config_dir, None).is_err());
+
+    let mut config = test.config.clone();
+    config.images[0].header_offset = 240;
+    assert!(process::load_firmware_images(&config, &test.config_dir, None).is_err());
+}
+
+#[test]
+#[serial]
+fn git_description_is_written_to_header() {
+    let mut test = IntegrationTest::new();
+    test.config.images[0].header_layout = "v1_git".to_string();
+    assert!(process::load_firmware_images(&test.config, &test.config_dir, None).is_err());
+
+    let repo = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let desc = retrieve_description(repo).unwrap();
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, Some(repo)).unwrap();
+    let app = &loaded.images[0].app;
+    assert_eq!(&app.data[4 + 28..4 + 31], &desc.commit_id.as_bytes()[..3]);
+    assert_eq!(app.data[4 + 31], desc.dirty as u8);
+    assert_eq!(
+        loaded.images[0]
+            .config
+            .version
+            .as_ref()
+            .unwrap()
+            .build
+            .as_str(),
+        desc.sha
+    );
+    // the reserved bytes of the v1 layout are left untouched
+    let app = &loaded.images[1].app;
+    assert_eq!(&app.data[4 + 28..4 + 32], &[33, 34, 35, 36]);
+
+    // the security version follows the git fields
+    test.config.images[0].header_layout = "v1_git_security".to_string();
+    test.config.images[0].security_version = Some(SecurityVersion::Fixed(3));
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, Some(repo)).unwrap();
+    let app = &loaded.images[0].app;
+    assert_eq!(&app.data[4 + 28..4 + 31], &desc.commit_id.as_bytes()[..3]);
+    assert_eq!(&app.data[4 + 32..4 + 34], &[3, 0]);
+}
+
+#[test]
+#[serial]
+fn security_version_is_enforced_by_bootloader() {
+    let mut test = IntegrationTest::new();
+    test.config.images.truncate(1);
+    test.config.images[0].security_version = Some(SecurityVersion::Fixed(3));
+    // the `v1` layout has no room for the security version
+    assert!(process::load_firmware_images(&test.config, &test.config_dir, None).is_err());
+
+    test.config.images[0].header_layout = "v1_security".to_string();
+    test.config.btl_version = 3;
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    let app = &loaded.images[0].app;
+    assert_eq!(&app.data[4 + 28..4 + 30], &[3, 0]);
+    let package = AppPackage::from_loaded_firmware_images(loaded.config.product_id, &loaded);
+    assert_eq!(package.app[0].security_version, Some(3));
+    let script = process::create_script(&loaded).unwrap();
+
+    let node = |security_version: u16| {
+        let mut node = VirtualNode::from_loaded_firmware(&loaded.config, &loaded.images[0]);
+        node.security_version = security_version;
+        node
+    };
+    let mut btl = VirtualBootloader::new(DDP_CMD_CODE);
+    btl.add_node(node(3));
+    script_executor::execute(&script, &mut btl, &mut LogListener).unwrap();
+
+    // a bootloader which already accepted a newer image rejects the rollback
+    let mut btl = VirtualBootloader::new(DDP_CMD_CODE);
+    btl.add_node(node(4));
+    assert!(script_executor::execute(&script, &mut btl, &mut LogListener).is_err());
+    let node_id = loaded.images[0].config.node_id;
+    assert_eq!(
+        btl.node(node_id).unwrap().status(),
+        ddp::STATUS_INCOMPATIBLE
+    );
+
+    // derived from the changelog, relative to the config file
+    let changelog = test.output_dir.join("CHANGELOG.md");
+    fs::write(
+        &changelog,
+        "## [Unreleased]\n### Security\n\n## [1.1.0]\n### Security\n- Fix\n\n## [1.0.0]\n### Added\n",
+    )
+    .unwrap();
+    test.config.images[0].security_version = Some(SecurityVersion::Changelog {
+        changelog: "out/CHANGELOG.md".to_string(),
+    });
+    process::generate(GenerateOptions {
+        config: test.config.clone(),
+        output_dir: test.output_dir.clone(),
+        config_dir: test.config_dir.clone(),
+        repo_dir: None,
+        emit_digests: false,
+    })
+    .unwrap();
+    let info = process::Info::load_from_file(&test.output_dir.join("info.json")).unwrap();
+    assert_eq!(info.images[0].security_version, Some(1));
+    let options = InspectOptions {
+        header_layout: HeaderLayout::v1_security(),
+        ..Default::default()
+    };
+    let report =
+        inspect::inspect_file(&test.output_dir.join(&info.images[0].app_file), &options).unwrap();
+    assert_eq!(report.images[0].security_version, Some(1));
+}
+
+#[test]
+#[serial]
+fn metadata_is_written_before_crc() {
+    let mut test = IntegrationTest::new();
+    std::env::set_var("MERGE_TOOL_TEST_SERIAL", "SN-0042");
+    test.config.images[0].metadata = serde_json::from_str(
+        r#"[
+            { "offset": 128, "type": "u32", "value": 4660 },
+            { "address": 43912, "type": "string", "size": 8, "env": "MERGE_TOOL_TEST_SERIAL" },
+            { "offset": 144, "type": "version" },
+            { "offset": 152, "type": "timestamp" },
+            { "offset": 200, "type": "bytes", "value": "deadbeef" }
+        ]"#,
+    )
+    .unwrap();
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    let app = &loaded.images[0].app;
+    assert_eq!(LittleEndian::read_u32(&app.data[128..132]), 4660);
+    assert_eq!(&app.data[136..144], b"SN-0042\0");
+    assert_eq!(&app.data[144..152], &[3, 0, 5, 0, 4, 0, 0, 0]);
+    assert_eq!(LittleEndian::read_u64(&app.data[152..160]), 1000);
+    assert_eq!(&app.data[200..204], &[0xDE, 0xAD, 0xBE, 0xEF]);
+
+    // the image length grows to include the meta-data
+    let mut fw = app.clone();
+    let report =
+        inspect::inspect_firmware(&mut fw, InputFormat::IntelHex, &Default::default()).unwrap();
+    assert_eq!(report.images[0].length, 256);
+    assert!(report.images[0].crc_valid());
+
+    let mut config = test.config.clone();
+    config.images[0].metadata[0].offset = Some(8);
+    assert!(process::load_firmware_images(&config, &test.config_dir, None).is_err());
+    let mut config = test.config.clone();
+    config.images[0].metadata[0].offset = Some(138);
+    assert!(process::load_firmware_images(&config, &test.config_dir, None).is_err());
+    let mut config = test.config.clone();
+    config.images[0].metadata[0].offset = Some(254);
+    assert!(process::load_firmware_images(&config, &test.config_dir, None).is_err());
+}
+
+#[test]
+#[serial]
+fn extract_header_and_metadata() {
+    let mut test = IntegrationTest::new();
+    test.config.images[0].metadata = serde_json::from_str(
+        r#"[
+            { "name": "hw_revision", "offset": 128, "type": "u16", "value": 7 },
+            { "name": "serial_prefix", "offset": 136, "type": "string", "size": 8, "value": "SN-" },
+            { "offset": 144, "type": "u32", "value": 1 }
+        ]"#,
+    )
+    .unwrap();
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    process::save_hex_and_bin_images(&loaded, &test.output_dir).unwrap();
+
+    let app_path = test.output_dir.join("app_f1.hex");
+    let extracted = extract::extract_with_config(&app_path, &test.config, Some(0)).unwrap();
+    let text = |x: &str| ExtractedValue::Text(x.to_string());
+    assert_eq!(
+        extracted.get("product_id"),
+        Some(&ExtractedValue::Number(0x605))
+    );
+    assert_eq!(extracted.get("node_id"), Some(&ExtractedValue::Number(1)));
+    assert_eq!(extracted.get("version"), Some(&text("3.5.4")));
+    assert_eq!(extracted.get("length"), Some(&ExtractedValue::Number(192)));
+    assert_eq!(
+        extracted.get("timestamp"),
+        Some(&ExtractedValue::Number(1000))
+    );
+    assert_eq!(
+        extracted.get("hw_revision"),
+        Some(&ExtractedValue::Number(7))
+    );
+    assert_eq!(extracted.get("serial_prefix"), Some(&text("SN-")));
+    assert_eq!(extracted.fields.len(), 12);
+
+    let header = extracted.to_c_header("FW_");
+    assert!(header.contains("#define FW_PRODUCT_ID 1541U\n"));
+    assert!(header.contains("#define FW_SERIAL_PREFIX \"SN-\"\n"));
+    let env = extracted.to_env("");
+    assert!(env.contains("VERSION=\"3.5.4\"\n"));
+
+    // compiler output without CRC, header at the default offset
+    let extracted = extract::extract(
+        Path::new("tests/app_f1.hex"),
+        &Default::default(),
+        None,
+        &[],
+    )
+    .unwrap();
+    assert_eq!(
+        extracted.get("product_id"),
+        Some(&ExtractedValue::Number(0x605))
+    );
+    assert_eq!(extracted.get("version"), Some(&text("3.5.4")));
+
+    assert!(extract::extract_with_config(&app_path, &test.config, Some(2)).is_err());
+}
+
+#[test]
+#[serial]
+fn srecord_header_and_entry_point_are_preserved() {
+    let mut test = IntegrationTest::new();
+    let mut data: Vec<_> = (1u8..0x50).collect();
+    data[4 + 2] = 1; // node id
+    let app = srecord::serialize_file(
+        false,
+        &AddressRange::new(0xAB00, 0xAB4F),
+        &data,
+        Some(b"app_f1"),
+        Some(0xAB04),
+        &RecordOptions::default(),
+    );
+    let btl = srecord::serialize_file(
+        false,
+        &AddressRange::new(0xAA00, 0xAA4F),
+        &data,
+        Some(b"btl_f1"),
+        Some(0xAA00),
+        &RecordOptions::default(),
+    );
+    let app_path = test.output_dir.join("app_in.s19");
+    let btl_path = test.output_dir.join("btl_in.s19");
+    fs::write(&app_path, app).unwrap();
+    fs::write(&btl_path, btl).unwrap();
+    test.config.images.truncate(1);
+    test.config.images[0].hex_file_format = Some(HexFileFormat::SRecord);
+    test.config.images[0].app_path = app_path.to_str().unwrap().to_string();
+    test.config.images[0].btl_path = btl_path.to_str().unwrap().to_string();
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    process::save_hex_and_bin_images(&loaded, &test.output_dir).unwrap();
+    let fws = process::merge_all(&loaded).unwrap();
+    process::save_merged_firmware_images(&fws, &test.output_dir).unwrap();
+
+    let app = fs::read_to_string(test.output_dir.join("app_f1.s37")).unwrap();
+    assert!(app.starts_with("S00900006170705F6631"));
+    assert!(app.lines().nth(1).unwrap().starts_with("S113AB00"));
+    assert_eq!(app.lines().last(), Some("S903AB044D"));
+    let merged = fs::read_to_string(test.output_dir.join("merged_f1.s37")).unwrap();
+    assert!(merged.starts_with("S00900006170705F6631"));
+    assert_eq!(merged.lines().last(), Some("S903AB044D"));
+}
+
+#[test]
+#[serial]
+fn intel_hex_entry_point_and_errors() {
+    let mut test = IntegrationTest::new();
+    let app_path = test.output_dir.join("app_in.hex");
+    let mut app = fs::read_to_string("tests/app_f1.hex").unwrap();
+    app = app.replace(":00000001FF", ":040000050000AB0448\n:00000001FF");
+    fs::write(&app_path, &app).unwrap();
+    test.config.images.truncate(1);
+    test.config.images[0].app_path = app_path.to_str().unwrap().to_string();
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    assert_eq!(loaded.images[0].app.entry_point, Some(0xAB04));
+    let fws = process::merge_all(&loaded).unwrap();
+    process::save_merged_firmware_images(&fws, &test.output_dir).unwrap();
+    let merged = fs::read_to_string(test.output_dir.join("merged_f1.hex")).unwrap();
+    assert!(merged.ends_with(":040000050000AB0448\n:00000001FF"));
+
+    // corrupt the checksum of the second line
+    let mut lines: Vec<_> = app.lines().map(|x| x.to_string()).collect();
+    let last = lines[1].pop().unwrap();
+    lines[1].push(if last == '0' { '1' } else { '0' });
+    fs::write(&app_path, lines.join("\n")).unwrap();
+    let err = process::load_firmware_images(&test.config, &test.config_dir, None)
+        .err()
+        .unwrap();
+    let msg = err.to_string();
+    assert!(msg.contains("app_in.hex:2: checksum is"), "{}", msg);
+}
+
+#[test]
+#[serial]
+fn app_package_contains_written_pages() {
+    let mut test = IntegrationTest::new();
+    test.config.images.truncate(1);
+    test.config.images[0].metadata =
+        serde_json::from_str(r#"[{ "offset": 200, "type": "bytes", "value": "ffff" }]"#).unwrap();
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    // the explicitly written 0xFF bytes extend the image
+    assert_eq!(loaded.images[0].app.image_length(), 256);
+    let package = AppPackage::from_loaded_firmware_images(loaded.config.product_id, &loaded);
+    let sections: Vec<_> = package.app[0]
+        .image
+        .iter()
+        .map(|x| (x.offset(), x.data().len()))
+        .collect();
+    assert_eq!(sections, vec![(0xAB00, 128), (0xABC0, 64)]);
+
+    process::save_app_package(&package, &test.output_dir, &loaded.app_package_file_name).unwrap();
+    let report = inspect::inspect_file(
+        &test.output_dir.join(&loaded.app_package_file_name),
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(report.images[0].length, 256);
+    assert!(report.images[0].crc_valid());
+}
+
+fn app_region(name: &str, path: &str, begin: u64) -> AppRegion {
+    AppRegion {
+        name: name.to_string(),
+        address: AddressRange::new(begin, begin + 0x400),
+        path: Some(path.to_string()),
+        format: None,
+        base_address: None,
+        page_size: Some(256),
+        include_in_script: true,
+    }
+}
+
+#[test]
+#[serial]
+fn app_regions() {
+    let mut test = IntegrationTest::new();
+    let region_path = test.output_dir.join("qspi.hex");
+    let region_data: Vec<_> = (0..300u32).map(|x| (x * 7) as u8).collect();
+    save_hex(
+        region_path.to_str().unwrap(),
+        &region_data,
+        &AddressRange::new(0x9000_0000, 0x9000_0000 + 300),
+    );
+    test.config.images.truncate(1);
+    test.config.images[0].app_regions.push(app_region(
+        "qspi",
+        region_path.to_str().unwrap(),
+        0x9000_0000,
+    ));
+    test.config.images[0].validate_app_regions().unwrap();
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    let fw = &loaded.images[0];
+    assert_eq!(fw.regions.len(), 1);
+    assert_eq!(fw.regions[0].fw.data[..300], region_data[..]);
+
+    // the region is programmed in its own address space
+    let script = process::create_script(&loaded).unwrap();
+    let mut btl = VirtualBootloader::new(DDP_CMD_CODE);
+    btl.add_node(VirtualNode::from_loaded_firmware(&loaded.config, fw));
+    script_executor::execute(&script, &mut btl, &mut LogListener).unwrap();
+    let node = btl.node(fw.config.node_id).unwrap();
+    assert_eq!(node.state(), ddp::STATE_NOT_IN_BTL);
+    assert_eq!(node.image().data, fw.app.data);
+    assert_eq!(node.region(1).unwrap().data[..300], region_data[..]);
+
+    let package = AppPackage::from_loaded_firmware_images(loaded.config.product_id, &loaded);
+    let sections: Vec<_> = package.app[0]
+        .image
+        .iter()
+        .filter(|x| x.region() == Some("qspi"))
+        .map(|x| (x.offset(), x.data().len()))
+        .collect();
+    assert_eq!(sections, vec![(0x9000_0000, 512)]);
+    process::save_app_package(&package, &test.output_dir, &loaded.app_package_file_name).unwrap();
+
+    // the region is written to separate files and verified
+    let fws = process::merge_all(&loaded).unwrap();
+    process::save_merged_firmware_images(&fws, &test.output_dir).unwrap();
+    process::save_hex_and_bin_images(&loaded, &test.output_dir).unwrap();
+    let info = process::generate_info(&loaded, &test.output_dir).unwrap();
+    process::save_info(&info, &test.output_dir).unwrap();
+    assert_eq!(info.images[0].regions[0].file, "app_f1_qspi.hex");
+    assert!(test.output_dir.join("app_f1_qspi.bin").exists());
+    let report =
+        verify::verify_info(&test.output_dir.join("info.json"), &Default::default()).unwrap();
+    assert!(report.passed, "{}", report);
+
+    // overlapping regions and duplicate names are rejected
+    let mut config = test.config.images[0].clone();
+    config
+        .app_regions
+        .push(app_region("qspi", "x.hex", 0x8000_0000));
+    assert!(config.validate_app_regions().is_err());
+    config.app_regions[1].name = "flash".to_string();
+    config.validate_app_regions().unwrap();
+    config.app_regions[1].address = AddressRange::new(0xA000, 0xAB10);
+    assert!(config.validate_app_regions().is_err());
+}
+
+fn extra_input(name: &str, path: &Path, begin: u64) -> ExtraInput {
+    ExtraInput {
+        name: name.to_string(),
+        path: path.to_str().unwrap().to_string(),
+        address: AddressRange::new(begin, begin + 0x40),
+        format: None,
+        base_address: None,
+        fill: None,
+        crc: false,
+    }
+}
+
+#[test]
+#[serial]
+fn extra_inputs_are_merged() {
+    let mut test = IntegrationTest::new();
+    test.config.images.truncate(1);
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    let script = process::create_script(&loaded).unwrap();
+
+    let config_path = test.output_dir.join("config.hex");
+    save_hex(
+        config_path.to_str().unwrap(),
+        &[1, 2, 3, 4],
+        &AddressRange::new(0xAC00, 0xAC04),
+    );
+    let calibration_path = test.output_dir.join("calibration.bin");
+    fs::write(&calibration_path, [5, 6, 7, 8]).unwrap();
+    let mut config_page = extra_input("config", &config_path, 0xAC00);
+    config_page.fill = Some(0);
+    config_page.crc = true;
+    let images = &mut test.config.images[0];
+    images.extra_inputs.push(config_page);
+    images
+        .extra_inputs
+        .push(extra_input("calibration", &calibration_path, 0xA900));
+    images.validate_extra_inputs().unwrap();
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    let fws = process::merge_all(&loaded).unwrap();
+    let merged = &fws.images[0].0;
+    assert_eq!(merged.range, AddressRange::new(0xA900, 0xAC40));
+    assert_eq!(merged.data[..4], [5, 6, 7, 8]);
+    assert_eq!(merged.data[4], 0xFF);
+    let config_page = &merged.data[0x300..0x340];
+    assert_eq!(config_page[..5], [1, 2, 3, 4, 0]);
+    assert_eq!(
+        LittleEndian::read_u32(&config_page[0x3C..]),
+        crc32(&config_page[..0x3C])
+    );
+    assert_eq!(merged.data[0x200..0x300], loaded.images[0].app.data[..]);
+
+    // the script is not affected by the extra inputs
+    assert_eq!(
+        process::create_script(&loaded).unwrap().serialize(),
+        script.serialize()
+    );
+
+    process::save_merged_firmware_images(&fws, &test.output_dir).unwrap();
+    process::save_hex_and_bin_images(&loaded, &test.output_dir).unwrap();
+    let info = process::generate_info(&loaded, &test.output_dir).unwrap();
+    process::save_info(&info, &test.output_dir).unwrap();
+    let package = AppPackage::from_loaded_firmware_images(loaded.config.product_id, &loaded);
+    process::save_app_package(&package, &test.output_dir, &loaded.app_package_file_name).unwrap();
+    let report =
+        verify::verify_info(&test.output_dir.join("info.json"), &Default::default()).unwrap();
+    assert!(report.passed, "{}", report);
+    assert!(report
+        .checks
+        .iter()
+        .any(|x| x.name == "crc" && x.message.starts_with("extra input `config`")));
+
+    // overlaps are rejected
+    let images = &mut test.config.images[0];
+    images.extra_inputs[1].address = AddressRange::new(0xA900, 0xAA40);
+    assert!(images.validate_extra_inputs().is_err());
+    images.extra_inputs[1].address = AddressRange::new(0xAC20, 0xAC60);
+    assert!(images.validate_extra_inputs().is_err());
+}
+
+#[test]
+#[serial]
+fn hex_data_outside_of_address_range() {
+    let mut test = IntegrationTest::new();
+    let app_path = test.output_dir.join("app_in.hex");
+    let app = fs::read_to_string("tests/app_f1.hex").unwrap();
+    let app = app.replace(":00000001FF", ":02AC1000AABBDD\n:00000001FF");
+    fs::write(&app_path, &app).unwrap();
+    test.config.images.truncate(1);
+    test.config.images[0].app_path = app_path.to_str().unwrap().to_string();
+
+    let err = process::load_firmware_images(&test.config, &test.config_dir, None)
+        .err()
+        .unwrap();
+    let msg = err.to_string();
+    assert!(
+        msg.ends_with("app_in.hex: data outside of the address range at 0xAC10..0xAC12"),
+        "{}",
+        msg
+    );
+
+    test.config.images[0].address_check = AddressCheck::Warn;
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    assert_eq!(loaded.images[0].app.range.end, 0xAC00);
+
+    // a second record overwrites 0xAB41 with a different value
+    let app = app.replace(":00000001FF", ":02AB40004100D2\n:00000001FF");
+    fs::write(&app_path, &app).unwrap();
+    test.config.images[0].address_check = AddressCheck::Strict;
+    test.config.images[0].app_address = AddressRange::new(0xAB00, 0xAD00);
+    let err = process::load_firmware_images(&test.config, &test.config_dir, None)
+        .err()
+        .unwrap();
+    let msg = err.to_string();
+    assert!(
+        msg.ends_with("app_in.hex: records write different values to 0xAB41..0xAB42"),
+        "{}",
+        msg
+    );
+    test.config.images[0].address_check = AddressCheck::Ignore;
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    assert_eq!(loaded.images[0].app.data[0x41], 0);
+}
+
+/// A little endian 32 bit ELF file with a single loadable segment.
+fn elf32(paddr: u32, data: &[u8]) -> Vec<u8> {
+    let mut ret = vec![0; 0x54];
+    ret[..7].copy_from_slice(&[0x7F, b'E', b'L', b'F', 1, 1, 1]);
+    LittleEndian::write_u32(&mut ret[0x18..], paddr);
+    LittleEndian::write_u32(&mut ret[0x1C..], 0x34);
+    LittleEndian::write_u16(&mut ret[0x2A..], 0x20);
+    LittleEndian::write_u16(&mut ret[0x2C..], 1);
+    LittleEndian::write_u32(&mut ret[0x34..], 1); // PT_LOAD
+    LittleEndian::write_u32(&mut ret[0x38..], 0x54);
+    LittleEndian::write_u32(&mut ret[0x3C..], 0x2000_0000);
+    LittleEndian::write_u32(&mut ret[0x40..], paddr);
+    LittleEndian::write_u32(&mut ret[0x44..], data.len() as u32);
+    LittleEndian::write_u32(&mut ret[0x48..], data.len() as u32);
+    ret.extend(data);
+    ret
+}
+
+#[test]
+#[serial]
+fn elf_and_binary_inputs_with_ti_txt_output() {
+    let mut test = IntegrationTest::new();
+    test.config.images.truncate(1);
+    let expected = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+
+    let mut data: Vec<_> = (1u8..0x50).collect();
+    let btl_path = test.output_dir.join("btl_in.bin");
+    fs::write(&btl_path, &data).unwrap();
+    // firmware id 1, version 3.5.4
+    data[4..16].copy_from_slice(&[5, 6, 1, 8, 3, 0, 5, 0, 4, 0, 0, 0]);
+    let app_path = test.output_dir.join("app_in.elf");
+    fs::write(&app_path, elf32(0xAB00, &data)).unwrap();
+
+    let fw_config = &mut test.config.images[0];
+    fw_config.output_formats = vec![HexFileFormat::TiTxt.into()];
+    fw_config.app_format = Some(HexFileFormat::Elf);
+    fw_config.btl_format = Some(HexFileFormat::Binary);
+    fw_config.app_path = app_path.to_str().unwrap().to_string();
+    fw_config.btl_path = btl_path.to_str().unwrap().to_string();
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    assert_eq!(loaded.images[0].app.data, expected.images[0].app.data);
+    assert_eq!(loaded.images[0].btl.data, expected.images[0].btl.data);
+    assert_eq!(loaded.images[0].app.entry_point, Some(0xAB00));
+    process::save_hex_and_bin_images(&loaded, &test.output_dir).unwrap();
+    let fws = process::merge_all(&loaded).unwrap();
+    process::save_merged_firmware_images(&fws, &test.output_dir).unwrap();
+
+    let merged = fs::read_to_string(test.output_dir.join("merged_f1.txt")).unwrap();
+    assert!(merged.starts_with("@AA00\n01 02 03"));
+    let lines = merged.lines().map(|x| x.to_string());
+    let parsed = ti_txt::parse(false, &fws.images[0].0.range, lines).unwrap();
+    assert_eq!(parsed, fws.images[0].0.data);
+
+    // a binary bootloader placed behind the start of its address range
+    test.config.images[0].btl_base_address = Some(0xAA40);
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    assert_eq!(loaded.images[0].btl.data[0x40..0x44], [1, 2, 3, 4]);
+    assert!(loaded.images[0].btl.data[..0x40].iter().all(|x| *x == 0xFF));
+}
+
+#[test]
+#[serial]
+fn detect_input_formats_and_write_multiple_outputs() {
+    let mut test = IntegrationTest::new();
+    test.config.images.truncate(1);
+    let btl_path = test.output_dir.join("btl_in.s37");
+    let btl = Firmware::load_from_file(
+        Path::new("tests/btl_f1.hex"),
+        &HexFileFormat::IntelHex,
+        &test.config.images[0].device_config,
+        &test.config.images[0].btl_address,
+        0,
+        AddressCheck::Strict,
+    )
+    .unwrap();
+    btl.write_to_file(&btl_path, &HexFileFormat::SRecord.into())
+        .unwrap();
+
+    let fw_config = &mut test.config.images[0];
+    fw_config.hex_file_format = None;
+    fw_config.output_formats = vec![
+        HexFileFormat::IntelHex.into(),
+        HexFileFormat::SRecord.into(),
+    ];
+    fw_config.btl_path = btl_path.to_str().unwrap().to_string();
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    assert_eq!(loaded.images[0].btl.data, btl.data);
+
+    let script = process::create_script(&loaded).unwrap();
+    process::save_script(&script, &loaded, &test.output_dir).unwrap();
+    let fws = process::merge_all(&loaded).unwrap();
+    process::save_merged_firmware_images(&fws, &test.output_dir).unwrap();
+    process::save_hex_and_bin_images(&loaded, &test.output_dir).unwrap();
+    let info = process::generate_info(&loaded, &test.output_dir).unwrap();
+    process::save_info(&info, &test.output_dir).unwrap();
+    let package = AppPackage::from_loaded_firmware_images(loaded.config.product_id, &loaded);
+    process::save_app_package(&package, &test.output_dir, &loaded.app_package_file_name).unwrap();
+    for name in ["app_f1", "btl_f1", "merged_f1"] {
+        let hex = test.output_dir.join(format!("{}.hex", name));
+        let s37 = test.output_dir.join(format!("{}.s37", name));
+        assert!(hex.exists() && s37.exists(), "{}", name);
+    }
+    assert_eq!(info.images[0].hex_file_format, HexFileFormat::IntelHex);
+    assert_eq!(
+        info.images[0].additional_files[0].merged_file,
+        "merged_f1.s37"
+    );
+    assert!(info.files.contains(&"btl_f1.s37".to_string()));
+
+    let info_path = test.output_dir.join("info.json");
+    let report = verify::verify_info(&info_path, &InspectOptions::default()).unwrap();
+    assert!(report.passed, "{}", report);
+    assert!(report
+        .checks
+        .iter()
+        .any(|x| x.file == "merged_f1.hex <-> merged_f1.s37"));
+
+    let bundle_dir = test.output_dir.join("bundle");
+    process::bundle(&info_path, &bundle_dir, true).unwrap();
+    assert!(bundle_dir.join("app_f1_3.5.4.s37").exists());
+
+    // content which matches none of the formats
+    fs::write(&btl_path, "garbage").unwrap();
+    let err = process::load_firmware_images(&test.config, &test.config_dir, None)
+        .err()
+        .unwrap();
+    assert!(
+        err.to_string().contains("Cannot detect the format"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+#[serial]
+fn output_record_options() {
+    let mut test = IntegrationTest::new();
+    test.config.images.truncate(1);
+    let outputs = r#"[
+        "SRecord",
+        { "format": "IntelHex", "record_length": 32, "line_ending": "CrLf" }
+    ]"#;
+    test.config.images[0].output_formats = serde_json::from_str(outputs).unwrap();
+    assert_eq!(
+        test.config.images[0].output_formats[0],
+        HexFileFormat::SRecord.into()
+    );
+    test.config.images[0].validate_output_formats().unwrap();
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    process::save_hex_and_bin_images(&loaded, &test.output_dir).unwrap();
+    let app = fs::read_to_string(test.output_dir.join("app_f1.hex")).unwrap();
+    let records: Vec<_> = app.split("\r\n").collect();
+    assert!(records[0].starts_with(":20AB0000"));
+    assert_eq!(records.last(), Some(&":00000001FF"));
+    let app = fs::read_to_string(test.output_dir.join("app_f1.s37")).unwrap();
+    assert!(!app.contains('\r'));
+
+    test.config.images[0].output_formats[1]
+        .records
+        .record_length = 251;
+    let err = test.config.images[0].validate_output_formats().unwrap_err();
+    let msg = err.to_string();
+    assert!(msg.contains("Invalid record length 251"), "{}", msg);
+}
+
+#[test]
+#[serial]
+fn inspect_merged_image_and_app_package() {
+    let mut test = IntegrationTest::new();
+    test.config.images[0].btl_trailer = true;
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    let fws = process::merge_all(&loaded).unwrap();
+    process::save_merged_firmware_images(&fws, &test.output_dir).unwrap();
+    let package = AppPackage::from_loaded_firmware_images(loaded.config.product_id, &loaded);
+    process::save_app_package(&package, &test.output_dir, &loaded.app_package_file_name).unwrap();
+
+    let options = InspectOptions::default();
+    for file in ["merged_f1.hex", "merged_f1.bin"] {
+        let options = InspectOptions {
+            base_address: 0xAA00,
+            ..Default::default()
+        };
+        let report = inspect::inspect_file(&test.output_dir.join(file), &options).unwrap();
+        assert_eq!(report.images.len(), 1, "{}", file);
+        let image = &report.images[0];
+        assert_eq!(image.address, 0xAB00);
+        assert_eq!(image.product_id, 0x605);
+        assert_eq!(image.node_id, 1);
+        assert_eq!(image.version.to_string(), "3.5.4");
+        assert_eq!(image.timestamp, 1000);
+        assert_eq!(image.stored_crc, loaded.images[0].load_crc());
+        assert!(image.crc_valid());
+        assert_eq!(image.signature, SignatureStatus::Unsigned);
+
+        let trailer = report.btl_trailer.as_ref().unwrap();
+        assert_eq!(trailer.end_address, 0xAB00);
+        assert!(trailer.error.is_none());
+        assert_eq!(trailer.trailer.as_ref().unwrap().btl_length, 128);
+    }
+
+    let report = inspect::inspect_file(
+        &test.output_dir.join(&loaded.app_package_file_name),
+        &options,
+    )
+    .unwrap();
+    assert_eq!(report.format, InputFormat::AppPackage);
+    assert_eq!(report.images.len(), 2);
+    for (image, fw) in report.images.iter().zip(&loaded.images) {
+        assert_eq!(image.node_id, fw.config.node_id);
+        assert!(image.crc_valid());
+        assert_eq!(image.package.as_ref().unwrap().crc, image.stored_crc);
+    }
+    assert!(report.btl_trailer.is_none());
+}
+
+#[test]
+#[serial]
+fn verify_generated_files() {
+    let mut test = IntegrationTest::new();
+    test.config.images[0].btl_trailer = true;
+
+    let loaded = process::load_firmware_images(&test.config, &test.config_dir, None).unwrap();
+    let fws = process::merge_all(&loaded).unwrap();
+    process::save_merged_firmware_images(&fws, &test.output_dir).unwrap();
+    process::save_hex_and_bin_images(&loaded, &test.output_dir).unwrap();
+    let info = process::generate_info(&loaded, &test.output_dir).unwrap();
+    process::save_info(&info, &test.output_dir).unwrap();
+    let package = AppPackage::from_loaded_firmware_images(loaded.config.product_id, &loaded);
+    process::save_app_package(&package, &test.output_dir, &loaded.app_package_file_name).unwrap();
+
+    let info_path = test.output_dir.join("info.json");
+    let options = InspectOptions::default();
+    let report = verify::verify_info(&info_path, &options).unwrap();
+    assert!(report.passed, "{}", report);
+    assert_eq!(report.count(CheckStatus::Failed), 0);
+    assert!(report.checks.iter().any(|x| x.name == "trailer"));
+    assert!(report.checks.iter().any(|x| x.name == "consistency"));
+
+    // unsigned images where info.json expects signed ones
+    let mut signed_info = info.clone();
+    signed_info.images[0].signature_type = SignatureType::Ed25519;
+    process::save_info(&signed_info, &test.output_dir).unwrap();
+    let report = verify::verify_info(&info_path, &options).unwrap();
+    let failed: Vec<_> = report
+        .checks
+        .iter()
+        .filter(|x| x.status == CheckStatus::Failed)
+        .map(|x| x.name)
+        .collect();
+    assert_eq!(failed, vec!["signature"; 3]);
+    process::save_info(&info, &test.output_dir).unwrap();
+
+    // corrupt the application in the merged binary
+    let merged_bin = test.output_dir.join("merged_f1.bin");
+    let mut data = fs::read(&merged_bin).unwrap();
+    data[256 + 40] ^= 0xFF;
+    fs::write(&merged_bin, data).unwrap();
+
+    let report = verify::verify_info(&info_path, &options).unwrap();
+    assert!(!report.passed);
+    let failed: Vec<_> = report
+        .checks
+        .iter()
+        .filter(|x| x.status == CheckStatus::Failed)
+        .map(|x| (x.file.as_str(), x.name))
+        .collect();
+    assert_eq!(
+        failed,
+        vec![
+            ("merged_f1.bin", "content"),
+            ("merged_f1.hex <-> merged_f1.bin", "consistency")
+        ]
+    );
+}
@@ -0,0 +1,11 @@
+#!/bin/sh
+# Mock of an external signer for the tests: reads the hex encoded digest from stdin and
+# writes the hex encoded Ed25519 signature made with the hex encoded private key seed
+# given as first argument.
+set -e
+dir=$(mktemp -d)
+trap 'rm -rf "$dir"' EXIT
+echo "302e020100300506032b657004220420$1" | xxd -r -p > "$dir/key.der"
+openssl pkey -inform DER -in "$dir/key.der" -out "$dir/key.pem"
+xxd -r -p > "$dir/digest.bin"
+openssl pkeyutl -sign -rawin -inkey "$dir/key.pem" -in "$dir/digest.bin" | xxd -p -c 64